@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use zap::env::symbols;
+use zap::prelude::{error_msg, Env, Error, Reader, Result, SandboxEnv, Value};
+
+// API docs of a codebase, one markdown page per namespace. The files are read in a scratch env
+// without being evaluated, so documenting code never runs it.
+
+struct Entry {
+    name: String,
+    arglist: Option<String>,
+    doc: Option<String>,
+    file: String,
+}
+
+// The defs of each namespace, the top ones under None
+type Namespaces = BTreeMap<Option<String>, Vec<Entry>>;
+
+fn symbol_name(val: &Value, env: &SandboxEnv) -> Option<String> {
+    match val {
+        Value::Symbol(s) => env.get_symbol(*s).ok().map(|s| s.to_string()),
+        _ => None,
+    }
+}
+
+// The docstring and params following the name of a def
+fn signature(form: &[Value], env: &mut SandboxEnv) -> (Option<String>, Option<String>) {
+    let rest = match form {
+        [Value::Symbol(symbols::DEFINE), _, Value::List(value)] => match &value[..] {
+            // A named fn
+            [Value::Symbol(symbols::FN), Value::Symbol(_), rest @ ..] => rest,
+            [Value::Symbol(symbols::FN), rest @ ..] => rest,
+            _ => return (None, None),
+        },
+        [Value::Symbol(symbols::DEFN | symbols::DEFMACRO), _, rest @ ..] => rest,
+        _ => return (None, None),
+    };
+    let (doc, rest) = match rest {
+        [Value::Str(doc), rest @ ..] => (Some(doc.to_string()), rest),
+        rest => (None, rest),
+    };
+    let arglist = match rest.first() {
+        Some(params @ Value::List(_)) => Some(params.pr_str(env)),
+        _ => None,
+    };
+    (doc, arglist)
+}
+
+fn collect_source(src: &str, file: &str, namespaces: &mut Namespaces) -> Result<()> {
+    let mut env = SandboxEnv::default().with_core(false);
+    let mut reader = Reader::new();
+    reader.tokenize(src);
+    reader.flush_token();
+
+    // Each file starts at the top
+    let mut ns = None;
+    while let Some(form) = reader.read_ast(&mut env)? {
+        let Value::List(form) = form else {
+            continue;
+        };
+        match &form[..] {
+            [Value::Symbol(symbols::NS), name] => ns = symbol_name(name, &env),
+            [Value::Symbol(symbols::DEFINE | symbols::DEFN | symbols::DEFMACRO), name, ..] => {
+                let Some(name) = symbol_name(name, &env) else {
+                    continue;
+                };
+                let (doc, arglist) = signature(&form, &mut env);
+                namespaces.entry(ns.clone()).or_default().push(Entry {
+                    name,
+                    arglist,
+                    doc,
+                    file: file.to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    if reader.is_pending() {
+        return Err(Error::reader("Unexpected end of input."));
+    }
+    Ok(())
+}
+
+fn render_entries(entries: &[Entry], heading: &str, out: &mut String) {
+    for entry in entries {
+        match &entry.arglist {
+            Some(arglist) if arglist.len() > 2 => out.push_str(&format!(
+                "\n{} `({} {})`\n",
+                heading,
+                entry.name,
+                &arglist[1..arglist.len() - 1]
+            )),
+            Some(_) => out.push_str(&format!("\n{} `({})`\n", heading, entry.name)),
+            None => out.push_str(&format!("\n{} `{}`\n", heading, entry.name)),
+        }
+        if let Some(doc) = &entry.doc {
+            out.push('\n');
+            out.push_str(doc);
+            out.push('\n');
+        }
+        out.push_str(&format!("\n*Defined in {}*\n", entry.file));
+    }
+}
+
+// The pages to write, by file name
+fn render(namespaces: &Namespaces) -> Vec<(String, String)> {
+    let mut index = String::from("# API\n");
+    let named: Vec<&String> = namespaces.keys().flatten().collect();
+    if !named.is_empty() {
+        index.push_str("\n## Namespaces\n\n");
+        for ns in &named {
+            index.push_str(&format!("- [{}]({}.md)\n", ns, ns));
+        }
+    }
+    if let Some(entries) = namespaces.get(&None) {
+        index.push_str("\n## Globals\n");
+        render_entries(entries, "###", &mut index);
+    }
+
+    let mut pages = vec![("index.md".to_string(), index)];
+    for (ns, entries) in namespaces {
+        if let Some(ns) = ns {
+            let mut page = format!("# {}\n", ns);
+            render_entries(entries, "##", &mut page);
+            pages.push((format!("{}.md", ns), page));
+        }
+    }
+    pages
+}
+
+fn zap_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|err| error_msg(&format!("Cannot read '{}': {}", dir.display(), err)))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            zap_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "zap") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+pub fn run(dir: &str, out: &str) -> Result<()> {
+    let mut files = Vec::new();
+    zap_files(Path::new(dir), &mut files)?;
+    files.sort();
+
+    let mut namespaces = Namespaces::new();
+    for path in &files {
+        let src = std::fs::read_to_string(path)
+            .map_err(|err| error_msg(&format!("Cannot read '{}': {}", path.display(), err)))?;
+        let file = path.strip_prefix(dir).unwrap_or(path).display().to_string();
+        collect_source(&src, &file, &mut namespaces)
+            .map_err(|err| error_msg(&format!("{}: {}", file, err)))?;
+    }
+
+    std::fs::create_dir_all(out)
+        .map_err(|err| error_msg(&format!("Cannot create '{}': {}", out, err)))?;
+    for (name, page) in render(&namespaces) {
+        let path = Path::new(out).join(name);
+        std::fs::write(&path, page)
+            .map_err(|err| error_msg(&format!("Cannot write '{}': {}", path.display(), err)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn render() {
+        let mut namespaces = super::Namespaces::new();
+        super::collect_source(
+            "(def version 1)\n(ns math)\n(defn add \"Adds two numbers\" (a b) (+ a b))\n(def zero (fn zero () 0))\n(println \"(def x 1)\")",
+            "math.zap",
+            &mut namespaces,
+        )
+        .unwrap();
+
+        let pages = super::render(&namespaces);
+        assert_eq!(
+            pages,
+            vec![
+                (
+                    "index.md".to_string(),
+                    "# API\n\n## Namespaces\n\n- [math](math.md)\n\n## Globals\n\n### `version`\n\n*Defined in math.zap*\n".to_string()
+                ),
+                (
+                    "math.md".to_string(),
+                    "# math\n\n## `(add a b)`\n\nAdds two numbers\n\n*Defined in math.zap*\n\n## `(zero)`\n\n*Defined in math.zap*\n".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,105 @@
+mod doc;
+mod notebook;
+#[cfg(feature = "repl")]
+mod repl;
+
+use std::io::IsTerminal;
+use std::process::ExitCode;
+
+use zap::env::Capability;
+use zap::prelude::{error_msg, format_source, Engine, Error, SandboxEnv, Severity};
+
+const USAGE: &str = "Usage:
+    zap run <file>    Evaluate a file
+    zap fmt <file>    Reformat a file in place
+    zap check <file>  Report the errors of a file without running it
+    zap notebook <md> Evaluate the zap blocks of a markdown file, writing their results
+    zap doc <dir> -o <out>  Write the API docs of the files of a directory
+    zap repl          Start an interactive session";
+
+// The code run from the command line is the user's own, it can load files
+fn new_engine() -> Result<Engine<SandboxEnv>, Error> {
+    let env = SandboxEnv::default().with_capabilities(vec![Capability::Files]);
+    Ok(Engine::with_env(env))
+}
+
+// The files run from a terminal get a progress line when they are at least that big
+const PROGRESS_MIN_SIZE: u64 = 8 * 1024 * 1024;
+
+// The file is streamed through the engine, its forms evaluated as they are read
+fn run_file(path: &str) -> Result<(), Error> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| error_msg(&format!("Cannot read '{}': {}", path, err)))?;
+    let size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+    let show_progress = size >= PROGRESS_MIN_SIZE && std::io::stderr().is_terminal();
+
+    let mut engine = new_engine()?;
+    let mut shown = None;
+    let res = engine.eval_read(file, |read| {
+        let percent = read as u64 * 100 / size.max(1);
+        if show_progress && shown != Some(percent) {
+            eprint!("\r{}: {}%", path, percent);
+            shown = Some(percent);
+        }
+    });
+    if shown.is_some() {
+        eprintln!();
+    }
+    println!("{}", res?.pr_str(engine.env_mut()));
+    Ok(())
+}
+
+fn format_file(path: &str) -> Result<(), Error> {
+    let src = std::fs::read_to_string(path)
+        .map_err(|err| error_msg(&format!("Cannot read '{}': {}", path, err)))?;
+    let formatted = format_source(&src)?;
+    if formatted != src {
+        std::fs::write(path, formatted)
+            .map_err(|err| error_msg(&format!("Cannot write '{}': {}", path, err)))?;
+    }
+    Ok(())
+}
+
+fn check_file(path: &str) -> Result<(), Error> {
+    let src = std::fs::read_to_string(path)
+        .map_err(|err| error_msg(&format!("Cannot read '{}': {}", path, err)))?;
+    let diagnostics = new_engine()?.check(&src);
+    for diagnostic in &diagnostics {
+        eprintln!("{}:{}", path, diagnostic);
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(error_msg(&format!("{} error(s) found", errors)));
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let res = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["run", path] => run_file(path),
+        ["fmt", path] => format_file(path),
+        ["check", path] => check_file(path),
+        ["doc", dir, "-o", out] => doc::run(dir, out),
+        ["notebook", path] => new_engine().and_then(|engine| notebook::run(path, engine)),
+        #[cfg(feature = "repl")]
+        ["repl"] | [] => new_engine().and_then(|engine| repl::start(engine.into_env())),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
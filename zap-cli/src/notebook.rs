@@ -0,0 +1,105 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use zap::output;
+use zap::prelude::{error_msg, Engine, Env, Result};
+
+// A notebook is a markdown file whose ```zap blocks are evaluated in order, in one env.
+// The result of each block is written under it, replacing the one of the previous run.
+
+const RESULT_MARKER: &str = "<!-- zap:result -->";
+
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// What a block printed, followed by its value
+fn eval_block<E: Env>(engine: &mut Engine<E>, code: &str) -> String {
+    let captured = Captured::default();
+    let previous = output::set_sink(Some(Box::new(captured.clone())));
+    let res = engine.eval_str(code);
+    output::set_sink(previous);
+
+    let mut out = String::from_utf8_lossy(&captured.0.borrow()).into_owned();
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    match res {
+        Ok(val) => out.push_str(&format!("=> {}", val.pr_str(engine.env_mut()))),
+        Err(err) => out.push_str(&format!("Error: {}", err)),
+    }
+    out
+}
+
+pub fn evaluate<E: Env>(engine: &mut Engine<E>, doc: &str) -> String {
+    let mut out = String::with_capacity(doc.len());
+    let mut lines = doc.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        out.push_str(line);
+        out.push('\n');
+        if line.trim_end() != "```zap" {
+            continue;
+        }
+
+        let mut code = String::new();
+        for line in lines.by_ref() {
+            out.push_str(line);
+            out.push('\n');
+            if line.trim_end() == "```" {
+                break;
+            }
+            code.push_str(line);
+            code.push('\n');
+        }
+
+        // The result of the previous run is dropped
+        if lines
+            .next_if(|line| line.trim_end() == RESULT_MARKER)
+            .is_some()
+            && lines.next_if(|line| line.trim_end() == "```text").is_some()
+        {
+            lines.by_ref().find(|line| line.trim_end() == "```");
+        }
+
+        out.push_str(RESULT_MARKER);
+        out.push_str("\n```text\n");
+        out.push_str(&eval_block(engine, &code));
+        out.push_str("\n```\n");
+    }
+    out
+}
+
+pub fn run(path: &str, mut engine: Engine<impl Env>) -> Result<()> {
+    let doc = std::fs::read_to_string(path)
+        .map_err(|err| error_msg(&format!("Cannot read '{}': {}", path, err)))?;
+    let evaluated = evaluate(&mut engine, &doc);
+    std::fs::write(path, evaluated)
+        .map_err(|err| error_msg(&format!("Cannot write '{}': {}", path, err)))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn evaluate() {
+        let doc = "# Title\n\n```zap\n(def x 2)\n(println \"x is\" x)\n```\n\nText\n\n```zap\n(+ x y)\n```\n";
+        let expected = "# Title\n\n```zap\n(def x 2)\n(println \"x is\" x)\n```\n<!-- zap:result -->\n```text\nx is 2\n=> nil\n```\n\nText\n\n```zap\n(+ x y)\n```\n<!-- zap:result -->\n```text\nError: line 1, col 1: symbol 'y' not in scope.\n```\n";
+
+        let mut engine = crate::new_engine().unwrap();
+        let evaluated = super::evaluate(&mut engine, doc);
+        assert_eq!(evaluated, expected);
+
+        // The results are replaced on the next run
+        let mut engine = crate::new_engine().unwrap();
+        assert_eq!(super::evaluate(&mut engine, &evaluated), expected);
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::{self, BufRead, Write};
+
+use zap::prelude::{compile_with_warnings, error_msg, Env, Extensions, Reader, Result, VM};
+
+// A REPL on stdin/stdout. Forms can span multiple lines. The errors are marked like the ones
+// of zap-server, ';; error[kind]: message', and so are the warnings, ';; warning: message'.
+// It starts with a banner, the version of zap and the features it was built with.
+pub fn start<E: Env>(mut env: E) -> Result<()> {
+    let mut reader = Reader::new();
+    let mut vm = VM::new();
+    let extensions = Extensions::new();
+    let stdin = io::stdin();
+    let mut line = String::new();
+    let mut warnings = Vec::new();
+
+    let features: Vec<&str> = zap::core::features().collect();
+    println!(
+        ";; zap {}, features: {}",
+        zap::core::VERSION,
+        if features.is_empty() {
+            "none".to_string()
+        } else {
+            features.join(", ")
+        }
+    );
+
+    loop {
+        print!("{}", if reader.is_pending() { ".. " } else { "> " });
+        io::stdout().flush().ok();
+
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(err) => return Err(error_msg(&err.to_string())),
+        }
+        reader.tokenize(&line);
+
+        loop {
+            match reader.read_ast(&mut env) {
+                Ok(Some(form)) => {
+                    let res = compile_with_warnings(form, &extensions, &mut env, &mut warnings);
+                    for warning in warnings.drain(..) {
+                        println!(";; warning: {}", warning);
+                    }
+                    match res {
+                        Ok(chunk) => match vm.run(chunk, &mut env) {
+                            Ok(result) => println!("{}", result.pr_str(&mut env)),
+                            Err(err) => println!(";; error[{}]: {}", err.kind(), err),
+                        },
+                        Err(err) => println!(";; error[{}]: {}", err.kind(), err),
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => println!(";; error[{}]: {}", err.kind(), err),
+            }
+        }
+    }
+}
@@ -1,47 +1,17 @@
-use zap::env::Env;
-use zap::{error_msg, Result, Value};
-
-fn is_float(args: &[Value]) -> Result<Value> {
-    if args.is_empty() {
-        return Err(error_msg("'float?' requires at least 1 argument."));
-    }
-    for v in args {
-        match v {
-            Value::Number(_) => continue,
-            _ => return Ok(Value::Bool(false)),
-        }
-    }
-    Ok(Value::Bool(true))
-}
-
-fn is_false(args: &[Value]) -> Result<Value> {
-    if args.is_empty() {
-        return Err(error_msg("'false?' requires at least 1 argument."));
-    }
-    for v in args {
-        match v {
-            Value::Bool(false) => continue,
-            _ => return Ok(Value::Bool(false)),
-        }
-    }
-    Ok(Value::Bool(true))
-}
-
-pub fn load<E: Env>(env: &mut E) -> Result<()> {
-    env.reg_fn("float?", is_float)?;
-    env.reg_fn("false?", is_false)?;
-    Ok(())
-}
+// The core functions now live in zap itself, so every env gets them. This crate is kept for
+// the embedders loading them explicitly.
+pub use zap::core::load;
 
 #[cfg(test)]
 pub mod tests {
     use super::load;
     use zap::env::SandboxEnv;
+    use zap::error_msg;
     use zap::tests::run_exp;
 
     fn test_exp_core(src: &str, expected: &str) {
-        let mut env = SandboxEnv::default();
-        load(&mut env);
+        let mut env = SandboxEnv::default().with_core(false);
+        load(&mut env).unwrap();
         assert_eq!(run_exp(src, env).unwrap(), expected);
     }
 
@@ -60,8 +30,232 @@ pub mod tests {
         test_exp_core("(float? false)", "false");
         test_exp_core("(float? nil)", "false");
         test_exp_core("(float? \"test\")", "false");
-        test_exp_core("(float? 12)", "true");
+        test_exp_core("(float? 12)", "false");
+        test_exp_core("(float? 12.0 1e3)", "true");
         test_exp_core("(float? true)", "false");
         test_exp_core("(float? ())", "false");
     }
+
+    #[test]
+    fn is_int() {
+        test_exp_core("(int? 12 -3)", "true");
+        test_exp_core("(int? 12.0)", "false");
+        test_exp_core("(int? 12M)", "false");
+        test_exp_core("(int? nil)", "false");
+    }
+
+    #[test]
+    fn concat() {
+        test_exp_core("(concat)", "()");
+        test_exp_core("(concat \"ab\" \"\" \"cd\")", "\"abcd\"");
+        test_exp_core("(concat '(1) '() '(2 3))", "(1 2 3)");
+
+        let mut env = SandboxEnv::default().with_core(false);
+        load(&mut env).unwrap();
+        assert_eq!(
+            run_exp("(concat \"a\" '(1))", env),
+            Err(error_msg("'concat' cannot mix strings with other values."))
+        );
+    }
+
+    #[test]
+    fn conversions() {
+        test_exp_core("(int \" 42 \")", "42");
+        test_exp_core("(int -2.7)", "-2");
+        test_exp_core("(float \"1.5\")", "1.5");
+        test_exp_core("(float 1)", "1.0");
+        test_exp_core("(parse-int \"4.2\")", "nil");
+        test_exp_core("(parse-int \"12\")", "12");
+        test_exp_core("(parse-float \"abc\")", "nil");
+        test_exp_core("(str \"a\" 1 nil true)", "\"a1true\"");
+        test_exp_core("(str)", "\"\"");
+        test_exp_core("(str :a 'b '(c :d))", "\":ab(c :d)\"");
+        test_exp_core("(keyword \"a\")", ":a");
+        test_exp_core("(= (keyword 'a) :a)", "true");
+        test_exp_core("(symbol :a)", "a");
+        test_exp_core("(= (symbol \"b\") 'b)", "true");
+        test_exp_core("(parse-keyword \"a b\")", "nil");
+        test_exp_core("(parse-symbol 12)", "nil");
+        test_exp_core("(parse-symbol \"12\")", "nil");
+        test_exp_core("(parse-keyword \"x\")", ":x");
+
+        let mut env = SandboxEnv::default().with_core(false);
+        load(&mut env).unwrap();
+        assert_eq!(
+            run_exp("(int \"abc\")", env),
+            Err(error_msg("'int' cannot convert \"abc\" to an integer."))
+        );
+        let mut env = SandboxEnv::default().with_core(false);
+        load(&mut env).unwrap();
+        assert_eq!(
+            run_exp("(float 1 2)", env),
+            Err(error_msg("'float' requires 1 argument."))
+        );
+        let mut env = SandboxEnv::default().with_core(false);
+        load(&mut env).unwrap();
+        assert_eq!(
+            run_exp("(keyword \"\")", env),
+            Err(error_msg("'keyword' cannot convert \"\" to a keyword."))
+        );
+        let mut env = SandboxEnv::default().with_core(false);
+        load(&mut env).unwrap();
+        assert_eq!(
+            run_exp("(symbol nil)", env),
+            Err(error_msg("'symbol' cannot convert nil to a symbol."))
+        );
+    }
+
+    #[test]
+    fn approx_eq() {
+        test_exp_core("(= 0.3 (+ 0.1 0.2))", "false");
+        test_exp_core("(approx= 0.3 (+ 0.1 0.2) 1e-9)", "true");
+        test_exp_core("(approx= 1 1.5 0.1)", "false");
+        test_exp_core("(approx= 0.30M 0.3 0)", "true");
+        test_exp_core("(str 1.50M)", "\"1.50\"");
+        test_exp_core("(float 1.25M)", "1.25");
+        test_exp_core("(int -2.75M)", "-2");
+    }
+
+    #[test]
+    fn ordering() {
+        test_exp_core("(< 1 2 3)", "true");
+        test_exp_core("(< 1 3 2)", "false");
+        test_exp_core("(<= 1 1 2.5)", "true");
+        test_exp_core("(> 0.3M 0.25M 0.2)", "true");
+        test_exp_core("(>= \"b\" \"a\")", "true");
+        test_exp_core("(< 1 (/ 0.0 0.0))", "false");
+        test_exp_core("(< (duration 59 :m) (duration \"1h\"))", "true");
+        test_exp_core(
+            "(> (datetime \"2024-03-01\") (datetime \"2024-02-29T23:59:59Z\"))",
+            "true",
+        );
+
+        let mut env = SandboxEnv::default().with_core(false);
+        load(&mut env).unwrap();
+        assert_eq!(
+            run_exp("(< 1 \"2\")", env),
+            Err(error_msg("'<' cannot compare 1 and \"2\"."))
+        );
+    }
+
+    #[test]
+    fn durations() {
+        test_exp_core("(duration 7384 :s)", "2h 3m 4s");
+        test_exp_core("(duration \"-1d 30m\")", "-1d 30m");
+        test_exp_core("(duration 0 :h)", "0s");
+        test_exp_core("(duration 1.5 :ms)", "1ms 500us");
+        test_exp_core("(* (duration 90 :m) 2)", "3h");
+        test_exp_core("(* 0.5 (duration 1 :h))", "30m");
+        test_exp_core("(/ (duration 1 :h) 8)", "7m 30s");
+        test_exp_core("(/ (duration 90 :m) (duration 1 :h))", "1.5");
+        test_exp_core("(rem (duration 100 :s) (duration 1 :m))", "40s");
+        test_exp_core(
+            "(- (duration 1 :d) (duration 1 :ns))",
+            "23h 59m 59s 999ms 999us 999ns",
+        );
+        test_exp_core("(round-to (duration 90 :s) :m)", "2m");
+        test_exp_core("(round-to (duration -90 :s) :m)", "-2m");
+        test_exp_core("(floor-to (duration 119 :s) (duration 1 :m))", "1m");
+        test_exp_core("(ceil-to (duration 61 :s) :m)", "2m");
+
+        let mut env = SandboxEnv::default().with_core(false);
+        load(&mut env).unwrap();
+        assert_eq!(
+            run_exp("(duration 2 :weeks)", env),
+            Err(error_msg(
+                "'duration' requires a unit from :d to :ns, or a duration, not :weeks."
+            ))
+        );
+        let mut env = SandboxEnv::default().with_core(false);
+        load(&mut env).unwrap();
+        assert_eq!(
+            run_exp("(/ (duration 1 :h) 0)", env),
+            Err(error_msg("Division by zero"))
+        );
+    }
+
+    #[test]
+    fn datetimes() {
+        test_exp_core("(datetime \"2024-03-01\")", "2024-03-01T00:00:00Z");
+        test_exp_core(
+            "(datetime \"2024-03-01T12:30:00.25+01:00\")",
+            "2024-03-01T11:30:00.250Z",
+        );
+        test_exp_core(
+            "(datetime \"1969-12-31T23:59:59.000000001Z\")",
+            "1969-12-31T23:59:59.000000001Z",
+        );
+        test_exp_core(
+            "(+ (datetime \"2024-02-28\") (duration 1 :d))",
+            "2024-02-29T00:00:00Z",
+        );
+        test_exp_core(
+            "(+ (duration 36 :h) (datetime \"2023-12-31\"))",
+            "2024-01-01T12:00:00Z",
+        );
+        test_exp_core(
+            "(- (datetime \"2024-03-01\") (datetime \"2024-02-01\"))",
+            "29d",
+        );
+        test_exp_core(
+            "(- (datetime \"2024-03-01\") (duration 1 :s))",
+            "2024-02-29T23:59:59Z",
+        );
+        test_exp_core(
+            "(floor-to (datetime \"2024-03-01T12:34:56Z\") :h)",
+            "2024-03-01T12:00:00Z",
+        );
+        test_exp_core(
+            "(round-to (datetime \"2024-03-01T12:34:56Z\") :d)",
+            "2024-03-02T00:00:00Z",
+        );
+        test_exp_core(
+            "(= (datetime \"2024-03-01T01:00:00+01:00\") (datetime \"2024-03-01\"))",
+            "true",
+        );
+        test_exp_core("(< (datetime \"2000-01-01\") (now))", "true");
+
+        let mut env = SandboxEnv::default().with_core(false);
+        load(&mut env).unwrap();
+        assert_eq!(
+            run_exp("(datetime \"2023-02-29\")", env),
+            Err(error_msg(
+                "'datetime' cannot convert \"2023-02-29\" to a datetime."
+            ))
+        );
+    }
+
+    #[test]
+    fn get() {
+        test_exp_core("(get '(1 2) 1)", "2");
+        test_exp_core("(get '(1 2) 2)", "nil");
+        test_exp_core("(get nil 0 'none)", "none");
+        test_exp_core("(get '(1) 0.5 0)", "0");
+    }
+
+    #[test]
+    fn println() {
+        use std::cell::RefCell;
+        use std::io::Write;
+        use std::rc::Rc;
+
+        #[derive(Clone, Default)]
+        struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        zap::output::set_sink(Some(Box::new(buffer.clone())));
+        test_exp_core("(do (print \"a\" 1) (println \"\" '(2)))", "nil");
+        zap::output::set_sink(None);
+
+        assert_eq!(buffer.0.borrow().as_slice(), b"a 1 (2)\n");
+    }
 }
@@ -1,26 +1,48 @@
 //#![feature(test)]
 
-use zap::compiler::compile;
+use std::sync::{Arc, Mutex};
+
+use zap::compiler::{compile_with, Extensions};
 use zap::env::SandboxEnv;
 use zap::reader::Reader;
-use zap::vm;
+use zap::vm::{Profile, VM};
 
 fn main() {
     let mut reader = Reader::new();
     let mut env = SandboxEnv::default();
+    let mut extensions = Extensions::new();
+    if std::env::args().any(|arg| arg == "--no-inline") {
+        extensions.set_inline_limit(0);
+    }
 
-    zap_core::load(&mut env);
+    // Counts what the VM runs itself, instead of leaving it to an external profiler
+    let profile = std::env::args()
+        .any(|arg| arg == "--profile")
+        .then(|| Arc::new(Mutex::new(Profile::default())));
+    let mut vm = match &profile {
+        Some(profile) => VM::new().with_profile(profile.clone()),
+        None => VM::new(),
+    };
 
-    let src = "(def rec (fn (x) (if (= x 1000000) \"boom\" (rec (+ x 1))))) (rec 0) (rec 0) (rec 0) (rec 0) (rec 0) (rec 0) (rec 0)";
+    // The same recursion, adding a local instead of a const, for the register-ops build to fuse
+    let src = if std::env::args().any(|arg| arg == "--slots") {
+        "(def rec (fn (x step) (if (= x 1000000) \"boom\" (rec (+ x step) step)))) (rec 0 1) (rec 0 1) (rec 0 1) (rec 0 1) (rec 0 1) (rec 0 1) (rec 0 1)"
+    } else {
+        "(def inc (fn (x) (+ x 1))) (def rec (fn (x) (if (= x 1000000) \"boom\" (rec (inc x))))) (rec 0) (rec 0) (rec 0) (rec 0) (rec 0) (rec 0) (rec 0)"
+    };
 
     reader.tokenize(src);
 
     while let Ok(Some(form)) = reader.read_ast(&mut env) {
-        let chunk = compile(form).unwrap();
-        if let Ok(result) = vm::run(chunk, &mut env) {
+        let chunk = compile_with(form, &extensions, &mut env).unwrap();
+        if let Ok(result) = vm.run(chunk, &mut env) {
             println!("{}", result.pr_str(&mut env));
         }
     }
+
+    if let Some(profile) = profile {
+        println!("{}", profile.lock().unwrap().report().pr_str(&mut env));
+    }
 }
 //
 //extern crate test;
@@ -0,0 +1,217 @@
+// A lightweight index of the defs of a source file. It works on the text, so it stays
+// usable while the file is being edited and doesn't read or compile.
+
+const DEF_FORMS: [&str; 3] = ["def", "defn", "defmacro"];
+
+#[derive(Debug, PartialEq)]
+pub struct Def {
+    pub name: String,
+    pub line: u32, // From 0, like LSP positions
+    pub col: u32,
+    pub arglist: Option<String>,
+    pub doc: Option<String>,
+}
+
+fn is_symbol_char(ch: char) -> bool {
+    !ch.is_whitespace()
+        && !matches!(
+            ch,
+            '(' | ')' | '[' | ']' | '"' | ';' | '\'' | '`' | ',' | '~' | '@'
+        )
+}
+
+fn position(src: &str, offset: usize) -> (u32, u32) {
+    let before = &src[..offset];
+    let line = before.matches('\n').count();
+    let col = before[before.rfind('\n').map_or(0, |i| i + 1)..]
+        .chars()
+        .count();
+    (line as u32, col as u32)
+}
+
+// The offsets of the parens opening lists, skipping those in strings and comments
+fn list_starts(src: &str) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut chars = src.char_indices();
+    while let Some((offset, ch)) = chars.next() {
+        match ch {
+            '(' => starts.push(offset),
+            ';' => {
+                chars.find(|(_, ch)| *ch == '\n');
+            }
+            '"' => {
+                let mut escaped = false;
+                for (_, ch) in chars.by_ref() {
+                    match ch {
+                        '"' if !escaped => break,
+                        '\\' => escaped = !escaped,
+                        _ => escaped = false,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    starts
+}
+
+fn skip_whitespace(src: &str) -> &str {
+    src.trim_start_matches(|ch: char| ch.is_whitespace() || ch == ',')
+}
+
+fn symbol(src: &str) -> &str {
+    let end = src.find(|ch| !is_symbol_char(ch)).unwrap_or(src.len());
+    &src[..end]
+}
+
+// The balanced list, or vector, at the start of src
+fn list(src: &str) -> Option<&str> {
+    let mut depth = 0;
+    for (offset, ch) in src.char_indices() {
+        match ch {
+            '(' | '[' => depth += 1,
+            ')' | ']' if depth == 1 => return Some(&src[..=offset]),
+            ')' | ']' => depth -= 1,
+            _ if depth == 0 => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+// The string literal at the start of src, without its quotes
+fn string(src: &str) -> Option<&str> {
+    let rest = src.strip_prefix('"')?;
+    let mut escaped = false;
+    for (offset, ch) in rest.char_indices() {
+        match ch {
+            '"' if !escaped => return Some(&rest[..offset]),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+// The docstring and params of what comes after a def's name
+fn signature(head: &str, src: &str) -> (Option<String>, Option<String>) {
+    let mut rest = skip_whitespace(src);
+    if head == "def" {
+        // Only a fn has an arglist
+        let Some(after) = rest.strip_prefix("(fn") else {
+            return (None, None);
+        };
+        if !after.starts_with(char::is_whitespace) {
+            return (None, None);
+        }
+        rest = skip_whitespace(after);
+        // A named fn
+        rest = skip_whitespace(&rest[symbol(rest).len()..]);
+    }
+
+    let doc = string(rest);
+    if let Some(doc) = doc {
+        rest = skip_whitespace(&rest[doc.len() + 2..]);
+    }
+    (
+        doc.map(str::to_string),
+        list(rest).map(|params| params.split_whitespace().collect::<Vec<_>>().join(" ")),
+    )
+}
+
+pub fn defs(src: &str) -> Vec<Def> {
+    let mut defs = Vec::new();
+    for start in list_starts(src) {
+        let after_paren = &src[start + 1..];
+        let head = symbol(after_paren);
+        if !DEF_FORMS.contains(&head) {
+            continue;
+        }
+
+        let rest = &after_paren[head.len()..];
+        let name_src = skip_whitespace(rest);
+        let name = symbol(name_src);
+        if name.is_empty() || name_src.len() == rest.len() {
+            continue;
+        }
+
+        let name_offset = src.len() - name_src.len();
+        let (line, col) = position(src, name_offset);
+        let (doc, arglist) = signature(head, &name_src[name.len()..]);
+        defs.push(Def {
+            name: name.to_string(),
+            line,
+            col,
+            arglist,
+            doc,
+        });
+    }
+    defs
+}
+
+// The symbol under the cursor, and the part of it before the cursor
+pub fn word_at(src: &str, line: u32, col: u32) -> Option<(&str, &str)> {
+    let text = src.lines().nth(line as usize)?;
+    let cursor = text
+        .char_indices()
+        .nth(col as usize)
+        .map_or(text.len(), |(offset, _)| offset);
+
+    let start = text[..cursor]
+        .rfind(|ch| !is_symbol_char(ch))
+        .map_or(0, |i| i + 1);
+    let end = text[cursor..]
+        .find(|ch| !is_symbol_char(ch))
+        .map_or(text.len(), |i| cursor + i);
+
+    if start == end {
+        None
+    } else {
+        Some((&text[start..end], &text[start..cursor]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_defs() {
+        let src = "; (def commented 1)\n(def x 1)\n(def add\n  (fn (a b) (+ a b)))\n(print \"(def s 1)\")\n(defn sq \"Square\" (n)\n  (* n n))";
+        assert_eq!(
+            defs(src),
+            vec![
+                Def {
+                    name: "x".to_string(),
+                    line: 1,
+                    col: 5,
+                    arglist: None,
+                    doc: None,
+                },
+                Def {
+                    name: "add".to_string(),
+                    line: 2,
+                    col: 5,
+                    arglist: Some("(a b)".to_string()),
+                    doc: None,
+                },
+                Def {
+                    name: "sq".to_string(),
+                    line: 5,
+                    col: 6,
+                    arglist: Some("(n)".to_string()),
+                    doc: Some("Square".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn word_under_cursor() {
+        let src = "(def x 1)\n(println (add x 2))";
+        assert_eq!(word_at(src, 1, 11), Some(("add", "a")));
+        assert_eq!(word_at(src, 1, 2), Some(("println", "p")));
+        assert_eq!(word_at(src, 1, 10), Some(("add", "")));
+        assert_eq!(word_at(src, 0, 9), None);
+    }
+}
@@ -0,0 +1,286 @@
+mod index;
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as NotificationTrait, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as RequestTrait};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, GotoDefinitionParams, GotoDefinitionResponse, Hover,
+    HoverContents, HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind,
+    OneOf, Position, PublishDiagnosticsParams, Range, ServerCapabilities,
+    TextDocumentPositionParams, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use zap::env::symbols::DEFAULT_SYMBOLS;
+use zap::prelude::{Engine, Env, SandboxEnv, Severity, Value};
+
+// A language server for zap, over stdio. The open files are checked on every change, and the
+// env with the core functions loaded is used for hover and completion.
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Sync + Send>>;
+
+struct Server {
+    documents: HashMap<Url, String>,
+    engine: Engine<SandboxEnv>,
+}
+
+impl Server {
+    fn new() -> Result<Self> {
+        Ok(Server {
+            documents: HashMap::new(),
+            engine: Engine::new(),
+        })
+    }
+
+    fn run(&mut self, connection: &Connection) -> Result<()> {
+        for msg in &connection.receiver {
+            match msg {
+                Message::Request(req) => {
+                    if connection.handle_shutdown(&req)? {
+                        return Ok(());
+                    }
+                    let resp = self.handle_request(req);
+                    connection.sender.send(Message::Response(resp))?;
+                }
+                Message::Notification(not) => {
+                    if let Some(uri) = self.handle_notification(not) {
+                        let diagnostics = self.diagnostics(&uri);
+                        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+                        connection
+                            .sender
+                            .send(Message::Notification(Notification::new(
+                                PublishDiagnostics::METHOD.to_string(),
+                                params,
+                            )))?;
+                    }
+                }
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the document to check again, if it changed
+    fn handle_notification(&mut self, not: Notification) -> Option<Url> {
+        match not.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let doc = params::<DidOpenTextDocument>(not)?.text_document;
+                self.documents.insert(doc.uri.clone(), doc.text);
+                Some(doc.uri)
+            }
+            DidChangeTextDocument::METHOD => {
+                // The sync is full, so the last change is the whole text
+                let mut params = params::<DidChangeTextDocument>(not)?;
+                let change = params.content_changes.pop()?;
+                let uri = params.text_document.uri;
+                self.documents.insert(uri.clone(), change.text);
+                Some(uri)
+            }
+            DidCloseTextDocument::METHOD => {
+                // Its diagnostics are cleared
+                let uri = params::<DidCloseTextDocument>(not)?.text_document.uri;
+                self.documents.remove(&uri);
+                Some(uri)
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_request(&mut self, req: Request) -> Response {
+        match req.method.as_str() {
+            HoverRequest::METHOD => respond::<HoverRequest, _>(req, |params| self.hover(params)),
+            GotoDefinition::METHOD => {
+                respond::<GotoDefinition, _>(req, |params| self.definition(params))
+            }
+            Completion::METHOD => respond::<Completion, _>(req, |params| self.completion(params)),
+            _ => Response::new_err(
+                req.id,
+                ErrorCode::MethodNotFound as i32,
+                format!("Unsupported request: {}", req.method),
+            ),
+        }
+    }
+
+    fn diagnostics(&mut self, uri: &Url) -> Vec<Diagnostic> {
+        let Some(src) = self.documents.get(uri) else {
+            return Vec::new();
+        };
+
+        self.engine
+            .check(src)
+            .into_iter()
+            .map(|diagnostic| {
+                // The whole line where the form starts
+                let line = diagnostic.line.saturating_sub(1);
+                let len = src
+                    .lines()
+                    .nth(line as usize)
+                    .map_or(0, |text| text.chars().count());
+                Diagnostic {
+                    range: Range::new(Position::new(line, 0), Position::new(line, len as u32)),
+                    severity: Some(match diagnostic.severity {
+                        Severity::Error => DiagnosticSeverity::ERROR,
+                        Severity::Warning => DiagnosticSeverity::WARNING,
+                    }),
+                    source: Some("zap".to_string()),
+                    message: diagnostic.message,
+                    ..Diagnostic::default()
+                }
+            })
+            .collect()
+    }
+
+    fn word_at<'a>(&'a self, pos: &TextDocumentPositionParams) -> Option<(&'a str, &'a str)> {
+        let src = self.documents.get(&pos.text_document.uri)?;
+        index::word_at(src, pos.position.line, pos.position.character)
+    }
+
+    fn hover(&mut self, params: HoverParams) -> Option<Hover> {
+        let (word, _) = self.word_at(&params.text_document_position_params)?;
+        let word = word.to_string();
+
+        let text = if let Some((_, def)) = self.find_defs(&word).into_iter().next() {
+            let mut text = match def.arglist {
+                Some(arglist) => format!(
+                    "```zap\n({} {})\n```",
+                    def.name,
+                    &arglist[1..arglist.len() - 1]
+                ),
+                None => format!("```zap\n{}\n```", def.name),
+            };
+            if let Some(doc) = def.doc {
+                text.push_str("\n\n");
+                text.push_str(&doc);
+            }
+            text
+        } else {
+            let val = self.global(&word)?;
+            match val {
+                Value::FuncNative(f) => format!(
+                    "```zap\n({} ...)\n```\n\nNative function of arity {}",
+                    f.name, f.arity
+                ),
+                val => format!("```zap\n{}\n```", val.pr_str(self.engine.env_mut())),
+            }
+        };
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: text,
+            }),
+            range: None,
+        })
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let (word, _) = self.word_at(&params.text_document_position_params)?;
+        let locations: Vec<Location> = self
+            .find_defs(word)
+            .into_iter()
+            .map(|(uri, def)| {
+                let start = Position::new(def.line, def.col);
+                let end = Position::new(def.line, def.col + def.name.chars().count() as u32);
+                Location::new(uri.clone(), Range::new(start, end))
+            })
+            .collect();
+
+        if locations.is_empty() {
+            None
+        } else {
+            Some(GotoDefinitionResponse::Array(locations))
+        }
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let (_, prefix) = self
+            .word_at(&params.text_document_position)
+            .unwrap_or(("", ""));
+
+        let mut items: Vec<CompletionItem> = Vec::new();
+        let mut add = |label: &str, kind| {
+            if label.starts_with(prefix) && !items.iter().any(|item| item.label == label) {
+                items.push(CompletionItem {
+                    label: label.to_string(),
+                    kind: Some(kind),
+                    ..CompletionItem::default()
+                });
+            }
+        };
+
+        for form in DEFAULT_SYMBOLS.iter().filter(|s| **s != "&") {
+            add(form, CompletionItemKind::KEYWORD);
+        }
+        for src in self.documents.values() {
+            for def in index::defs(src) {
+                add(&def.name, CompletionItemKind::FUNCTION);
+            }
+        }
+        let env = self.engine.env();
+        for id in DEFAULT_SYMBOLS.len()..env.symbols_count() {
+            let id = id as zap::Symbol;
+            if let (Ok(name), Ok(_)) = (env.get_symbol(id), env.get_by_id(id)) {
+                add(&name, CompletionItemKind::FUNCTION);
+            }
+        }
+
+        Some(CompletionResponse::Array(items))
+    }
+
+    fn find_defs(&self, name: &str) -> Vec<(&Url, index::Def)> {
+        self.documents
+            .iter()
+            .flat_map(|(uri, src)| index::defs(src).into_iter().map(move |def| (uri, def)))
+            .filter(|(_, def)| def.name == name)
+            .collect()
+    }
+
+    fn global(&mut self, name: &str) -> Option<Value> {
+        let env = self.engine.env_mut();
+        (0..env.symbols_count())
+            .map(|id| id as zap::Symbol)
+            .find(|id| env.get_symbol(*id).is_ok_and(|s| s == name))
+            .and_then(|id| env.get_by_id(id).ok())
+    }
+}
+
+fn params<N: NotificationTrait>(not: Notification) -> Option<N::Params> {
+    not.extract(N::METHOD).ok()
+}
+
+fn respond<R, T>(req: Request, handler: impl FnOnce(R::Params) -> T) -> Response
+where
+    R: RequestTrait,
+    T: serde::Serialize,
+{
+    let id = req.id.clone();
+    match req.extract(R::METHOD) {
+        Ok((id, params)) => Response::new_ok(id, handler(params)),
+        Err(err) => Response::new_err(id, ErrorCode::InvalidParams as i32, format!("{:?}", err)),
+    }
+}
+
+fn main() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = serde_json::to_value(ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        ..ServerCapabilities::default()
+    })?;
+    connection.initialize(capabilities)?;
+
+    Server::new()?.run(&connection)?;
+
+    // The io threads end once the connection is gone
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
@@ -0,0 +1,14 @@
+// A plugin adding a `hello` function, returning how many arguments it received.
+
+use zap::env::Env;
+use zap::{Result, String, Value};
+
+fn hello(args: &[Value]) -> Result<Value> {
+    Ok(Value::Str(String::from(format!("hello {}", args.len()))))
+}
+
+fn load(env: &mut dyn Env) -> Result<()> {
+    env.reg_fn("hello", hello)
+}
+
+zap_plugin::declare_plugin!(load);
@@ -0,0 +1,122 @@
+use zap::env::{Capability, Env};
+use zap::{error_msg, Result};
+
+pub use zap;
+
+// Native extensions for zap, loaded at runtime from dynamic libraries.
+//
+// A plugin is a cdylib exporting `zap_plugin_load` (see `declare_plugin!`), built against
+// the same zap version and with the same compiler as the host, since zap types cross the
+// library boundary as is.
+
+pub const ABI_VERSION: u32 = 1;
+
+pub type LoadFn = fn(&mut dyn Env) -> Result<()>;
+
+#[macro_export]
+macro_rules! declare_plugin {
+    ($load:path) => {
+        #[no_mangle]
+        #[allow(non_upper_case_globals)]
+        pub static zap_plugin_abi_version: u32 = $crate::ABI_VERSION;
+
+        #[no_mangle]
+        pub fn zap_plugin_load(env: &mut dyn $crate::zap::env::Env) -> $crate::zap::Result<()> {
+            $load(env)
+        }
+    };
+}
+
+// Load the plugin at path into env. The library is never unloaded, since the functions
+// it registered can be called at any time.
+pub fn load(path: &str, env: &mut dyn Env) -> Result<()> {
+    if !env.has_capability(Capability::Plugins) {
+        return Err(error_msg("Plugins are not allowed in this env."));
+    }
+
+    let err =
+        |err: libloading::Error| error_msg(&format!("Cannot load plugin '{}': {}", path, err));
+
+    unsafe {
+        let lib = libloading::Library::new(path).map_err(err)?;
+
+        let version = **lib
+            .get::<*const u32>(b"zap_plugin_abi_version")
+            .map_err(err)?;
+        if version != ABI_VERSION {
+            return Err(error_msg(&format!(
+                "Plugin '{}' was built for ABI version {}, expected {}.",
+                path, version, ABI_VERSION
+            )));
+        }
+
+        let load = *lib.get::<LoadFn>(b"zap_plugin_load").map_err(err)?;
+        load(env)?;
+
+        std::mem::forget(lib);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use zap::env::{Capability, Env, SandboxEnv};
+    use zap::tests::run_exp;
+    use zap::{error_msg, Result, String, Symbol, Value};
+
+    // A SandboxEnv allowed to load plugins
+    #[derive(Default)]
+    struct PluginEnv(SandboxEnv);
+
+    impl Env for PluginEnv {
+        fn get_by_id(&self, id: Symbol) -> Result<Value> {
+            self.0.get_by_id(id)
+        }
+        fn set(&mut self, key: &Value, val: &Value) -> Result<()> {
+            self.0.set(key, val)
+        }
+        fn reg_symbol(&mut self, s: String) -> Value {
+            self.0.reg_symbol(s)
+        }
+        fn get_symbol(&self, key: Symbol) -> Result<String> {
+            self.0.get_symbol(key)
+        }
+        fn symbols_count(&self) -> usize {
+            self.0.symbols_count()
+        }
+        fn has_capability(&self, cap: Capability) -> bool {
+            cap == Capability::Plugins
+        }
+    }
+
+    fn example_path() -> std::string::String {
+        let mut path = std::env::current_exe().unwrap();
+        path.pop();
+        if path.ends_with("deps") {
+            path.pop();
+        }
+        path.push("examples");
+        path.push(format!(
+            "{}hello_plugin{}",
+            std::env::consts::DLL_PREFIX,
+            std::env::consts::DLL_SUFFIX
+        ));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn load_plugin() {
+        let mut env = PluginEnv::default();
+        super::load(&example_path(), &mut env).unwrap();
+        assert_eq!(run_exp("(hello 1 2)", env.0).unwrap(), "\"hello 2\"");
+    }
+
+    #[test]
+    fn plugins_not_allowed() {
+        let mut env = SandboxEnv::default();
+        assert_eq!(
+            super::load(&example_path(), &mut env),
+            Err(error_msg("Plugins are not allowed in this env."))
+        );
+    }
+}
@@ -6,11 +6,12 @@ mod shared_env;
 //#[global_allocator]
 //static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 
-use crate::repl::start_repl;
+use crate::repl::{start_repl, Mode};
 use std::fs::remove_file;
 use tokio::net::UnixListener;
 
 use crate::shared_env::SharedEnv;
+use zap::env::Capability;
 
 //#[cfg(not(target_env = "msvc"))]
 //#[global_allocator]
@@ -24,15 +25,30 @@ async fn main() -> std::io::Result<()> {
 
     println!("Server listening.");
 
-    let env = SharedEnv::default();
+    let mut capabilities = Vec::new();
+    if std::env::args().any(|arg| arg == "--allow-plugins") {
+        capabilities.push(Capability::Plugins);
+    }
+    if std::env::args().any(|arg| arg == "--allow-files") {
+        capabilities.push(Capability::Files);
+    }
+
+    // Tools ask for messages they can parse
+    let mode = if std::env::args().any(|arg| arg == "--protocol") {
+        Mode::Protocol
+    } else {
+        Mode::Human
+    };
+
+    let env = SharedEnv::default().with_capabilities(capabilities);
 
     // accept connections and process them serially
     loop {
         let (stream, _) = listener.accept().await.unwrap();
         let env = env.clone();
         tokio::spawn(async move {
-            let (mut input, mut output) = stream.into_split();
-            start_repl(&mut input, &mut output, env).await.ok();
+            let (mut input, output) = stream.into_split();
+            start_repl(&mut input, output, env, mode).await.ok();
         });
     }
 }
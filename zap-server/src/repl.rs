@@ -1,84 +1,1052 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Instant;
 
 use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
 use tokio::task;
 
-use zap::compiler::compile;
-use zap::env::Env;
+use zap::compiler::{compile_with, compile_with_warnings, Extensions};
+use zap::env::{Capability, Env};
+use zap::output;
 use zap::reader::Reader;
-use zap::vm;
-use zap::ZapErr;
+use zap::vm::{Chunk, Op, VM};
+use zap::{Symbol, Value, ZapErr};
 
-pub async fn start_repl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin, E: Env>(
+use crate::shared_env::SharedEnv;
+
+// How many steps of the latest evaluation are kept for (replay), and how many it prints by default.
+const REPLAY_CAPACITY: usize = 256;
+const REPLAY_STEPS: usize = 20;
+
+// How many pending writes a session can have before printing blocks the evaluation.
+const OUTPUT_BUFFER: usize = 64;
+
+// How many items of an inspected list are shown at once.
+const INSPECT_PAGE: usize = 50;
+
+// The version of the framing of the protocol mode, raised on every incompatible change.
+const PROTOCOL_VERSION: u32 = 1;
+
+// How the messages of a session are framed. A human gets a prompt and plain text, with errors
+// behind a stable marker. A tool gets one form per line, so the output, the results and the
+// errors can't be mistaken for each other:
+//   (out "printed text")
+//   (result <value>)
+//   (error kind runtime message "..." trace ("in f, op 3 (line 2, col 5)") span (line 1 col 1))
+//   (warning message "...")
+//
+// The warnings of the compiler about a form come before its result, they don't stop it.
+// A tool is greeted with what the server is and allows, and must answer with the protocol it
+// speaks before anything else. Any other answer is an error of kind protocol, and the end of
+// the session, as is input that isn't UTF-8:
+//   (hello server "zap-server" version "0.1.0" zap-version "0.1.0" features (io)
+//          protocol 1 capabilities (plugins) limits (replay-capacity 256 output-buffer 64))
+//   > (hello protocol 1)
+//   (ready)
+//
+// (inspect exp) keeps the value of exp under a handle, and shows it shallowly, a page of items
+// at a time. (inspect-path handle (index...) offset) shows what's deeper, without evaluating:
+//   (inspection handle 1 path () offset 0 count 3 items ((value 1) (list 1000) (value "a")))
+//   (inspection handle 1 path (1 0) value 42)
+//
+// (watch-expr '(count users)) evaluates the form it's given, and again every time a global it
+// looks up is defined, by any session. Each value is pushed to the client, until (unwatch 1):
+//   (watch handle 1 value 12)
+//   (watch handle 1 error "...")
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Human,
+    Protocol,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ErrorKind {
+    Protocol,
+    Reader,
+    Compile,
+    Runtime,
+}
+
+impl ErrorKind {
+    fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Protocol => "protocol",
+            ErrorKind::Reader => "reader",
+            ErrorKind::Compile => "compile",
+            ErrorKind::Runtime => "runtime",
+        }
+    }
+}
+
+fn quoted(text: &str) -> String {
+    format!("{}", Value::Str(zap::String::from(text)))
+}
+
+impl Mode {
+    fn hello(self, capabilities: &[&str]) -> String {
+        let version = env!("CARGO_PKG_VERSION");
+        let features: Vec<&str> = zap::core::features().collect();
+        let listed = |names: &[&str]| {
+            if names.is_empty() {
+                "none".to_string()
+            } else {
+                names.join(", ")
+            }
+        };
+        match self {
+            Mode::Human => format!(
+                ";; zap-server {} (zap {}, protocol {}), capabilities: {}, features: {}\n",
+                version,
+                zap::core::VERSION,
+                PROTOCOL_VERSION,
+                listed(capabilities),
+                listed(&features)
+            ),
+            Mode::Protocol => format!(
+                "(hello server \"zap-server\" version {} zap-version {} features ({}) protocol {} capabilities ({}) limits (replay-capacity {} output-buffer {}))\n",
+                quoted(version),
+                quoted(zap::core::VERSION),
+                features.join(" "),
+                PROTOCOL_VERSION,
+                capabilities.join(" "),
+                REPLAY_CAPACITY,
+                OUTPUT_BUFFER
+            ),
+        }
+    }
+
+    fn prompt(self) -> Option<String> {
+        match self {
+            Mode::Human => Some("> ".to_string()),
+            Mode::Protocol => None,
+        }
+    }
+
+    fn output(self, text: &str) -> String {
+        match self {
+            Mode::Human => text.to_string(),
+            Mode::Protocol => format!("(out {})\n", quoted(text)),
+        }
+    }
+
+    fn result(self, printed: &str) -> String {
+        match self {
+            Mode::Human => format!("{}\n", printed),
+            Mode::Protocol => format!("(result {})\n", printed),
+        }
+    }
+
+    fn inspection<E: Env>(self, inspected: &Inspected, val: &Value, env: &E) -> String {
+        let printed = |val: &Value| {
+            let mut printed = String::new();
+            val.write_to(&mut printed, env).ok();
+            printed
+        };
+        let Value::List(list) = val else {
+            return match self {
+                Mode::Human => format!(";; #{} {}\n", inspected.handle, printed(val)),
+                Mode::Protocol => format!(
+                    "(inspection handle {} path ({}) value {})\n",
+                    inspected.handle,
+                    inspected.path(),
+                    printed(val)
+                ),
+            };
+        };
+
+        let page = list
+            .iter()
+            .enumerate()
+            .skip(inspected.offset)
+            .take(INSPECT_PAGE);
+        match self {
+            Mode::Human => {
+                let mut text = format!(
+                    ";; #{} list of {} items, from {}\n",
+                    inspected.handle,
+                    list.len(),
+                    inspected.offset
+                );
+                for (i, item) in page {
+                    match item {
+                        Value::List(items) => {
+                            text.push_str(&format!("{}: list of {} items\n", i, items.len()))
+                        }
+                        item => text.push_str(&format!("{}: {}\n", i, printed(item))),
+                    }
+                }
+                text
+            }
+            Mode::Protocol => {
+                let items: Vec<String> = page
+                    .map(|(_, item)| match item {
+                        Value::List(items) => format!("(list {})", items.len()),
+                        item => format!("(value {})", printed(item)),
+                    })
+                    .collect();
+                format!(
+                    "(inspection handle {} path ({}) offset {} count {} items ({}))\n",
+                    inspected.handle,
+                    inspected.path(),
+                    inspected.offset,
+                    list.len(),
+                    items.join(" ")
+                )
+            }
+        }
+    }
+
+    fn watch(self, handle: usize, res: zap::Result<String>) -> String {
+        match (self, res) {
+            (Mode::Human, Ok(printed)) => format!(";; watch #{}: {}\n", handle, printed),
+            (Mode::Human, Err(err)) => {
+                format!(";; watch #{} error: {}\n", handle, err)
+            }
+            (Mode::Protocol, Ok(printed)) => {
+                format!("(watch handle {} value {})\n", handle, printed)
+            }
+            (Mode::Protocol, Err(err)) => {
+                format!(
+                    "(watch handle {} error {})\n",
+                    handle,
+                    quoted(&err.message())
+                )
+            }
+        }
+    }
+
+    fn warning(self, msg: &str) -> String {
+        match self {
+            Mode::Human => format!(";; warning: {}\n", msg),
+            Mode::Protocol => format!("(warning message {})\n", quoted(msg)),
+        }
+    }
+
+    fn error(self, kind: ErrorKind, err: ZapErr) -> String {
+        match self {
+            Mode::Human => format!(";; error[{}]: {}\n", kind.name(), err),
+            Mode::Protocol => {
+                let trace: Vec<String> = err.trace().iter().map(|call| quoted(call)).collect();
+                let span = match err.span() {
+                    Some(span) => format!("(line {} col {})", span.line, span.col),
+                    None => "nil".to_string(),
+                };
+                format!(
+                    "(error kind {} message {} trace ({}) span {})\n",
+                    kind.name(),
+                    quoted(&err.message()),
+                    trace.join(" "),
+                    span
+                )
+            }
+        }
+    }
+}
+
+// Sends the output of an evaluation to the session's writer task as it's produced.
+#[derive(Clone)]
+struct StreamSink(mpsc::Sender<Vec<u8>>, Mode);
+
+impl std::io::Write for StreamSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let framed = match self.1 {
+            Mode::Human => buf.to_vec(),
+            Mode::Protocol => self.1.output(&String::from_utf8_lossy(buf)).into_bytes(),
+        };
+        // Blocks when the client is not reading fast enough
+        self.0
+            .blocking_send(framed)
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Polls an evaluation off the async threads, with the sink of the session as the output of
+// whichever thread polls it. While it waits for an async native, no thread is held.
+struct Evaluation<F> {
+    run: F,
+    sink: StreamSink,
+}
+
+impl<F: Future + Unpin> Future for Evaluation<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        task::block_in_place(|| {
+            let previous = output::set_sink(Some(Box::new(this.sink.clone())));
+            let res = Pin::new(&mut this.run).poll(cx);
+            output::set_sink(previous);
+            res
+        })
+    }
+}
+
+async fn send(out: &mpsc::Sender<Vec<u8>>, msg: String) -> io::Result<()> {
+    out.send(msg.into_bytes())
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+}
+
+// (load-plugin "path") needs the env, so it's handled by the server itself.
+fn load_plugin<E: Env>(
+    form: &Value,
+    load_symbol: &Value,
+    env: &mut E,
+) -> Option<zap::Result<Value>> {
+    match form {
+        Value::List(list) if list.len() == 2 && list[0] == *load_symbol => Some(match &list[1] {
+            Value::Str(path) => zap_plugin::load(path, env).map(|_| Value::Nil),
+            _ => Err(zap::error_msg("load-plugin expects the path of the plugin")),
+        }),
+        _ => None,
+    }
+}
+
+// Where a client is in the values it inspects.
+struct Inspected {
+    handle: usize, // From 1, the values kept by the session
+    path: Vec<usize>,
+    offset: usize,
+}
+
+impl Inspected {
+    fn path(&self) -> String {
+        let path: Vec<String> = self.path.iter().map(usize::to_string).collect();
+        path.join(" ")
+    }
+
+    // The value it's at, in the ones kept by the session
+    fn get<'a>(&self, kept: &'a [Value]) -> zap::Result<&'a Value> {
+        let mut val = self
+            .handle
+            .checked_sub(1)
+            .and_then(|i| kept.get(i))
+            .ok_or_else(|| zap::error_msg(&format!("No inspected value #{}", self.handle)))?;
+        for i in &self.path {
+            val = match val {
+                Value::List(list) => list.get(*i),
+                _ => None,
+            }
+            .ok_or_else(|| zap::error_msg(&format!("No item at path ({})", self.path())))?;
+        }
+        Ok(val)
+    }
+}
+
+// (inspect-path handle (index...)) and (inspect-path handle (index...) offset)
+fn inspect_path(form: &Value, inspect_path_symbol: &Value) -> Option<zap::Result<Inspected>> {
+    let Value::List(list) = form else {
+        return None;
+    };
+    if list.first() != Some(inspect_path_symbol) {
+        return None;
+    }
+    let index = |val: &Value| match val {
+        Value::Int(n) => usize::try_from(*n).ok(),
+        _ => None,
+    };
+    let inspected = match &list[1..] {
+        [handle, Value::List(path), rest @ ..] if rest.len() <= 1 => (|| {
+            Some(Inspected {
+                handle: index(handle)?,
+                path: path.iter().map(index).collect::<Option<_>>()?,
+                offset: rest.first().map_or(Some(0), index)?,
+            })
+        })(),
+        _ => None,
+    };
+    Some(inspected.ok_or_else(|| {
+        zap::error_msg("inspect-path expects a handle, a list of indexes and an optional offset")
+    }))
+}
+
+// A form evaluated again when the globals it looks up change.
+struct Watch {
+    form: Value,
+    deps: Vec<Symbol>,
+}
+
+// The globals a chunk looks up, and those the fns it creates look up. The fns and macros in the
+// globals are followed too, so a watch depends on what the fns it calls depend on.
+fn lookups<E: Env>(chunk: &Chunk, env: &E, deps: &mut Vec<Symbol>) {
+    for op in &chunk.ops {
+        if let Op::LookUp(s, _) = op {
+            global_lookups(*s, env, deps);
+        }
+    }
+    for val in &chunk.consts {
+        if let Some(chunk) = chunk_of(val) {
+            lookups(chunk, env, deps);
+        }
+    }
+}
+
+// The global itself, and what it looks up when it's a fn or a macro
+fn global_lookups<E: Env>(s: Symbol, env: &E, deps: &mut Vec<Symbol>) {
+    if deps.contains(&s) {
+        return;
+    }
+    deps.push(s);
+    if let Some(chunk) = env.lookup(s).as_ref().and_then(chunk_of) {
+        lookups(chunk, env, deps);
+    }
+}
+
+fn chunk_of(val: &Value) -> Option<&Chunk> {
+    match val {
+        Value::Func(f) | Value::Macro(f) => Some(&f.chunk),
+        Value::Closure(closure) => Some(closure.chunk()),
+        _ => None,
+    }
+}
+
+// The macros a form uses are expanded away by the compiler, they're found in the form
+fn macro_lookups<E: Env>(form: &Value, env: &E, deps: &mut Vec<Symbol>) {
+    match form {
+        Value::Symbol(s) if matches!(env.lookup(*s), Some(Value::Macro(_))) => {
+            global_lookups(*s, env, deps);
+        }
+        Value::List(list) | Value::Vector(list) => {
+            for val in list.iter() {
+                macro_lookups(val, env, deps);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn printed<E: Env>(val: &Value, env: &E) -> String {
+    let mut printed = String::new();
+    val.write_to(&mut printed, env).ok();
+    printed
+}
+
+// Evaluate a watched form, with its output sent to the client, into the message of its value
+fn evaluate_watch(
+    handle: usize,
+    watch: &mut Watch,
+    extensions: &Extensions,
+    env: &mut SharedEnv,
+    vm: &mut VM,
+    sink: StreamSink,
+) -> String {
+    let mode = sink.1;
+    let res = compile_with(watch.form.clone(), extensions, env).and_then(|chunk| {
+        watch.deps.clear();
+        macro_lookups(&watch.form, env, &mut watch.deps);
+        lookups(&chunk, env, &mut watch.deps);
+        let previous = output::set_sink(Some(Box::new(sink)));
+        let res = vm.run(chunk, env);
+        output::set_sink(previous);
+        res
+    });
+    mode.watch(handle, res.map(|val| printed(&val, env)))
+}
+
+// (unwatch handle) stops pushing the values of a watch
+fn unwatch(
+    form: &Value,
+    unwatch_symbol: &Value,
+    watches: &mut [Option<Watch>],
+) -> Option<zap::Result<()>> {
+    match form {
+        Value::List(list) if list.len() == 2 && list[0] == *unwatch_symbol => {
+            let watch = match list[1] {
+                Value::Int(n) if n >= 1 => watches.get_mut(n as usize - 1),
+                _ => None,
+            };
+            Some(match watch {
+                Some(watch @ Some(_)) => {
+                    *watch = None;
+                    Ok(())
+                }
+                _ => Err(zap::error_msg(&format!("No watch #{}", list[1]))),
+            })
+        }
+        _ => None,
+    }
+}
+
+// What's done with the value of a form read from the client
+enum Then {
+    Print,
+    Inspect,
+    Watch,
+}
+
+// The client's hello, (hello protocol n), must be for the version of the server.
+fn check_hello(form: &Value, hello_symbol: &Value, protocol_symbol: &Value) -> zap::Result<()> {
+    let version = match form {
+        Value::List(list)
+            if list.len() == 3 && list[0] == *hello_symbol && list[1] == *protocol_symbol =>
+        {
+            &list[2]
+        }
+        _ => {
+            return Err(zap::error_msg(&format!(
+                "Expected (hello protocol {}) before anything else",
+                PROTOCOL_VERSION
+            )))
+        }
+    };
+    match version {
+        Value::Int(n) if *n == i64::from(PROTOCOL_VERSION) => Ok(()),
+        version => Err(zap::error_msg(&format!(
+            "Unsupported protocol {}, this server speaks protocol {}",
+            version, PROTOCOL_VERSION
+        ))),
+    }
+}
+
+// (replay) and (replay n) print the last steps of the previous evaluation. They look at the
+// session's VM, so they're handled by the server too.
+fn replay(form: &Value, replay_symbol: &Value, vm: &VM) -> Option<zap::Result<String>> {
+    let n = match form {
+        Value::List(list) if !list.is_empty() && list[0] == *replay_symbol => match list.get(1) {
+            None if list.len() == 1 => REPLAY_STEPS,
+            Some(Value::Int(n)) if list.len() == 2 && *n >= 0 => *n as usize,
+            _ => return Some(Err(zap::error_msg("replay expects a number of steps"))),
+        },
+        _ => return None,
+    };
+
+    let steps = vm.last_steps(n);
+    if steps.is_empty() {
+        return Some(Ok("No steps recorded.\n".to_string()));
+    }
+    Some(Ok(steps.iter().map(|step| format!("{}\n", step)).collect()))
+}
+
+pub async fn start_repl<R, W>(
     input: &mut R,
-    output: &mut W,
-    mut env: E,
-) -> io::Result<()> {
+    output: W,
+    mut env: SharedEnv,
+    mode: Mode,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
     let mut buf = [0; 1024];
+    // The start of a char split between two reads
+    let mut carry: Vec<u8> = Vec::new();
 
     let mut reader = Reader::new();
 
-    zap_core::load(&mut env).unwrap(); // TODO: Handle thi
+    let load_symbol = env.reg_symbol(zap::String::from("load-plugin"));
+    let replay_symbol = env.reg_symbol(zap::String::from("replay"));
+    let hello_symbol = env.reg_symbol(zap::String::from("hello"));
+    let protocol_symbol = env.reg_symbol(zap::String::from("protocol"));
+    let inspect_symbol = env.reg_symbol(zap::String::from("inspect"));
+    let inspect_path_symbol = env.reg_symbol(zap::String::from("inspect-path"));
+    let mut inspected: Vec<Value> = Vec::new();
+    let watch_symbol = env.reg_symbol(zap::String::from("watch-expr"));
+    let unwatch_symbol = env.reg_symbol(zap::String::from("unwatch"));
+    let mut watches: Vec<Option<Watch>> = Vec::new();
+    let mut changes = env.subscribe();
+    let mut watch_vm = VM::new();
+    let mut vm = VM::with_recording(REPLAY_CAPACITY);
+    let extensions = Extensions::new();
 
-    loop {
-        output.write("> ".as_bytes()).await?;
-        output.flush().await?;
+    // Everything written to the client goes through the writer task, in order
+    let (out, mut pending) = mpsc::channel::<Vec<u8>>(OUTPUT_BUFFER);
+    let writer = tokio::spawn(async move {
+        let mut output = output;
+        while let Some(bytes) = pending.recv().await {
+            output.write_all(&bytes).await?;
+            output.flush().await?;
+        }
+        Ok::<(), io::Error>(())
+    });
 
-        loop {
-            let n = match input.read(&mut buf[..]).await {
-                Ok(0) => return Ok(()),
-                Ok(n) => n,
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    continue;
-                }
-                Err(e) => {
-                    return Err(e);
-                }
-            };
+    let capabilities: Vec<&str> = Capability::ALL
+        .into_iter()
+        .filter(|cap| env.has_capability(*cap))
+        .map(Capability::name)
+        .collect();
+    // A human doesn't answer the hello
+    let mut greeted = mode == Mode::Human;
 
-            let src = std::str::from_utf8(&buf[..n]).unwrap();
-            reader.tokenize(src);
+    let res = async {
+        send(&out, mode.hello(&capabilities)).await?;
+
+        loop {
+            if let Some(prompt) = mode.prompt() {
+                send(&out, prompt).await?;
+            }
 
             loop {
-                match reader.read_ast(&mut env) {
-                    Ok(Some(form)) => {
-                        let env_ref = &mut env;
-
-                        let evaluated = task::block_in_place(move || {
-                            let chunk = compile(form)?;
-                            let start = Instant::now();
-                            let res = vm::run(chunk, env_ref)?;
-                            let end = Instant::now();
-                            println!("Evaluated in {:?}\n", end - start);
-                            Ok(res)
-                        });
-
-                        match evaluated {
-                            Ok(result) => {
-                                let env = &mut env;
-                                output
-                                    .write(format!("{}\n", result.pr_str(env)).as_bytes())
-                                    .await?;
-                            }
-                            Err(ZapErr::Msg(err)) => {
-                                output
-                                    .write(format!("Runtime error: {}\n", err).as_bytes())
-                                    .await?;
+                let n = tokio::select! {
+                    // The definitions made meanwhile come first, the next form sees them
+                    biased;
+                    changed = changes.recv() => {
+                        // Which globals changed is lost when the session fell behind
+                        let changed = match changed {
+                            Ok(id) => Some(id),
+                            Err(broadcast::error::RecvError::Lagged(_)) => None,
+                            Err(broadcast::error::RecvError::Closed) => continue,
+                        };
+                        match changed {
+                            Some(id) => env.refresh(id),
+                            None => (0..env.symbols_count()).for_each(|id| env.refresh(id as Symbol)),
+                        }
+                        for (i, watch) in watches.iter_mut().enumerate() {
+                            let Some(watch) = watch else {
+                                continue;
+                            };
+                            if changed.is_some_and(|id| !watch.deps.contains(&id)) {
+                                continue;
                             }
+                            let sink = StreamSink(out.clone(), mode);
+                            let msg = task::block_in_place(|| {
+                                evaluate_watch(i + 1, watch, &extensions, &mut env, &mut watch_vm, sink)
+                            });
+                            send(&out, msg).await?;
                         }
+                        continue;
                     }
-                    Ok(None) => break,
-                    Err(ZapErr::Msg(err)) => {
-                        output
-                            .write(format!("Reader error: {}\n", err).as_bytes())
-                            .await?;
+                    read = input.read(&mut buf[..]) => match read {
+                        Ok(0) => return Ok(()),
+                        Ok(n) => n,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            continue;
+                        }
+                        Err(e) => {
+                            return Err(e);
+                        }
+                    },
+                };
+
+                carry.extend_from_slice(&buf[..n]);
+                let valid = match std::str::from_utf8(&carry) {
+                    Ok(src) => src.len(),
+                    Err(err) if err.error_len().is_none() => err.valid_up_to(),
+                    Err(_) => {
+                        let err = zap::error_msg("The input is not valid UTF-8");
+                        send(&out, mode.error(ErrorKind::Protocol, err)).await?;
+                        return Ok(());
                     }
+                };
+                let src = String::from_utf8_lossy(&carry[..valid]).into_owned();
+                carry.drain(..valid);
+                reader.tokenize(&src);
+
+                loop {
+                    match reader.read_ast(&mut env) {
+                        Ok(Some(form)) if !greeted => {
+                            if let Err(err) = check_hello(&form, &hello_symbol, &protocol_symbol) {
+                                send(&out, mode.error(ErrorKind::Protocol, err)).await?;
+                                return Ok(());
+                            }
+                            greeted = true;
+                            send(&out, "(ready)\n".to_string()).await?;
+                        }
+                        Ok(Some(form)) => {
+                            if let Some(res) = replay(&form, &replay_symbol, &vm) {
+                                let msg = match res {
+                                    Ok(steps) => mode.output(&steps),
+                                    Err(err) => mode.error(ErrorKind::Runtime, err),
+                                };
+                                send(&out, msg).await?;
+                                continue;
+                            }
+                            if let Some(res) = inspect_path(&form, &inspect_path_symbol) {
+                                let msg = match res.and_then(|at| {
+                                    at.get(&inspected)
+                                        .map(|val| mode.inspection(&at, val, &env))
+                                }) {
+                                    Ok(msg) => msg,
+                                    Err(err) => mode.error(ErrorKind::Runtime, err),
+                                };
+                                send(&out, msg).await?;
+                                continue;
+                            }
+                            if let Some(res) = unwatch(&form, &unwatch_symbol, &mut watches) {
+                                let msg = match res {
+                                    Ok(()) => mode.result("nil"),
+                                    Err(err) => mode.error(ErrorKind::Runtime, err),
+                                };
+                                send(&out, msg).await?;
+                                continue;
+                            }
+
+                            // The value of (inspect exp) is kept, instead of printed in full, and
+                            // the one of (watch-expr exp) is the form to watch
+                            let (form, then) = match form {
+                                Value::List(list)
+                                    if list.len() == 2 && list[0] == inspect_symbol =>
+                                {
+                                    (list[1].clone(), Then::Inspect)
+                                }
+                                Value::List(list)
+                                    if list.len() == 2 && list[0] == watch_symbol =>
+                                {
+                                    (list[1].clone(), Then::Watch)
+                                }
+                                form => (form, Then::Print),
+                            };
+
+                            let mut warnings = Vec::new();
+                            let plugin =
+                                task::block_in_place(|| load_plugin(&form, &load_symbol, &mut env));
+                            let compiled = match plugin {
+                                Some(res) => Err(res.map_err(|err| (ErrorKind::Runtime, err))),
+                                None => task::block_in_place(|| {
+                                    compile_with_warnings(form, &extensions, &mut env, &mut warnings)
+                                        .map_err(|err| Err((ErrorKind::Compile, err)))
+                                }),
+                            };
+                            let evaluated = match compiled {
+                                Ok(chunk) => {
+                                    let start = Instant::now();
+                                    let res = Evaluation {
+                                        run: Box::pin(vm.run_async(chunk, &mut env)),
+                                        sink: StreamSink(out.clone(), mode),
+                                    }
+                                    .await;
+                                    if res.is_ok() {
+                                        println!("Evaluated in {:?}\n", start.elapsed());
+                                    }
+                                    res.map_err(|err| (ErrorKind::Runtime, err))
+                                }
+                                Err(done) => done,
+                            };
+
+                            for warning in &warnings {
+                                send(&out, mode.warning(warning)).await?;
+                            }
+                            let msg = match (evaluated, then) {
+                                (Ok(result), Then::Inspect) => {
+                                    inspected.push(result);
+                                    let at = Inspected {
+                                        handle: inspected.len(),
+                                        path: Vec::new(),
+                                        offset: 0,
+                                    };
+                                    mode.inspection(&at, &inspected[inspected.len() - 1], &env)
+                                }
+                                (Ok(form), Then::Watch) => {
+                                    watches.push(Some(Watch {
+                                        form,
+                                        deps: Vec::new(),
+                                    }));
+                                    let handle = watches.len();
+                                    let watch = watches[handle - 1].as_mut().unwrap();
+                                    let sink = StreamSink(out.clone(), mode);
+                                    task::block_in_place(|| {
+                                        evaluate_watch(
+                                            handle,
+                                            watch,
+                                            &extensions,
+                                            &mut env,
+                                            &mut watch_vm,
+                                            sink,
+                                        )
+                                    })
+                                }
+                                (Ok(result), Then::Print) => mode.result(&printed(&result, &env)),
+                                (Err((kind, err)), _) => mode.error(kind, err),
+                            };
+                            send(&out, msg).await?;
+                        }
+                        Ok(None) => break,
+                        Err(err) => {
+                            send(&out, mode.error(ErrorKind::Reader, err)).await?;
+                        }
+                    }
+                }
+
+                if src.ends_with('\n') {
+                    break;
                 }
             }
+        }
+    }
+    .await;
 
-            if src.ends_with('\n') {
-                break;
+    // Let the writer drain what's left before closing the connection
+    drop(out);
+    writer.await??;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, BufReader, DuplexStream, Lines, ReadHalf, WriteHalf};
+
+    use super::*;
+
+    // A session of start_repl, driven like a client would over a connection
+    struct Client {
+        lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+        input: WriteHalf<DuplexStream>,
+    }
+
+    impl Client {
+        fn start(mode: Mode) -> Client {
+            let (client, server) = io::duplex(4096);
+            let (mut server_input, server_output) = io::split(server);
+            tokio::spawn(async move {
+                start_repl(&mut server_input, server_output, SharedEnv::default(), mode).await
+            });
+            let (output, input) = io::split(client);
+            Client {
+                lines: BufReader::new(output).lines(),
+                input,
             }
         }
+
+        // A session that said hello
+        async fn ready() -> Client {
+            let mut client = Client::start(Mode::Protocol);
+            client.line().await;
+            client.send("(hello protocol 1)\n").await;
+            assert_eq!(client.line().await, "(ready)");
+            client
+        }
+
+        async fn send(&mut self, src: &str) {
+            self.send_bytes(src.as_bytes()).await;
+        }
+
+        async fn send_bytes(&mut self, bytes: &[u8]) {
+            self.input.write_all(bytes).await.unwrap();
+            self.input.flush().await.unwrap();
+        }
+
+        // The next line, or an empty one when the session is over
+        async fn line(&mut self) -> String {
+            self.lines.next_line().await.unwrap().unwrap_or_default()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn framing() {
+        let mut client = Client::ready().await;
+        client.send("(+ 1 2)\n").await;
+        assert_eq!(client.line().await, "(result 3)");
+
+        // Two forms on one line, each with its own message
+        client.send("(def a \"x\") (str a a)\n").await;
+        assert_eq!(client.line().await, "(result \"x\")");
+        assert_eq!(client.line().await, "(result \"xx\")");
+
+        client.send("(throw \"no\")\n").await;
+        assert_eq!(
+            client.line().await,
+            "(error kind runtime message \"no\" trace () span nil)"
+        );
+        client.send("undefined-thing\n").await;
+        assert_eq!(
+            client.line().await,
+            "(error kind runtime message \"symbol 'undefined-thing' not in scope.\" trace () span nil)"
+        );
+        client.send(")\n").await;
+        assert_eq!(
+            client.line().await,
+            "(error kind reader message \"A form cannot begin with ')'\" trace () span nil)"
+        );
+        client.send("(set! x 1)\n").await;
+        assert_eq!(
+            client.line().await,
+            "(error kind compile message \"set! can only change a local\" trace () span nil)"
+        );
+
+        // A human gets a prompt and the values as they're printed
+        let mut client = Client::start(Mode::Human);
+        assert!(client.line().await.starts_with(";; zap-server "));
+        client.send("(+ 1 2)\n").await;
+        assert_eq!(client.line().await, "> 3");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn hello() {
+        let mut client = Client::start(Mode::Protocol);
+        let hello = client.line().await;
+        assert!(hello.starts_with("(hello server \"zap-server\" version "));
+        assert!(hello.contains(
+            " protocol 1 capabilities () limits (replay-capacity 256 output-buffer 64))"
+        ));
+
+        // Another version is the end of the session
+        client.send("(hello protocol 2)\n").await;
+        assert_eq!(
+            client.line().await,
+            "(error kind protocol message \"Unsupported protocol 2, this server speaks protocol 1\" trace () span nil)"
+        );
+        assert_eq!(client.line().await, "");
+
+        // So is anything else before the hello
+        let mut client = Client::start(Mode::Protocol);
+        client.line().await;
+        client.send("(+ 1 2)\n").await;
+        assert!(client
+            .line()
+            .await
+            .starts_with("(error kind protocol message \"Expected (hello protocol 1)"));
+        assert_eq!(client.line().await, "");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn split_chars() {
+        let mut client = Client::ready().await;
+        // A char cut in two by the reads is read whole
+        let src = "(str \"é\")\n".as_bytes();
+        let cut = src.iter().position(|b| !b.is_ascii()).unwrap() + 1;
+        client.send_bytes(&src[..cut]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        client.send_bytes(&src[cut..]).await;
+        assert_eq!(client.line().await, "(result \"é\")");
+
+        // Bytes that are no char end the session
+        client.send_bytes(b"(str \"\xff\")\n").await;
+        assert_eq!(
+            client.line().await,
+            "(error kind protocol message \"The input is not valid UTF-8\" trace () span nil)"
+        );
+        assert_eq!(client.line().await, "");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn inspect() {
+        let mut client = Client::ready().await;
+        client.send("(inspect '(1 (2 3) \"a\"))\n").await;
+        assert_eq!(
+            client.line().await,
+            "(inspection handle 1 path () offset 0 count 3 items ((value 1) (list 2) (value \"a\")))"
+        );
+        client.send("(inspect-path 1 (1 0))\n").await;
+        assert_eq!(
+            client.line().await,
+            "(inspection handle 1 path (1 0) value 2)"
+        );
+        client.send("(inspect-path 1 (1) 1)\n").await;
+        assert_eq!(
+            client.line().await,
+            "(inspection handle 1 path (1) offset 1 count 2 items ((value 3)))"
+        );
+        client.send("(inspect-path 2 ())\n").await;
+        assert_eq!(
+            client.line().await,
+            "(error kind runtime message \"No inspected value #2\" trace () span nil)"
+        );
+        client.send("(inspect-path 1 (5))\n").await;
+        assert_eq!(
+            client.line().await,
+            "(error kind runtime message \"No item at path (5)\" trace () span nil)"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streamed_output() {
+        let mut client = Client::ready().await;
+        // Each print is sent as it's made, before the result
+        client.send("(do (print \"a\") (println \"b\") 1)\n").await;
+        assert_eq!(client.line().await, "(out \"a\")");
+        assert_eq!(client.line().await, "(out \"b\\n\")");
+        assert_eq!(client.line().await, "(result 1)");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replay() {
+        let mut client = Client::ready().await;
+        client.send("(replay)\n").await;
+        assert_eq!(client.line().await, "(out \"No steps recorded.\\n\")");
+
+        client.send("(+ 1 2)\n").await;
+        client.line().await;
+        client.send("(replay 1)\n").await;
+        assert_eq!(
+            client.line().await,
+            "(out \"RETURN                             1 -1\\n\")"
+        );
+
+        client.send("(replay -1)\n").await;
+        assert_eq!(
+            client.line().await,
+            "(error kind runtime message \"replay expects a number of steps\" trace () span nil)"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watched() {
+        let mut client = Client::ready().await;
+        client.send("(def w 1)\n").await;
+        client.line().await;
+        client.send("(defn f () (+ w 1))\n").await;
+        client.line().await;
+        client.send("(watch-expr '(f))\n").await;
+        assert_eq!(client.line().await, "(watch handle 1 value 2)");
+
+        // Pushed again when a global the fn it calls looks up is defined
+        client.send("(def w 10)\n").await;
+        assert_eq!(client.line().await, "(result 10)");
+        assert_eq!(client.line().await, "(watch handle 1 value 11)");
+
+        client.send("(unwatch 1)\n").await;
+        assert_eq!(client.line().await, "(result nil)");
+        client.send("(def w 20)\n").await;
+        assert_eq!(client.line().await, "(result 20)");
+        client.send("w\n").await;
+        assert_eq!(client.line().await, "(result 20)");
+    }
+
+    fn read(src: &str, env: &mut SharedEnv) -> Value {
+        let mut reader = Reader::new();
+        reader.tokenize(src);
+        reader.flush_token();
+        reader.read_ast(env).unwrap().unwrap()
+    }
+
+    fn eval(src: &str, env: &mut SharedEnv) {
+        let chunk = compile_with(read(src, env), &Extensions::new(), env).unwrap();
+        VM::new().run(chunk, env).unwrap();
+    }
+
+    // The names of the globals a watched form depends on
+    fn deps(src: &str, env: &mut SharedEnv) -> Vec<String> {
+        let form = read(src, env);
+        let chunk = compile_with(form.clone(), &Extensions::new(), env).unwrap();
+        let mut deps = Vec::new();
+        macro_lookups(&form, env, &mut deps);
+        lookups(&chunk, env, &mut deps);
+        let mut names: Vec<String> = deps
+            .into_iter()
+            .map(|s| env.get_symbol(s).unwrap().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn watch_deps() {
+        let mut env = SharedEnv::default();
+        eval("(def x 1)", &mut env);
+        eval("(def y 2)", &mut env);
+
+        // Through the fns called, and the ones they call
+        eval("(defn f () x)", &mut env);
+        eval("(defn g () (f))", &mut env);
+        assert_eq!(deps("(g)", &mut env), ["f", "g", "x"]);
+
+        // In a closure, made by the form or by a fn it calls
+        assert_eq!(deps("(let (z 1) ((fn () (+ z x))))", &mut env), ["x"]);
+        eval("(defn adder (n) (fn () (+ n y)))", &mut env);
+        assert_eq!(deps("((adder 1))", &mut env), ["adder", "y"]);
+
+        // A macro, expanded away, and what its expansion looks up
+        eval("(defmacro the-x () 'x)", &mut env);
+        assert_eq!(deps("(the-x)", &mut env), ["the-x", "x"]);
+
+        // A recursive fn is followed once
+        eval("(defn down (n) (if (= n 0) y (down (- n 1))))", &mut env);
+        assert_eq!(deps("(down 3)", &mut env), ["down", "y"]);
     }
 }
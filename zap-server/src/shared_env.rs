@@ -1,17 +1,31 @@
+use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use zap::env::{symbols, Env, Scope, SymbolTable};
-use zap::{error_msg, Result, String, Symbol, Value};
+use tokio::sync::broadcast;
+
+use zap::env::{new_generation, not_in_scope, symbols, Capability, Env, Scope, SymbolTable};
+use zap::prelude::{Ctx, VM};
+use zap::{error_msg, Arity, Pending, Result, String, Symbol, Task, Value};
 
 // SharedEnv, a shared environement.
 // Every changes to the env made from the runtime are
 // made available to all other shared envs on the same
 // hub.
 
+// How many definitions a session can fall behind on before it misses which ones changed.
+const CHANGES_CAPACITY: usize = 256;
+
 pub struct SharedEnv {
     globals: Scope,
     shared_globals: Arc<RwLock<Scope>>,
     symbols: Arc<RwLock<SymbolTable>>,
+    capabilities: Arc<Vec<Capability>>,
+    layers: Vec<Scope>,        // Nothing defined in a layer is shared
+    namespace: Option<String>, // Each session is in its own one
+    aliases: HashMap<(Option<String>, String), String>, // And has its own requires
+    changes: broadcast::Sender<Symbol>, // The globals defined by every session
+    generation: u64,           // Of globals, this copy's own
 }
 
 impl Default for SharedEnv {
@@ -20,22 +34,131 @@ impl Default for SharedEnv {
             globals: Scope::default(),
             shared_globals: Arc::new(RwLock::new(Scope::default())),
             symbols: Arc::new(RwLock::new(SymbolTable::default())),
+            capabilities: Arc::new(Vec::new()),
+            layers: Vec::new(),
+            namespace: None,
+            aliases: HashMap::new(),
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+            generation: new_generation(),
         };
 
         for s in symbols::DEFAULT_SYMBOLS {
             this.reg_symbol(String::from(s));
         }
 
+        // Every session starts with the same vocabulary as the other frontends, and what only
+        // a server can do without blocking
+        zap::core::load(&mut this).unwrap();
+        this.reg_fn_async("sleep", Arity::Exactly(1), sleep)
+            .unwrap();
+        this.reg_fn_ctx("spawn", Arity::AtLeast(1), spawn).unwrap();
+        this.reg_fn_async("join", Arity::Exactly(1), join).unwrap();
         this
     }
 }
 
+// (sleep ms) suspends the evaluation, the thread is free for the other sessions meanwhile.
+fn sleep(args: &[Value]) -> Pending {
+    let ms = match args[0] {
+        Value::Int(ms) if ms >= 0 => ms.unsigned_abs(),
+        _ => {
+            let err = error_msg("'sleep' requires a number of milliseconds.");
+            return Box::pin(async { Err(err) });
+        }
+    };
+    Box::pin(async move {
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+        Ok(Value::Nil)
+    })
+}
+
+// (spawn f args...) calls f on a tokio task of its own, with a fork of the env, and is that task.
+// What it defines is shared like the definitions of a session.
+fn spawn(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    if !matches!(
+        args[0],
+        Value::Func(_) | Value::Closure(_) | Value::FuncNative(_)
+    ) {
+        return Err(error_msg("'spawn' requires a function."));
+    }
+    let Some(mut env) = ctx.env().fork() else {
+        return Err(error_msg("This env can't be shared with a task."));
+    };
+    let runtime = tokio::runtime::Handle::try_current()
+        .map_err(|_| error_msg("'spawn' can only be used on the server."))?;
+    let chunk = zap::vm::call_chunk(args[0].clone(), &args[1..])?;
+    let task = Arc::new(Task::default());
+    let running = task.clone();
+    runtime.spawn(async move {
+        let res = VM::new().run_async(chunk, &mut *env).await;
+        running.finish(res);
+    });
+    Ok(Value::Task(task))
+}
+
+// (join task) waits for the task, and is what its fn returned. Its error is raised here.
+fn join(args: &[Value]) -> Pending {
+    match &args[0] {
+        Value::Task(task) => task.join(),
+        _ => Box::pin(async { Err(error_msg("'join' requires a task.")) }),
+    }
+}
+
 impl Clone for SharedEnv {
     fn clone(&self) -> Self {
         SharedEnv {
             globals: self.shared_globals.read().unwrap().clone(), // I don't like copying all the globals every time we get a new env
             shared_globals: self.shared_globals.clone(),
             symbols: self.symbols.clone(),
+            capabilities: self.capabilities.clone(),
+            layers: Vec::new(),
+            namespace: None,
+            aliases: HashMap::new(),
+            changes: self.changes.clone(),
+            generation: new_generation(),
+        }
+    }
+}
+
+impl SharedEnv {
+    pub fn with_capabilities(mut self, capabilities: Vec<Capability>) -> Self {
+        self.capabilities = Arc::new(capabilities);
+        self
+    }
+
+    // The globals defined from now on, by any session
+    pub fn subscribe(&self) -> broadcast::Receiver<Symbol> {
+        self.changes.subscribe()
+    }
+
+    // Take the shared value of a global, defined by another session
+    pub fn refresh(&mut self, id: Symbol) {
+        let shared = self.shared_globals.read().unwrap();
+        if self.globals.len() < shared.len() {
+            self.globals.resize(shared.len(), None);
+        }
+        if let Some(val) = shared.get(id as usize) {
+            self.globals[id as usize] = val.clone();
+            self.generation = new_generation();
+        }
+    }
+
+    // A global this copy hasn't seen, defined by another session or a spawned task. Its symbol
+    // may be newer than this copy's globals too.
+    fn shared(&self, id: Symbol) -> Option<Value> {
+        self.shared_globals
+            .read()
+            .unwrap()
+            .get(id as usize)
+            .cloned()
+            .flatten()
+    }
+
+    // Room in the globals for the symbols registered by the others
+    fn grow(&mut self) {
+        let count = self.symbols_count();
+        if self.globals.len() < count {
+            self.globals.resize(count, None);
         }
     }
 }
@@ -43,19 +166,51 @@ impl Clone for SharedEnv {
 impl Env for SharedEnv {
     #[inline(always)]
     fn get_by_id(&self, id: Symbol) -> Result<Value> {
-        match unsafe { self.globals.get_unchecked(id as usize) } {
-            Some(val) => Ok(val.clone()),
+        if let Some(Some(val)) = self.globals.get(id as usize) {
+            return Ok(val.clone());
+        }
+        match self.shared(id) {
+            Some(val) => Ok(val),
             None => Err(match self.get_symbol(id) {
-                Ok(s) => error_msg(format!("symbol '{}' not in scope.", s).as_str()),
+                Ok(s) => not_in_scope(
+                    &s,
+                    self.symbols
+                        .read()
+                        .unwrap()
+                        .iter()
+                        // Other sessions can register symbols this one hasn't seen yet
+                        .filter(|(_, id)| {
+                            self.globals.get(*id as usize).is_some_and(Option::is_some)
+                        })
+                        .map(|(s, _)| s),
+                ),
                 Err(err) => err,
             }),
         }
     }
 
+    fn lookup(&self, id: Symbol) -> Option<Value> {
+        self.globals
+            .get(id as usize)
+            .cloned()
+            .flatten()
+            .or_else(|| self.shared(id))
+    }
+
+    fn generation(&self) -> Option<u64> {
+        Some(self.generation)
+    }
+
     fn set(&mut self, key: &Value, val: &Value) -> Result<()> {
         if let Value::Symbol(id) = key {
-            self.shared_globals.write().unwrap()[*id as usize] = Some(val.clone());
+            self.grow();
             self.globals[*id as usize] = Some(val.clone());
+            self.generation = new_generation();
+            if self.layers.is_empty() {
+                self.shared_globals.write().unwrap()[*id as usize] = Some(val.clone());
+                // Nobody may be listening
+                self.changes.send(*id).ok();
+            }
             Ok(())
         } else {
             Err(error_msg("Env set: only symbols can be used as keys."))
@@ -64,21 +219,115 @@ impl Env for SharedEnv {
 
     fn reg_symbol(&mut self, s: String) -> Value {
         let mut symbols = self.symbols.write().unwrap();
-        let len = symbols.len();
-        let id = symbols.entry(s).or_insert_with(|| {
+        let (id, new) = symbols.intern(s);
+        if new {
             self.shared_globals.write().unwrap().push(None);
-            self.globals.push(None);
-            len.try_into().unwrap()
-        });
-        Value::Symbol(*id)
+        }
+        drop(symbols);
+        self.grow();
+        Value::Symbol(id)
     }
 
     fn get_symbol(&self, id: Symbol) -> Result<String> {
-        let symbols = self.symbols.read().unwrap();
-        symbols
-            .iter()
-            .find(|(_, v)| **v == id)
-            .map(|(k, _)| k.clone())
-            .ok_or_else(|| error_msg(format!("No known symbol for id={}", id).as_str()))
+        self.symbols.read().unwrap().name(id)
+    }
+
+    fn symbols_count(&self) -> usize {
+        self.symbols.read().unwrap().len()
+    }
+
+    fn find_symbol(&self, name: &str) -> Option<Symbol> {
+        self.symbols.read().unwrap().id(name)
+    }
+
+    fn namespace(&self) -> Option<String> {
+        self.namespace.clone()
+    }
+
+    fn set_namespace(&mut self, ns: Option<&str>) -> Result<()> {
+        self.namespace = ns.map(String::from);
+        Ok(())
+    }
+
+    fn add_alias(&mut self, alias: &str, target: &str) -> Result<()> {
+        self.aliases.insert(
+            (self.namespace.clone(), String::from(alias)),
+            String::from(target),
+        );
+        Ok(())
+    }
+
+    fn alias(&self, alias: &str) -> Option<String> {
+        self.aliases
+            .get(&(self.namespace.clone(), String::from(alias)))
+            .cloned()
+    }
+
+    fn has_capability(&self, cap: Capability) -> bool {
+        self.capabilities.contains(&cap)
+    }
+
+    fn enter_layer(&mut self) -> Result<()> {
+        self.layers.push(self.globals.clone());
+        Ok(())
+    }
+
+    fn leave_layer(&mut self) {
+        if let Some(mut globals) = self.layers.pop() {
+            globals.resize(self.globals.len(), None);
+            self.globals = globals;
+            self.generation = new_generation();
+        }
+    }
+
+    fn fork(&self) -> Option<Box<dyn Env + Send>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zap::compiler::{compile_with, Extensions};
+    use zap::reader::Reader;
+
+    use super::*;
+
+    async fn eval(src: &str, env: &mut SharedEnv) -> Result<Value> {
+        let mut reader = Reader::new();
+        reader.tokenize(src);
+        reader.flush_token();
+        let mut res = Ok(Value::Nil);
+        while let Some(form) = reader.read_ast(env)? {
+            let chunk = compile_with(form, &Extensions::new(), env)?;
+            res = VM::new().run_async(chunk, env).await;
+        }
+        res
+    }
+
+    #[tokio::test]
+    async fn spawned_definitions() {
+        let mut env = SharedEnv::default();
+        // A global the parent never registered, read and set after the task defined it
+        assert_eq!(
+            eval("(join (spawn (fn () (def from-task 41))))", &mut env).await,
+            Ok(Value::Int(41))
+        );
+        assert_eq!(eval("from-task", &mut env).await, Ok(Value::Int(41)));
+        assert_eq!(
+            eval("(def from-task (+ from-task 1)) from-task", &mut env).await,
+            Ok(Value::Int(42))
+        );
+
+        // Two tasks at once, each registering its own globals
+        assert_eq!(
+            eval(
+                "(def a (spawn (fn () (def in-a 1) in-a)))
+                 (def b (spawn (fn () (def in-b 2) in-b)))
+                 (+ (join a) (join b) in-a in-b)",
+                &mut env
+            )
+            .await,
+            Ok(Value::Int(6))
+        );
     }
 }
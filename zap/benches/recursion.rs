@@ -0,0 +1,49 @@
+// The recursion benchmark of zap-for-profiling, on the two backends: the stack ops, and the
+// register-ops build, whose AddSlots and EqSlots take both their operands from the frame's slots
+// rather than loading them on the stack first. The slots recursion is the one they apply to, the
+// const one is there to show the other ops aren't slowed down.
+//
+// cargo bench -p zap --bench recursion
+// cargo bench -p zap --bench recursion --features register-ops
+//
+// On the slots recursion, 100,000 calls took 6.6 ms on the stack ops and 3.9 to 4.8 ms with
+// register-ops. The consts one took 3.5 to 5.5 ms on both, in the noise of the machine.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use zap::compiler::{compile_with, Extensions};
+use zap::env::SandboxEnv;
+use zap::reader::Reader;
+use zap::vm::VM;
+
+const SLOTS: &str = "(def rec (fn (x step) (if (= x 100000) \"boom\" (rec (+ x step) step))))";
+const CONSTS: &str = "(def rec (fn (x) (if (= x 100000) \"boom\" (rec (+ x 1)))))";
+
+fn run(c: &mut Criterion, name: &str, def: &str, call: &str) {
+    let mut env = SandboxEnv::default();
+    let mut extensions = Extensions::new();
+    // Calls to rec, not its body inlined
+    extensions.set_inline_limit(0);
+    let mut vm = VM::new();
+    let compile = |src: &str, env: &mut SandboxEnv| {
+        let mut reader = Reader::new();
+        reader.tokenize(src);
+        reader.flush_token();
+        let ast = reader.read_ast(env).unwrap().unwrap();
+        compile_with(ast, &extensions, env).unwrap()
+    };
+    let def = compile(def, &mut env);
+    vm.run(def, &mut env).unwrap();
+    let call = compile(call, &mut env);
+
+    c.bench_function(name, |b| {
+        b.iter(|| vm.run(call.clone(), &mut env).unwrap());
+    });
+}
+
+fn recursion(c: &mut Criterion) {
+    run(c, "recursion slots", SLOTS, "(rec 0 1)");
+    run(c, "recursion consts", CONSTS, "(rec 0)");
+}
+
+criterion_group!(benches, recursion);
+criterion_main!(benches);
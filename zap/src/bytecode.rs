@@ -0,0 +1,727 @@
+use std::sync::Arc;
+
+use crate::compiler::Outer;
+use crate::decimal::Decimal;
+use crate::env::Env;
+use crate::reader::Span;
+use crate::time::{DateTime, Duration};
+use crate::vm::{CaseKey, Chunk, Journal, JumpTable, Op};
+use crate::zap::{error_msg, Closure, Result, String, Symbol, Value, ZapFn};
+
+// Compiled chunks as bytes, to ship a program and run it without the reader and the compiler.
+// Symbols belong to an env, so they are written by name and registered again when loading. The
+// native functions are written by name too, and taken from the env the chunk is loaded in.
+//
+// The chunks are verified when loading, the outer one as a top-level chunk and the others as the
+// chunks of functions.
+
+const MAGIC: &[u8; 4] = b"ZAPC";
+const FORMAT_VERSION: u8 = 3;
+
+impl Chunk {
+    // The chunk, its consts and the chunks of the functions in them, as bytes.
+    pub fn serialize<E: Env + ?Sized>(&self, env: &E) -> Result<Vec<u8>> {
+        let mut writer = Writer {
+            bytes: MAGIC.to_vec(),
+            env,
+        };
+        writer.u8(FORMAT_VERSION);
+        writer.chunk(self)?;
+        Ok(writer.bytes)
+    }
+
+    // A chunk written by serialize, with its symbols registered in env.
+    pub fn deserialize<E: Env + ?Sized>(bytes: &[u8], env: &mut E) -> Result<Chunk> {
+        let bytes = bytes
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| error_msg("Not a compiled zap chunk."))?;
+        let mut reader = BytesReader { bytes, env };
+        if reader.u8()? != FORMAT_VERSION {
+            return Err(error_msg(
+                "The chunk was compiled by another version of zap.",
+            ));
+        }
+        let chunk = reader.chunk()?;
+        if !reader.bytes.is_empty() {
+            return Err(error_msg("Unexpected bytes after the chunk."));
+        }
+        chunk.verify_top_level()?;
+        Ok(chunk)
+    }
+}
+
+const JOURNAL_MAGIC: &[u8; 4] = b"ZAPJ";
+
+// A journal is written like the consts of a chunk, the errors by their message. A value that's
+// only good for the run that made it, like a coroutine, can't be written.
+impl Journal {
+    pub fn serialize<E: Env + ?Sized>(&self, env: &E) -> Result<Vec<u8>> {
+        let mut writer = Writer {
+            bytes: JOURNAL_MAGIC.to_vec(),
+            env,
+        };
+        writer.u8(FORMAT_VERSION);
+        writer.len(self.results.len(), "results")?;
+        for res in &self.results {
+            match res {
+                Ok(val) => {
+                    writer.u8(0);
+                    writer.value(val)?;
+                }
+                Err(err) => {
+                    writer.u8(1);
+                    writer.str(&err.message())?;
+                }
+            }
+        }
+        Ok(writer.bytes)
+    }
+
+    pub fn deserialize<E: Env + ?Sized>(bytes: &[u8], env: &mut E) -> Result<Journal> {
+        let bytes = bytes
+            .strip_prefix(JOURNAL_MAGIC)
+            .ok_or_else(|| error_msg("Not a zap journal."))?;
+        let mut reader = BytesReader { bytes, env };
+        if reader.u8()? != FORMAT_VERSION {
+            return Err(error_msg(
+                "The journal was recorded by another version of zap.",
+            ));
+        }
+        let mut journal = Journal::default();
+        for _ in 0..reader.len()? {
+            let res = if reader.bool()? {
+                Err(error_msg(reader.str()?))
+            } else {
+                Ok(reader.value()?)
+            };
+            journal.results.push_back(res);
+        }
+        if !reader.bytes.is_empty() {
+            return Err(error_msg("Unexpected bytes after the journal."));
+        }
+        Ok(journal)
+    }
+}
+
+fn too_big(what: &str) -> crate::zap::ZapErr {
+    error_msg(&format!("The chunk has too many {} to be written.", what))
+}
+
+struct Writer<'a, E: Env + ?Sized> {
+    bytes: Vec<u8>,
+    env: &'a E,
+}
+
+impl<E: Env + ?Sized> Writer<'_, E> {
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    fn u16(&mut self, n: u16) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn len(&mut self, n: usize, what: &str) -> Result<()> {
+        self.u32(n.try_into().map_err(|_| too_big(what))?);
+        Ok(())
+    }
+
+    fn str(&mut self, s: &str) -> Result<()> {
+        self.len(s.len(), "bytes in a string")?;
+        self.bytes.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+
+    fn symbol(&mut self, s: Symbol) -> Result<()> {
+        let name = self
+            .env
+            .get_symbol(s)
+            .map_err(|_| error_msg(&format!("Symbol #{} has no name, it can't be written.", s)))?;
+        self.str(&name)
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) -> Result<()> {
+        self.len(chunk.scope_size, "locals")?;
+        self.u8(chunk.arity);
+        self.u8(chunk.variadic.into());
+        match chunk.self_slot {
+            Some(slot) => {
+                self.u8(1);
+                self.u16(slot);
+            }
+            None => self.u8(0),
+        }
+        match chunk.name {
+            Some(name) => {
+                self.u8(1);
+                self.symbol(name)?;
+            }
+            None => self.u8(0),
+        }
+
+        self.len(chunk.consts.len(), "consts")?;
+        for val in &chunk.consts {
+            self.value(val)?;
+        }
+        self.len(chunk.tables.len(), "jump tables")?;
+        for table in &chunk.tables {
+            self.table(table)?;
+        }
+        self.len(chunk.ops.len(), "ops")?;
+        for op in &chunk.ops {
+            self.op(*op)?;
+        }
+        self.len(chunk.spans.len(), "spans")?;
+        for (idx, span) in &chunk.spans {
+            self.len(*idx, "ops")?;
+            self.u32(span.line);
+            self.u32(span.col);
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, val: &Value) -> Result<()> {
+        match val {
+            Value::Nil => self.u8(0),
+            Value::Bool(b) => {
+                self.u8(1);
+                self.u8((*b).into());
+            }
+            Value::Number(n) => {
+                self.u8(2);
+                self.bytes.extend_from_slice(&n.to_bits().to_le_bytes());
+            }
+            Value::Int(n) => {
+                self.u8(11);
+                self.bytes.extend_from_slice(&n.to_le_bytes());
+            }
+            // Written as it's printed, which keeps its scale
+            Value::Decimal(d) => {
+                self.u8(3);
+                self.str(&d.to_string())?;
+            }
+            Value::Duration(d) => {
+                self.u8(14);
+                self.bytes.extend_from_slice(&d.nanos().to_le_bytes());
+            }
+            Value::DateTime(t) => {
+                self.u8(15);
+                self.bytes.extend_from_slice(&t.unix_nanos().to_le_bytes());
+            }
+            Value::Symbol(s) => {
+                self.u8(4);
+                self.symbol(*s)?;
+            }
+            Value::Keyword(s) => {
+                self.u8(12);
+                self.symbol(*s)?;
+            }
+            Value::Str(s) => {
+                self.u8(5);
+                self.str(s)?;
+            }
+            Value::List(list) => {
+                self.u8(6);
+                self.len(list.len(), "items in a list")?;
+                for val in list.iter() {
+                    self.value(val)?;
+                }
+            }
+            Value::Vector(vector) => {
+                self.u8(13);
+                self.len(vector.len(), "items in a vector")?;
+                for val in vector.iter() {
+                    self.value(val)?;
+                }
+            }
+            Value::FuncNative(f) => {
+                self.u8(7);
+                self.str(&f.name)?;
+            }
+            Value::Func(f) | Value::Macro(f) => {
+                self.u8(if matches!(val, Value::Func(_)) { 8 } else { 9 });
+                self.len(f.locals.len(), "locals")?;
+                for val in &f.locals {
+                    self.value(val)?;
+                }
+                self.chunk(&f.chunk)?;
+            }
+            Value::Closure(closure) => {
+                self.u8(10);
+                self.len(closure.outers.len(), "outers")?;
+                for outer in &closure.outers {
+                    self.u16(outer.position);
+                    self.u16(outer.dest);
+                }
+                self.chunk(&closure.chunk)?;
+            }
+            // Their state is only good for the run that made them
+            Value::Coroutine(_) => {
+                return Err(error_msg("A coroutine can't be written in a chunk."));
+            }
+            Value::Task(_) => return Err(error_msg("A task can't be written in a chunk.")),
+            Value::Channel(_) => return Err(error_msg("A channel can't be written in a chunk.")),
+            Value::Atom(_) => return Err(error_msg("An atom can't be written in a chunk.")),
+        }
+        Ok(())
+    }
+
+    fn case_key(&mut self, key: &CaseKey) -> Result<()> {
+        match key {
+            CaseKey::Nil => self.u8(0),
+            CaseKey::Bool(b) => {
+                self.u8(1);
+                self.u8((*b).into());
+            }
+            CaseKey::Number(bits) => {
+                self.u8(2);
+                self.bytes.extend_from_slice(&bits.to_le_bytes());
+            }
+            CaseKey::Int(n) => {
+                self.u8(6);
+                self.bytes.extend_from_slice(&n.to_le_bytes());
+            }
+            CaseKey::Decimal(d) => {
+                self.u8(3);
+                self.str(&d.to_string())?;
+            }
+            CaseKey::Duration(d) => {
+                self.u8(8);
+                self.bytes.extend_from_slice(&d.nanos().to_le_bytes());
+            }
+            CaseKey::DateTime(t) => {
+                self.u8(9);
+                self.bytes.extend_from_slice(&t.unix_nanos().to_le_bytes());
+            }
+            CaseKey::Symbol(s) => {
+                self.u8(4);
+                self.symbol(*s)?;
+            }
+            CaseKey::Keyword(s) => {
+                self.u8(7);
+                self.symbol(*s)?;
+            }
+            CaseKey::Str(s) => {
+                self.u8(5);
+                self.str(s)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The targets are sorted by their bytes, the same table is always written the same way
+    fn table(&mut self, table: &JumpTable) -> Result<()> {
+        let mut targets = Vec::with_capacity(table.targets.len());
+        for (key, target) in &table.targets {
+            let mut entry = Writer {
+                bytes: Vec::new(),
+                env: self.env,
+            };
+            entry.case_key(key)?;
+            entry.u16(*target);
+            targets.push(entry.bytes);
+        }
+        targets.sort_unstable();
+
+        self.u16(table.default);
+        self.len(targets.len(), "targets in a jump table")?;
+        for entry in targets {
+            self.bytes.extend_from_slice(&entry);
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, op: Op) -> Result<()> {
+        let (tag, operand) = match op {
+            Op::Push(n) => (0, Some(n)),
+            Op::Call(n) => (1, Some(n)),
+            Op::Tailcall(n) => (2, Some(n)),
+            Op::TailcallSelf(n) => (3, Some(n)),
+            Op::Apply(n) => (47, Some(n)),
+            Op::TailApply(n) => (48, Some(n)),
+            Op::CondJmp(n) => (4, Some(n)),
+            Op::Jmp(n) => (5, Some(n)),
+            Op::Loop(n) => (6, Some(n)),
+            Op::CondJmpW(n) | Op::JmpW(n) | Op::LoopW(n) => {
+                self.u8(match op {
+                    Op::CondJmpW(_) => 35,
+                    Op::JmpW(_) => 36,
+                    _ => 37,
+                });
+                self.u32(n);
+                return Ok(());
+            }
+            // The cache is given when loading
+            Op::LookUp(s, _) => {
+                self.u8(7);
+                return self.symbol(s);
+            }
+            Op::Define => (8, None),
+            Op::Pop => (9, None),
+            Op::Load(slot) => {
+                self.u8(10);
+                self.u8(slot);
+                return Ok(());
+            }
+            Op::Store(slot) => {
+                self.u8(11);
+                self.u8(slot);
+                return Ok(());
+            }
+            Op::LoadAddConst(slot, n) => {
+                self.u8(41);
+                self.u8(slot);
+                self.u16(n);
+                return Ok(());
+            }
+            Op::LoadEqConstJmp(slot, c, n) => {
+                self.u8(42);
+                self.u8(slot);
+                self.u16(c);
+                self.u16(n);
+                return Ok(());
+            }
+            Op::AddSlots(a, b) | Op::EqSlots(a, b) => {
+                self.u8(if matches!(op, Op::AddSlots(..)) {
+                    44
+                } else {
+                    45
+                });
+                self.u8(a);
+                self.u8(b);
+                return Ok(());
+            }
+            Op::AddConst(n) => (12, Some(n)),
+            Op::Add => (13, None),
+            Op::EqConst(n) => (14, Some(n)),
+            Op::Eq => (15, None),
+            Op::Return => (16, None),
+            Op::Closure => (17, None),
+            Op::Switch(n) => (18, Some(n)),
+            Op::Try(n) => (19, Some(n)),
+            Op::EndTry => (20, None),
+            Op::Throw => (21, None),
+            Op::EnterEnv => (22, None),
+            Op::LeaveEnv => (23, None),
+            Op::LoadFile => (24, None),
+            Op::Disasm => (25, None),
+            Op::SubConst(n) => (26, Some(n)),
+            Op::Sub => (27, None),
+            Op::MulConst(n) => (28, Some(n)),
+            Op::Mul => (29, None),
+            Op::DivConst(n) => (30, Some(n)),
+            Op::Div => (31, None),
+            Op::RemConst(n) => (32, Some(n)),
+            Op::Rem => (33, None),
+            Op::Not => (34, None),
+            Op::LoadW(n) => (38, Some(n)),
+            Op::StoreW(n) => (39, Some(n)),
+            Op::Break => (40, None),
+            Op::Yield => (43, None),
+            Op::MakeList(n) => (46, Some(n)),
+            Op::MakeVec(n) => (49, Some(n)),
+        };
+        self.u8(tag);
+        if let Some(n) = operand {
+            self.u16(n);
+        }
+        Ok(())
+    }
+}
+
+fn corrupted(what: &str) -> crate::zap::ZapErr {
+    error_msg(&format!("The chunk is corrupted: {}.", what))
+}
+
+struct BytesReader<'a, E: Env + ?Sized> {
+    bytes: &'a [u8],
+    env: &'a mut E,
+}
+
+impl<E: Env + ?Sized> BytesReader<'_, E> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let (taken, rest) = self
+            .bytes
+            .split_first_chunk::<N>()
+            .ok_or_else(|| error_msg("The chunk is truncated."))?;
+        self.bytes = rest;
+        Ok(*taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(corrupted("a bool is neither 0 nor 1")),
+        }
+    }
+
+    fn str(&mut self) -> Result<&str> {
+        let len = self.len()?;
+        if len > self.bytes.len() {
+            return Err(error_msg("The chunk is truncated."));
+        }
+        let (s, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        std::str::from_utf8(s).map_err(|_| corrupted("a string is not valid UTF-8"))
+    }
+
+    fn symbol(&mut self) -> Result<Symbol> {
+        let name = String::from(self.str()?);
+        match self.env.reg_symbol(name) {
+            Value::Symbol(s) => Ok(s),
+            _ => Err(corrupted("a symbol can't be registered")),
+        }
+    }
+
+    fn decimal(&mut self) -> Result<Decimal> {
+        Decimal::parse(self.str()?).ok_or_else(|| corrupted("a decimal can't be parsed"))
+    }
+
+    fn chunk(&mut self) -> Result<Chunk> {
+        let mut chunk = Chunk {
+            scope_size: self.len()?,
+            arity: self.u8()?,
+            variadic: self.bool()?,
+            ..Chunk::default()
+        };
+        chunk.self_slot = if self.bool()? {
+            Some(self.u16()?)
+        } else {
+            None
+        };
+        chunk.name = if self.bool()? {
+            Some(self.symbol()?)
+        } else {
+            None
+        };
+
+        for _ in 0..self.len()? {
+            let val = self.value()?;
+            chunk.consts.push(val);
+        }
+        for _ in 0..self.len()? {
+            let table = self.table()?;
+            chunk.tables.push(table);
+        }
+        for _ in 0..self.len()? {
+            let op = self.op()?;
+            chunk.ops.push(op);
+        }
+        for _ in 0..self.len()? {
+            let idx = self.len()?;
+            let span = Span {
+                line: self.u32()?,
+                col: self.u32()?,
+            };
+            chunk.spans.push((idx, span));
+        }
+        chunk.cache_lookups();
+        Ok(chunk)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        let val = match self.u8()? {
+            0 => Value::Nil,
+            1 => Value::Bool(self.bool()?),
+            2 => Value::Number(f64::from_bits(self.u64()?)),
+            11 => Value::Int(self.u64()?.cast_signed()),
+            3 => Value::Decimal(self.decimal()?),
+            14 => Value::Duration(Duration::from_nanos(self.u64()?.cast_signed())),
+            15 => Value::DateTime(DateTime::from_unix_nanos(self.u64()?.cast_signed())),
+            4 => Value::Symbol(self.symbol()?),
+            12 => Value::Keyword(self.symbol()?),
+            5 => Value::Str(String::from(self.str()?)),
+            6 => {
+                let len = self.len()?;
+                let mut list = Vec::with_capacity(len.min(self.bytes.len()));
+                for _ in 0..len {
+                    list.push(self.value()?);
+                }
+                Value::List(Value::new_list(list))
+            }
+            13 => {
+                let len = self.len()?;
+                let mut vector = Vec::with_capacity(len.min(self.bytes.len()));
+                for _ in 0..len {
+                    vector.push(self.value()?);
+                }
+                Value::Vector(Value::new_list(vector))
+            }
+            7 => self.native()?,
+            tag @ (8 | 9) => {
+                let len = self.len()?;
+                let mut locals = Vec::with_capacity(len.min(self.bytes.len()));
+                for _ in 0..len {
+                    locals.push(self.value()?);
+                }
+                let chunk = self.chunk()?;
+                chunk.verify()?;
+                if locals.len() + usize::from(chunk.arity) != chunk.scope_size {
+                    return Err(corrupted("a function has the wrong number of locals"));
+                }
+                let f = Arc::new(ZapFn {
+                    locals,
+                    chunk: Arc::new(chunk),
+                });
+                if tag == 8 {
+                    Value::Func(f)
+                } else {
+                    Value::Macro(f)
+                }
+            }
+            10 => {
+                let mut outers = Vec::new();
+                for _ in 0..self.len()? {
+                    outers.push(Outer {
+                        position: self.u16()?,
+                        dest: self.u16()?,
+                    });
+                }
+                let chunk = self.chunk()?;
+                chunk.verify()?;
+                let arity = chunk.arity;
+                if outers.iter().any(|outer| {
+                    outer.dest < arity.into() || usize::from(outer.dest) >= chunk.scope_size
+                }) {
+                    return Err(corrupted("a closure captures outside of its locals"));
+                }
+                Value::Closure(Arc::new(Closure {
+                    outers,
+                    chunk: Arc::new(chunk),
+                }))
+            }
+            _ => return Err(corrupted("a value has an unknown type")),
+        };
+        Ok(val)
+    }
+
+    // The native function of the env with that name
+    fn native(&mut self) -> Result<Value> {
+        let name = self.str()?.to_string();
+        let Value::Symbol(s) = self.env.reg_symbol(String::from(name.as_str())) else {
+            return Err(corrupted("a symbol can't be registered"));
+        };
+        match self.env.get_by_id(s) {
+            Ok(f @ Value::FuncNative(_)) => Ok(f),
+            _ => Err(error_msg(&format!(
+                "The chunk needs the native function '{}', which this env doesn't have.",
+                name
+            ))),
+        }
+    }
+
+    fn case_key(&mut self) -> Result<CaseKey> {
+        Ok(match self.u8()? {
+            0 => CaseKey::Nil,
+            1 => CaseKey::Bool(self.bool()?),
+            2 => CaseKey::Number(self.u64()?),
+            6 => CaseKey::Int(self.u64()?.cast_signed()),
+            3 => CaseKey::Decimal(self.decimal()?),
+            8 => CaseKey::Duration(Duration::from_nanos(self.u64()?.cast_signed())),
+            9 => CaseKey::DateTime(DateTime::from_unix_nanos(self.u64()?.cast_signed())),
+            4 => CaseKey::Symbol(self.symbol()?),
+            7 => CaseKey::Keyword(self.symbol()?),
+            5 => CaseKey::Str(String::from(self.str()?)),
+            _ => return Err(corrupted("a case key has an unknown type")),
+        })
+    }
+
+    fn table(&mut self) -> Result<JumpTable> {
+        let mut table = JumpTable::default();
+        table.set_default(self.u16()?);
+        for _ in 0..self.len()? {
+            let key = self.case_key()?;
+            let target = self.u16()?;
+            table.targets.insert(key, target);
+        }
+        Ok(table)
+    }
+
+    fn op(&mut self) -> Result<Op> {
+        let tag = self.u8()?;
+        let op = match tag {
+            7 => Op::LookUp(self.symbol()?, 0),
+            35 => Op::CondJmpW(self.u32()?),
+            36 => Op::JmpW(self.u32()?),
+            37 => Op::LoopW(self.u32()?),
+            10 => Op::Load(self.u8()?),
+            11 => Op::Store(self.u8()?),
+            41 => Op::LoadAddConst(self.u8()?, self.u16()?),
+            42 => Op::LoadEqConstJmp(self.u8()?, self.u16()?, self.u16()?),
+            44 => Op::AddSlots(self.u8()?, self.u8()?),
+            45 => Op::EqSlots(self.u8()?, self.u8()?),
+            8 => Op::Define,
+            9 => Op::Pop,
+            13 => Op::Add,
+            15 => Op::Eq,
+            16 => Op::Return,
+            17 => Op::Closure,
+            20 => Op::EndTry,
+            21 => Op::Throw,
+            22 => Op::EnterEnv,
+            23 => Op::LeaveEnv,
+            24 => Op::LoadFile,
+            25 => Op::Disasm,
+            27 => Op::Sub,
+            29 => Op::Mul,
+            31 => Op::Div,
+            33 => Op::Rem,
+            34 => Op::Not,
+            40 => Op::Break,
+            43 => Op::Yield,
+            _ => {
+                let n = self.u16()?;
+                match tag {
+                    0 => Op::Push(n),
+                    1 => Op::Call(n),
+                    2 => Op::Tailcall(n),
+                    3 => Op::TailcallSelf(n),
+                    47 => Op::Apply(n),
+                    48 => Op::TailApply(n),
+                    4 => Op::CondJmp(n),
+                    5 => Op::Jmp(n),
+                    6 => Op::Loop(n),
+                    12 => Op::AddConst(n),
+                    14 => Op::EqConst(n),
+                    26 => Op::SubConst(n),
+                    28 => Op::MulConst(n),
+                    30 => Op::DivConst(n),
+                    32 => Op::RemConst(n),
+                    38 => Op::LoadW(n),
+                    39 => Op::StoreW(n),
+                    18 => Op::Switch(n),
+                    19 => Op::Try(n),
+                    46 => Op::MakeList(n),
+                    49 => Op::MakeVec(n),
+                    _ => return Err(corrupted("an op is unknown")),
+                }
+            }
+        };
+        Ok(op)
+    }
+}
@@ -0,0 +1,159 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+// The string of zap with the compact-values feature. A smartstring keeps the short strings
+// inline, which makes it 24 bytes; this one is a box of a heap string, a thin pointer, so a Value
+// holding it is 16 bytes. It derefs to str and has the methods of a smartstring that change it.
+#[allow(clippy::box_collection)]
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct String(Box<std::string::String>);
+
+impl String {
+    pub fn new() -> String {
+        String::default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn as_mut_str(&mut self) -> &mut str {
+        &mut self.0
+    }
+
+    pub fn push(&mut self, ch: char) {
+        self.0.push(ch);
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.0.push_str(s);
+    }
+
+    pub fn pop(&mut self) -> Option<char> {
+        self.0.pop()
+    }
+
+    pub fn insert(&mut self, idx: usize, ch: char) {
+        self.0.insert(idx, ch);
+    }
+
+    pub fn insert_str(&mut self, idx: usize, s: &str) {
+        self.0.insert_str(idx, s);
+    }
+
+    pub fn remove(&mut self, idx: usize) -> char {
+        self.0.remove(idx)
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.0.truncate(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+impl Deref for String {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl DerefMut for String {
+    fn deref_mut(&mut self) -> &mut str {
+        &mut self.0
+    }
+}
+
+impl From<&str> for String {
+    fn from(s: &str) -> String {
+        String(Box::new(s.into()))
+    }
+}
+
+impl From<std::string::String> for String {
+    fn from(s: std::string::String) -> String {
+        String(Box::new(s))
+    }
+}
+
+impl From<&std::string::String> for String {
+    fn from(s: &std::string::String) -> String {
+        String::from(s.as_str())
+    }
+}
+
+impl From<String> for std::string::String {
+    fn from(s: String) -> std::string::String {
+        *s.0
+    }
+}
+
+impl Borrow<str> for String {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for String {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for String {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for String {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for &str {
+    fn eq(&self, other: &String) -> bool {
+        *self == other.as_str()
+    }
+}
+
+impl fmt::Display for String {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Debug for String {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Write for String {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.push_str(s);
+        Ok(())
+    }
+}
+
+impl FromIterator<char> for String {
+    fn from_iter<I: IntoIterator<Item = char>>(iter: I) -> String {
+        String::from(iter.into_iter().collect::<std::string::String>())
+    }
+}
+
+impl<'a> FromIterator<&'a str> for String {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> String {
+        String::from(iter.into_iter().collect::<std::string::String>())
+    }
+}
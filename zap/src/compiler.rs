@@ -1,22 +1,58 @@
-use crate::env::symbols;
-use crate::vm::{Chunk, LocalIndex, Op};
-use crate::zap::{error_msg, Result, Symbol, Value, ZapFn, ZapList};
-use std::cmp::max;
+use crate::env::{symbols, Capability, Env};
+use crate::reader::{Span, Spans};
+use crate::vm::{self, CaseKey, Chunk, JumpTable, LocalIndex, Op};
+use crate::zap::{
+    error_msg, NativeFunc, Result, String, Structural, Symbol, Value, ZapFn, ZapFnNative, ZapList,
+};
+use fxhash::FxHashMap;
 use std::sync::Arc;
 
 // The compiler takes the expression returned by the reader and return an array of bytecodes
 // which can be executed by the VM.
 
+// The locals of a function being compiled. Every local gets its own slot, slots are never reused,
+// so a captured outer can't be overwritten by a later binding.
+#[derive(Default)]
+struct Scope {
+    locals: Vec<(Symbol, LocalIndex)>,
+    captures: Vec<(Symbol, LocalIndex)>,
+    outers: Vec<Outer>,
+    size: usize,
+    name: Option<Symbol>, // The global the function is defined as, by (def name (fn ...))
+    loaded: Vec<LocalIndex>, // The slots whose value is used, here or by an inner fn
+}
+
+impl Scope {
+    fn alloc(&mut self) -> Result<LocalIndex> {
+        let slot = self
+            .size
+            .try_into()
+            .map_err(|_| error_msg("Too many locals in scope!"))?;
+        self.size += 1;
+        Ok(slot)
+    }
+
+    fn find(&self, s: Symbol) -> Option<LocalIndex> {
+        // The locals are always more inner than the captured outers
+        self.locals
+            .iter()
+            .rev()
+            .chain(self.captures.iter())
+            .find(|(symbol, _)| *symbol == s)
+            .map(|(_, slot)| *slot)
+    }
+}
+
 struct Scoping {
-    scopes: Vec<(LocalIndex, Vec<Symbol>)>,
-    outers: Vec<Vec<Outer>>,
+    scopes: Vec<Scope>,
+    unused: Vec<Symbol>, // The locals that went out of scope without being loaded
 }
 
 impl Default for Scoping {
     fn default() -> Self {
         Scoping {
-            scopes: vec![(0, Vec::new())],
-            outers: vec![Vec::new()],
+            scopes: vec![Scope::default()],
+            unused: Vec::new(),
         }
     }
 }
@@ -24,110 +60,533 @@ impl Default for Scoping {
 impl Scoping {
     pub fn push_local(&mut self, symbol: Symbol) -> Result<LocalIndex> {
         // Add a symbol in the scope
-        let (max_len, mut locals) = self.scopes.pop().unwrap();
-        locals.push(symbol);
-        let len = locals
-            .len()
-            .try_into()
-            .map_err(|_| error_msg("Too many locals in scope!"))?;
-        self.scopes.push((max(max_len, len), locals));
-        Ok(len - 1)
+        let scope = self.scopes.last_mut().unwrap();
+        let slot = scope.alloc()?;
+        scope.locals.push((symbol, slot));
+        Ok(slot)
     }
 
     pub fn pop_locals(&mut self, count: usize) {
         // Pop symbols from the scope
-        let (_, scope) = self.scopes.last_mut().unwrap();
-        let new_len = scope.len() - count;
-        scope.truncate(new_len);
-    }
-
-    pub fn get_local(&mut self, s: Symbol) -> Option<usize> {
-        // Look if this symbol is in the current scope
-        let (_, scope) = self.scopes.last_mut().unwrap();
-        for (offset, local) in scope.iter().enumerate().rev() {
-            if *local == s {
-                return Some(offset);
+        let scope = self.scopes.last_mut().unwrap();
+        let new_len = scope.locals.len() - count;
+        for (symbol, slot) in scope.locals.drain(new_len..) {
+            if !scope.loaded.contains(&slot) {
+                self.unused.push(symbol);
             }
         }
-        None
     }
 
-    pub fn get_outer(&mut self, s: Symbol) -> Option<(usize, usize)> {
+    pub fn mark_loaded(&mut self, slot: LocalIndex) {
+        self.scopes.last_mut().unwrap().loaded.push(slot);
+    }
+
+    pub fn is_fn(&self) -> bool {
+        // In the body of a fn, rather than at the top
+        self.scopes.len() > 1
+    }
+
+    pub fn last_locals(&self, count: usize) -> Vec<LocalIndex> {
+        // The slots of the most recent locals, in the order they were bound
+        let locals = &self.scopes.last().unwrap().locals;
+        locals[locals.len() - count..]
+            .iter()
+            .map(|(_, slot)| *slot)
+            .collect()
+    }
+
+    pub fn get_own_local(&self, s: Symbol) -> Option<LocalIndex> {
+        // Look if this symbol is bound here, not captured from an enclosing function
+        self.scopes
+            .last()
+            .unwrap()
+            .locals
+            .iter()
+            .rev()
+            .find(|(symbol, _)| *symbol == s)
+            .map(|(_, slot)| *slot)
+    }
+
+    pub fn is_bound(&self, s: Symbol) -> bool {
+        // Look if this symbol is a local here or in an enclosing function
+        self.scopes.iter().any(|scope| scope.find(s).is_some())
+    }
+
+    pub fn get_local(&self, s: Symbol) -> Option<LocalIndex> {
         // Look if this symbol is in the current scope
-        for (level, (_, scope)) in self.scopes.iter().enumerate().rev() {
-            for (position, symbol) in scope.iter().enumerate().rev() {
-                if *symbol == s {
-                    return Some((level, position));
-                }
-            }
-        }
-        None
+        self.scopes.last().unwrap().find(s)
     }
 
-    pub fn push_outer(&mut self, level: usize, position: usize, dest: LocalIndex) {
-        let outer = Outer {
-            level,
-            position,
-            dest,
+    pub fn capture(&mut self, s: Symbol) -> Result<Option<LocalIndex>> {
+        // Look if this symbol is a local of an enclosing function
+        let current = self.scopes.len() - 1;
+        let Some((level, mut slot)) = (0..current)
+            .rev()
+            .find_map(|level| self.scopes[level].find(s).map(|slot| (level, slot)))
+        else {
+            return Ok(None);
         };
-        self.outers.last_mut().unwrap().push(outer);
+        self.scopes[level].loaded.push(slot);
+
+        // Every function in between captures it too, so it's always taken from the parent frame
+        for scope in &mut self.scopes[level + 1..] {
+            let dest = scope.alloc()?;
+            scope.captures.push((s, dest));
+            scope.outers.push(Outer {
+                position: slot,
+                dest,
+            });
+            slot = dest;
+        }
+        Ok(Some(slot))
+    }
+
+    pub fn push(&mut self, name: Option<Symbol>) {
+        self.scopes.push(Scope {
+            name,
+            ..Scope::default()
+        });
+    }
+
+    pub fn name(&self) -> Option<Symbol> {
+        self.scopes.last().unwrap().name
+    }
+
+    pub fn size(&self) -> usize {
+        self.scopes.last().unwrap().size
     }
 
-    pub fn push(&mut self) {
-        self.scopes.push((0, Vec::new()));
-        self.outers.push(Vec::new());
+    pub fn alloc(&mut self) -> Result<LocalIndex> {
+        // A slot without a symbol, for values the compiler keeps around
+        self.scopes.last_mut().unwrap().alloc()
     }
 
     pub fn pop(&mut self) -> (usize, Vec<Outer>) {
-        let (size, _) = self.scopes.pop().unwrap();
-        let outers = self.outers.pop().unwrap();
-        (size.into(), outers)
+        let scope = self.scopes.pop().unwrap();
+        for (symbol, slot) in scope.locals {
+            if !scope.loaded.contains(&slot) {
+                self.unused.push(symbol);
+            }
+        }
+        (scope.size, scope.outers)
     }
 }
 
-#[derive(Debug)]
+#[doc(hidden)]
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct Outer {
-    pub level: usize,
-    pub position: usize,
+    pub position: LocalIndex,
     pub dest: LocalIndex,
 }
 
+// A special form provided by an embedder. It receives the whole form, (name args...),
+// and must leave exactly one value on the stack through the emitter.
+pub type SpecialForm = fn(&ZapList, &mut Emitter) -> Result<()>;
+
+// The ops a global function can have, its return included, to be inlined where it's called.
+pub const INLINE_LIMIT: usize = 12;
+
+// The custom special forms known to the compiler, on top of the built-in ones, and how much it
+// inlines.
+#[derive(Clone)]
+pub struct Extensions {
+    forms: FxHashMap<Symbol, SpecialForm>,
+    inline_limit: usize,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Extensions {
+            forms: FxHashMap::default(),
+            inline_limit: INLINE_LIMIT,
+        }
+    }
+}
+
+impl Extensions {
+    #[must_use]
+    pub fn new() -> Self {
+        Extensions::default()
+    }
+
+    // The functions of at most ops ops get inlined, none with 0
+    pub fn set_inline_limit(&mut self, ops: usize) {
+        self.inline_limit = ops;
+    }
+
+    pub fn register<E: Env>(&mut self, env: &mut E, name: &str, form: SpecialForm) -> Result<()> {
+        match env.reg_symbol(String::from(name)) {
+            Value::Symbol(s) if (s as usize) < symbols::DEFAULT_SYMBOLS.len() => Err(error_msg(
+                format!("'{name}' is a built-in special form.").as_str(),
+            )),
+            Value::Symbol(s) => {
+                self.forms.insert(s, form);
+                Ok(())
+            }
+            _ => Err(error_msg("A special form must be named by a symbol.")),
+        }
+    }
+
+    fn get(&self, s: Symbol) -> Option<SpecialForm> {
+        self.forms.get(&s).copied()
+    }
+}
+
+// The handle given to custom special forms. The steps are compiled in the order they are given.
+pub struct Emitter {
+    steps: Vec<Form>,
+}
+
+impl Emitter {
+    // Compile an expression, leaving its value on the stack
+    pub fn compile(&mut self, exp: Value) {
+        self.steps.push(Form::Value(exp));
+    }
+
+    // Push a constant on the stack
+    pub fn push_const(&mut self, val: Value) {
+        self.steps.push(Form::Const(val));
+    }
+
+    // Call the function pushed right before its argc args
+    pub fn call(&mut self, argc: u16) {
+        self.steps.push(Form::Emit(Op::Call(argc)));
+    }
+
+    // Discard the value at the top of the stack
+    pub fn pop(&mut self) {
+        self.steps.push(Form::Emit(Op::Pop));
+    }
+}
+
 #[derive(Debug)]
 enum Form {
     Value(Value),
-    List(ZapList, u8),
+    Const(Value),
+    Emit(Op),
+    List(ZapList, u16),
     Apply,
+    ApplySelf,
     IfCond(ZapList),
-    IfThen(ZapList, Vec<Op>),
-    IfElse(Vec<Op>, Vec<Op>),
+    IfThen(ZapList, Code),
+    IfElse(Code, Code),
     Do(ZapList, usize),
     Define,
-    Return(Chunk),
-    AddMany(ZapList, usize),
-    Add,
+    Return(Chunk, ConstIndex),
+    Arith(Arith, ZapList, usize),
     Equal,
     EqualConst(u16),
     Let(usize),
     Binding(Symbol),
-    Quoting,
+    WhileBody(ZapList, usize),
+    WhileEnd(usize, usize),
+    DoseqBegin(ZapList),
+    DoseqEnd(LocalIndex, usize, usize),
+    LoopBegin(ZapList, usize),
+    LoopEnd(usize, Vec<LocalIndex>),
+    Recur(Vec<LocalIndex>),
+    CaseSwitch(ZapList),
+    CaseBranch(ZapList, usize, usize, Vec<usize>),
+    CaseEnd(Vec<usize>),
+    TryEnd(ZapList, usize),
+    CatchEnd(usize),
+    InlineBody(ZapList, Arc<ZapFn>, usize),
+    InlineEnd(usize),
+    Span(Option<Span>), // Back to the span of the enclosing list
+}
+
+// The arithmetic forms, each compiled to its op and to its op taking a const operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl Arith {
+    fn of(sym: Symbol) -> Option<Arith> {
+        match sym {
+            symbols::PLUS => Some(Arith::Add),
+            symbols::MINUS => Some(Arith::Sub),
+            symbols::TIMES => Some(Arith::Mul),
+            symbols::DIVIDE => Some(Arith::Div),
+            symbols::REM => Some(Arith::Rem),
+            _ => None,
+        }
+    }
+
+    fn op(self) -> Op {
+        match self {
+            Arith::Add => Op::Add,
+            Arith::Sub => Op::Sub,
+            Arith::Mul => Op::Mul,
+            Arith::Div => Op::Div,
+            Arith::Rem => Op::Rem,
+        }
+    }
+
+    fn const_op(self, idx: u16) -> Op {
+        match self {
+            Arith::Add => Op::AddConst(idx),
+            Arith::Sub => Op::SubConst(idx),
+            Arith::Mul => Op::MulConst(idx),
+            Arith::Div => Op::DivConst(idx),
+            Arith::Rem => Op::RemConst(idx),
+        }
+    }
+
+    fn apply(self, a: &Value, b: &Value) -> Result<Value> {
+        match self {
+            Arith::Add => a + b,
+            Arith::Sub => a - b,
+            Arith::Mul => a * b,
+            Arith::Div => a / b,
+            Arith::Rem => a % b,
+        }
+    }
+
+    // The form with the operands it leaves implicit: (+) is (+ 0), (- x) is (- 0 x)
+    fn operands(self, list: &ZapList) -> Result<ZapList> {
+        let with = |operands: &[Value]| {
+            let mut full = vec![list[0].clone()];
+            full.extend_from_slice(operands);
+            Value::new_list(full)
+        };
+        match (self, &list[1..]) {
+            (Arith::Add, []) => Ok(with(&[Value::Int(0)])),
+            (Arith::Mul, []) => Ok(with(&[Value::Int(1)])),
+            (Arith::Sub, []) => Err(error_msg("'-' requires at least 1 argument.")),
+            (Arith::Div, []) => Err(error_msg("'/' requires at least 1 argument.")),
+            (Arith::Sub, [x]) => Ok(with(&[Value::Int(0), x.clone()])),
+            (Arith::Div, [x]) => Ok(with(&[Value::Int(1), x.clone()])),
+            (Arith::Rem, operands) if operands.len() != 2 => {
+                Err(error_msg("'rem' requires 2 arguments."))
+            }
+            _ => Ok(list.clone()),
+        }
+    }
+
+    // The value of operands that are all numbers, unless computing it fails, which is left to
+    // the runtime to raise
+    fn fold(self, operands: &[Value]) -> Option<Value> {
+        let (first, rest) = operands.split_first()?;
+        if !is_number(first) {
+            return None;
+        }
+        rest.iter().try_fold(first.clone(), |acc, val| {
+            if is_number(val) {
+                self.apply(&acc, val).ok()
+            } else {
+                None
+            }
+        })
+    }
+}
+
+// Finds a const already in the chunk without scanning the consts, so compiling a chunk with n
+// consts is O(n) rather than O(n^2). A key is only equal to another when their values can't be
+// told apart: the floats are keyed by their bits, as -0.0 == 0.0 but 1/-0.0 isn't 1/0.0, and the
+// decimals by their digits, 1.0M printing unlike 1.00M. The other scalars are keyed by their
+// value, the lists, vectors and functions by their address, which the consts keep alive.
+// Closures get no key.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Scalar(CaseKey),
+    Number(u64),
+    Decimal(String),
+    List(usize),
+    Vector(usize),
+    Native(usize),
+    Func(usize),
+    Macro(usize),
+}
+
+impl ConstKey {
+    fn of(val: &Value) -> Option<ConstKey> {
+        match val {
+            Value::Number(n) => Some(ConstKey::Number(n.to_bits())),
+            Value::Decimal(d) => Some(ConstKey::Decimal(String::from(d.to_string()))),
+            Value::List(list) => Some(ConstKey::List(Arc::as_ptr(list) as usize)),
+            Value::Vector(vector) => Some(ConstKey::Vector(Arc::as_ptr(vector) as usize)),
+            Value::FuncNative(f) => Some(ConstKey::Native(Arc::as_ptr(f) as usize)),
+            Value::Func(f) => Some(ConstKey::Func(Arc::as_ptr(f) as usize)),
+            Value::Macro(f) => Some(ConstKey::Macro(Arc::as_ptr(f) as usize)),
+            _ => CaseKey::of(val).map(ConstKey::Scalar),
+        }
+    }
+}
+
+// Not an FxHashMap: the bits of a whole number end with zeros, and FxHash would put all of them
+// in the same bucket.
+type ConstIndex = std::collections::HashMap<ConstKey, u16>;
+
+// The chunks of the fns compiled so far, so a fn compiled twice, as a macro may expand it, shares
+// one chunk and its consts. Two chunks are only pooled together when they'd also give the same
+// name and spans to their errors, and when the fns in their consts share their chunks too.
+#[derive(Default)]
+struct ChunkPool(std::collections::HashSet<Pooled>);
+
+struct Pooled(Arc<Chunk>);
+
+impl PartialEq for Pooled {
+    fn eq(&self, other: &Self) -> bool {
+        let (a, b) = (&self.0, &other.0);
+        a == b
+            && a.name == b.name
+            && a.spans == b.spans
+            && a.consts.iter().zip(&b.consts).all(|pair| match pair {
+                (Value::Func(a), Value::Func(b)) | (Value::Macro(a), Value::Macro(b)) => {
+                    Arc::ptr_eq(&a.chunk, &b.chunk)
+                }
+                (Value::Closure(a), Value::Closure(b)) => Arc::ptr_eq(&a.chunk, &b.chunk),
+                _ => true,
+            })
+    }
+}
+
+impl Eq for Pooled {}
+
+impl std::hash::Hash for Pooled {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl ChunkPool {
+    fn intern(&mut self, chunk: Chunk) -> Arc<Chunk> {
+        let pooled = Pooled(Arc::new(chunk));
+        if let Some(Pooled(found)) = self.0.get(&pooled) {
+            return found.clone();
+        }
+        let chunk = pooled.0.clone();
+        self.0.insert(pooled);
+        chunk
+    }
+}
+
+// The lists and vectors in the consts of the whole compilation, so one quoted in several fns, as
+// a macro may expand it in each, is the same list in all their chunks. Only the ones holding
+// nothing but data are interned, a fn in one has a name and spans of its own.
+#[derive(Default)]
+struct ConstPool(std::collections::HashSet<Interned>);
+
+struct Interned(Value);
+
+impl PartialEq for Interned {
+    fn eq(&self, other: &Self) -> bool {
+        Structural(&self.0) == Structural(&other.0)
+    }
+}
+
+impl Eq for Interned {}
+
+impl std::hash::Hash for Interned {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        Structural(&self.0).hash(state);
+    }
+}
+
+impl ConstPool {
+    fn intern(&mut self, val: &Value) -> Value {
+        if !matches!(val, Value::List(_) | Value::Vector(_)) || !is_data(val) {
+            return val.clone();
+        }
+        let interned = Interned(val.clone());
+        if let Some(Interned(found)) = self.0.get(&interned) {
+            return found.clone();
+        }
+        self.0.insert(interned);
+        val.clone()
+    }
 }
 
-struct Compiler {
+fn is_data(val: &Value) -> bool {
+    match val {
+        Value::List(list) | Value::Vector(list) => list.iter().all(is_data),
+        Value::Nil
+        | Value::Bool(_)
+        | Value::Int(_)
+        | Value::Number(_)
+        | Value::Decimal(_)
+        | Value::Duration(_)
+        | Value::DateTime(_)
+        | Value::Symbol(_)
+        | Value::Keyword(_)
+        | Value::Str(_) => true,
+        _ => false,
+    }
+}
+
+// Ops compiled apart from the chunk with their spans, until they are spliced in it
+#[derive(Debug, Default)]
+struct Code {
+    ops: Vec<Op>,
+    spans: Vec<(usize, Span)>,
+}
+
+struct Compiler<'a> {
     chunk: Chunk,
+    consts: ConstIndex, // The consts of the chunk
+    chunks: ChunkPool,  // Of the whole compilation
+    pool: ConstPool,    // Of the whole compilation too
     forms: Vec<Form>,
     scopes: Scoping,
-    argc: u8,
-    quoting: bool,
+    argc: u16,
+    gensym: Symbol,
+    defining: Option<Symbol>, // The name of the def whose value is compiled next
+    extensions: &'a Extensions,
+    env: &'a mut dyn Env, // Where the macros are found
+    warnings: Vec<std::string::String>,
+    spans: &'a Spans,
+    span: Option<Span>, // Of the innermost list being compiled
 }
 
-impl Compiler {
-    pub fn init(ast: Value) -> Self {
+impl<'a> Compiler<'a> {
+    pub fn init(
+        ast: Value,
+        extensions: &'a Extensions,
+        env: &'a mut dyn Env,
+        spans: &'a Spans,
+    ) -> Self {
         Compiler {
             chunk: Chunk::default(),
+            consts: ConstIndex::default(),
+            chunks: ChunkPool::default(),
+            pool: ConstPool::default(),
             forms: vec![Form::Value(ast)],
             scopes: Scoping::default(),
             argc: 0,
-            quoting: false,
+            gensym: Symbol::MAX,
+            defining: None,
+            extensions,
+            env,
+            warnings: Vec::new(),
+            spans,
+            span: None,
+        }
+    }
+
+    // A warning about the symbol, named in place of {}. The hidden symbols have no name, they
+    // come from the compiler and the user can't do anything about them.
+    fn warn(&mut self, s: Symbol, msg: &str) {
+        let Ok(name) = self.env.get_symbol(s) else {
+            return;
+        };
+        self.warnings.push(msg.replace("{}", &name));
+    }
+
+    // Warn about the locals that went out of scope without being loaded
+    fn warn_unused(&mut self) {
+        for s in std::mem::take(&mut self.scopes.unused) {
+            if self
+                .env
+                .get_symbol(s)
+                .is_ok_and(|name| !name.starts_with('_'))
+            {
+                self.warn(s, "Local '{}' is never used");
+            }
         }
     }
 
@@ -138,17 +597,30 @@ impl Compiler {
     fn is_last_exp(&self) -> bool {
         for form in self.forms.iter().rev() {
             match form {
-                Form::IfThen(_, _) | Form::IfElse(_, _) | Form::Let(_) => continue,
-                Form::Return(_) => return true,
+                Form::IfThen(_, _)
+                | Form::IfElse(_, _)
+                | Form::Let(_)
+                | Form::CaseBranch(..)
+                | Form::CaseEnd(_)
+                | Form::CatchEnd(_)
+                | Form::InlineEnd(_)
+                | Form::Span(_) => {}
+                Form::Return(..) => return true,
                 _ => return false,
             }
         }
         true
     }
 
+    // Hidden symbols are taken from the top of the symbol space, they can only be bound as locals.
+    fn gensym(&mut self) -> Symbol {
+        self.gensym -= 1;
+        self.gensym
+    }
+
     pub fn register_binding(&mut self, symbol: Symbol) -> Result<()> {
         let idx = self.scopes.push_local(symbol)?;
-        self.emit(Op::Store(idx));
+        self.emit(Op::store(idx));
         Ok(())
     }
 
@@ -156,201 +628,1383 @@ impl Compiler {
         self.emit(Op::Return);
         let (count, _) = self.scopes.pop();
         self.chunk.scope_size = count;
+        fuse(&mut self.chunk.ops);
+        self.chunk.cache_lookups();
         self.chunk.ops.shrink_to_fit();
         self.chunk.consts.shrink_to_fit();
+        self.chunk.tables.shrink_to_fit();
+        debug_assert_eq!(self.chunk.verify_top_level(), Ok(()));
         Arc::new(self.chunk)
     }
 
-    pub fn set_argc(&mut self, argc: u8) {
+    pub fn set_argc(&mut self, argc: u16) {
         self.argc = argc - 1;
     }
 
-    fn push(&mut self, val: &Value) -> Result<()> {
-        let const_idx = self.get_const_idx(val)?;
-        self.emit(Op::Push(const_idx));
+    fn push(&mut self, val: &Value) -> Result<()> {
+        let const_idx = self.get_const_idx(val)?;
+        self.emit(Op::Push(const_idx));
+        Ok(())
+    }
+
+    fn emit(&mut self, op: Op) {
+        if let Some(span) = self.span {
+            if self
+                .chunk
+                .spans
+                .last()
+                .is_none_or(|(_, last)| *last != span)
+            {
+                self.chunk.spans.push((self.chunk.ops.len(), span));
+            }
+        }
+        self.chunk.ops.push(op);
+    }
+
+    // The ops compiled so far, leaving the chunk with code
+    fn replace_code(&mut self, code: Code) -> Code {
+        Code {
+            ops: std::mem::replace(&mut self.chunk.ops, code.ops),
+            spans: std::mem::replace(&mut self.chunk.spans, code.spans),
+        }
+    }
+
+    fn append(&mut self, code: Code) {
+        let start = self.chunk.ops.len();
+        self.chunk.spans.extend(
+            code.spans
+                .into_iter()
+                .map(|(idx, span)| (start + idx, span)),
+        );
+        self.chunk.ops.extend(code.ops);
+    }
+
+    // Compile the list under its span, if the reader gave it one
+    fn eval_spanned_list(&mut self, list: ZapList) -> Result<()> {
+        if let Some(span) = self.spans.of(&list) {
+            self.forms.push(Form::Span(self.span));
+            self.span = Some(span);
+        }
+        self.eval_list(list)
+    }
+
+    fn get_const_idx(&mut self, val: &Value) -> Result<u16> {
+        let val = &self.pool.intern(val);
+        let key = ConstKey::of(val);
+        if let Some(idx) = key.as_ref().and_then(|key| self.consts.get(key)) {
+            return Ok(*idx);
+        }
+        let idx = self
+            .chunk
+            .consts
+            .len()
+            .try_into()
+            .map_err(|_| error_msg("Too many constants in the constants table"))?;
+        self.chunk.consts.push(val.clone());
+        if let Some(key) = key {
+            self.consts.insert(key, idx);
+        }
+        Ok(idx)
+    }
+
+    pub fn eval_list(&mut self, list: ZapList) -> Result<()> {
+        if list.len() > u16::MAX.into() {
+            return Err(error_msg("A call cannot have more than 65534 arguments."));
+        }
+
+        match list[0] {
+            Value::Symbol(symbols::DO) => {
+                if list.len() < 2 {
+                    return Err(error_msg("A do form must contains at least 1 parameter"));
+                }
+                self.forms.push(Form::Do(list, 1));
+            }
+            Value::Symbol(symbols::FN) => self.eval_fn(&list)?,
+            Value::Symbol(symbols::DEFINE) => self.eval_def(&list)?,
+            Value::Symbol(symbols::IF) => self.eval_if(list)?,
+            Value::Symbol(symbols::LET) => self.eval_let(&list)?,
+            Value::Symbol(symbols::EQUAL) => self.eval_eq(&list)?,
+            Value::Symbol(
+                sym @ (symbols::PLUS
+                | symbols::MINUS
+                | symbols::TIMES
+                | symbols::DIVIDE
+                | symbols::REM),
+            ) => self.eval_arith(list, Arith::of(sym).unwrap())?,
+            Value::Symbol(symbols::QUOTE) => {
+                if list.len() != 2 {
+                    return Err(error_msg("'quote' require only 1 value"));
+                }
+
+                self.push(&list[1])?;
+            }
+            Value::Symbol(symbols::QUASIQUOTE) => self.eval_quasiquote(&list)?,
+            Value::FuncNative(ref f) if is_make_list(f) => self.eval_make_list(list),
+            Value::Symbol(symbols::WHILE) => self.eval_while(list)?,
+            Value::Symbol(symbols::DOTO) => self.eval_doto(&list)?,
+            Value::Symbol(symbols::DOSEQ_INDEXED) => self.eval_doseq(list)?,
+            Value::Symbol(symbols::WHEN | symbols::UNLESS) => self.eval_when(&list)?,
+            Value::Symbol(symbols::CASE) => self.eval_case(&list)?,
+            Value::Symbol(symbols::DEFMACRO) => self.eval_defmacro(&list)?,
+            Value::Symbol(symbols::DEFN) => self.eval_defn(&list)?,
+            Value::Symbol(symbols::THREAD_FIRST) => self.eval_thread(&list, false)?,
+            Value::Symbol(symbols::THREAD_LAST) => self.eval_thread(&list, true)?,
+            Value::Symbol(symbols::SOME_THREAD_FIRST) => self.eval_some_thread(&list, false)?,
+            Value::Symbol(symbols::SOME_THREAD_LAST) => self.eval_some_thread(&list, true)?,
+            Value::Symbol(symbols::OR) => self.eval_or(&list),
+            Value::Symbol(symbols::SET) => self.eval_set(&list)?,
+            Value::Symbol(symbols::WITH_ENV) => self.eval_with_env(&list),
+            Value::Symbol(symbols::LETFN) => self.eval_letfn(&list)?,
+            Value::Symbol(symbols::NS) => self.eval_ns(&list)?,
+            Value::Symbol(symbols::REQUIRE) => self.eval_require(&list)?,
+            Value::Symbol(symbols::COMPILE_IF | symbols::WHEN_AVAILABLE) => {
+                self.eval_compile_if(&list)?;
+            }
+            Value::Symbol(symbols::LOAD) => {
+                self.eval_unary(&list, Op::LoadFile, "A load form must have a path")?;
+            }
+            Value::Symbol(symbols::NOT) => self.eval_not(&list)?,
+            Value::Symbol(symbols::BREAK) => self.eval_break(&list)?,
+            Value::Symbol(symbols::YIELD) => self.eval_yield(&list)?,
+            Value::Symbol(symbols::APPLY) => self.eval_apply(list)?,
+            Value::Symbol(symbols::DISASM) => {
+                self.eval_unary(&list, Op::Disasm, "A disasm form must have a function")?;
+            }
+            Value::Symbol(symbols::TRY) => self.eval_try(list)?,
+            Value::Symbol(symbols::THROW) => {
+                self.eval_unary(&list, Op::Throw, "A throw form must have 1 parameter")?;
+            }
+            Value::Symbol(symbols::LOOP) => self.eval_loop(&list)?,
+            Value::Symbol(symbols::RECUR) => self.eval_recur(&list)?,
+            Value::Symbol(s) if self.extensions.get(s).is_some() => {
+                let form = self.extensions.get(s).unwrap();
+                self.eval_extension(form, &list)?;
+            }
+            _ => {
+                if let Some(expander) = self.get_macro(&list[0]) {
+                    let expansion = self.expand(expander, &list)?;
+                    self.forms.push(Form::Value(expansion));
+                } else if self.is_self_call(&list[0]) && self.is_last_exp() {
+                    // The function is called again with its own frame, the head isn't evaluated
+                    self.forms.push(Form::ApplySelf);
+                    self.forms.push(Form::List(list, 1));
+                } else if let Some((s, func)) = self.inline_target(&list) {
+                    self.eval_inline(list, s, func)?;
+                } else {
+                    self.forms.push(Form::Apply);
+                    self.forms.push(Form::List(list, 0));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn eval_quasiquote(&mut self, list: &ZapList) -> Result<()> {
+        if list.len() != 2 {
+            return Err(error_msg("'quasiquote' require only 1 value"));
+        }
+
+        if is_const_template(&list[1]) {
+            // Nothing to evaluate in the template, load it as a single constant
+            self.push(&list[1])?;
+        } else {
+            self.forms.push(Form::Value(expand_quasiquote(&list[1])?));
+        }
+        Ok(())
+    }
+
+    // The items of a template are evaluated on the stack and gathered by MakeList, rather than
+    // copied into the list by a native
+    fn eval_make_list(&mut self, list: ZapList) {
+        let len = u16::try_from(list.len() - 1).unwrap_or(u16::MAX);
+        self.forms.push(Form::Emit(Op::MakeList(len)));
+        self.forms.push(Form::List(list, 1));
+    }
+
+    // [a b] evaluates its items like the args of a call, and MakeVec gathers them
+    fn eval_vector(&mut self, vector: ZapList) {
+        let len = u16::try_from(vector.len()).unwrap_or(u16::MAX);
+        self.forms.push(Form::Emit(Op::MakeVec(len)));
+        self.forms.push(Form::List(vector, 0));
+    }
+
+    // A form of one arg, whose value the op takes from the stack
+    fn eval_unary(&mut self, list: &ZapList, op: Op, arity_error: &str) -> Result<()> {
+        if list.len() != 2 {
+            return Err(error_msg(arity_error));
+        }
+        self.forms.push(Form::Emit(op));
+        self.forms.push(Form::Value(list[1].clone()));
+        Ok(())
+    }
+
+    // (break) stops at the debugger of the VM, and is nil
+    fn eval_break(&mut self, list: &ZapList) -> Result<()> {
+        if list.len() != 1 {
+            return Err(error_msg("A break form takes no parameter"));
+        }
+        self.emit(Op::Break);
+        Ok(())
+    }
+
+    // (yield x) suspends the coroutine running, x being what resumed it returns. (yield) is
+    // (yield nil).
+    fn eval_yield(&mut self, list: &ZapList) -> Result<()> {
+        let val = match list.len() {
+            1 => Value::Nil,
+            2 => list[1].clone(),
+            _ => return Err(error_msg("A yield form takes at most 1 parameter")),
+        };
+        self.forms.push(Form::Emit(Op::Yield));
+        self.forms.push(Form::Value(val));
+        Ok(())
+    }
+
+    // (apply f a b xs) calls f with a, b and the items of xs, which are put on the stack by the
+    // call rather than copied through a native
+    fn eval_apply(&mut self, list: ZapList) -> Result<()> {
+        if list.len() < 3 {
+            return Err(error_msg("An apply form must have a function and a list"));
+        }
+        let argc = u16::try_from(list.len() - 2).unwrap_or(u16::MAX);
+        // At the top there's no frame to replace
+        if self.is_last_exp() && self.scopes.is_fn() {
+            self.forms.push(Form::Emit(Op::TailApply(argc)));
+        } else {
+            self.forms.push(Form::Emit(Op::Apply(argc)));
+        }
+        self.forms.push(Form::List(list, 1));
+        Ok(())
+    }
+
+    fn eval_not(&mut self, list: &ZapList) -> Result<()> {
+        match fold(&Value::List(list.clone())) {
+            Some(val) => self.push(&val),
+            None => self.eval_unary(list, Op::Not, "A not form must have 1 parameter"),
+        }
+    }
+
+    // The head names the function being compiled, by its own name or the one it's defined as,
+    // and no local shadows it
+    fn is_self_call(&self, head: &Value) -> bool {
+        let Value::Symbol(s) = *head else {
+            return false;
+        };
+        match self.scopes.get_local(s) {
+            Some(slot) => self.chunk.self_slot == Some(slot),
+            None => !self.scopes.is_bound(s) && self.scopes.name() == Some(s),
+        }
+    }
+
+    // The global function a call can be inlined with: a small one whose only locals are its
+    // params, taking the args given. The env must know it when the call is compiled.
+    fn inline_target(&mut self, list: &ZapList) -> Option<(Symbol, Arc<ZapFn>)> {
+        let Value::Symbol(s) = list[0] else {
+            return None;
+        };
+        if self.extensions.inline_limit == 0
+            || self.scopes.is_bound(s)
+            || self.is_self_call(&list[0])
+        {
+            return None;
+        }
+        let s = self.resolve(s);
+        let Some(Value::Func(func)) = self.env.lookup(s) else {
+            return None;
+        };
+        let chunk = &func.chunk;
+        let arity = usize::from(chunk.arity);
+        let inlinable = chunk.ops.len() <= self.extensions.inline_limit
+            && !chunk.variadic
+            && chunk.scope_size == arity
+            && arity == list.len() - 1
+            && self.scopes.size() + arity <= usize::from(LocalIndex::MAX)
+            && !chunk
+                .ops
+                .iter()
+                .any(|op| matches!(op, Op::TailcallSelf(_) | Op::Closure));
+        inlinable.then_some((s, func))
+    }
+
+    // The global is checked to still be the function inlined, else it's called as usual:
+    //   LOOKUP f, EQCONST f, CONDJMP call, <args>, <body>, JMP end, call: <call f args>, end:
+    fn eval_inline(&mut self, list: ZapList, s: Symbol, func: Arc<ZapFn>) -> Result<()> {
+        let inlined = self.get_const_idx(&Value::Func(func.clone()))?;
+        self.emit(Op::LookUp(s, 0));
+        self.emit(Op::EqConst(inlined));
+        self.emit(Op::CondJmp(0));
+        let guard = self.chunk.ops.len() - 1;
+        self.forms.push(Form::InlineBody(list.clone(), func, guard));
+        self.forms.push(Form::List(list, 1));
+        Ok(())
+    }
+
+    pub fn eval_inline_body(&mut self, list: ZapList, func: &ZapFn, guard: usize) -> Result<()> {
+        let chunk = &func.chunk;
+        let mut body = &chunk.ops[..chunk.ops.len() - 1];
+        let slots = if takes_args_in_order(chunk) {
+            // The body starts by loading its params once each, they are the args already pushed
+            body = &body[chunk.arity.into()..];
+            Vec::new()
+        } else {
+            // The params get slots in this frame, the args on the stack are stored there
+            let slots = (0..chunk.arity)
+                .map(|_| self.scopes.alloc())
+                .collect::<Result<Vec<_>>>()?;
+            for slot in slots.iter().rev() {
+                self.emit(Op::store(*slot));
+            }
+            slots
+        };
+
+        // An error in the body says where the function was written, as if it was called
+        let skip = chunk.ops.len() - 1 - body.len();
+        let call_span = self.span;
+        for (i, op) in body.iter().enumerate() {
+            self.span = chunk.span_at(skip + i).or(call_span);
+            // The caller's chunk is fused again once it's done
+            let op = match op.unfused() {
+                Op::Push(idx) => Op::Push(self.get_const_idx(&chunk.consts[usize::from(idx)])?),
+                Op::AddConst(idx) => {
+                    Op::AddConst(self.get_const_idx(&chunk.consts[usize::from(idx)])?)
+                }
+                Op::SubConst(idx) => {
+                    Op::SubConst(self.get_const_idx(&chunk.consts[usize::from(idx)])?)
+                }
+                Op::MulConst(idx) => {
+                    Op::MulConst(self.get_const_idx(&chunk.consts[usize::from(idx)])?)
+                }
+                Op::DivConst(idx) => {
+                    Op::DivConst(self.get_const_idx(&chunk.consts[usize::from(idx)])?)
+                }
+                Op::RemConst(idx) => {
+                    Op::RemConst(self.get_const_idx(&chunk.consts[usize::from(idx)])?)
+                }
+                Op::EqConst(idx) => {
+                    Op::EqConst(self.get_const_idx(&chunk.consts[usize::from(idx)])?)
+                }
+                Op::Load(slot) => Op::load(slots[usize::from(slot)]),
+                Op::Store(slot) => Op::store(slots[usize::from(slot)]),
+                Op::LoadW(slot) => Op::load(slots[usize::from(slot)]),
+                Op::StoreW(slot) => Op::store(slots[usize::from(slot)]),
+                Op::Switch(idx) => {
+                    let table = self
+                        .chunk
+                        .tables
+                        .len()
+                        .try_into()
+                        .map_err(|_| error_msg("Too many case forms in a function."))?;
+                    self.chunk
+                        .tables
+                        .push(chunk.tables[usize::from(idx)].clone());
+                    Op::Switch(table)
+                }
+                // The function returns to the end of its body, there is no frame to leave
+                Op::Tailcall(argc) => Op::Call(argc),
+                Op::TailApply(argc) => Op::Apply(argc),
+                Op::Return => Op::jmp(body.len() - i - 1)?,
+                op => op,
+            };
+            self.emit(op);
+        }
+        self.span = call_span;
+
+        self.emit(Op::Jmp(0));
+        let exit = self.chunk.ops.len() - 1;
+        self.chunk.ops[guard] = Op::cond_jmp(exit - guard)?;
+        self.forms.push(Form::InlineEnd(exit));
+        self.forms.push(Form::Apply);
+        self.forms.push(Form::List(list, 0));
+        Ok(())
+    }
+
+    pub fn close_inline(&mut self, exit: usize) -> Result<()> {
+        self.chunk.ops[exit] = Op::jmp(self.chunk.ops.len() - exit - 1)?;
+        Ok(())
+    }
+
+    // The macro a form starts with, unless a local has its name
+    fn get_macro(&self, head: &Value) -> Option<Value> {
+        let Value::Symbol(s) = head else {
+            return None;
+        };
+        if self.scopes.is_bound(*s) {
+            return None;
+        }
+        match self.global_value(*s) {
+            Some(expander @ Value::Macro(_)) => Some(expander),
+            _ => None,
+        }
+    }
+
+    // The value of the global s stands for, when the env has it at compile time
+    fn global_value(&self, s: Symbol) -> Option<Value> {
+        let env = &*self.env;
+        let defined = |name: Option<std::string::String>| {
+            let s = env.find_symbol(&name?)?;
+            env.lookup(s)
+        };
+        defined(self.alias_target(s))
+            .or_else(|| defined(self.qualified_name(s)))
+            .or_else(|| env.lookup(s))
+    }
+
+    fn eval_compile_if(&mut self, list: &ZapList) -> Result<()> {
+        let test_error =
+            || error_msg("A compile-if test is (available? 'name) or (capability? 'name)");
+        let (taken, then, otherwise) = match &list[..] {
+            // (when-available 'f body...) is (compile-if (available? 'f) (do body...) nil)
+            [Value::Symbol(symbols::WHEN_AVAILABLE), name, body @ ..] => {
+                let name = quoted_symbol(name)
+                    .ok_or_else(|| error_msg("A when-available form must have a symbol"))?;
+                (self.is_available(name), implicit_do(body), Value::Nil)
+            }
+            [Value::Symbol(symbols::WHEN_AVAILABLE)] => {
+                return Err(error_msg("A when-available form must have a symbol"))
+            }
+            [_, Value::List(test), then, otherwise @ ..] if otherwise.len() < 2 => {
+                let [Value::Symbol(query), name] = &test[..] else {
+                    return Err(test_error());
+                };
+                let name = quoted_symbol(name).ok_or_else(test_error)?;
+                let otherwise = otherwise.first().cloned().unwrap_or(Value::Nil);
+                (self.query(*query, name)?, then.clone(), otherwise)
+            }
+            [_, _, _] | [_, _, _, _] => return Err(test_error()),
+            _ => {
+                return Err(error_msg(
+                    "A compile-if form must have a test and 1 or 2 branches",
+                ))
+            }
+        };
+        self.forms
+            .push(Form::Value(if taken { then } else { otherwise }));
+        Ok(())
+    }
+
+    // A test of compile-if, answered by the env the code is compiled in
+    fn query(&self, query: Symbol, name: Symbol) -> Result<bool> {
+        let env = &*self.env;
+        match &*env.get_symbol(query)? {
+            "available?" => Ok(self.is_available(name)),
+            "capability?" => {
+                let name = env.get_symbol(name)?;
+                let cap = Capability::ALL
+                    .into_iter()
+                    .find(|cap| cap.name() == &*name)
+                    .ok_or_else(|| error_msg(&format!("Unknown capability '{name}'")))?;
+                Ok(env.has_capability(cap))
+            }
+            _ => Err(error_msg(
+                "A compile-if test is (available? 'name) or (capability? 'name)",
+            )),
+        }
+    }
+
+    fn is_available(&self, s: Symbol) -> bool {
+        self.scopes.is_bound(s) || self.global_value(s).is_some()
+    }
+
+    fn expand(&mut self, expander: Value, list: &ZapList) -> Result<Value> {
+        let Value::Macro(f) = expander else {
+            unreachable!()
+        };
+        let env = &mut *self.env;
+        vm::call(Value::Func(f), &list[1..], env).map_err(|err| {
+            let name = env.get_symbol(match list[0] {
+                Value::Symbol(s) => s,
+                _ => unreachable!(),
+            });
+            error_msg(&format!(
+                "Error expanding '{}': {}",
+                name.unwrap_or_default(),
+                err
+            ))
+        })
+    }
+
+    fn eval_defmacro(&mut self, list: &ZapList) -> Result<()> {
+        // (defmacro name "doc" (params) body) becomes (def name (<macro> (fn name (params) body)))
+        let (name, params, body) = definition_parts(list, "defmacro")?;
+        let expander = Value::List(Value::new_list(vec![
+            Value::Symbol(symbols::FN),
+            name.clone(),
+            params.clone(),
+            implicit_do(body),
+        ]));
+        self.forms
+            .push(Form::Value(Value::List(Value::new_list(vec![
+                Value::Symbol(symbols::DEFINE),
+                name.clone(),
+                Value::List(Value::new_list(vec![native("macro", make_macro), expander])),
+            ]))));
+        Ok(())
+    }
+
+    fn eval_defn(&mut self, list: &ZapList) -> Result<()> {
+        // (defn name "doc" (params) body) becomes (def name (fn (params) body))
+        let (name, params, body) = definition_parts(list, "defn")?;
+        let func = Value::List(Value::new_list(vec![
+            Value::Symbol(symbols::FN),
+            params.clone(),
+            implicit_do(body),
+        ]));
+        self.forms
+            .push(Form::Value(Value::List(Value::new_list(vec![
+                Value::Symbol(symbols::DEFINE),
+                name.clone(),
+                func,
+            ]))));
+        Ok(())
+    }
+
+    fn eval_thread(&mut self, list: &ZapList, thread_last: bool) -> Result<()> {
+        // (-> x (f 1) g) becomes (g (f x 1)), and (->> x (f 1) g) becomes (g (f 1 x))
+        let Some(mut threaded) = list.get(1).cloned() else {
+            return Err(error_msg(&format!(
+                "A {} form must have a value to thread",
+                if thread_last { "->>" } else { "->" }
+            )));
+        };
+        for step in &list[2..] {
+            threaded = thread_step(threaded, step, thread_last);
+        }
+        self.forms.push(Form::Value(threaded));
+        Ok(())
+    }
+
+    fn eval_some_thread(&mut self, list: &ZapList, thread_last: bool) -> Result<()> {
+        // (some-> x f g) becomes (let (G x) (if (= G nil) nil (some-> (f G) g)))
+        let Some(value) = list.get(1) else {
+            return Err(error_msg(&format!(
+                "A {} form must have a value to thread",
+                if thread_last { "some->>" } else { "some->" }
+            )));
+        };
+        if list.len() == 2 {
+            self.forms.push(Form::Value(value.clone()));
+            return Ok(());
+        }
+
+        let threaded = Value::Symbol(self.gensym());
+        let mut rest = vec![
+            list[0].clone(),
+            thread_step(threaded.clone(), &list[2], thread_last),
+        ];
+        rest.extend_from_slice(&list[3..]);
+        let is_nil = Value::List(Value::new_list(vec![
+            Value::Symbol(symbols::EQUAL),
+            threaded.clone(),
+            Value::Nil,
+        ]));
+        self.forms
+            .push(Form::Value(Value::List(Value::new_list(vec![
+                Value::Symbol(symbols::LET),
+                Value::List(Value::new_list(vec![threaded, value.clone()])),
+                Value::List(Value::new_list(vec![
+                    Value::Symbol(symbols::IF),
+                    is_nil,
+                    Value::Nil,
+                    Value::List(Value::new_list(rest)),
+                ])),
+            ]))));
+        Ok(())
+    }
+
+    fn eval_or(&mut self, list: &ZapList) {
+        // (or a b) becomes (let (G a) (if G G (or b))), the first truthy value or the last one
+        match list.len() {
+            1 => self.forms.push(Form::Const(Value::Nil)),
+            2 => self.forms.push(Form::Value(list[1].clone())),
+            _ => {
+                let first = Value::Symbol(self.gensym());
+                let mut rest = vec![list[0].clone()];
+                rest.extend_from_slice(&list[2..]);
+                self.forms
+                    .push(Form::Value(Value::List(Value::new_list(vec![
+                        Value::Symbol(symbols::LET),
+                        Value::List(Value::new_list(vec![first.clone(), list[1].clone()])),
+                        Value::List(Value::new_list(vec![
+                            Value::Symbol(symbols::IF),
+                            first.clone(),
+                            first,
+                            Value::List(Value::new_list(rest)),
+                        ])),
+                    ]))));
+            }
+        }
+    }
+
+    fn eval_extension(&mut self, form: SpecialForm, list: &ZapList) -> Result<()> {
+        let mut emitter = Emitter { steps: Vec::new() };
+        form(list, &mut emitter)?;
+        self.forms.extend(emitter.steps.into_iter().rev());
+        Ok(())
+    }
+
+    fn eval_while(&mut self, list: ZapList) -> Result<()> {
+        if list.len() < 2 {
+            return Err(error_msg("A while form must have a condition"));
+        }
+        let cond = list[1].clone();
+        let loop_start = self.chunk.ops.len();
+        self.forms.push(Form::WhileBody(list, loop_start));
+        self.forms.push(Form::Value(cond));
+        Ok(())
+    }
+
+    fn eval_doto(&mut self, list: &ZapList) -> Result<()> {
+        if list.len() < 2 {
+            return Err(error_msg("A doto form must have a value"));
+        }
+
+        // (doto x (f a)) becomes (let (G x) (do (f G a) G))
+        let target = Value::Symbol(self.gensym());
+        let mut body = Vec::with_capacity(list.len() - 1);
+        for form in &list[2..] {
+            let call = match form {
+                Value::List(call) if !call.is_empty() => {
+                    let mut call = call.to_vec();
+                    call.insert(1, target.clone());
+                    call
+                }
+                Value::Symbol(_) => vec![form.clone(), target.clone()],
+                _ => return Err(error_msg("doto can only thread through calls")),
+            };
+            body.push(Value::List(Value::new_list(call)));
+        }
+        body.push(target.clone());
+
+        let bindings = Value::List(Value::new_list(vec![target, list[1].clone()]));
+        self.eval_let(&Value::new_list(vec![
+            Value::Symbol(symbols::LET),
+            bindings,
+            implicit_do(&body),
+        ]))
+    }
+
+    fn eval_fn(&mut self, list: &ZapList) -> Result<()> {
+        // A named fn, (fn name (args) body...), can refer to itself by its name
+        let (name, list) = match list.get(1) {
+            Some(Value::Symbol(name)) => (Some(*name), &list[1..]),
+            _ => (None, &list[..]),
+        };
+        if list.len() < 2 {
+            return Err(error_msg("A fn form must have a list of params"));
+        }
+
+        // Get into another scope
+        let defining = self.defining.take();
+        self.scopes.push(defining);
+
+        match &list[1] {
+            Value::List(args) | Value::Vector(args) => {
+                // We save the current chunk
+                let parent_chunk = std::mem::take(&mut self.chunk);
+                let parent_consts = std::mem::take(&mut self.consts);
+                self.forms.push(Form::Return(parent_chunk, parent_consts));
+                self.chunk.name = name.or(defining);
+
+                // Everything after a & is collected in the rest param
+                let (fixed, rest) =
+                    match args.iter().position(|a| *a == Value::Symbol(symbols::REST)) {
+                        Some(pos) if pos + 2 == args.len() => (&args[..pos], args.last()),
+                        Some(_) => {
+                            return Err(error_msg(
+                                "A & in fn's params must be followed by exactly one symbol.",
+                            ))
+                        }
+                        None => (&args[..], None),
+                    };
+
+                self.chunk.arity = fixed
+                    .len()
+                    .try_into()
+                    .map_err(|_| error_msg("A fn cannot have more than 255 params."))?;
+                self.chunk.variadic = rest.is_some();
+
+                // Set all the params in the locals.
+                for arg in fixed.iter().chain(rest) {
+                    if let Value::Symbol(symbol) = arg {
+                        if let Some(shadowed) = self.scopes.get_own_local(*symbol) {
+                            // Warned about once, it can't be used anyway
+                            self.scopes.mark_loaded(shadowed);
+                            self.warn(*symbol, "Param '{}' shadows an earlier param");
+                        }
+                        self.scopes.push_local(*symbol)?;
+                    } else {
+                        return Err(error_msg("Only symbols can be used as args in fn."));
+                    }
+                }
+                if let Some(name) = name {
+                    let slot = self.scopes.push_local(name)?;
+                    // Not calling itself is no reason to warn
+                    self.scopes.mark_loaded(slot);
+                    self.chunk.self_slot = Some(slot);
+                }
+                // Several body forms are evaluated as an implicit do
+                self.forms.push(Form::Value(implicit_do(&list[2..])));
+                Ok(())
+            }
+            _ => Err(error_msg("fn's first parameter must be a list or a vector")),
+        }
+    }
+
+    fn eval_if(&mut self, list: ZapList) -> Result<()> {
+        if list.len() != 4 {
+            return Err(error_msg("An if form must have 3 parameters"));
+        }
+        let mut cond = fold(&list[1]).unwrap_or_else(|| list[1].clone());
+        let mut swapped = false;
+        // (if (not x) a b) jumps on x to the other branch, there's no boolean to test again
+        while let Some(negated) = negated(&cond) {
+            cond = negated;
+            swapped = !swapped;
+        }
+        let list = if swapped {
+            Value::new_list(vec![
+                list[0].clone(),
+                cond.clone(),
+                list[3].clone(),
+                list[2].clone(),
+            ])
+        } else {
+            list
+        };
+        if is_const(&cond) {
+            // Only the branch taken is compiled, without any jump
+            let taken = if cond.is_truthy() { &list[2] } else { &list[3] };
+            self.forms.push(Form::Value(taken.clone()));
+        } else {
+            self.forms.push(Form::IfCond(list));
+            self.forms.push(Form::Value(cond));
+        }
+        Ok(())
+    }
+
+    fn eval_def(&mut self, list: &ZapList) -> Result<()> {
+        if list.len() < 2 {
+            return Err(error_msg("A def form must have 2 parameters"));
+        }
+        if let (Value::Symbol(name), true) = (&list[1], self.scopes.is_fn()) {
+            self.warn(*name, "A def of '{}' in a fn defines a global");
+        }
+        // A fn defined here knows its name, to call itself without looking it up
+        if let (Value::Symbol(name), Some(Value::List(value))) = (&list[1], list.get(2)) {
+            if value.first() == Some(&Value::Symbol(symbols::FN)) {
+                self.defining = Some(*name);
+            }
+        }
+        match list[1] {
+            Value::Symbol(s) => {
+                let s = self.qualify(s);
+                self.push(&Value::Symbol(s))?;
+            }
+            ref name => self.push(name)?,
+        }
+        self.forms.push(Form::Define);
+        self.forms.push(Form::Value(list[2].clone()));
+        Ok(())
+    }
+
+    // The name s has in the current namespace, unless it's qualified already
+    fn qualified_name(&self, s: Symbol) -> Option<std::string::String> {
+        let env = &*self.env;
+        let ns = env.namespace()?;
+        let name = env.get_symbol(s).ok()?;
+        (!is_qualified(&name)).then(|| format!("{ns}/{name}"))
+    }
+
+    // The global a def in the current namespace defines
+    fn qualify(&mut self, s: Symbol) -> Symbol {
+        match self.qualified_name(s) {
+            Some(name) => match self.env.reg_symbol(String::from(name.as_str())) {
+                Value::Symbol(s) => s,
+                _ => s,
+            },
+            None => s,
+        }
+    }
+
+    // The name s stands for through the aliases required by the current namespace: a name it
+    // refers to, or one qualified by a namespace alias
+    fn alias_target(&self, s: Symbol) -> Option<std::string::String> {
+        let env = &*self.env;
+        let name = env.get_symbol(s).ok()?;
+        match name.split_once('/') {
+            Some((alias, rest)) if is_qualified(&name) => {
+                Some(format!("{}{rest}", env.alias(&format!("{alias}/"))?))
+            }
+            _ => env.alias(&name).map(|target| target.to_string()),
+        }
+    }
+
+    // The global a symbol looks up: the one it's an alias for, else the one of the current
+    // namespace once it's there, else the top one if it's defined, else the one the namespace
+    // will define later
+    fn resolve(&mut self, s: Symbol) -> Symbol {
+        if let Some(target) = self.alias_target(s) {
+            if let Value::Symbol(s) = self.env.reg_symbol(String::from(target.as_str())) {
+                return s;
+            }
+        }
+        let Some(name) = self.qualified_name(s) else {
+            return s;
+        };
+        match self.env.find_symbol(&name) {
+            Some(qualified) => qualified,
+            None if self.env.lookup(s).is_some() => s,
+            None => self.qualify(s),
+        }
+    }
+
+    fn eval_ns(&mut self, list: &ZapList) -> Result<()> {
+        let [_, Value::Symbol(ns)] = &list[..] else {
+            return Err(error_msg("A ns form must have a name"));
+        };
+        // It applies to the forms compiled after it
+        let env = &mut *self.env;
+        let name = env.get_symbol(*ns)?;
+        env.set_namespace(Some(&name))?;
+        self.push(&Value::Nil)
+    }
+
+    fn eval_require(&mut self, list: &ZapList) -> Result<()> {
+        // (require 'lib) or (require '(lib :as l :refer (f g))), quoted or not
+        let spec = match &list[..] {
+            [_, Value::List(quoted)] => match &quoted[..] {
+                [Value::Symbol(symbols::QUOTE), spec] => spec.clone(),
+                _ => Value::List(quoted.clone()),
+            },
+            [_, spec] => spec.clone(),
+            _ => return Err(error_msg("A require form must have a namespace")),
+        };
+        let (ns, options) = match &spec {
+            Value::Symbol(ns) => (*ns, &[][..]),
+            Value::List(spec) => match &spec[..] {
+                [Value::Symbol(ns), options @ ..] => (*ns, options),
+                _ => return Err(error_msg("A require form must have a namespace")),
+            },
+            _ => return Err(error_msg("A require form must have a namespace")),
+        };
+        let env = &mut *self.env;
+        let name = env.get_symbol(ns)?;
+        let prefix = format!("{name}/");
+        // A namespace is there once it has defined something
+        let loaded = (0..env.symbols_count()).any(|id| {
+            #[allow(clippy::cast_possible_truncation)]
+            let id = id as Symbol;
+            env.get_symbol(id).is_ok_and(|s| s.starts_with(&prefix)) && env.lookup(id).is_some()
+        });
+        if !loaded {
+            return Err(error_msg(&format!("Namespace '{name}' is not loaded")));
+        }
+
+        for option in options.chunks(2) {
+            let key = match option.first() {
+                Some(Value::Keyword(key)) => env.get_symbol(*key)?,
+                _ => String::from(""),
+            };
+            match (&*key, option.get(1)) {
+                ("as", Some(Value::Symbol(alias))) => {
+                    let alias = env.get_symbol(*alias)?;
+                    env.add_alias(&format!("{alias}/"), &prefix)?;
+                }
+                ("refer", Some(Value::List(names))) => {
+                    for referred in names.iter() {
+                        let Value::Symbol(referred) = referred else {
+                            return Err(error_msg("A require form refers to symbols"));
+                        };
+                        let referred = env.get_symbol(*referred)?;
+                        let target = format!("{prefix}{referred}");
+                        if env
+                            .find_symbol(&target)
+                            .is_none_or(|s| env.lookup(s).is_none())
+                        {
+                            return Err(error_msg(&format!(
+                                "'{referred}' is not defined in namespace '{name}'"
+                            )));
+                        }
+                        env.add_alias(&referred, &target)?;
+                    }
+                }
+                _ => {
+                    return Err(error_msg(
+                        "A require form takes :as alias and :refer (names...)",
+                    ))
+                }
+            }
+        }
+        self.push(&Value::Nil)
+    }
+
+    fn eval_arith(&mut self, list: ZapList, arith: Arith) -> Result<()> {
+        let mut list = arith.operands(&fold_operands(list))?;
+        if let Some(val) = arith.fold(&list[1..]) {
+            return self.push(&val);
+        }
+        if arith == Arith::Add {
+            list = reassociate_consts(list);
+        }
+        if list.len() == 2 {
+            // (+ x) and (* x) are x
+            self.forms.push(Form::Value(list[1].clone()));
+        } else {
+            self.forms.push(Form::Arith(arith, list, 1));
+        }
+        Ok(())
+    }
+
+    fn eval_set(&mut self, list: &ZapList) -> Result<()> {
+        let [_, Value::Symbol(s), val] = &list[..] else {
+            return Err(error_msg("A set! form must have a symbol and a value"));
+        };
+        // A captured local is a copy, changing it wouldn't change the original
+        let Some(slot) = self.scopes.get_own_local(*s) else {
+            return Err(error_msg(if self.scopes.is_bound(*s) {
+                "set! cannot change a local of an enclosing fn"
+            } else {
+                "set! can only change a local"
+            }));
+        };
+        self.forms.push(Form::Emit(Op::load(slot)));
+        self.forms.push(Form::Emit(Op::store(slot)));
+        self.forms.push(Form::Value(val.clone()));
+        Ok(())
+    }
+
+    fn eval_with_env(&mut self, list: &ZapList) {
+        // The body runs in a layer of the env. The VM leaves it when an error gets out.
+        self.emit(Op::EnterEnv);
+        self.forms.push(Form::Emit(Op::LeaveEnv));
+        self.forms.push(Form::Value(implicit_do(&list[1..])));
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn eval_letfn(&mut self, list: &ZapList) -> Result<()> {
+        // (letfn ((f (x) a) (g (y) b)) body) becomes
+        //   (let (G (fn G (i) (case i 0 (fn f (x) (let (g (G 1)) a))
+        //                            1 (fn g (y) (let (f (G 0)) b))))
+        //         f (G 0)
+        //         g (G 1))
+        //     body)
+        // The closures capture values, so the functions can't hold each other. Each one gets the
+        // others it uses from G when it's called, and refers to itself by its own name.
+        let malformed = || error_msg("A letfn form must have a list of (name (params) body...)");
+        let Some(Value::List(fns) | Value::Vector(fns)) = list.get(1) else {
+            return Err(malformed());
+        };
+        let mut defs = Vec::with_capacity(fns.len());
+        for def in fns.iter() {
+            match def {
+                Value::List(def) if def.len() >= 2 => match (&def[0], &def[1]) {
+                    (Value::Symbol(name), Value::List(_) | Value::Vector(_)) => {
+                        defs.push((*name, def));
+                    }
+                    _ => return Err(malformed()),
+                },
+                _ => return Err(malformed()),
+            }
+        }
+
+        let group = Value::Symbol(self.gensym());
+        #[allow(clippy::cast_possible_wrap)]
+        let get =
+            |i: usize| Value::List(Value::new_list(vec![group.clone(), Value::Int(i as i64)]));
+
+        let index = Value::Symbol(self.gensym());
+        let mut branches = vec![Value::Symbol(symbols::CASE), index.clone()];
+        for (i, (name, def)) in defs.iter().enumerate() {
+            let body = implicit_do(&def[2..]);
+            let mut others = Vec::new();
+            for (j, (other, _)) in defs.iter().enumerate() {
+                if j != i && mentions(&body, *other) {
+                    others.push(Value::Symbol(*other));
+                    others.push(get(j));
+                }
+            }
+            let body = if others.is_empty() {
+                body
+            } else {
+                Value::List(Value::new_list(vec![
+                    Value::Symbol(symbols::LET),
+                    Value::List(Value::new_list(others)),
+                    body,
+                ]))
+            };
+            #[allow(clippy::cast_possible_wrap)]
+            branches.push(Value::Int(i as i64));
+            branches.push(Value::List(Value::new_list(vec![
+                Value::Symbol(symbols::FN),
+                Value::Symbol(*name),
+                def[1].clone(),
+                body,
+            ])));
+        }
+
+        let mut bindings = vec![
+            group.clone(),
+            Value::List(Value::new_list(vec![
+                Value::Symbol(symbols::FN),
+                group.clone(),
+                Value::List(Value::new_list(vec![index])),
+                Value::List(Value::new_list(branches)),
+            ])),
+        ];
+        let body = implicit_do(&list[2..]);
+        for (i, (name, _)) in defs.iter().enumerate() {
+            if mentions(&body, *name) {
+                bindings.push(Value::Symbol(*name));
+                bindings.push(get(i));
+            }
+        }
+        self.forms
+            .push(Form::Value(Value::List(Value::new_list(vec![
+                Value::Symbol(symbols::LET),
+                Value::List(Value::new_list(bindings)),
+                body,
+            ]))));
+        Ok(())
+    }
+
+    fn eval_let(&mut self, list: &ZapList) -> Result<()> {
+        if list.len() != 3 {
+            return Err(error_msg("A let form must have 2 parameters"));
+        }
+
+        if let Value::List(bindings) | Value::Vector(bindings) = &list[1] {
+            // Check for even number of bindings
+            if bindings.len() % 2 == 1 {
+                return Err(error_msg("Bindings must have an even number of bindings"));
+            }
+            self.forms.push(Form::Let(bindings.len() / 2));
+            self.forms.push(Form::Value(list[2].clone()));
+
+            for pair in bindings.rchunks(2) {
+                if let Value::Symbol(s) = pair[0] {
+                    self.forms.push(Form::Binding(s));
+                    self.forms.push(Form::Value(pair[1].clone()));
+                } else {
+                    return Err(error_msg(
+                        "A binding must consist of a symbol and an expression",
+                    ));
+                }
+            }
+            Ok(())
+        } else {
+            Err(error_msg("A let form must have a list of bindings"))
+        }
+    }
+
+    fn eval_when(&mut self, list: &ZapList) -> Result<()> {
+        if list.len() < 2 {
+            return Err(error_msg("A when or unless form must have a condition"));
+        }
+
+        // (when c a b) becomes (if c (do a b) nil), unless swaps the branches
+        let body = implicit_do(&list[2..]);
+        let (then, otherwise) = if list[0] == Value::Symbol(symbols::WHEN) {
+            (body, Value::Nil)
+        } else {
+            (Value::Nil, body)
+        };
+        self.forms
+            .push(Form::Value(Value::List(Value::new_list(vec![
+                Value::Symbol(symbols::IF),
+                list[1].clone(),
+                then,
+                otherwise,
+            ]))));
+        Ok(())
+    }
+
+    fn eval_case(&mut self, list: &ZapList) -> Result<()> {
+        if list.len() < 2 {
+            return Err(error_msg("A case form must have a subject"));
+        }
+
+        let mut keys = list[2..].chunks_exact(2).map(|pair| case_key(&pair[0]));
+        if keys.all(|key| key.is_some_and(|key| JumpTable::accepts(&key))) {
+            self.forms.push(Form::CaseSwitch(list.clone()));
+            self.forms.push(Form::Value(list[1].clone()));
+            return Ok(());
+        }
+
+        // Some keys are only known at runtime, they are compared in order.
+        // (case x k1 a k2 b c) becomes (let (G x) (if (= G k1) a (if (= G k2) b c)))
+        let subject = Value::Symbol(self.gensym());
+        let mut chain = if list.len() % 2 == 1 {
+            list[list.len() - 1].clone()
+        } else {
+            Value::Nil
+        };
+        for pair in list[2..].chunks_exact(2).rev() {
+            let test = Value::List(Value::new_list(vec![
+                Value::Symbol(symbols::EQUAL),
+                subject.clone(),
+                pair[0].clone(),
+            ]));
+            chain = Value::List(Value::new_list(vec![
+                Value::Symbol(symbols::IF),
+                test,
+                pair[1].clone(),
+                chain,
+            ]));
+        }
+        self.forms
+            .push(Form::Value(Value::List(Value::new_list(vec![
+                Value::Symbol(symbols::LET),
+                Value::List(Value::new_list(vec![subject, list[1].clone()])),
+                chain,
+            ]))));
+        Ok(())
+    }
+
+    pub fn eval_case_switch(&mut self, list: ZapList) -> Result<()> {
+        let table = self
+            .chunk
+            .tables
+            .len()
+            .try_into()
+            .map_err(|_| error_msg("Too many case forms in a function."))?;
+        self.chunk.tables.push(JumpTable::default());
+        self.emit(Op::Switch(table));
+        self.forms.push(Form::CaseBranch(
+            list,
+            self.chunk.ops.len() - 1,
+            0,
+            Vec::new(),
+        ));
+        Ok(())
+    }
+
+    pub fn eval_case_branch(
+        &mut self,
+        list: ZapList,
+        switch: usize,
+        branch: usize,
+        mut exits: Vec<usize>,
+    ) -> Result<()> {
+        // The previous branch jumps to the end of the case, patched once it's known
+        if branch > 0 {
+            if self.is_last_exp() {
+                self.emit(Op::Return);
+            } else {
+                self.emit(Op::Jmp(0));
+                exits.push(self.chunk.ops.len() - 1);
+            }
+        }
+
+        let Op::Switch(table) = self.chunk.ops[switch] else {
+            unreachable!()
+        };
+        let table = &mut self.chunk.tables[table as usize];
+        let target = (self.chunk.ops.len() - switch - 1)
+            .try_into()
+            .map_err(|_| error_msg("Case branches are too big."))?;
+
+        let key = 2 + 2 * branch;
+        if key + 1 < list.len() {
+            table.insert(&case_key(&list[key]).unwrap_or_default(), target);
+            let body = list[key + 1].clone();
+            self.forms
+                .push(Form::CaseBranch(list, switch, branch + 1, exits));
+            self.forms.push(Form::Value(body));
+        } else {
+            // Without a default, a case evaluates to nil
+            table.set_default(target);
+            self.forms.push(Form::CaseEnd(exits));
+            self.forms
+                .push(Form::Value(list.get(key).cloned().unwrap_or_default()));
+        }
+        Ok(())
+    }
+
+    pub fn close_case(&mut self, exits: &[usize]) -> Result<()> {
+        for exit in exits {
+            self.chunk.ops[*exit] = Op::jmp(self.chunk.ops.len() - exit - 1)?;
+        }
+        Ok(())
+    }
+
+    fn eval_try(&mut self, list: ZapList) -> Result<()> {
+        // (try body... (catch e handler...))
+        let catch = match list.last() {
+            Some(Value::List(catch)) if catch.first() == Some(&Value::Symbol(symbols::CATCH)) => {
+                catch
+            }
+            _ => return Err(error_msg("A try form must end with a catch")),
+        };
+        if !matches!(catch.get(1), Some(Value::Symbol(_))) {
+            return Err(error_msg("A catch must bind the error to a symbol"));
+        }
+
+        // Patched once the body is compiled, the catch starts right after it
+        self.emit(Op::Try(0));
+        let body = implicit_do(&list[1..list.len() - 1]);
+        self.forms
+            .push(Form::TryEnd(list, self.chunk.ops.len() - 1));
+        self.forms.push(Form::Value(body));
+        Ok(())
+    }
+
+    pub fn eval_catch(&mut self, list: &ZapList, try_start: usize) -> Result<()> {
+        let Some(Value::List(catch)) = list.last() else {
+            unreachable!()
+        };
+        let Value::Symbol(error) = catch[1] else {
+            unreachable!()
+        };
+
+        // Without an error, the catch is skipped
+        self.emit(Op::EndTry);
+        self.emit(Op::Jmp(0));
+        let skip = self.chunk.ops.len() - 1;
+
+        let to_catch = (self.chunk.ops.len() - try_start - 1)
+            .try_into()
+            .map_err(|_| error_msg("Try body is too big."))?;
+        self.chunk.ops[try_start] = Op::Try(to_catch);
+
+        // The error is at the top of the stack
+        self.register_binding(error)?;
+        self.forms.push(Form::CatchEnd(skip));
+        self.forms.push(Form::Let(1));
+        self.forms.push(Form::Value(implicit_do(&catch[2..])));
         Ok(())
     }
 
-    fn emit(&mut self, op: Op) {
-        self.chunk.ops.push(op);
+    pub fn close_catch(&mut self, skip: usize) -> Result<()> {
+        self.chunk.ops[skip] = Op::jmp(self.chunk.ops.len() - skip - 1)?;
+        Ok(())
     }
 
-    fn get_const_idx(&mut self, val: &Value) -> Result<u16> {
-        if let Some(idx) = self.chunk.consts.iter().position(|x| x == val) {
-            idx
-        } else {
-            let idx = self.chunk.consts.len();
-            self.chunk.consts.push(val.clone());
-            idx
+    fn eval_loop(&mut self, list: &ZapList) -> Result<()> {
+        let Some(Value::List(bindings) | Value::Vector(bindings)) = list.get(1) else {
+            return Err(error_msg("A loop form must have a list of bindings"));
+        };
+        if bindings.len() % 2 == 1 {
+            return Err(error_msg("Bindings must have an even number of bindings"));
         }
-        .try_into()
-        .map_err(|_| error_msg("Too many constants in the constants table"))
-    }
 
-    pub fn eval_list(&mut self, list: ZapList) -> Result<()> {
-        if list.len() > 255 {
-            return Err(error_msg(
-                "A function cannot have more than 254 parameters.",
-            ));
+        // The bindings are the same as let's, the loop starts right after them
+        self.forms
+            .push(Form::LoopBegin(list.clone(), bindings.len() / 2));
+        for pair in bindings.rchunks(2) {
+            if let Value::Symbol(s) = pair[0] {
+                self.forms.push(Form::Binding(s));
+                self.forms.push(Form::Value(pair[1].clone()));
+            } else {
+                return Err(error_msg(
+                    "A binding must consist of a symbol and an expression",
+                ));
+            }
         }
+        Ok(())
+    }
 
-        // In quoting
-        if self.quoting {
-            self.forms.push(Form::List(list.clone(), 0));
-            self.forms.push(Form::Value(list[0].clone()));
-            return Ok(());
-        }
+    pub fn eval_loop_begin(&mut self, list: &ZapList, locals_count: usize) {
+        let slots = self.scopes.last_locals(locals_count);
+        self.forms.push(Form::LoopEnd(self.chunk.ops.len(), slots));
+        self.forms.push(Form::Value(implicit_do(&list[2..])));
+    }
 
-        match list[0] {
-            Value::Symbol(symbols::DO) => {
-                if list.len() < 2 {
-                    return Err(error_msg("A do form must contains at least 1 parameter"));
-                }
-                self.forms.push(Form::Do(list, 1));
+    pub fn close_loop(&mut self, loop_start: usize, slots: &[LocalIndex]) -> Result<()> {
+        // Every recur in the body jumps back to the start. Those of inner loops are already patched.
+        for idx in loop_start..self.chunk.ops.len() {
+            if self.chunk.ops[idx] == Op::Loop(0) {
+                self.chunk.ops[idx] = Op::loop_back(idx + 1 - loop_start)?;
             }
-            Value::Symbol(symbols::FN) => {
-                if list.len() != 3 {
-                    return Err(error_msg("A fn form must contains 2 parameters"));
-                }
-
-                // Get into another scope
-                self.scopes.push();
-
-                match &list[1] {
-                    Value::List(args) => {
-                        // We save the current chunk
-                        let parent_chunk = std::mem::take(&mut self.chunk);
-                        self.forms.push(Form::Return(parent_chunk));
-
-                        self.chunk.arity = args.len().try_into().unwrap();
+        }
+        self.scopes.pop_locals(slots.len());
+        Ok(())
+    }
 
-                        // Set all the params in the locals.
-                        for arg in args.iter() {
-                            if let Value::Symbol(symbol) = arg {
-                                self.scopes.push_local(*symbol)?;
-                            } else {
-                                return Err(error_msg("Only symbols can be used as args in fn."));
-                            }
-                        }
-                        self.forms.push(Form::Value(list[2].clone()));
-                    }
-                    _ => {
-                        return Err(error_msg("fn's first parameter must be a list"));
-                    }
-                }
-            }
-            Value::Symbol(symbols::DEFINE) => {
-                if list.len() < 2 {
-                    return Err(error_msg("A def form must have 2 parameters"));
-                }
-                self.push(&list[1])?;
-                self.forms.push(Form::Define);
-                self.forms.push(Form::Value(list[2].clone()));
-            }
-            Value::Symbol(symbols::IF) => {
-                if list.len() != 4 {
-                    return Err(error_msg("An if form must have 3 parameters"));
+    fn eval_recur(&mut self, list: &ZapList) -> Result<()> {
+        // Nothing can be left to do in the loop body after a recur, so the stack is as it was at the start
+        let mut slots = None;
+        for form in self.forms.iter().rev() {
+            match form {
+                Form::IfThen(_, _)
+                | Form::IfElse(_, _)
+                | Form::Let(_)
+                | Form::CaseBranch(..)
+                | Form::CaseEnd(_)
+                | Form::CatchEnd(_)
+                | Form::Span(_) => {}
+                Form::LoopEnd(_, loop_slots) => {
+                    slots = Some(loop_slots.clone());
+                    break;
                 }
-                let cond = list[1].clone();
-                self.forms.push(Form::IfCond(list));
-                self.forms.push(Form::Value(cond));
+                _ => break,
             }
-            Value::Symbol(symbols::LET) => {
-                if list.len() != 3 {
-                    return Err(error_msg("A let form must have 2 parameters"));
-                }
+        }
+        let Some(slots) = slots else {
+            return Err(error_msg(
+                "recur can only be used in tail position of a loop",
+            ));
+        };
 
-                if let Value::List(bindings) = &list[1] {
-                    // Check for even number of bindings
-                    if bindings.len() % 2 == 1 {
-                        return Err(error_msg("Bindings must have an even number of bindings"));
-                    }
-                    self.forms.push(Form::Let(bindings.len() / 2));
-                    self.forms.push(Form::Value(list[2].clone()));
-
-                    for pair in bindings.rchunks(2) {
-                        if let Value::Symbol(s) = pair[0] {
-                            self.forms.push(Form::Binding(s));
-                            self.forms.push(Form::Value(pair[1].clone()));
-                        } else {
-                            return Err(error_msg(
-                                "A binding must consist of a symbol and an expression",
-                            ));
-                        }
-                    }
-                } else {
-                    return Err(error_msg("A let form must have a list of bindings"));
-                }
-            }
-            Value::Symbol(symbols::EQUAL) => {
-                if list.len() != 3 {
-                    return Err(error_msg("A = form must have 2 parameters"));
-                }
+        if slots.len() != list.len() - 1 {
+            return Err(error_msg(
+                format!(
+                    "recur expects {} args, got {}.",
+                    slots.len(),
+                    list.len() - 1
+                )
+                .as_str(),
+            ));
+        }
 
-                if is_const(&list[1]) == is_const(&list[2]) {
-                    // Compile time compare on constants
-                    self.push(&Value::Bool(list[1] == list[2]))?;
-                } else if is_const(&list[1]) {
-                    let idx = self.get_const_idx(&list[1].clone())?;
-                    self.forms.push(Form::EqualConst(idx));
-                    self.forms.push(Form::Value(list[2].clone()));
-                } else if is_const(&list[2]) {
-                    let idx = self.get_const_idx(&list[2].clone())?;
-                    self.forms.push(Form::EqualConst(idx));
-                    self.forms.push(Form::Value(list[1].clone()));
-                } else {
-                    self.forms.push(Form::Equal);
-                    self.forms.push(Form::Value(list[1].clone()));
-                    self.forms.push(Form::Value(list[2].clone()));
-                }
-            }
-            Value::Symbol(symbols::PLUS) => {
-                match list.len() {
-                    1 => {
-                        // Push 0 on the stack
-                        let const_idx = self.get_const_idx(&Value::Number(0.0))?;
-                        self.emit(Op::Push(const_idx));
-                    }
-                    2 => {
-                        self.forms.push(Form::Value(list[1].clone()));
-                    }
-                    _ => {
-                        self.forms.push(Form::AddMany(list, 1));
-                    }
-                }
-            }
-            Value::Symbol(symbols::QUOTE) => {
-                if list.len() != 2 {
-                    return Err(error_msg("'quote' require only 1 value"));
-                }
+        self.forms.push(Form::Recur(slots));
+        for arg in list[1..].iter().rev() {
+            self.forms.push(Form::Value(arg.clone()));
+        }
+        Ok(())
+    }
 
-                self.push(&list[1])?;
-            }
-            Value::Symbol(symbols::QUASIQUOTE) => {
-                if list.len() != 2 {
-                    return Err(error_msg("'quasiquote' require only 1 value"));
-                }
+    pub fn eval_recur_jump(&mut self, slots: &[LocalIndex]) {
+        // All the new values are on the stack, the last one at the top
+        for slot in slots.iter().rev() {
+            self.emit(Op::store(*slot));
+        }
+        // Patched when the loop is closed
+        self.emit(Op::Loop(0));
+    }
 
-                self.quoting = true;
-                self.forms.push(Form::Quoting);
-                self.forms.push(Form::Value(list[1].clone()));
-            }
-            _ => {
-                self.forms.push(Form::Apply);
-                self.forms.push(Form::List(list, 0));
-            }
+    fn eval_eq(&mut self, list: &ZapList) -> Result<()> {
+        if list.len() != 3 {
+            return Err(error_msg("A = form must have 2 parameters"));
+        }
+
+        let list = fold_operands(list.clone());
+        if is_const(&list[1]) && is_const(&list[2]) {
+            // Compile time compare on constants
+            self.push(&Value::Bool(list[1] == list[2]))?;
+        } else if is_const(&list[1]) {
+            let idx = self.get_const_idx(&list[1].clone())?;
+            self.forms.push(Form::EqualConst(idx));
+            self.forms.push(Form::Value(list[2].clone()));
+        } else if is_const(&list[2]) {
+            let idx = self.get_const_idx(&list[2].clone())?;
+            self.forms.push(Form::EqualConst(idx));
+            self.forms.push(Form::Value(list[1].clone()));
+        } else {
+            self.forms.push(Form::Equal);
+            self.forms.push(Form::Value(list[1].clone()));
+            self.forms.push(Form::Value(list[2].clone()));
         }
         Ok(())
     }
 
-    pub fn eval_next_in_list(&mut self, list: ZapList, idx: u8) {
+    pub fn eval_next_in_list(&mut self, list: ZapList, idx: u16) {
         let item = list[idx as usize].clone();
         self.forms.push(Form::List(list, idx + 1));
         self.forms.push(Form::Value(item));
     }
 
     pub fn eval_next_in_do(&mut self, list: ZapList, idx: usize) {
-        let item = list[idx as usize].clone();
+        let item = list[idx].clone();
         if (list.len() - 1) > idx {
             self.forms.push(Form::Do(list, idx + 1));
         }
@@ -366,14 +2020,14 @@ impl Compiler {
     }
 
     pub fn eval_symbol(&mut self, s: Symbol) -> Result<()> {
-        if let Some(offset) = self.scopes.get_local(s) {
-            self.emit(Op::Load(offset.try_into().unwrap()));
-        } else if let Some((level, position)) = self.scopes.get_outer(s) {
-            let dest = self.scopes.push_local(s)?;
-            self.scopes.push_outer(level, position, dest);
-            self.emit(Op::Load(dest));
+        if let Some(slot) = self.scopes.get_local(s) {
+            self.scopes.mark_loaded(slot);
+            self.emit(Op::load(slot));
+        } else if let Some(slot) = self.scopes.capture(s)? {
+            self.emit(Op::load(slot));
         } else {
-            self.emit(Op::LookUp(s));
+            let s = self.resolve(s);
+            self.emit(Op::LookUp(s, 0));
         }
         Ok(())
     }
@@ -383,7 +2037,8 @@ impl Compiler {
     }
 
     pub fn apply(&mut self) {
-        if self.is_last_exp() {
+        // At the top there's no frame to replace
+        if self.is_last_exp() && self.scopes.is_fn() {
             self.emit(Op::Tailcall(self.argc));
         } else {
             self.emit(Op::Call(self.argc));
@@ -392,63 +2047,52 @@ impl Compiler {
 
     pub fn eval_then_branch(&mut self, args: ZapList) {
         let branch = args[2].clone();
-        self.forms
-            .push(Form::IfThen(args, std::mem::take(&mut self.chunk.ops)));
+        let cond = self.replace_code(Code::default());
+        self.forms.push(Form::IfThen(args, cond));
         self.forms.push(Form::Value(branch));
     }
 
-    pub fn eval_else_branch(&mut self, args: &ZapList, chunk: Vec<Op>) {
+    pub fn eval_else_branch(&mut self, args: &ZapList, cond: Code) {
         let branch = args[3].clone();
-        self.forms
-            .push(Form::IfElse(chunk, std::mem::take(&mut self.chunk.ops)));
+        let then_branch = self.replace_code(Code::default());
+        self.forms.push(Form::IfElse(cond, then_branch));
         self.forms.push(Form::Value(branch));
     }
 
-    pub fn combine_branches(&mut self, chunk: Vec<Op>, then_branch: Vec<Op>) -> Result<()> {
-        let else_branch = std::mem::replace(&mut self.chunk.ops, chunk);
+    pub fn combine_branches(&mut self, cond: Code, then_branch: Code) -> Result<()> {
+        let else_branch = self.replace_code(cond);
 
-        let then_jump = (then_branch.len() + 1)
-            .try_into()
-            .map_err(|_| error_msg("Then branch jump is too big."))?;
-        self.emit(Op::CondJmp(then_jump));
-        self.chunk.ops.extend(then_branch);
+        self.emit(Op::cond_jmp(then_branch.ops.len() + 1)?);
+        self.append(then_branch);
 
-        let else_jump = else_branch
-            .len()
-            .try_into()
-            .map_err(|_| error_msg("Else branch jump is too big."))?;
         if self.is_last_exp() {
             self.emit(Op::Return);
         } else {
-            self.emit(Op::Jmp(else_jump));
+            self.emit(Op::jmp(else_branch.ops.len())?);
         }
-        self.chunk.ops.extend(else_branch);
+        self.append(else_branch);
 
         Ok(())
     }
 
-    pub fn eval_next_in_add(&mut self, list: &ZapList, idx: usize) -> Result<()> {
+    pub fn eval_next_in_arith(&mut self, arith: Arith, list: &ZapList, idx: usize) -> Result<()> {
         if idx == 1 {
-            self.forms.push(Form::AddMany(list.clone(), idx + 1));
+            self.forms.push(Form::Arith(arith, list.clone(), idx + 1));
             self.forms.push(Form::Value(list[idx].clone()));
         } else if list.len() > idx {
-            self.forms.push(Form::AddMany(list.clone(), idx + 1));
+            self.forms.push(Form::Arith(arith, list.clone(), idx + 1));
             if is_const(&list[idx]) {
                 // It's a constant
                 let const_idx = self.get_const_idx(&list[idx])?;
-                self.emit(Op::AddConst(const_idx));
+                self.emit(arith.const_op(const_idx));
             } else {
-                self.forms.push(Form::Add);
+                self.forms.push(Form::Emit(arith.op()));
                 self.forms.push(Form::Value(list[idx].clone()));
             }
         }
         Ok(())
     }
 
-    pub fn eval_add(&mut self) {
-        self.emit(Op::Add);
-    }
-
     pub fn eval_equal(&mut self) {
         self.emit(Op::Eq);
     }
@@ -457,7 +2101,102 @@ impl Compiler {
         self.emit(Op::EqConst(idx));
     }
 
-    pub fn wrap_fn(&mut self, mut chunk: Chunk) -> Result<()> {
+    pub fn eval_while_body(&mut self, list: &ZapList, loop_start: usize) {
+        // The jump out of the loop is patched once the body is compiled
+        self.emit(Op::CondJmp(0));
+        let exit_jump = self.chunk.ops.len() - 1;
+        self.forms.push(Form::WhileEnd(loop_start, exit_jump));
+        self.forms.push(Form::Value(implicit_do(&list[2..])));
+    }
+
+    pub fn close_while(&mut self, loop_start: usize, exit_jump: usize) -> Result<()> {
+        // The value of the body is discarded on every iteration
+        self.emit(Op::Pop);
+        self.emit_loop(loop_start, exit_jump)?;
+
+        // A while always evaluates to nil
+        self.push(&Value::Nil)
+    }
+
+    fn emit_loop(&mut self, loop_start: usize, exit_jump: usize) -> Result<()> {
+        self.emit(Op::loop_back(self.chunk.ops.len() + 1 - loop_start)?);
+        self.chunk.ops[exit_jump] = Op::cond_jmp(self.chunk.ops.len() - 1 - exit_jump)?;
+        Ok(())
+    }
+
+    fn eval_doseq(&mut self, list: ZapList) -> Result<()> {
+        if list.len() < 2 {
+            return Err(error_msg("A doseq-indexed form must have bindings"));
+        }
+        match &list[1] {
+            Value::List(bindings) | Value::Vector(bindings) if bindings.len() == 3 => {
+                let coll = bindings[2].clone();
+                self.forms.push(Form::DoseqBegin(list));
+                self.forms.push(Form::Value(coll));
+                Ok(())
+            }
+            _ => Err(error_msg(
+                "doseq-indexed bindings must be a list of (index item coll)",
+            )),
+        }
+    }
+
+    pub fn eval_doseq_begin(&mut self, list: &ZapList) -> Result<()> {
+        let (Value::List(bindings) | Value::Vector(bindings)) = &list[1] else {
+            unreachable!()
+        };
+        let (Value::Symbol(index), Value::Symbol(item)) = (&bindings[0], &bindings[1]) else {
+            return Err(error_msg("doseq-indexed can only bind symbols"));
+        };
+
+        // The collection is evaluated once and kept in a hidden local
+        let hidden = self.gensym();
+        let coll = self.scopes.push_local(hidden)?;
+        self.emit(Op::store(coll));
+        let index = self.scopes.push_local(*index)?;
+        self.push(&Value::Int(0))?;
+        self.emit(Op::store(index));
+
+        let loop_start = self.chunk.ops.len();
+        self.push(&native("in-bounds?", seq_in_bounds))?;
+        self.emit(Op::load(coll));
+        self.emit(Op::load(index));
+        self.emit(Op::Call(2));
+        self.emit(Op::CondJmp(0));
+        let exit_jump = self.chunk.ops.len() - 1;
+
+        self.push(&native("nth", seq_nth))?;
+        self.emit(Op::load(coll));
+        self.emit(Op::load(index));
+        self.emit(Op::Call(2));
+        let item = self.scopes.push_local(*item)?;
+        self.emit(Op::store(item));
+
+        self.forms
+            .push(Form::DoseqEnd(index, loop_start, exit_jump));
+        self.forms.push(Form::Value(implicit_do(&list[2..])));
+        Ok(())
+    }
+
+    pub fn close_doseq(
+        &mut self,
+        index: LocalIndex,
+        loop_start: usize,
+        exit_jump: usize,
+    ) -> Result<()> {
+        self.emit(Op::Pop);
+        self.emit(Op::load(index));
+        let one = self.get_const_idx(&Value::Int(1))?;
+        self.emit(Op::AddConst(one));
+        self.emit(Op::store(index));
+        self.emit_loop(loop_start, exit_jump)?;
+
+        // Forget the collection, the index and the item
+        self.scopes.pop_locals(3);
+        self.push(&Value::Nil)
+    }
+
+    pub fn wrap_fn(&mut self, mut chunk: Chunk, consts: ConstIndex) -> Result<()> {
         #[cfg(debug_assertions)]
         dbg!(&self.chunk);
 
@@ -465,9 +2204,14 @@ impl Compiler {
 
         let (size, outers) = self.scopes.pop();
         self.chunk.scope_size = size;
+        fuse(&mut self.chunk.ops);
+        self.chunk.cache_lookups();
 
         // Swap the chunks
         std::mem::swap(&mut self.chunk, &mut chunk);
+        self.consts = consts;
+        debug_assert_eq!(chunk.verify(), Ok(()));
+        let chunk = self.chunks.intern(chunk);
 
         if outers.is_empty() {
             self.push(&ZapFn::new(size, chunk))?;
@@ -480,9 +2224,66 @@ impl Compiler {
     }
 }
 
-pub fn compile(ast: Value) -> Result<Arc<Chunk>> {
-    let mut compiler = Compiler::init(ast);
+// The macros are found in env, and the globals it defines are inlined. compile_with takes the
+// extensions to compile with, compile_spanned the spans the ast was read with.
+pub fn compile<E: Env>(ast: Value, env: &mut E) -> Result<Arc<Chunk>> {
+    compile_with(ast, &Extensions::default(), env)
+}
+
+pub fn compile_with<E: Env>(
+    ast: Value,
+    extensions: &Extensions,
+    env: &mut E,
+) -> Result<Arc<Chunk>> {
+    compile_ast(ast, extensions, env, &Spans::default(), &mut Vec::new())
+}
+
+// Like compile_with, adding to warnings what looks wrong in the code without stopping it from
+// compiling: the locals never used, the params shadowing an earlier one, the defs in a fn.
+// The locals named with a leading _ can go unused.
+pub fn compile_with_warnings<E: Env>(
+    ast: Value,
+    extensions: &Extensions,
+    env: &mut E,
+    warnings: &mut Vec<std::string::String>,
+) -> Result<Arc<Chunk>> {
+    compile_ast(ast, extensions, env, &Spans::default(), warnings)
+}
+
+// Like compile_with_warnings, for an ast read with its spans. The ops keep the span of the list
+// they were compiled from, and an error says the line and col of the list it was raised in.
+pub fn compile_spanned<E: Env>(
+    ast: Value,
+    spans: &Spans,
+    extensions: &Extensions,
+    env: &mut E,
+    warnings: &mut Vec<std::string::String>,
+) -> Result<Arc<Chunk>> {
+    compile_ast(ast, extensions, env, spans, warnings)
+}
+
+fn compile_ast<'a>(
+    ast: Value,
+    extensions: &'a Extensions,
+    env: &'a mut dyn Env,
+    spans: &'a Spans,
+    warnings: &mut Vec<std::string::String>,
+) -> Result<Arc<Chunk>> {
+    let mut compiler = Compiler::init(ast, extensions, env, spans);
+    if let Err(err) = compile_forms(&mut compiler) {
+        let err = err.into_compile();
+        return Err(match compiler.span {
+            Some(span) => err.at(span),
+            None => err,
+        });
+    }
+
+    compiler.warn_unused();
+    warnings.append(&mut compiler.warnings);
+    Ok(compiler.chunk())
+}
 
+fn compile_forms(compiler: &mut Compiler) -> Result<()> {
     while let Some(form) = compiler.get_form() {
         match form {
             Form::Value(val) => match val {
@@ -490,10 +2291,14 @@ pub fn compile(ast: Value) -> Result<Arc<Chunk>> {
                     if list.is_empty() {
                         compiler.eval_const(&Value::List(list))?;
                     } else {
-                        compiler.eval_list(list)?;
+                        compiler.eval_spanned_list(list)?;
                     }
                 }
                 Value::Symbol(s) => compiler.eval_symbol(s)?,
+                // Only known once its items are evaluated
+                Value::Vector(vector) if !vector.iter().all(is_const) => {
+                    compiler.eval_vector(vector);
+                }
                 atom => compiler.eval_const(&atom)?,
             },
             Form::List(list, idx) => {
@@ -503,9 +2308,12 @@ pub fn compile(ast: Value) -> Result<Arc<Chunk>> {
                     compiler.set_argc(idx);
                 }
             }
+            Form::Const(val) => compiler.push(&val)?,
+            Form::Emit(op) => compiler.emit(op),
             Form::Apply => {
                 compiler.apply();
             }
+            Form::ApplySelf => compiler.emit(Op::TailcallSelf(compiler.argc)),
             Form::IfCond(args) => {
                 // Then branch
                 compiler.eval_then_branch(args);
@@ -518,11 +2326,8 @@ pub fn compile(ast: Value) -> Result<Arc<Chunk>> {
                 // Combine the branches in the chunk
                 compiler.combine_branches(chunk, then_branch)?;
             }
-            Form::AddMany(list, idx) => {
-                compiler.eval_next_in_add(&list, idx)?;
-            }
-            Form::Add => {
-                compiler.eval_add();
+            Form::Arith(arith, list, idx) => {
+                compiler.eval_next_in_arith(arith, &list, idx)?;
             }
             Form::EqualConst(idx) => {
                 compiler.eval_equal_const(idx);
@@ -536,22 +2341,425 @@ pub fn compile(ast: Value) -> Result<Arc<Chunk>> {
             Form::Define => {
                 compiler.eval_define();
             }
-            Form::Return(chunk) => compiler.wrap_fn(chunk)?,
+            Form::Return(chunk, consts) => compiler.wrap_fn(chunk, consts)?,
             Form::Let(locals_count) => {
                 compiler.scopes.pop_locals(locals_count);
             }
             Form::Binding(symbol) => {
                 compiler.register_binding(symbol)?;
             }
-            Form::Quoting => {
-                // TODO
+            Form::WhileBody(list, loop_start) => {
+                compiler.eval_while_body(&list, loop_start);
+            }
+            Form::WhileEnd(loop_start, exit_jump) => {
+                compiler.close_while(loop_start, exit_jump)?;
             }
+            Form::DoseqBegin(list) => {
+                compiler.eval_doseq_begin(&list)?;
+            }
+            Form::DoseqEnd(index, loop_start, exit_jump) => {
+                compiler.close_doseq(index, loop_start, exit_jump)?;
+            }
+            Form::LoopBegin(list, locals_count) => {
+                compiler.eval_loop_begin(&list, locals_count);
+            }
+            Form::LoopEnd(loop_start, slots) => {
+                compiler.close_loop(loop_start, &slots)?;
+            }
+            Form::Recur(slots) => {
+                compiler.eval_recur_jump(&slots);
+            }
+            Form::CaseSwitch(list) => compiler.eval_case_switch(list)?,
+            Form::CaseBranch(list, switch, branch, exits) => {
+                compiler.eval_case_branch(list, switch, branch, exits)?;
+            }
+            Form::CaseEnd(exits) => compiler.close_case(&exits)?,
+            Form::TryEnd(list, try_start) => compiler.eval_catch(&list, try_start)?,
+            Form::CatchEnd(skip) => compiler.close_catch(skip)?,
+            Form::InlineBody(list, func, guard) => compiler.eval_inline_body(list, &func, guard)?,
+            Form::InlineEnd(exit) => compiler.close_inline(exit)?,
+            Form::Span(span) => compiler.span = span,
         }
     }
+    Ok(())
+}
 
-    Ok(compiler.chunk())
+// The step of a threading form called with the threaded value, first or last.
+fn thread_step(threaded: Value, step: &Value, thread_last: bool) -> Value {
+    let call = match step {
+        Value::List(call) if !call.is_empty() => {
+            let mut call = call.to_vec();
+            if thread_last {
+                call.push(threaded);
+            } else {
+                call.insert(1, threaded);
+            }
+            call
+        }
+        f => vec![f.clone(), threaded],
+    };
+    Value::List(Value::new_list(call))
+}
+
+// The chunk starts by loading its params in order, and never gets back to them
+fn takes_args_in_order(chunk: &Chunk) -> bool {
+    let arity = usize::from(chunk.arity);
+    let (params, body) = chunk.ops.split_at(arity.min(chunk.ops.len()));
+    params
+        .iter()
+        .enumerate()
+        .all(|(i, op)| matches!(op.unfused(), Op::Load(slot) if usize::from(slot) == i))
+        && !body.iter().any(|op| {
+            matches!(
+                op.unfused(),
+                Op::Load(_)
+                    | Op::Store(_)
+                    | Op::LoadW(_)
+                    | Op::StoreW(_)
+                    | Op::Loop(_)
+                    | Op::LoopW(_)
+            )
+        })
+}
+
+// Turn the sequences of ops that run the most into superinstructions. Each one replaces the
+// first op of its sequence, the others stay where they are, so no jump has to be moved. The
+// register-ops build also fuses the ops on two locals, to compare with the stack shuffling.
+fn fuse(ops: &mut [Op]) {
+    let mut i = 0;
+    while i + 1 < ops.len() {
+        i += match (ops[i], ops[i + 1], ops.get(i + 2)) {
+            (Op::Load(a), Op::Load(b), Some(Op::Add)) if cfg!(feature = "register-ops") => {
+                ops[i] = Op::AddSlots(a, b);
+                3
+            }
+            (Op::Load(a), Op::Load(b), Some(Op::Eq)) if cfg!(feature = "register-ops") => {
+                ops[i] = Op::EqSlots(a, b);
+                3
+            }
+            (Op::Load(slot), Op::EqConst(c), Some(Op::CondJmp(n))) => {
+                ops[i] = Op::LoadEqConstJmp(slot, c, *n);
+                3
+            }
+            (Op::Load(slot), Op::AddConst(c), _) => {
+                ops[i] = Op::LoadAddConst(slot, c);
+                2
+            }
+            _ => 1,
+        };
+    }
+}
+
+// The symbol of 'name, or name
+fn quoted_symbol(val: &Value) -> Option<Symbol> {
+    match val {
+        Value::Symbol(s) => Some(*s),
+        Value::List(quoted) => match &quoted[..] {
+            [Value::Symbol(symbols::QUOTE), Value::Symbol(s)] => Some(*s),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Whether a symbol name is namespace/name
+fn is_qualified(name: &str) -> bool {
+    name.split_once('/')
+        .is_some_and(|(ns, name)| !ns.is_empty() && !name.is_empty())
+}
+
+// Whether the symbol appears anywhere in the expression
+fn mentions(exp: &Value, s: Symbol) -> bool {
+    match exp {
+        Value::Symbol(symbol) => *symbol == s,
+        Value::List(list) | Value::Vector(list) => list.iter().any(|exp| mentions(exp, s)),
+        _ => false,
+    }
+}
+
+// Wraps a sequence of expressions in a do form, so they can be compiled as a single one.
+fn implicit_do(body: &[Value]) -> Value {
+    match body.len() {
+        0 => Value::Nil,
+        1 => body[0].clone(),
+        _ => {
+            let mut list = Vec::with_capacity(body.len() + 1);
+            list.push(Value::Symbol(symbols::DO));
+            list.extend_from_slice(body);
+            Value::List(Value::new_list(list))
+        }
+    }
+}
+
+// The name, params and body of (form name "doc" (params) body...), the docstring being optional.
+fn definition_parts<'a>(
+    list: &'a ZapList,
+    form: &str,
+) -> Result<(&'a Value, &'a Value, &'a [Value])> {
+    let malformed = || {
+        error_msg(&format!(
+            "A {form} form must have a name and a list of params"
+        ))
+    };
+    let (Some(name @ Value::Symbol(_)), Some(rest)) = (list.get(1), list.get(2..)) else {
+        return Err(malformed());
+    };
+    let rest = match rest {
+        [Value::Str(_), rest @ ..] if !rest.is_empty() => rest,
+        rest => rest,
+    };
+    let [params @ (Value::List(_) | Value::Vector(_)), body @ ..] = rest else {
+        return Err(malformed());
+    };
+    Ok((name, params, body))
+}
+
+fn seq_in_bounds(args: &[Value]) -> Result<Value> {
+    match args {
+        [Value::List(list) | Value::Vector(list), Value::Int(idx)] => Ok(Value::Bool(
+            usize::try_from(*idx).is_ok_and(|idx| idx < list.len()),
+        )),
+        _ => Err(error_msg(
+            "doseq-indexed can only iterate over lists and vectors",
+        )),
+    }
+}
+
+fn seq_nth(args: &[Value]) -> Result<Value> {
+    match args {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        [Value::List(list) | Value::Vector(list), Value::Int(idx)] => {
+            Ok(list[*idx as usize].clone())
+        }
+        _ => Err(error_msg(
+            "doseq-indexed can only iterate over lists and vectors",
+        )),
+    }
+}
+
+// The value of an arithmetic, not or = form of constants, nested ones included, computed at
+// compile time. Only the operands of those forms are folded, a macro still gets its args as
+// written.
+fn fold(val: &Value) -> Option<Value> {
+    let Value::List(list) = val else {
+        return None;
+    };
+    let operand = |val: &Value| {
+        if is_const(val) {
+            Some(val.clone())
+        } else {
+            fold(val)
+        }
+    };
+    match list.first()? {
+        Value::Symbol(sym) if Arith::of(*sym).is_some() => {
+            let arith = Arith::of(*sym)?;
+            let list = arith.operands(list).ok()?;
+            let operands = list[1..].iter().map(operand).collect::<Option<Vec<_>>>()?;
+            arith.fold(&operands)
+        }
+        Value::Symbol(symbols::NOT) if list.len() == 2 => {
+            Some(Value::Bool(!operand(&list[1])?.is_truthy()))
+        }
+        Value::Symbol(symbols::EQUAL) if list.len() == 3 => {
+            Some(Value::Bool(operand(&list[1])? == operand(&list[2])?))
+        }
+        _ => None,
+    }
+}
+
+// What a (not x) form negates
+fn negated(val: &Value) -> Option<Value> {
+    match val {
+        Value::List(list) if list.len() == 2 && list[0] == Value::Symbol(symbols::NOT) => {
+            Some(list[1].clone())
+        }
+        _ => None,
+    }
+}
+
+// The form with its constant operands folded
+fn fold_operands(list: ZapList) -> ZapList {
+    if list[1..].iter().all(|val| fold(val).is_none()) {
+        return list;
+    }
+    let mut folded = Vec::with_capacity(list.len());
+    folded.push(list[0].clone());
+    folded.extend(
+        list[1..]
+            .iter()
+            .map(|val| fold(val).unwrap_or_else(|| val.clone())),
+    );
+    Value::new_list(folded)
+}
+
+// (+ a 1 b 2) is compiled as (+ a b 3)
+fn reassociate_consts(list: ZapList) -> ZapList {
+    let (consts, mut operands): (Vec<_>, Vec<_>) = list[1..].iter().cloned().partition(is_number);
+    if consts.len() < 2 {
+        return list;
+    }
+    let Some(sum) = Arith::Add.fold(&consts) else {
+        return list;
+    };
+    operands.insert(0, list[0].clone());
+    operands.push(sum);
+    Value::new_list(operands)
+}
+
+fn is_number(val: &Value) -> bool {
+    matches!(val, Value::Int(_) | Value::Number(_) | Value::Decimal(_))
+}
+
+// Turns a quasiquoted template into the expression building it at runtime.
+fn expand_quasiquote(template: &Value) -> Result<Value> {
+    let (Value::List(list) | Value::Vector(list)) = template else {
+        return Ok(quote(template));
+    };
+
+    match list.first().filter(|_| matches!(template, Value::List(_))) {
+        Some(Value::Symbol(symbols::UNQUOTE)) => {
+            return if list.len() == 2 {
+                Ok(list[1].clone())
+            } else {
+                Err(error_msg("'unquote' require only 1 value"))
+            };
+        }
+        Some(Value::Symbol(symbols::SPLICE_UNQUOTE)) => {
+            return Err(error_msg(
+                "'splice-unquote' can only be used inside a list or a vector",
+            ));
+        }
+        _ => {}
+    }
+
+    if is_const_template(template) {
+        return Ok(quote(template));
+    }
+
+    // (a ~b ~@c d) becomes (concat (list 'a b) c (list 'd)), and (a ~b) becomes (list 'a b)
+    let mut parts = vec![native("concat", concat_lists)];
+    let mut items = vec![native("list", make_list)];
+    let mut spliced = false;
+    for item in list.iter() {
+        match item {
+            Value::List(splice)
+                if matches!(splice.first(), Some(Value::Symbol(symbols::SPLICE_UNQUOTE))) =>
+            {
+                if splice.len() != 2 {
+                    return Err(error_msg("'splice-unquote' require only 1 value"));
+                }
+                if items.len() > 1 {
+                    let group = std::mem::replace(&mut items, vec![native("list", make_list)]);
+                    parts.push(Value::List(Value::new_list(group)));
+                }
+                parts.push(splice[1].clone());
+                spliced = true;
+            }
+            _ => items.push(expand_quasiquote(item)?),
+        }
+    }
+    // Without a splice, there's nothing to concat. A vector evaluates its items by itself.
+    if !spliced {
+        return Ok(match template {
+            Value::Vector(_) => Value::Vector(Value::new_list(items.split_off(1))),
+            _ => Value::List(Value::new_list(items)),
+        });
+    }
+    if items.len() > 1 {
+        parts.push(Value::List(Value::new_list(items)));
+    }
+
+    let concat = Value::List(Value::new_list(parts));
+    Ok(match template {
+        Value::Vector(_) => Value::List(Value::new_list(vec![native("vec", make_vector), concat])),
+        _ => concat,
+    })
+}
+
+fn quote(val: &Value) -> Value {
+    Value::List(Value::new_list(vec![
+        Value::Symbol(symbols::QUOTE),
+        val.clone(),
+    ]))
+}
+
+fn native(name: &str, func: fn(&[Value]) -> Result<Value>) -> Value {
+    Value::FuncNative(ZapFnNative::new(String::from(name), func))
+}
+
+fn make_macro(args: &[Value]) -> Result<Value> {
+    match args {
+        [Value::Func(f)] => Ok(Value::Macro(f.clone())),
+        _ => Err(error_msg("A macro must be made from a fn")),
+    }
+}
+
+fn is_make_list(f: &ZapFnNative) -> bool {
+    let make: fn(&[Value]) -> Result<Value> = make_list;
+    matches!(f.func, NativeFunc::Plain(func) if std::ptr::fn_addr_eq(func, make))
+}
+
+#[allow(clippy::unnecessary_wraps)] // The signature of a native
+fn make_list(args: &[Value]) -> Result<Value> {
+    Ok(Value::List(Value::new_list(args.to_vec())))
+}
+
+fn make_vector(args: &[Value]) -> Result<Value> {
+    match args {
+        [Value::List(list)] => Ok(Value::Vector(list.clone())),
+        _ => Err(error_msg("A vector must be made from a list")),
+    }
+}
+
+fn concat_lists(args: &[Value]) -> Result<Value> {
+    let mut len = 0;
+    for arg in args {
+        match arg {
+            Value::List(list) => len += list.len(),
+            _ => return Err(error_msg("'splice-unquote' can only splice lists")),
+        }
+    }
+
+    let mut res = Vec::with_capacity(len);
+    for arg in args {
+        if let Value::List(list) = arg {
+            res.extend_from_slice(list);
+        }
+    }
+    Ok(Value::List(Value::new_list(res)))
+}
+
+// A quasiquoted template without any unquote can be used as is.
+fn is_const_template(val: &Value) -> bool {
+    match val {
+        Value::List(list) => match list.first() {
+            Some(Value::Symbol(symbols::UNQUOTE | symbols::SPLICE_UNQUOTE)) => false,
+            _ => list.iter().all(is_const_template),
+        },
+        Value::Vector(vector) => vector.iter().all(is_const_template),
+        _ => true,
+    }
+}
+
+// A case key known at compile time, a literal or a quoted atom.
+fn case_key(val: &Value) -> Option<Value> {
+    match val {
+        Value::List(list) if list.len() == 2 && list[0] == Value::Symbol(symbols::QUOTE) => {
+            match &list[1] {
+                Value::List(_) => None,
+                atom => Some(atom.clone()),
+            }
+        }
+        val if is_const(val) => Some(val.clone()),
+        _ => None,
+    }
 }
 
 fn is_const(val: &Value) -> bool {
-    !matches!(val, Value::List(_) | Value::Symbol(_))
+    match val {
+        Value::List(_) | Value::Symbol(_) => false,
+        Value::Vector(vector) => vector.iter().all(is_const),
+        _ => true,
+    }
 }
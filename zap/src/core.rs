@@ -0,0 +1,731 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+#[cfg(not(feature = "gc"))]
+use std::sync::RwLock;
+
+use crate::env::Env;
+use crate::output;
+use crate::reader::is_number_like;
+use crate::time::{self, DateTime, Duration};
+use crate::vm::{Coroutine, Ctx};
+use crate::zap::{error_msg, Arity, Channel, Pending, Result, String, Symbol, Value};
+
+// The core functions, the base vocabulary every env starts with.
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// The optional parts of zap, and whether this build has them
+const FEATURES: [(&str, bool); 4] = [
+    ("http", cfg!(feature = "http")),
+    ("io", cfg!(feature = "io")),
+    ("gc", cfg!(feature = "gc")),
+    ("wasm", cfg!(feature = "wasm")),
+];
+
+// The features compiled in
+pub fn features() -> impl Iterator<Item = &'static str> {
+    FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+}
+
+fn is_int(args: &[Value]) -> Result<Value> {
+    if args.is_empty() {
+        return Err(error_msg("'int?' requires at least 1 argument."));
+    }
+    for v in args {
+        match v {
+            Value::Int(_) => continue,
+            _ => return Ok(Value::Bool(false)),
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+fn is_float(args: &[Value]) -> Result<Value> {
+    if args.is_empty() {
+        return Err(error_msg("'float?' requires at least 1 argument."));
+    }
+    for v in args {
+        match v {
+            Value::Number(_) => continue,
+            _ => return Ok(Value::Bool(false)),
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+fn is_false(args: &[Value]) -> Result<Value> {
+    if args.is_empty() {
+        return Err(error_msg("'false?' requires at least 1 argument."));
+    }
+    for v in args {
+        match v {
+            Value::Bool(false) => continue,
+            _ => return Ok(Value::Bool(false)),
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+fn concat(args: &[Value]) -> Result<Value> {
+    match args.first() {
+        None => Ok(Value::List(Value::new_list(Vec::new()))),
+        Some(Value::Str(_)) => {
+            let mut len = 0;
+            for v in args {
+                match v {
+                    Value::Str(s) => len += s.len(),
+                    _ => return Err(error_msg("'concat' cannot mix strings with other values.")),
+                }
+            }
+            let mut res = std::string::String::with_capacity(len);
+            for v in args {
+                if let Value::Str(s) = v {
+                    res.push_str(s);
+                }
+            }
+            Ok(Value::Str(String::from(res)))
+        }
+        Some(Value::List(_)) => {
+            let mut len = 0;
+            for v in args {
+                match v {
+                    Value::List(l) => len += l.len(),
+                    _ => return Err(error_msg("'concat' cannot mix lists with other values.")),
+                }
+            }
+            let mut res = Vec::with_capacity(len);
+            for v in args {
+                if let Value::List(l) = v {
+                    res.extend_from_slice(l);
+                }
+            }
+            Ok(Value::List(Value::new_list(res)))
+        }
+        Some(_) => Err(error_msg("'concat' only works on strings and lists.")),
+    }
+}
+
+fn print_args(args: &[Value], end: &str) -> Result<Value> {
+    use std::fmt::Write;
+
+    let mut out = std::string::String::new();
+    for (i, v) in args.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        match v {
+            Value::Str(s) => out.push_str(s),
+            v => write!(out, "{}", v).unwrap(),
+        }
+    }
+    out.push_str(end);
+    output::write_str(&out)?;
+    Ok(Value::Nil)
+}
+
+fn print(args: &[Value]) -> Result<Value> {
+    print_args(args, "")
+}
+
+fn println(args: &[Value]) -> Result<Value> {
+    print_args(args, "\n")
+}
+
+// The conversions. The plain ones raise an error when the value can't be converted, the parse-
+// ones return nil.
+
+fn to_int(val: &Value) -> Option<Value> {
+    let int = match val {
+        Value::Int(n) => *n,
+        // Saturated to the integers' range
+        Value::Number(n) if n.is_finite() => *n as i64,
+        Value::Decimal(d) => d.to_f64() as i64,
+        Value::Str(s) => s.trim().parse::<i64>().ok()?,
+        _ => return None,
+    };
+    Some(Value::Int(int))
+}
+
+fn to_float(val: &Value) -> Option<Value> {
+    match val {
+        Value::Str(s) => s.trim().parse::<f64>().ok(),
+        val => to_float_number(val),
+    }
+    .map(Value::Number)
+}
+
+fn convert(name: &str, args: &[Value], conv: fn(&Value) -> Option<Value>) -> Result<Option<Value>> {
+    match args {
+        [val] => Ok(conv(val)),
+        _ => Err(error_msg(&format!("'{}' requires 1 argument.", name))),
+    }
+}
+
+fn int(args: &[Value]) -> Result<Value> {
+    convert("int", args, to_int)?
+        .ok_or_else(|| error_msg(&format!("'int' cannot convert {} to an integer.", args[0])))
+}
+
+fn float(args: &[Value]) -> Result<Value> {
+    convert("float", args, to_float)?
+        .ok_or_else(|| error_msg(&format!("'float' cannot convert {} to a number.", args[0])))
+}
+
+fn parse_int(args: &[Value]) -> Result<Value> {
+    Ok(convert("parse-int", args, to_int)?.unwrap_or(Value::Nil))
+}
+
+fn parse_float(args: &[Value]) -> Result<Value> {
+    Ok(convert("parse-float", args, to_float)?.unwrap_or(Value::Nil))
+}
+
+// The name of a symbol or a keyword, as the reader would read it back
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(':')
+        && !is_number_like(name)
+        && !matches!(name, "nil" | "true" | "false")
+        && !name.contains(|ch: char| ch.is_whitespace() || "()[]\";'`~@,".contains(ch))
+}
+
+// The symbol of a symbol, a keyword or a string that is a name
+fn to_name(ctx: &mut Ctx, val: &Value) -> Option<Symbol> {
+    match val {
+        Value::Symbol(s) | Value::Keyword(s) => Some(*s),
+        Value::Str(s) if is_name(s) => match ctx.env().reg_symbol(s.clone()) {
+            Value::Symbol(s) => Some(s),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn keyword(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    to_name(ctx, &args[0]).map(Value::Keyword).ok_or_else(|| {
+        error_msg(&format!(
+            "'keyword' cannot convert {} to a keyword.",
+            args[0]
+        ))
+    })
+}
+
+fn symbol(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    to_name(ctx, &args[0])
+        .map(Value::Symbol)
+        .ok_or_else(|| error_msg(&format!("'symbol' cannot convert {} to a symbol.", args[0])))
+}
+
+fn parse_keyword(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    Ok(to_name(ctx, &args[0]).map_or(Value::Nil, Value::Keyword))
+}
+
+fn parse_symbol(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    Ok(to_name(ctx, &args[0]).map_or(Value::Nil, Value::Symbol))
+}
+
+// The args printed like print does, but without spaces and nil being empty. The symbols and
+// keywords are written by their name.
+fn str(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    use std::fmt::Write;
+
+    let mut out = std::string::String::new();
+    for v in args {
+        match v {
+            Value::Nil => {}
+            Value::Str(s) => out.push_str(s),
+            Value::Decimal(d) => write!(out, "{}", d).unwrap(),
+            v => out.push_str(&v.pr_str(ctx.env())),
+        }
+    }
+    Ok(Value::Str(String::from(out)))
+}
+
+// (approx= a b eps) is true when the numbers are at most eps apart, since floats are rarely
+// exactly equal after some arithmetic
+fn approx_eq(args: &[Value]) -> Result<Value> {
+    let [a, b, eps] = args else {
+        return Err(error_msg("'approx=' requires 3 arguments."));
+    };
+    match (to_float_number(a), to_float_number(b), to_float_number(eps)) {
+        (Some(a), Some(b), Some(eps)) => Ok(Value::Bool((a - b).abs() <= eps)),
+        _ => Err(error_msg("'approx=' only compares numbers.")),
+    }
+}
+
+fn to_float_number(val: &Value) -> Option<f64> {
+    match val {
+        Value::Int(n) => Some(*n as f64),
+        Value::Number(n) => Some(*n),
+        Value::Decimal(d) => Some(d.to_f64()),
+        _ => None,
+    }
+}
+
+// The values of the arithmetic forms and not, for when they're passed rather than called, as to
+// apply. They leave out the same operands: (+) is 0, (*) is 1, (- x) is (- 0 x) and (/ x) is
+// (/ 1 x).
+fn arith(args: &[Value], op: fn(&Value, &Value) -> Result<Value>) -> Result<Value> {
+    let (first, rest) = args.split_first().expect("args checked by the caller");
+    rest.iter()
+        .try_fold(first.clone(), |acc, val| op(&acc, val))
+}
+
+fn add(args: &[Value]) -> Result<Value> {
+    match args {
+        [] => Ok(Value::Int(0)),
+        // In the order of the add op, which takes the top of the stack first
+        _ => arith(args, |acc, val| val + acc),
+    }
+}
+
+fn sub(args: &[Value]) -> Result<Value> {
+    match args {
+        [] => Err(error_msg("'-' requires at least 1 argument.")),
+        [x] => &Value::Int(0) - x,
+        _ => arith(args, |a, b| a - b),
+    }
+}
+
+fn mul(args: &[Value]) -> Result<Value> {
+    match args {
+        [] => Ok(Value::Int(1)),
+        _ => arith(args, |a, b| a * b),
+    }
+}
+
+fn div(args: &[Value]) -> Result<Value> {
+    match args {
+        [] => Err(error_msg("'/' requires at least 1 argument.")),
+        [x] => &Value::Int(1) / x,
+        _ => arith(args, |a, b| a / b),
+    }
+}
+
+fn rem(args: &[Value]) -> Result<Value> {
+    match args {
+        [a, b] => a % b,
+        _ => Err(error_msg("'rem' requires 2 arguments.")),
+    }
+}
+
+fn list(args: &[Value]) -> Result<Value> {
+    Ok(Value::List(Value::new_list(args.to_vec())))
+}
+
+fn not(args: &[Value]) -> Result<Value> {
+    match args {
+        [x] => Ok(Value::Bool(!x.is_truthy())),
+        _ => Err(error_msg("'not' requires 1 argument.")),
+    }
+}
+
+// The order of two numbers, durations, datetimes or strings. Numbers of different kinds are
+// compared as floats, and NaN is in no order.
+fn compare(name: &str, a: &Value, b: &Value) -> Result<Option<Ordering>> {
+    Ok(match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+        (Value::Decimal(a), Value::Decimal(b)) => Some(a.cmp(b)),
+        (Value::Duration(a), Value::Duration(b)) => Some(a.cmp(b)),
+        (Value::DateTime(a), Value::DateTime(b)) => Some(a.cmp(b)),
+        (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+        _ => match (to_float_number(a), to_float_number(b)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => {
+                return Err(error_msg(&format!(
+                    "'{}' cannot compare {} and {}.",
+                    name, a, b
+                )))
+            }
+        },
+    })
+}
+
+// (< a b c) is true when every arg is in that order with the next one
+fn ordered(name: &str, args: &[Value], test: fn(Ordering) -> bool) -> Result<Value> {
+    if args.is_empty() {
+        return Err(error_msg(&format!(
+            "'{}' requires at least 1 argument.",
+            name
+        )));
+    }
+    for pair in args.windows(2) {
+        if !compare(name, &pair[0], &pair[1])?.is_some_and(test) {
+            return Ok(Value::Bool(false));
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+fn lt(args: &[Value]) -> Result<Value> {
+    ordered("<", args, Ordering::is_lt)
+}
+
+fn le(args: &[Value]) -> Result<Value> {
+    ordered("<=", args, Ordering::is_le)
+}
+
+fn gt(args: &[Value]) -> Result<Value> {
+    ordered(">", args, Ordering::is_gt)
+}
+
+fn ge(args: &[Value]) -> Result<Value> {
+    ordered(">=", args, Ordering::is_ge)
+}
+
+// (duration 90 :m) is so many of a unit, from :d down to :ns, and (duration "1h 30m") reads one
+// the way it's printed
+fn duration(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    let d = match args {
+        [Value::Str(s)] => Duration::parse(s),
+        [amount, unit] => {
+            let unit = to_unit(ctx, "duration", unit)?;
+            match amount {
+                Value::Int(n) => unit.checked_mul(*n),
+                Value::Number(n) => unit.mul_f64(*n),
+                _ => {
+                    return Err(error_msg(&format!(
+                        "'duration' requires a number of units, not {}.",
+                        amount
+                    )))
+                }
+            }
+        }
+        _ => {
+            return Err(error_msg(
+                "'duration' requires a string, or an amount and a unit.",
+            ))
+        }
+    };
+    d.map(Value::Duration).ok_or_else(|| {
+        error_msg(&format!(
+            "'duration' cannot convert {} to a duration.",
+            Value::List(Value::new_list(args.to_vec()))
+        ))
+    })
+}
+
+// A unit keyword, like :h, or a duration
+fn to_unit(ctx: &mut Ctx, name: &str, val: &Value) -> Result<Duration> {
+    let unit = match val {
+        Value::Duration(d) if d.nanos() != 0 => Some(*d),
+        Value::Keyword(s) => Duration::unit(&ctx.env().get_symbol(*s)?),
+        _ => None,
+    };
+    unit.ok_or_else(|| {
+        error_msg(&format!(
+            "'{}' requires a unit from :d to :ns, or a duration, not {}.",
+            name,
+            val.pr_str(ctx.env())
+        ))
+    })
+}
+
+fn datetime(args: &[Value]) -> Result<Value> {
+    match args {
+        [Value::Str(s)] => DateTime::parse(s).map(Value::DateTime).ok_or_else(|| {
+            error_msg(&format!(
+                "'datetime' cannot convert \"{}\" to a datetime.",
+                s
+            ))
+        }),
+        [val] => Err(error_msg(&format!(
+            "'datetime' cannot convert {} to a datetime.",
+            val
+        ))),
+        _ => Err(error_msg("'datetime' requires 1 argument.")),
+    }
+}
+
+fn now(args: &[Value]) -> Result<Value> {
+    if !args.is_empty() {
+        return Err(error_msg("'now' takes no arguments."));
+    }
+    DateTime::now()
+        .map(Value::DateTime)
+        .ok_or_else(|| error_msg("'now' cannot read the clock."))
+}
+
+// (round-to t :h) is the duration or the datetime t to a multiple of the unit. A datetime counts
+// from the epoch, so to a day is to midnight UTC.
+fn to_multiple(
+    ctx: &mut Ctx,
+    name: &str,
+    args: &[Value],
+    by: fn(i64, Duration) -> Option<i64>,
+) -> Result<Value> {
+    let unit = to_unit(ctx, name, &args[1])?;
+    let val = match &args[0] {
+        Value::Duration(d) => by(d.nanos(), unit).map(|n| Value::Duration(Duration::from_nanos(n))),
+        Value::DateTime(t) => {
+            by(t.unix_nanos(), unit).map(|n| Value::DateTime(DateTime::from_unix_nanos(n)))
+        }
+        val => {
+            return Err(error_msg(&format!(
+                "'{}' requires a duration or a datetime, not {}.",
+                name, val
+            )))
+        }
+    };
+    val.ok_or_else(|| error_msg(&format!("'{}' overflows on {}.", name, args[0])))
+}
+
+fn floor_to(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    to_multiple(ctx, "floor-to", args, time::floor)
+}
+
+fn ceil_to(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    to_multiple(ctx, "ceil-to", args, time::ceil)
+}
+
+fn round_to(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    to_multiple(ctx, "round-to", args, time::round)
+}
+
+// (get coll index default) is the default, or nil, when there is nothing at index, even when
+// coll is nil.
+fn get(args: &[Value]) -> Result<Value> {
+    let (coll, index, default) = match args {
+        [coll, index] => (coll, index, &Value::Nil),
+        [coll, index, default] => (coll, index, default),
+        _ => return Err(error_msg("'get' requires 2 or 3 arguments.")),
+    };
+    let found = match (coll, index) {
+        (Value::List(list) | Value::Vector(list), Value::Int(i)) => {
+            usize::try_from(*i).ok().and_then(|i| list.get(i))
+        }
+        _ => None,
+    };
+    Ok(found.unwrap_or(default).clone())
+}
+
+fn zap_version(args: &[Value]) -> Result<Value> {
+    if !args.is_empty() {
+        return Err(error_msg("'zap-version' takes no arguments."));
+    }
+    Ok(Value::Str(String::from(VERSION)))
+}
+
+// The names of the features compiled in, so a script can check for one before using it
+fn features_list(args: &[Value]) -> Result<Value> {
+    if !args.is_empty() {
+        return Err(error_msg("'features' takes no arguments."));
+    }
+    let names = features()
+        .map(|name| Value::Str(String::from(name)))
+        .collect();
+    Ok(Value::List(Value::new_list(names)))
+}
+
+// (coroutine f args...) calls f with args on its first resume, and stops there
+fn coroutine(args: &[Value]) -> Result<Value> {
+    match args {
+        [f @ (Value::Func(_) | Value::Closure(_) | Value::FuncNative(_)), args @ ..] => {
+            Ok(Value::Coroutine(Arc::new(Coroutine::new(f.clone(), args)?)))
+        }
+        _ => Err(error_msg("'coroutine' requires a function.")),
+    }
+}
+
+// (resume co val) goes on with the coroutine, its yield being val, and is what it yields next or
+// what it returns
+fn resume(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    match args {
+        [Value::Coroutine(co)] => ctx.resume(co, Value::Nil),
+        [Value::Coroutine(co), sent] => ctx.resume(co, sent.clone()),
+        _ => Err(error_msg("'resume' requires a coroutine.")),
+    }
+}
+
+fn is_done(args: &[Value]) -> Result<Value> {
+    match args {
+        [Value::Coroutine(co)] => Ok(Value::Bool(co.is_done())),
+        [Value::Task(task)] => Ok(Value::Bool(task.is_done())),
+        _ => Err(error_msg("'done?' requires a coroutine or a task.")),
+    }
+}
+
+fn chan(args: &[Value]) -> Result<Value> {
+    if !args.is_empty() {
+        return Err(error_msg("'chan' takes no arguments."));
+    }
+    Ok(Value::Channel(Arc::default()))
+}
+
+fn send(args: &[Value]) -> Result<Value> {
+    match args {
+        [Value::Channel(chan), val] => chan.send(val.clone()).map(|()| Value::Nil),
+        _ => Err(error_msg("'send!' requires a channel and a value.")),
+    }
+}
+
+fn close(args: &[Value]) -> Result<Value> {
+    match args {
+        [Value::Channel(chan)] => {
+            chan.close();
+            Ok(Value::Nil)
+        }
+        _ => Err(error_msg("'close!' requires a channel.")),
+    }
+}
+
+// (recv! c) is the oldest value sent to c, waiting for one when there's none yet
+fn recv(args: &[Value]) -> Pending {
+    match &args[0] {
+        Value::Channel(chan) => Channel::recv(chan),
+        _ => Box::pin(async { Err(error_msg("'recv!' requires a channel.")) }),
+    }
+}
+
+fn atom(args: &[Value]) -> Result<Value> {
+    match args {
+        #[cfg(feature = "gc")]
+        [val] => Ok(crate::gc::atom(val.clone())),
+        #[cfg(not(feature = "gc"))]
+        [val] => Ok(Value::Atom(Arc::new(RwLock::new(val.clone())))),
+        _ => Err(error_msg("'atom' requires 1 argument.")),
+    }
+}
+
+// @a reads as (deref a)
+fn deref(args: &[Value]) -> Result<Value> {
+    match args {
+        [Value::Atom(atom)] => Ok(atom.read().unwrap().clone()),
+        _ => Err(error_msg("'deref' requires an atom.")),
+    }
+}
+
+fn reset(args: &[Value]) -> Result<Value> {
+    match args {
+        [Value::Atom(atom), val] => {
+            *atom.write().unwrap() = val.clone();
+            Ok(val.clone())
+        }
+        _ => Err(error_msg("'reset!' requires an atom and a value.")),
+    }
+}
+
+// (swap! a f args...) sets a to (f @a args...), and is its new value. f is called without holding
+// the atom, so it's called again when another thread changed a meanwhile.
+fn swap(ctx: &mut Ctx, args: &[Value]) -> Result<Value> {
+    let [Value::Atom(atom), f, rest @ ..] = args else {
+        return Err(error_msg("'swap!' requires an atom and a function."));
+    };
+    loop {
+        let old = atom.read().unwrap().clone();
+        let mut call = vec![old.clone()];
+        call.extend_from_slice(rest);
+        let new = ctx.call(f, &call)?;
+        let mut current = atom.write().unwrap();
+        if *current == old {
+            *current = new.clone();
+            return Ok(new);
+        }
+    }
+}
+
+// (gc) frees the atoms left in cycles, and is how many
+#[cfg(feature = "gc")]
+fn gc(args: &[Value]) -> Result<Value> {
+    match args {
+        [] => Ok(Value::Int(
+            crate::gc::collect_cycles().try_into().unwrap_or(i64::MAX),
+        )),
+        _ => Err(error_msg("'gc' takes no argument.")),
+    }
+}
+
+#[cfg(feature = "gc")]
+fn heap_stats(args: &[Value]) -> Result<Value> {
+    match args {
+        [] => Ok(crate::gc::heap_stats().to_value()),
+        _ => Err(error_msg("'heap-stats' takes no argument.")),
+    }
+}
+
+#[cfg(feature = "gc")]
+const GC_FUNCTIONS: [(&str, NativeFn); 2] = [("gc", gc), ("heap-stats", heap_stats)];
+#[cfg(not(feature = "gc"))]
+const GC_FUNCTIONS: [(&str, NativeFn); 0] = [];
+
+type NativeFn = fn(&[Value]) -> Result<Value>;
+
+const FUNCTIONS: [(&str, NativeFn); 35] = [
+    ("int?", is_int),
+    ("float?", is_float),
+    ("false?", is_false),
+    ("concat", concat),
+    ("print", print),
+    ("println", println),
+    ("int", int),
+    ("float", float),
+    ("parse-int", parse_int),
+    ("parse-float", parse_float),
+    ("get", get),
+    ("approx=", approx_eq),
+    ("zap-version", zap_version),
+    ("features", features_list),
+    ("coroutine", coroutine),
+    ("done?", is_done),
+    ("chan", chan),
+    ("send!", send),
+    ("close!", close),
+    ("atom", atom),
+    ("deref", deref),
+    ("reset!", reset),
+    ("<", lt),
+    ("<=", le),
+    (">", gt),
+    (">=", ge),
+    ("datetime", datetime),
+    ("now", now),
+    ("+", add),
+    ("-", sub),
+    ("*", mul),
+    ("/", div),
+    ("rem", rem),
+    ("not", not),
+    ("list", list),
+];
+
+type CtxFn = fn(&mut Ctx, &[Value]) -> Result<Value>;
+
+// The natives getting the env, or calling back into zap
+const CTX_FUNCTIONS: [(&str, Arity, CtxFn); 11] = [
+    ("str", Arity::AtLeast(0), str),
+    ("keyword", Arity::Exactly(1), keyword),
+    ("symbol", Arity::Exactly(1), symbol),
+    ("parse-keyword", Arity::Exactly(1), parse_keyword),
+    ("parse-symbol", Arity::Exactly(1), parse_symbol),
+    ("resume", Arity::AtLeast(1), resume),
+    ("swap!", Arity::AtLeast(2), swap),
+    ("duration", Arity::AtLeast(1), duration),
+    ("floor-to", Arity::Exactly(2), floor_to),
+    ("ceil-to", Arity::Exactly(2), ceil_to),
+    ("round-to", Arity::Exactly(2), round_to),
+];
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    FUNCTIONS
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(CTX_FUNCTIONS.iter().map(|(name, _, _)| *name))
+        .chain(["recv!"])
+        .chain(GC_FUNCTIONS.iter().map(|(name, _)| *name))
+}
+
+pub fn load<E: Env + ?Sized>(env: &mut E) -> Result<()> {
+    for (name, f) in FUNCTIONS {
+        env.reg_fn(name, f)?;
+    }
+    for (name, arity, f) in CTX_FUNCTIONS {
+        env.reg_fn_ctx(name, arity, f)?;
+    }
+    env.reg_fn_async("recv!", Arity::Exactly(1), recv)?;
+    for (name, f) in GC_FUNCTIONS {
+        env.reg_fn(name, f)?;
+    }
+    Ok(())
+}
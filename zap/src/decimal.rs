@@ -0,0 +1,249 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+// A fixed-point number, units / 10^scale, for the arithmetic that must be exact, like money.
+// It's written with an M suffix, 12.50M, and keeps the scale it's written with.
+
+const MAX_SCALE: u8 = 18;
+
+#[cfg(not(feature = "compact-values"))]
+#[derive(Debug, Clone)]
+pub struct Decimal {
+    units: i64,
+    scale: u8,
+}
+
+// With the compact-values feature, a decimal is 64 bits. When its units fit in 58 of them, about
+// 1.4e17, they're packed with the scale: the units above the 6th bit, the scale in the bits 1 to
+// 5, and the lowest bit set. The others are boxed, the bits are the address of the box, whose
+// lowest bit is clear.
+#[cfg(feature = "compact-values")]
+pub struct Decimal(u64);
+
+#[cfg(feature = "compact-values")]
+struct Boxed {
+    units: i64,
+    scale: u8,
+}
+
+impl Decimal {
+    #[cfg(not(feature = "compact-values"))]
+    pub fn new(units: i64, scale: u8) -> Option<Decimal> {
+        (scale <= MAX_SCALE).then_some(Decimal { units, scale })
+    }
+
+    #[cfg(feature = "compact-values")]
+    pub fn new(units: i64, scale: u8) -> Option<Decimal> {
+        if scale > MAX_SCALE {
+            return None;
+        }
+        Some(match units.checked_mul(64) {
+            Some(packed) => Decimal(packed as u64 | u64::from(scale) << 1 | 1),
+            None => Decimal::boxing(units, scale),
+        })
+    }
+
+    #[cfg(feature = "compact-values")]
+    fn boxing(units: i64, scale: u8) -> Decimal {
+        Decimal(Box::into_raw(Box::new(Boxed { units, scale })) as usize as u64)
+    }
+
+    #[cfg(not(feature = "compact-values"))]
+    fn units(&self) -> i64 {
+        self.units
+    }
+
+    #[cfg(not(feature = "compact-values"))]
+    fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    #[cfg(feature = "compact-values")]
+    fn boxed(&self) -> Option<&Boxed> {
+        // The box lives as long as the decimal
+        (self.0 & 1 == 0).then(|| unsafe { &*(self.0 as usize as *const Boxed) })
+    }
+
+    #[cfg(feature = "compact-values")]
+    fn units(&self) -> i64 {
+        self.boxed().map_or(self.0 as i64 >> 6, |boxed| boxed.units)
+    }
+
+    #[cfg(feature = "compact-values")]
+    fn scale(&self) -> u8 {
+        self.boxed()
+            .map_or((self.0 >> 1 & 31) as u8, |boxed| boxed.scale)
+    }
+
+    // From digits with an optional sign and point, like -12.50
+    pub fn parse(s: &str) -> Option<Decimal> {
+        let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+        let digits = int.strip_prefix(['-', '+']).unwrap_or(int);
+        if digits.is_empty() || !(digits.bytes().chain(frac.bytes())).all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let units = format!("{}{}", int, frac).parse().ok()?;
+        Decimal::new(units, u8::try_from(frac.len()).ok()?)
+    }
+
+    // An integral float, exactly
+    pub fn from_integer(n: f64) -> Option<Decimal> {
+        let units = n as i64;
+        if n.fract() == 0.0 && units as f64 == n {
+            Decimal::new(units, 0)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.units() as f64 / 10f64.powi(self.scale().into())
+    }
+
+    // The units at a larger scale
+    fn units_at(&self, scale: u8) -> Option<i64> {
+        10i64
+            .checked_pow((scale - self.scale()).into())?
+            .checked_mul(self.units())
+    }
+
+    pub fn checked_add(&self, other: &Decimal) -> Option<Decimal> {
+        let scale = self.scale().max(other.scale());
+        let units = self.units_at(scale)?.checked_add(other.units_at(scale)?)?;
+        Decimal::new(units, scale)
+    }
+
+    pub fn checked_sub(&self, other: &Decimal) -> Option<Decimal> {
+        let scale = self.scale().max(other.scale());
+        let units = self.units_at(scale)?.checked_sub(other.units_at(scale)?)?;
+        Decimal::new(units, scale)
+    }
+
+    pub fn checked_mul(&self, other: &Decimal) -> Option<Decimal> {
+        Decimal::new(
+            self.units().checked_mul(other.units())?,
+            self.scale() + other.scale(),
+        )
+    }
+
+    // The exact quotient at the smallest scale that holds it, or cut at the largest scale
+    pub fn checked_div(&self, other: &Decimal) -> Option<Decimal> {
+        if other.is_zero() {
+            return None;
+        }
+        let den = i128::from(other.units());
+        for scale in self.scale().max(other.scale())..=MAX_SCALE {
+            let shift = u32::from(scale + other.scale() - self.scale());
+            let num = i128::from(self.units()).checked_mul(10i128.checked_pow(shift)?)?;
+            if num % den == 0 || scale == MAX_SCALE {
+                return Decimal::new(i64::try_from(num / den).ok()?, scale);
+            }
+        }
+        None
+    }
+
+    // With the sign of the dividend, like the remainder of numbers
+    pub fn checked_rem(&self, other: &Decimal) -> Option<Decimal> {
+        let scale = self.scale().max(other.scale());
+        let units = self.units_at(scale)?.checked_rem(other.units_at(scale)?)?;
+        Decimal::new(units, scale)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.units() == 0
+    }
+
+    // Without the trailing zeros, so 1.50 and 1.5 are the same
+    fn normalized(&self) -> (i64, u8) {
+        let (mut units, mut scale) = (self.units(), self.scale());
+        while scale > 0 && units % 10 == 0 {
+            units /= 10;
+            scale -= 1;
+        }
+        (units, scale)
+    }
+
+    // Equal with the same scale too, when their printing matters
+    pub fn identical(&self, other: &Decimal) -> bool {
+        self.units() == other.units() && self.scale() == other.scale()
+    }
+}
+
+#[cfg(feature = "compact-values")]
+impl Clone for Decimal {
+    fn clone(&self) -> Self {
+        match self.boxed() {
+            Some(boxed) => Decimal::boxing(boxed.units, boxed.scale),
+            None => Decimal(self.0),
+        }
+    }
+}
+
+#[cfg(feature = "compact-values")]
+impl Drop for Decimal {
+    fn drop(&mut self) {
+        if self.boxed().is_some() {
+            drop(unsafe { Box::from_raw(self.0 as usize as *mut Boxed) });
+        }
+    }
+}
+
+#[cfg(feature = "compact-values")]
+impl fmt::Debug for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Decimal")
+            .field("units", &self.units())
+            .field("scale", &self.scale())
+            .finish()
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.normalized() == other.normalized()
+    }
+}
+
+impl Eq for Decimal {}
+
+// By the units at the same scale, which can't overflow an i128
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let scale = self.scale().max(other.scale());
+        let at = |d: &Decimal| i128::from(d.units()) * 10i128.pow((scale - d.scale()).into());
+        at(self).cmp(&at(other))
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hash for Decimal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.normalized().hash(state);
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scale = usize::from(self.scale());
+        let digits = format!(
+            "{:0>width$}",
+            self.units().unsigned_abs(),
+            width = scale + 1
+        );
+        let (int, frac) = digits.split_at(digits.len() - scale);
+        if self.units() < 0 {
+            f.write_str("-")?;
+        }
+        if scale == 0 {
+            f.write_str(int)
+        } else {
+            write!(f, "{}.{}", int, frac)
+        }
+    }
+}
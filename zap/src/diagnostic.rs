@@ -0,0 +1,45 @@
+use crate::zap::ZapErr;
+
+// What the reader and the compiler found wrong in some source, without running it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: u32, // Where the faulty form starts, from 1
+}
+
+impl Diagnostic {
+    // At the line of the error when it's located, else at the given one.
+    pub fn error(err: ZapErr, line: u32) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: err.message(),
+            line: err.span().map_or(line, |span| span.line),
+        }
+    }
+
+    pub fn warning(message: String, line: u32) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            message,
+            line,
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", self.line, severity, self.message)
+    }
+}
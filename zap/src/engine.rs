@@ -0,0 +1,325 @@
+use std::io::{ErrorKind, Read};
+use std::sync::{Arc, Mutex};
+
+use crate::compiler::{compile_spanned, compile_with_warnings, Extensions, SpecialForm};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::env::{Capability, Env, SandboxEnv};
+use crate::reader::Reader;
+use crate::vm::{Catcher, Chunk, Debugger, Journal, Profile, Step, Tracer, VM};
+use crate::zap::{error_msg, Arity, Result, Value, ZapErr};
+
+// The Engine ties a reader, the compiler and a VM to an env.
+// It's the simplest way to embed zap.
+
+// The bytes read at once when evaluating a stream
+const STREAM_BUFFER: usize = 64 * 1024;
+
+// Evaluate every form of src in env, with a reader and a VM of its own, returning the value of
+// the last one. An error says the line and col of src where it was raised. An Engine keeps them
+// from one eval_str to the next.
+pub fn eval_str<E: Env + ?Sized>(src: &str, mut env: &mut E) -> Result<Value> {
+    let mut reader = Reader::new();
+    reader.tokenize(src);
+    reader.flush_token();
+
+    let extensions = Extensions::new();
+    let mut vm = VM::new();
+    let mut res = Ok(Value::Nil);
+    while res.is_ok() {
+        res = match reader.read_ast(&mut env) {
+            Ok(Some(ast)) => {
+                compile_spanned(ast, reader.spans(), &extensions, &mut env, &mut Vec::new())
+                    .and_then(|chunk| vm.run(chunk, env))
+            }
+            Ok(None) if reader.is_pending() => Err(ZapErr::reader("Unexpected end of input.")),
+            Ok(None) => break,
+            Err(err) => Err(err),
+        };
+    }
+    res
+}
+
+// Evaluate the file at path in env, returning the value of its last form. It's what (load path)
+// does, when the env has the Files capability. A namespace set by the file ends with it. An
+// error says the line and col where it was raised in the file.
+pub fn load_file<E: Env + ?Sized>(path: &str, env: &mut E) -> Result<Value> {
+    if !env.has_capability(Capability::Files) {
+        return Err(error_msg("Loading files is not allowed in this env."));
+    }
+    let src = std::fs::read_to_string(path)
+        .map_err(|err| error_msg(&format!("Cannot read '{}': {}", path, err)))?;
+
+    let ns = env.namespace();
+    let res = eval_str(&src, env);
+    if env.namespace() != ns {
+        env.set_namespace(ns.as_deref())?;
+    }
+    res.map_err(|err| error_msg(&format!("Error loading '{}': {}", path, err)))
+}
+
+pub struct Engine<E: Env = SandboxEnv> {
+    env: E,
+    reader: Reader,
+    vm: VM,
+    extensions: Extensions,
+}
+
+impl Default for Engine<SandboxEnv> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine<SandboxEnv> {
+    // An engine with the core functions loaded.
+    pub fn new() -> Self {
+        Engine::with_env(SandboxEnv::default())
+    }
+
+    pub fn with_core(self, core: bool) -> Self {
+        Engine {
+            env: self.env.with_core(core),
+            ..self
+        }
+    }
+}
+
+impl<E: Env> Engine<E> {
+    pub fn with_env(env: E) -> Self {
+        Engine {
+            env,
+            reader: Reader::new(),
+            vm: VM::new(),
+            extensions: Extensions::new(),
+        }
+    }
+
+    pub fn env(&self) -> &E {
+        &self.env
+    }
+
+    pub fn env_mut(&mut self) -> &mut E {
+        &mut self.env
+    }
+
+    pub fn into_env(self) -> E {
+        self.env
+    }
+
+    // Make the compiler hand (name ...) forms to the given special form.
+    pub fn register_form(&mut self, name: &str, form: SpecialForm) -> Result<()> {
+        self.extensions.register(&mut self.env, name, form)
+    }
+
+    // Inline the calls to the global functions of at most ops ops, none with 0.
+    pub fn set_inline_limit(&mut self, ops: usize) {
+        self.extensions.set_inline_limit(ops);
+    }
+
+    // Keep the last steps of each evaluation, to see what led to an error.
+    pub fn record_steps(&mut self, capacity: usize) {
+        self.vm = VM::with_recording(capacity);
+    }
+
+    // Show every op of the evaluations to tracer, as they run.
+    pub fn trace(&mut self, tracer: Box<dyn Tracer>) {
+        self.vm = std::mem::take(&mut self.vm).with_tracer(tracer);
+    }
+
+    // Stop the evaluations at each (break), to let debugger look at them.
+    pub fn debug(&mut self, debugger: Box<dyn Debugger>) {
+        self.vm = std::mem::take(&mut self.vm).with_debugger(debugger);
+    }
+
+    // Give catcher the errors no try caught. The value it catches one with is the one of its form,
+    // and the forms after it are evaluated.
+    pub fn catch(&mut self, catcher: Box<dyn Catcher>) {
+        self.vm = std::mem::take(&mut self.vm).with_catcher(catcher);
+    }
+
+    // Profile the evaluations from now on. The profile is returned, and (profile-report) is it
+    // as zap data.
+    pub fn profile(&mut self) -> Result<Arc<Mutex<Profile>>> {
+        let profile = Arc::new(Mutex::new(Profile::default()));
+        let reported = profile.clone();
+        self.env.reg_closure(
+            "profile-report",
+            Arity::Exactly(0),
+            Box::new(move |_| Ok(reported.lock().unwrap().report())),
+        )?;
+        self.vm = std::mem::take(&mut self.vm).with_profile(profile.clone());
+        Ok(profile)
+    }
+
+    // Journal the results of the natives called from now on, to replay the evaluations later.
+    pub fn record_journal(&mut self) {
+        self.vm.record_journal();
+    }
+
+    // Evaluate from now on with the results of the natives in journal.
+    pub fn replay_journal(&mut self, journal: Journal) {
+        self.vm.replay_journal(journal);
+    }
+
+    pub fn take_journal(&mut self) -> Option<Journal> {
+        self.vm.take_journal()
+    }
+
+    // The last n steps of the latest form evaluated, when they are recorded.
+    pub fn last_steps(&self, n: usize) -> Vec<Step> {
+        self.vm.last_steps(n)
+    }
+
+    // Run a chunk compiled beforehand, like one loaded with Chunk::deserialize.
+    pub fn eval_chunk(&mut self, chunk: Arc<Chunk>) -> Result<Value> {
+        self.vm.run(chunk, &mut self.env)
+    }
+
+    // Evaluate every form of src, returning the value of the last one. An error says the line
+    // and col of src where it was raised.
+    pub fn eval_str(&mut self, src: &str) -> Result<Value> {
+        self.reader.new_source();
+        self.reader.tokenize(src);
+        self.reader.flush_token();
+
+        let res = self.eval_forms();
+        if res.is_err() || self.reader.is_pending() {
+            self.reader.reset();
+        }
+        res
+    }
+
+    // Evaluate every form read from input, returning the value of the last one. The input is read
+    // a buffer at a time and each form is evaluated once complete, so a large file is never held
+    // in memory whole. progress is given the count of bytes read after each buffer.
+    pub fn eval_read<R: Read>(&mut self, input: R, progress: impl FnMut(usize)) -> Result<Value> {
+        self.reader.new_source();
+        let res = self.eval_stream(input, progress);
+        if res.is_err() || self.reader.is_pending() {
+            self.reader.reset();
+        }
+        res
+    }
+
+    fn eval_stream<R: Read>(
+        &mut self,
+        mut input: R,
+        mut progress: impl FnMut(usize),
+    ) -> Result<Value> {
+        let mut buf = vec![0; STREAM_BUFFER];
+        let mut kept = 0; // The start of a char cut by the end of the previous buffer
+        let mut total = 0;
+        let mut res = Value::Nil;
+        loop {
+            let n = match input.read(&mut buf[kept..]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(error_msg(&format!("Cannot read the input: {}", err))),
+            };
+            total += n;
+            let end = kept + n;
+            let valid = match std::str::from_utf8(&buf[..end]) {
+                Ok(text) => text.len(),
+                Err(err) if err.error_len().is_none() => err.valid_up_to(),
+                Err(_) => return Err(ZapErr::reader("The input is not valid UTF-8.")),
+            };
+            self.reader
+                .tokenize(std::str::from_utf8(&buf[..valid]).unwrap_or_default());
+            self.eval_read_forms(&mut res)?;
+
+            buf.copy_within(valid..end, 0);
+            kept = end - valid;
+            progress(total);
+        }
+        if kept > 0 {
+            return Err(ZapErr::reader("The input is not valid UTF-8."));
+        }
+
+        self.reader.flush_token();
+        self.eval_read_forms(&mut res)?;
+        if self.reader.is_pending() {
+            return Err(ZapErr::reader("Unexpected end of input."));
+        }
+        Ok(res)
+    }
+
+    fn eval_forms(&mut self) -> Result<Value> {
+        let mut res = Value::Nil;
+        self.eval_read_forms(&mut res)?;
+
+        if self.reader.is_pending() {
+            return Err(ZapErr::reader("Unexpected end of input."));
+        }
+        Ok(res)
+    }
+
+    // Evaluate the forms the reader has complete, keeping the value of the last one in res
+    fn eval_read_forms(&mut self, res: &mut Value) -> Result<()> {
+        while let Some(ast) = self.reader.read_ast(&mut self.env)? {
+            let chunk = compile_spanned(
+                ast,
+                self.reader.spans(),
+                &self.extensions,
+                &mut self.env,
+                &mut Vec::new(),
+            )?;
+            *res = self.vm.run(chunk, &mut self.env)?;
+        }
+        Ok(())
+    }
+
+    // Read and compile src without evaluating it, collecting every error and warning on the way.
+    pub fn check(&mut self, src: &str) -> Vec<Diagnostic> {
+        let mut reader = Reader::new();
+        let mut diagnostics = Vec::new();
+        let mut warnings = Vec::new();
+        let mut form_line = 1;
+
+        // Fed line by line, so each form can be tied to the line it starts on
+        for (line, text) in (1..).zip(src.split_inclusive('\n')) {
+            if !reader.is_pending() {
+                form_line = line;
+            }
+            reader.tokenize(text);
+            if !text.ends_with('\n') {
+                reader.flush_token();
+            }
+
+            loop {
+                match reader.read_ast(&mut self.env) {
+                    Ok(Some(ast)) => {
+                        let res = compile_with_warnings(
+                            ast,
+                            &self.extensions,
+                            &mut self.env,
+                            &mut warnings,
+                        );
+                        for warning in warnings.drain(..) {
+                            diagnostics.push(Diagnostic::warning(warning, form_line));
+                        }
+                        if let Err(err) = res {
+                            diagnostics.push(Diagnostic::error(err, form_line));
+                        }
+                        form_line = line;
+                    }
+                    Ok(None) => break,
+                    Err(err) => {
+                        diagnostics.push(Diagnostic::error(err, line));
+                        reader.reset();
+                        break;
+                    }
+                }
+            }
+        }
+
+        if reader.is_pending() {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                message: "Unexpected end of input.".to_string(),
+                line: form_line,
+            });
+        }
+        diagnostics
+    }
+}
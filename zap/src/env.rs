@@ -1,15 +1,67 @@
-use crate::zap::{error_msg, Result, String, Symbol, Value, ZapFnNative};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::vm::Ctx;
+use crate::zap::{
+    error_msg, Arity, NativeClosure, Pending, Result, String, Symbol, Value, ZapErr, ZapFnNative,
+};
 use fxhash::FxHashMap;
 
 pub type Scope = Vec<Option<Value>>;
-pub type SymbolTable = FxHashMap<String, Symbol>;
+
+// The names of the symbols registered in an env, both ways: the ids are given in order, so a
+// name is found by its id without going through all of them.
+#[derive(Debug, Default, Clone)]
+pub struct SymbolTable {
+    ids: FxHashMap<String, Symbol>,
+    names: Vec<String>,
+}
+
+impl SymbolTable {
+    // The id of the name, and whether it was just registered
+    pub fn intern(&mut self, name: String) -> (Symbol, bool) {
+        if let Some(id) = self.ids.get(&name) {
+            return (*id, false);
+        }
+        let id: Symbol = self.names.len().try_into().unwrap();
+        self.names.push(name.clone());
+        self.ids.insert(name, id);
+        (id, true)
+    }
+
+    pub fn id(&self, name: &str) -> Option<Symbol> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: Symbol) -> Result<String> {
+        self.names
+            .get(id as usize)
+            .cloned()
+            .ok_or_else(|| error_msg(format!("No known symbol for id={}", id).as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    // The names with their ids, in the order they were registered
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Symbol)> {
+        self.names
+            .iter()
+            .enumerate()
+            .map(|(id, name)| (name.as_str(), id as Symbol))
+    }
+}
 
 pub mod symbols {
     use crate::zap::Symbol;
     //
     // TODO: Make sures all the default symbols (for special forms) are here.
     // TODO: Make a macro that generate const Symbol for each default symbols.
-    pub const DEFAULT_SYMBOLS: [&str; 11] = [
+    pub const DEFAULT_SYMBOLS: [&str; 48] = [
         "if",
         "let",
         "fn",
@@ -21,6 +73,43 @@ pub mod symbols {
         "splice-unquote",
         "+",
         "=",
+        "while",
+        "doto",
+        "doseq-indexed",
+        "deref",
+        "&",
+        "loop",
+        "recur",
+        "when",
+        "unless",
+        "case",
+        "defmacro",
+        "try",
+        "catch",
+        "throw",
+        "defn",
+        "->",
+        "->>",
+        "some->",
+        "some->>",
+        "or",
+        "set!",
+        "with-env",
+        "letfn",
+        "ns",
+        "require",
+        "load",
+        "compile-if",
+        "when-available",
+        "disasm",
+        "-",
+        "*",
+        "/",
+        "rem",
+        "not",
+        "break",
+        "yield",
+        "apply",
     ];
 
     pub const IF: Symbol = 0;
@@ -34,13 +123,136 @@ pub mod symbols {
     pub const SPLICE_UNQUOTE: Symbol = 8;
     pub const PLUS: Symbol = 9;
     pub const EQUAL: Symbol = 10;
+    pub const WHILE: Symbol = 11;
+    pub const DOTO: Symbol = 12;
+    pub const DOSEQ_INDEXED: Symbol = 13;
+    pub const DEREF: Symbol = 14;
+    pub const REST: Symbol = 15;
+    pub const LOOP: Symbol = 16;
+    pub const RECUR: Symbol = 17;
+    pub const WHEN: Symbol = 18;
+    pub const UNLESS: Symbol = 19;
+    pub const CASE: Symbol = 20;
+    pub const DEFMACRO: Symbol = 21;
+    pub const TRY: Symbol = 22;
+    pub const CATCH: Symbol = 23;
+    pub const THROW: Symbol = 24;
+    pub const DEFN: Symbol = 25;
+    pub const THREAD_FIRST: Symbol = 26;
+    pub const THREAD_LAST: Symbol = 27;
+    pub const SOME_THREAD_FIRST: Symbol = 28;
+    pub const SOME_THREAD_LAST: Symbol = 29;
+    pub const OR: Symbol = 30;
+    pub const SET: Symbol = 31;
+    pub const WITH_ENV: Symbol = 32;
+    pub const LETFN: Symbol = 33;
+    pub const NS: Symbol = 34;
+    pub const REQUIRE: Symbol = 35;
+    pub const LOAD: Symbol = 36;
+    pub const COMPILE_IF: Symbol = 37;
+    pub const WHEN_AVAILABLE: Symbol = 38;
+    pub const DISASM: Symbol = 39;
+    pub const MINUS: Symbol = 40;
+    pub const TIMES: Symbol = 41;
+    pub const DIVIDE: Symbol = 42;
+    pub const REM: Symbol = 43;
+    pub const NOT: Symbol = 44;
+    pub const BREAK: Symbol = 45;
+    pub const YIELD: Symbol = 46;
+    pub const APPLY: Symbol = 47;
+}
+
+// What an env allows its code to do, beyond pure computation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Plugins, // Loading native extensions
+    Files,   // Loading source files, with (load path)
+}
+
+impl Capability {
+    pub const ALL: [Capability; 2] = [Capability::Plugins, Capability::Files];
+
+    // How it's named to clients and users
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Plugins => "plugins",
+            Capability::Files => "files",
+        }
+    }
+}
+
+// The generations of the envs, shared by all of them so no two are ever at the same one
+static GENERATIONS: AtomicU64 = AtomicU64::new(0);
+
+// A generation no env has been at yet
+pub fn new_generation() -> u64 {
+    GENERATIONS.fetch_add(1, Ordering::Relaxed)
 }
 
 pub trait Env {
     fn get_by_id(&self, id: Symbol) -> Result<Value>;
+
+    // Where the globals are at, a new generation each time one is set. The VM keeps what a
+    // LookUp found until it changes, without asking again. None when the env has none, then it
+    // is always asked.
+    fn generation(&self) -> Option<u64> {
+        None
+    }
+
+    // The value of a global when it's defined, without the error get_by_id makes otherwise
+    fn lookup(&self, id: Symbol) -> Option<Value> {
+        self.get_by_id(id).ok()
+    }
+
     fn set(&mut self, key: &Value, val: &Value) -> Result<()>;
     fn reg_symbol(&mut self, s: String) -> Value;
     fn get_symbol(&self, key: Symbol) -> Result<String>;
+    fn symbols_count(&self) -> usize;
+
+    // The symbol with that name, if it's registered
+    fn find_symbol(&self, name: &str) -> Option<Symbol> {
+        (0..self.symbols_count())
+            .map(|id| id as Symbol)
+            .find(|id| self.get_symbol(*id).is_ok_and(|s| s == name))
+    }
+
+    // The namespace set by (ns name), None at the top. The globals defined in it are named
+    // name/symbol, and its code sees them under their short name.
+    fn namespace(&self) -> Option<String> {
+        None
+    }
+
+    fn set_namespace(&mut self, _ns: Option<&str>) -> Result<()> {
+        Err(error_msg("This env has no namespaces."))
+    }
+
+    // Make a name stand for another one in the code of the current namespace, as required by
+    // (require '(lib :as l :refer (f))): the alias l/ stands for lib/, and f for lib/f.
+    fn add_alias(&mut self, _alias: &str, _target: &str) -> Result<()> {
+        Err(error_msg("This env has no namespaces."))
+    }
+
+    fn alias(&self, _alias: &str) -> Option<String> {
+        None
+    }
+
+    fn has_capability(&self, _cap: Capability) -> bool {
+        false
+    }
+
+    // The globals defined after entering a layer are discarded when it's left, the previous
+    // values coming back. (with-env ...) evaluates its body in a layer.
+    fn enter_layer(&mut self) -> Result<()> {
+        Err(error_msg("This env cannot evaluate in a discarded layer."))
+    }
+
+    fn leave_layer(&mut self) {}
+
+    // An env of its own for an evaluation spawned from this one, which can run on another
+    // thread. None when the env can't be shared.
+    fn fork(&self) -> Option<Box<dyn Env + Send>> {
+        None
+    }
 
     fn reg_fn(&mut self, symbol: &str, f: fn(&[Value]) -> Result<Value>) -> Result<()> {
         let id = self.reg_symbol(String::from(symbol));
@@ -51,6 +263,61 @@ pub trait Env {
         Ok(())
     }
 
+    // Same as reg_fn, but the VM checks the number of args before calling f
+    fn reg_fn_arity(
+        &mut self,
+        symbol: &str,
+        arity: Arity,
+        f: fn(&[Value]) -> Result<Value>,
+    ) -> Result<()> {
+        let id = self.reg_symbol(String::from(symbol));
+        self.set(
+            &id,
+            &Value::FuncNative(ZapFnNative::with_arity(String::from(symbol), arity, f)),
+        )?;
+        Ok(())
+    }
+
+    // A native given a Ctx, for the ones that need the env or to call zap fns
+    fn reg_fn_ctx(
+        &mut self,
+        symbol: &str,
+        arity: Arity,
+        f: fn(&mut Ctx, &[Value]) -> Result<Value>,
+    ) -> Result<()> {
+        let id = self.reg_symbol(String::from(symbol));
+        self.set(
+            &id,
+            &Value::FuncNative(ZapFnNative::with_ctx(String::from(symbol), arity, f)),
+        )?;
+        Ok(())
+    }
+
+    // A closure, for a native keeping some state of the host
+    fn reg_closure(&mut self, symbol: &str, arity: Arity, f: Box<NativeClosure>) -> Result<()> {
+        let id = self.reg_symbol(String::from(symbol));
+        self.set(
+            &id,
+            &Value::FuncNative(ZapFnNative::with_closure(String::from(symbol), arity, f)),
+        )?;
+        Ok(())
+    }
+
+    // A native returning a future. VM::run_async is suspended until it's ready, run blocks on it.
+    fn reg_fn_async(
+        &mut self,
+        symbol: &str,
+        arity: Arity,
+        f: fn(&[Value]) -> Pending,
+    ) -> Result<()> {
+        let id = self.reg_symbol(String::from(symbol));
+        self.set(
+            &id,
+            &Value::FuncNative(ZapFnNative::with_async(String::from(symbol), arity, f)),
+        )?;
+        Ok(())
+    }
+
     #[inline(always)]
     fn get(&self, key: &Value) -> Result<Value> {
         match key {
@@ -60,9 +327,106 @@ pub trait Env {
     }
 }
 
+// So an env behind a reference can be handed to what takes a sized one
+impl<E: Env + ?Sized> Env for &mut E {
+    fn get_by_id(&self, id: Symbol) -> Result<Value> {
+        (**self).get_by_id(id)
+    }
+    fn lookup(&self, id: Symbol) -> Option<Value> {
+        (**self).lookup(id)
+    }
+    fn generation(&self) -> Option<u64> {
+        (**self).generation()
+    }
+    fn set(&mut self, key: &Value, val: &Value) -> Result<()> {
+        (**self).set(key, val)
+    }
+    fn reg_symbol(&mut self, s: String) -> Value {
+        (**self).reg_symbol(s)
+    }
+    fn get_symbol(&self, key: Symbol) -> Result<String> {
+        (**self).get_symbol(key)
+    }
+    fn symbols_count(&self) -> usize {
+        (**self).symbols_count()
+    }
+    fn find_symbol(&self, name: &str) -> Option<Symbol> {
+        (**self).find_symbol(name)
+    }
+    fn namespace(&self) -> Option<String> {
+        (**self).namespace()
+    }
+    fn set_namespace(&mut self, ns: Option<&str>) -> Result<()> {
+        (**self).set_namespace(ns)
+    }
+    fn add_alias(&mut self, alias: &str, target: &str) -> Result<()> {
+        (**self).add_alias(alias, target)
+    }
+    fn alias(&self, alias: &str) -> Option<String> {
+        (**self).alias(alias)
+    }
+    fn has_capability(&self, cap: Capability) -> bool {
+        (**self).has_capability(cap)
+    }
+    fn enter_layer(&mut self) -> Result<()> {
+        (**self).enter_layer()
+    }
+    fn leave_layer(&mut self) {
+        (**self).leave_layer();
+    }
+    fn fork(&self) -> Option<Box<dyn Env + Send>> {
+        (**self).fork()
+    }
+}
+
+// How many single char edits turn a into b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+// The error of looking up an undefined global, suggesting the closest of the defined ones
+pub fn not_in_scope<'a>(name: &str, defined: impl Iterator<Item = &'a str>) -> ZapErr {
+    let closest = defined
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        // Not a name that shares none of its chars, as a one char operator would with any short one
+        .filter(|(distance, candidate)| {
+            *distance <= 2
+                && *distance < name.chars().count()
+                && *distance < candidate.chars().count()
+        })
+        .min();
+    match closest {
+        Some((_, candidate)) => error_msg(&format!(
+            "symbol '{}' not in scope. Did you mean '{}'?",
+            name, candidate
+        )),
+        None => error_msg(&format!("symbol '{}' not in scope.", name)),
+    }
+}
+
 pub struct SandboxEnv {
     globals: Scope,
     symbols: SymbolTable,
+    layers: Vec<Scope>, // The globals as they were when each layer was entered
+    namespace: Option<String>,
+    aliases: FxHashMap<(Option<String>, String), String>, // By namespace and alias
+    capabilities: Vec<Capability>,                        // None by default, so it stays hermetic
+    generation: u64,
 }
 
 impl Default for SandboxEnv {
@@ -70,13 +434,40 @@ impl Default for SandboxEnv {
         let mut this = SandboxEnv {
             globals: Scope::default(),
             symbols: SymbolTable::default(),
+            layers: Vec::new(),
+            namespace: None,
+            aliases: FxHashMap::default(),
+            capabilities: Vec::new(),
+            generation: new_generation(),
         };
 
         for s in symbols::DEFAULT_SYMBOLS {
             this.reg_symbol(String::from(s));
         }
 
-        this
+        this.with_core(true)
+    }
+}
+
+impl SandboxEnv {
+    // The core functions are there by default, with_core(false) leaves them out.
+    pub fn with_core(mut self, core: bool) -> Self {
+        for name in crate::core::names() {
+            if let Value::Symbol(id) = self.reg_symbol(String::from(name)) {
+                self.globals[id as usize] = None;
+            }
+        }
+        self.generation = new_generation();
+        if core {
+            // Setting a global of a SandboxEnv can't fail
+            crate::core::load(&mut self).unwrap();
+        }
+        self
+    }
+
+    pub fn with_capabilities(mut self, capabilities: Vec<Capability>) -> Self {
+        self.capabilities = capabilities;
+        self
     }
 }
 
@@ -86,15 +477,30 @@ impl Env for SandboxEnv {
         match unsafe { &self.globals.get_unchecked(id as usize) } {
             Some(val) => Ok(val.clone()),
             None => Err(match self.get_symbol(id) {
-                Ok(s) => error_msg(format!("symbol '{}' not in scope.", s).as_str()),
+                Ok(s) => not_in_scope(
+                    &s,
+                    self.symbols
+                        .iter()
+                        .filter(|(_, id)| self.globals[*id as usize].is_some())
+                        .map(|(s, _)| s),
+                ),
                 Err(err) => err,
             }),
         }
     }
 
+    fn lookup(&self, id: Symbol) -> Option<Value> {
+        self.globals.get(id as usize).cloned().flatten()
+    }
+
+    fn generation(&self) -> Option<u64> {
+        Some(self.generation)
+    }
+
     fn set(&mut self, key: &Value, val: &Value) -> Result<()> {
         if let Value::Symbol(s) = key {
             self.globals[*s as usize] = Some(val.clone());
+            self.generation = new_generation();
             Ok(())
         } else {
             Err(error_msg("Env set: only symbols can be used as keys."))
@@ -102,19 +508,63 @@ impl Env for SandboxEnv {
     }
 
     fn reg_symbol(&mut self, s: String) -> Value {
-        let len = self.symbols.len();
-        let id = self.symbols.entry(s).or_insert_with(|| {
+        let (id, new) = self.symbols.intern(s);
+        if new {
             self.globals.push(None);
-            len.try_into().unwrap()
-        });
-        Value::Symbol(*id)
+        }
+        Value::Symbol(id)
     }
 
     fn get_symbol(&self, id: Symbol) -> Result<String> {
-        self.symbols
-            .iter()
-            .find(|(_, v)| **v == id)
-            .map(|(k, _)| k.clone())
-            .ok_or_else(|| error_msg(format!("No known symbol for id={}", id).as_str()))
+        self.symbols.name(id)
+    }
+
+    fn symbols_count(&self) -> usize {
+        self.symbols.len()
+    }
+
+    fn find_symbol(&self, name: &str) -> Option<Symbol> {
+        self.symbols.id(name)
+    }
+
+    fn namespace(&self) -> Option<String> {
+        self.namespace.clone()
+    }
+
+    fn set_namespace(&mut self, ns: Option<&str>) -> Result<()> {
+        self.namespace = ns.map(String::from);
+        Ok(())
+    }
+
+    fn add_alias(&mut self, alias: &str, target: &str) -> Result<()> {
+        self.aliases.insert(
+            (self.namespace.clone(), String::from(alias)),
+            String::from(target),
+        );
+        Ok(())
+    }
+
+    fn alias(&self, alias: &str) -> Option<String> {
+        self.aliases
+            .get(&(self.namespace.clone(), String::from(alias)))
+            .cloned()
+    }
+
+    fn has_capability(&self, cap: Capability) -> bool {
+        self.capabilities.contains(&cap)
+    }
+
+    fn enter_layer(&mut self) -> Result<()> {
+        self.layers.push(self.globals.clone());
+        Ok(())
+    }
+
+    fn leave_layer(&mut self) {
+        if let Some(mut globals) = self.layers.pop() {
+            // The symbols registered in the layer are kept
+            globals.resize(self.globals.len(), None);
+            self.globals = globals;
+            self.generation = new_generation();
+        }
     }
 }
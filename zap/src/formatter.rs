@@ -0,0 +1,381 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::zap::{error_msg, Result, ZapErr};
+
+// The formatter reprints source code with a canonical layout. It has its own lossless parser,
+// since the reader drops the comments and interns the symbols.
+
+const MAX_WIDTH: usize = 80;
+const INDENT: usize = 2;
+
+// The forms whose first args stay on the line of the head, the others being a body.
+const BODY_FORMS: [(&str, usize); 20] = [
+    ("case", 1),
+    ("catch", 1),
+    ("compile-if", 1),
+    ("def", 1),
+    ("defmacro", 2),
+    ("defn", 2),
+    ("do", 0),
+    ("doseq-indexed", 1),
+    ("doto", 1),
+    ("fn", 1),
+    ("if", 1),
+    ("let", 1),
+    ("letfn", 1),
+    ("loop", 1),
+    ("try", 0),
+    ("unless", 1),
+    ("when", 1),
+    ("when-available", 1),
+    ("while", 1),
+    ("with-env", 0),
+];
+
+// The forms whose first arg is a list of bindings, broken in pairs.
+const BINDING_FORMS: [&str; 3] = ["doseq-indexed", "let", "loop"];
+
+enum Node {
+    Atom(String), // Symbols, numbers and strings, as written
+    List(Vec<Item>),
+    Vector(Vec<Item>),
+    Prefixed(&'static str, Box<Node>), // Reader macros
+    Comment(String),
+}
+
+struct Item {
+    node: Node,
+    blank_before: bool, // There was an empty line before it
+    trailing: bool,     // A comment on the same line as the previous item
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    // Skip the whitespaces, returning how many lines were ended
+    fn skip_whitespace(&mut self) -> usize {
+        let mut newlines = 0;
+        while let Some(ch) = self.chars.peek() {
+            match ch {
+                '\n' => newlines += 1,
+                ' ' | '\t' | '\r' | ',' => {}
+                _ => break,
+            }
+            self.chars.next();
+        }
+        newlines
+    }
+
+    // The items up to the closing char, or up to the end of the source without one
+    fn parse_items(&mut self, end: Option<char>) -> Result<Vec<Item>> {
+        let mut items: Vec<Item> = Vec::new();
+        loop {
+            let newlines = self.skip_whitespace();
+            let node = match self.chars.peek() {
+                None if end.is_some() => return Err(ZapErr::reader("Unexpected end of input.")),
+                None => return Ok(items),
+                Some(ch) if Some(*ch) == end => {
+                    self.chars.next();
+                    return Ok(items);
+                }
+                Some(ch @ (')' | ']')) => return Err(error_msg(&format!("Unexpected '{}'.", ch))),
+                Some(';') => {
+                    let mut comment = String::new();
+                    while let Some(ch) = self.chars.next_if(|ch| *ch != '\n') {
+                        comment.push(ch);
+                    }
+                    Node::Comment(comment.trim_end().to_string())
+                }
+                Some(_) => self.parse_node()?,
+            };
+            items.push(Item {
+                trailing: newlines == 0 && !items.is_empty(),
+                blank_before: newlines > 1,
+                node,
+            });
+        }
+    }
+
+    fn parse_node(&mut self) -> Result<Node> {
+        match self.chars.next() {
+            Some('(') => Ok(Node::List(self.parse_items(Some(')'))?)),
+            Some('[') => Ok(Node::Vector(self.parse_items(Some(']'))?)),
+            Some('\'') => self.parse_prefixed("'"),
+            Some('`') => self.parse_prefixed("`"),
+            Some('@') => self.parse_prefixed("@"),
+            Some('#') if self.chars.peek() == Some(&'(') => self.parse_prefixed("#"),
+            Some('~') if self.chars.next_if_eq(&'@').is_some() => self.parse_prefixed("~@"),
+            Some('~') => self.parse_prefixed("~"),
+            Some('"') => {
+                let mut atom = String::from('"');
+                let mut escaped = false;
+                loop {
+                    let ch = self
+                        .chars
+                        .next()
+                        .ok_or_else(|| ZapErr::reader("Unexpected end of input."))?;
+                    atom.push(ch);
+                    match ch {
+                        '"' if !escaped => return Ok(Node::Atom(atom)),
+                        '\\' => escaped = !escaped,
+                        _ => escaped = false,
+                    }
+                }
+            }
+            Some(first) => {
+                let mut atom = String::from(first);
+                while let Some(ch) = self.chars.next_if(|ch| {
+                    !ch.is_whitespace()
+                        && !matches!(ch, ',' | '(' | ')' | '[' | ']' | ';' | '"' | '\'')
+                }) {
+                    atom.push(ch);
+                }
+                Ok(Node::Atom(atom))
+            }
+            None => Err(ZapErr::reader("Unexpected end of input.")),
+        }
+    }
+
+    fn parse_prefixed(&mut self, prefix: &'static str) -> Result<Node> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            None | Some(')' | ']' | ';') => {
+                Err(error_msg(&format!("Expected a form after '{}'.", prefix)))
+            }
+            Some(_) => Ok(Node::Prefixed(prefix, Box::new(self.parse_node()?))),
+        }
+    }
+}
+
+// The node on a single line, if it can be.
+fn flat(node: &Node) -> Option<String> {
+    match node {
+        Node::Atom(atom) if atom.contains('\n') => None,
+        Node::Atom(atom) => Some(atom.clone()),
+        Node::Prefixed(prefix, node) => flat(node).map(|s| format!("{}{}", prefix, s)),
+        Node::List(items) => {
+            let items = items
+                .iter()
+                .map(|item| flat(&item.node))
+                .collect::<Option<Vec<_>>>()?;
+            Some(format!("({})", items.join(" ")))
+        }
+        Node::Vector(items) => {
+            let items = items
+                .iter()
+                .map(|item| flat(&item.node))
+                .collect::<Option<Vec<_>>>()?;
+            Some(format!("[{}]", items.join(" ")))
+        }
+        Node::Comment(_) => None,
+    }
+}
+
+fn is_symbol(node: &Node) -> bool {
+    match node {
+        Node::Atom(atom) => !atom.starts_with('"') && atom.parse::<f64>().is_err(),
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct Printer {
+    out: String,
+}
+
+impl Printer {
+    fn column(&self) -> usize {
+        let start = self.out.rfind('\n').map_or(0, |i| i + 1);
+        self.out[start..].chars().count()
+    }
+
+    fn newline(&mut self, indent: usize) {
+        self.out.push('\n');
+        self.out.extend(std::iter::repeat_n(' ', indent));
+    }
+
+    fn write(&mut self, node: &Node) {
+        if let Some(line) = flat(node) {
+            if self.column() + line.chars().count() <= MAX_WIDTH {
+                self.out.push_str(&line);
+                return;
+            }
+        }
+
+        match node {
+            Node::Atom(atom) | Node::Comment(atom) => self.out.push_str(atom),
+            Node::Prefixed(prefix, node) => {
+                self.out.push_str(prefix);
+                self.write(node);
+            }
+            Node::List(items) => self.write_list(items),
+            Node::Vector(items) => self.write_vector(items),
+        }
+    }
+
+    fn write_list(&mut self, items: &[Item]) {
+        let open = self.column();
+        self.out.push('(');
+
+        let Some(head) = items.first() else {
+            self.out.push(')');
+            return;
+        };
+        self.write(&head.node);
+
+        let head_name = match &head.node {
+            Node::Atom(atom) if is_symbol(&head.node) => Some(atom.as_str()),
+            _ => None,
+        };
+        let body_form = head_name.and_then(|name| {
+            BODY_FORMS
+                .iter()
+                .find(|(form, _)| *form == name)
+                .map(|(_, args)| *args)
+        });
+
+        let mut next = 1;
+        let indent = if let Some(mut args) = body_form {
+            // A named fn has its name before the params
+            if head_name == Some("fn") && items.len() > 2 && is_symbol(&items[1].node) {
+                args += 1;
+            }
+            let bindings = head_name.is_some_and(|name| BINDING_FORMS.contains(&name));
+            while next <= args && next < items.len() && !is_comment(&items[next]) {
+                self.out.push(' ');
+                match &items[next].node {
+                    Node::List(pairs) | Node::Vector(pairs) if bindings && next == args => {
+                        self.write_bindings(&items[next].node, pairs);
+                    }
+                    node => self.write(node),
+                }
+                next += 1;
+            }
+            open + INDENT
+        } else if head_name.is_some() && items.len() > 1 && !is_comment(&items[1]) {
+            // The args of a call are aligned on the first one
+            self.out.push(' ');
+            let align = self.column();
+            self.write(&items[1].node);
+            next = 2;
+            align
+        } else {
+            open + 1
+        };
+
+        self.write_rest(&items[next..], indent);
+
+        // The closing paren can't be commented out
+        if items.last().is_some_and(is_comment) {
+            self.newline(indent);
+        }
+        self.out.push(')');
+    }
+
+    // Data rather than a call, every item is aligned on the first one
+    fn write_vector(&mut self, items: &[Item]) {
+        let indent = self.column() + 1;
+        self.out.push('[');
+        if let Some((first, rest)) = items.split_first() {
+            self.write(&first.node);
+            self.write_rest(rest, indent);
+        }
+        if items.last().is_some_and(is_comment) {
+            self.newline(indent);
+        }
+        self.out.push(']');
+    }
+
+    // Every item on its own line, except trailing comments
+    fn write_rest(&mut self, items: &[Item], indent: usize) {
+        for item in items {
+            if item.trailing && is_comment(item) {
+                self.out.push(' ');
+            } else {
+                if item.blank_before {
+                    self.out.push('\n');
+                }
+                self.newline(indent);
+            }
+            self.write(&item.node);
+        }
+    }
+
+    // One binding per line, with its value
+    fn write_bindings(&mut self, node: &Node, items: &[Item]) {
+        if flat(node).is_some_and(|line| self.column() + line.chars().count() <= MAX_WIDTH) {
+            return self.out.push_str(&flat(node).unwrap());
+        }
+
+        let (open, close) = match node {
+            Node::Vector(_) => ('[', ']'),
+            _ => ('(', ')'),
+        };
+        let align = self.column() + 1;
+        self.out.push(open);
+        let mut bound = 0;
+        let mut after_comment = false;
+        for (i, item) in items.iter().enumerate() {
+            if is_comment(item) {
+                if item.trailing {
+                    self.out.push(' ');
+                } else if i > 0 {
+                    self.newline(align);
+                }
+                after_comment = true;
+            } else {
+                if bound % 2 == 1 && !after_comment {
+                    self.out.push(' ');
+                } else if i > 0 {
+                    if item.blank_before {
+                        self.out.push('\n');
+                    }
+                    self.newline(align);
+                }
+                bound += 1;
+                after_comment = false;
+            }
+            self.write(&item.node);
+        }
+        if after_comment {
+            self.newline(align);
+        }
+        self.out.push(close);
+    }
+}
+
+fn is_comment(item: &Item) -> bool {
+    matches!(item.node, Node::Comment(_))
+}
+
+// Reprint the source with the canonical layout. The comments are kept, and so are the empty
+// lines between forms, squashed to one.
+pub fn format_source(src: &str) -> Result<String> {
+    let mut parser = Parser {
+        chars: src.chars().peekable(),
+    };
+    let items = parser.parse_items(None)?;
+
+    let mut printer = Printer::default();
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            if item.trailing && is_comment(item) {
+                printer.out.push(' ');
+            } else {
+                printer.out.push('\n');
+                if item.blank_before {
+                    printer.out.push('\n');
+                }
+            }
+        }
+        printer.write(&item.node);
+    }
+
+    if !printer.out.is_empty() {
+        printer.out.push('\n');
+    }
+    Ok(printer.out)
+}
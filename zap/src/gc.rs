@@ -0,0 +1,197 @@
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, Weak};
+
+use crate::zap::{String, Value};
+use fxhash::{FxHashMap, FxHashSet};
+
+// Collects the cycles of atoms, which counting the references never frees: an atom holding a value
+// that leads back to itself keeps itself alive. The atoms are registered when they're made, and
+// collect_cycles looks for the ones that nothing but other atoms reaches. Their value is replaced
+// by nil, which breaks the cycles and lets the counts drop them.
+//
+// Only the lists, the vectors, the fns and the atoms are looked into. A native, a coroutine, a
+// channel or a task may hold anything, so what they reference is taken as reachable.
+
+struct Registry {
+    atoms: Vec<Weak<RwLock<Value>>>,
+    collected: usize,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    atoms: Vec::new(),
+    collected: 0,
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub atoms: usize,     // The atoms alive
+    pub collected: usize, // The atoms collect_cycles freed so far
+}
+
+impl HeapStats {
+    pub fn to_value(self) -> Value {
+        let row = |name: &str, n: usize| {
+            Value::List(Value::new_list(vec![
+                Value::Str(String::from(name)),
+                Value::Int(n.try_into().unwrap_or(i64::MAX)),
+            ]))
+        };
+        Value::List(Value::new_list(vec![
+            row("atoms", self.atoms),
+            row("collected", self.collected),
+        ]))
+    }
+}
+
+// A new atom the collector knows about
+pub fn atom(val: Value) -> Value {
+    let atom = Arc::new(RwLock::new(val));
+    let mut registry = REGISTRY.lock().unwrap();
+    // The dead ones are dropped before it grows, so it stays within twice the atoms alive
+    if registry.atoms.len() == registry.atoms.capacity() {
+        registry.atoms.retain(|atom| atom.strong_count() > 0);
+    }
+    registry.atoms.push(Arc::downgrade(&atom));
+    Value::Atom(atom)
+}
+
+pub fn heap_stats() -> HeapStats {
+    let registry = REGISTRY.lock().unwrap();
+    HeapStats {
+        atoms: registry
+            .atoms
+            .iter()
+            .filter(|atom| atom.strong_count() > 0)
+            .count(),
+        collected: registry.collected,
+    }
+}
+
+// Frees the atoms only reachable from cycles, and is how many there were. Meant to be called once
+// in a while by a long-running host, other threads can keep using the atoms meanwhile.
+pub fn collect_cycles() -> usize {
+    let atoms: Vec<Arc<RwLock<Value>>> = {
+        let mut registry = REGISTRY.lock().unwrap();
+        registry.atoms.retain(|atom| atom.strong_count() > 0);
+        registry.atoms.iter().filter_map(Weak::upgrade).collect()
+    };
+
+    let garbage: Vec<&Arc<RwLock<Value>>> = {
+        // Holding them all keeps a reference from being moved into an atom while they're counted
+        let contents: Vec<RwLockReadGuard<Value>> =
+            atoms.iter().map(|atom| atom.read().unwrap()).collect();
+        let mut graph = Graph::default();
+        for (atom, content) in atoms.iter().zip(&contents) {
+            // Less the one in atoms
+            graph.add_atom(atom, Arc::strong_count(atom) - 1, content);
+        }
+        let reachable = graph.reachable();
+        atoms
+            .iter()
+            .filter(|atom| !reachable.contains(&addr(atom)))
+            .collect()
+    };
+
+    for atom in &garbage {
+        let val = std::mem::take(&mut *atom.write().unwrap());
+        drop(val);
+    }
+    REGISTRY.lock().unwrap().collected += garbage.len();
+    garbage.len()
+}
+
+fn addr<T>(arc: &Arc<T>) -> usize {
+    Arc::as_ptr(arc) as *const () as usize
+}
+
+struct Node {
+    strong: usize,
+    internal: usize, // The references coming from the other nodes
+    children: Vec<usize>,
+}
+
+// The lists, vectors, fns and atoms reachable from the atoms, by address
+#[derive(Default)]
+struct Graph {
+    nodes: FxHashMap<usize, Node>,
+}
+
+impl Graph {
+    fn add_atom(&mut self, atom: &Arc<RwLock<Value>>, strong: usize, content: &Value) {
+        let mut children = Vec::new();
+        self.add_edges(content, &mut children);
+        self.nodes.insert(
+            addr(atom),
+            Node {
+                strong,
+                internal: 0,
+                children,
+            },
+        );
+    }
+
+    fn add_edges(&mut self, val: &Value, children: &mut Vec<usize>) {
+        match val {
+            // The atoms are all added by collect_cycles
+            Value::Atom(atom) => children.push(addr(atom)),
+            Value::List(list) | Value::Vector(list) => {
+                children.push(addr(list));
+                self.add_node(addr(list), Arc::strong_count(list), list);
+            }
+            Value::Func(func) | Value::Macro(func) => {
+                children.push(addr(func));
+                self.add_node(addr(func), Arc::strong_count(func), &func.locals);
+            }
+            _ => (),
+        }
+    }
+
+    fn add_node(&mut self, addr: usize, strong: usize, vals: &[Value]) {
+        if self.nodes.contains_key(&addr) {
+            return;
+        }
+        // Inserted before its children, for the cycles
+        self.nodes.insert(
+            addr,
+            Node {
+                strong,
+                internal: 0,
+                children: Vec::new(),
+            },
+        );
+        let mut children = Vec::new();
+        for val in vals {
+            self.add_edges(val, &mut children);
+        }
+        self.nodes.get_mut(&addr).unwrap().children = children;
+    }
+
+    // What's referenced from outside of the graph, and everything it leads to
+    fn reachable(mut self) -> FxHashSet<usize> {
+        let edges: Vec<usize> = self
+            .nodes
+            .values()
+            .flat_map(|node| node.children.iter().copied())
+            .collect();
+        for child in edges {
+            if let Some(node) = self.nodes.get_mut(&child) {
+                node.internal += 1;
+            }
+        }
+
+        let mut reachable = FxHashSet::default();
+        let mut todo: Vec<usize> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.strong > node.internal)
+            .map(|(addr, _)| *addr)
+            .collect();
+        while let Some(addr) = todo.pop() {
+            if reachable.insert(addr) {
+                if let Some(node) = self.nodes.get(&addr) {
+                    todo.extend(node.children.iter().copied());
+                }
+            }
+        }
+        reachable
+    }
+}
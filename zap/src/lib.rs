@@ -1,17 +1,32 @@
+pub mod bytecode;
+#[cfg(feature = "compact-values")]
+pub mod compact;
 #[warn(clippy::pedantic)]
 #[allow(clippy::missing_errors_doc)]
 pub mod compiler;
+pub mod core;
+pub mod decimal;
+pub mod diagnostic;
+pub mod engine;
 pub mod env;
+pub mod formatter;
+#[cfg(feature = "gc")]
+pub mod gc;
+pub mod output;
+pub mod prelude;
 pub mod printer;
 pub mod reader;
+pub mod time;
+pub mod verifier;
 pub mod vm;
 pub mod zap;
 
 pub use crate::zap::*;
 
 //#[cfg(debug_assertions)]
+#[doc(hidden)]
 pub mod tests {
-    use crate::compiler::compile;
+    use crate::compiler::{compile, compile_with, Extensions};
     use crate::env::SandboxEnv;
     use crate::reader::Reader;
     use crate::vm;
@@ -23,8 +38,9 @@ pub mod tests {
         reader.tokenize(src);
         reader.flush_token();
 
+        let extensions = Extensions::new();
         let mut ast = reader.read_ast(&mut env)?;
-        let mut chunk = compile(ast.unwrap())?;
+        let mut chunk = compile_with(ast.unwrap(), &extensions, &mut env)?;
         let mut res = vm::run(chunk, &mut env)?;
 
         loop {
@@ -32,11 +48,19 @@ pub mod tests {
             if ast.is_none() {
                 return Ok(zap::String::from(res.to_string(&mut env)));
             }
-            chunk = compile(ast.unwrap())?;
+            chunk = compile_with(ast.unwrap(), &extensions, &mut env)?;
             res = vm::run(chunk, &mut env)?;
         }
     }
 
+    pub fn compile_exp(src: &str) -> std::sync::Arc<vm::Chunk> {
+        let mut env = SandboxEnv::default();
+        let mut reader = Reader::new();
+        reader.tokenize(src);
+        reader.flush_token();
+        compile(reader.read_ast(&mut env).unwrap().unwrap(), &mut env).unwrap()
+    }
+
     pub fn test_exp(src: &str, expected: &str) {
         let env = SandboxEnv::default();
         assert_eq!(run_exp(src, env).unwrap(), expected);
@@ -49,7 +73,10 @@ pub mod tests {
 
     #[test]
     fn value_size() {
-        assert_eq!(std::mem::size_of::<zap::Value>(), 32)
+        #[cfg(not(feature = "compact-values"))]
+        assert_eq!(std::mem::size_of::<zap::Value>(), 32);
+        #[cfg(feature = "compact-values")]
+        assert_eq!(std::mem::size_of::<zap::Value>(), 16);
     }
 
     #[test]
@@ -88,12 +115,71 @@ pub mod tests {
     #[test]
     fn lookup_symbol() {
         let env = SandboxEnv::default();
+        #[cfg(not(feature = "gc"))]
+        assert_eq!(
+            run_exp("gg", env),
+            Err(zap::error_msg("symbol 'gg' not in scope."))
+        );
+        // The natives of the gc feature are suggested like the others
+        #[cfg(feature = "gc")]
         assert_eq!(
             run_exp("gg", env),
-            Err(zap::ZapErr::Msg("symbol 'gg' not in scope.".to_string()))
+            Err(zap::error_msg(
+                "symbol 'gg' not in scope. Did you mean 'gc'?"
+            ))
         );
     }
 
+    #[test]
+    fn lookup_cache() {
+        use crate::prelude::{Engine, Env, Value};
+        use zap::{Result, String, Symbol};
+
+        // Counting the lookups that reach it
+        #[derive(Default)]
+        struct Counting(SandboxEnv, std::cell::Cell<usize>);
+
+        impl Env for Counting {
+            fn get_by_id(&self, id: Symbol) -> Result<Value> {
+                self.1.set(self.1.get() + 1);
+                self.0.get_by_id(id)
+            }
+            fn generation(&self) -> Option<u64> {
+                self.0.generation()
+            }
+            fn set(&mut self, key: &Value, val: &Value) -> Result<()> {
+                self.0.set(key, val)
+            }
+            fn reg_symbol(&mut self, s: String) -> Value {
+                self.0.reg_symbol(s)
+            }
+            fn get_symbol(&self, key: Symbol) -> Result<String> {
+                self.0.get_symbol(key)
+            }
+            fn symbols_count(&self) -> usize {
+                self.0.symbols_count()
+            }
+        }
+
+        let mut engine = Engine::with_env(Counting::default());
+        engine.set_inline_limit(0);
+        engine
+            .eval_str("(do (def step 2) (def add (fn (x) (+ x step))))")
+            .unwrap();
+        engine
+            .eval_str("(def run (fn (n acc) (if (= n 0) acc (run (- n 1) (add acc)))))")
+            .unwrap();
+        engine.env_mut().1.set(0);
+        assert_eq!(engine.eval_str("(run 100 0)"), Ok(Value::Int(200)));
+        // Each LookUp asks once, run from the top and from itself, add and step, then the caches
+        // have them
+        assert_eq!(engine.env_mut().1.get(), 4);
+
+        // Setting a global is seen by the next lookup
+        engine.eval_str("(def step 3)").unwrap();
+        assert_eq!(engine.eval_str("(run 10 0)"), Ok(Value::Int(30)));
+    }
+
     #[test]
     fn eval_def() {
         test_exp("(def x 3)", "3");
@@ -102,6 +188,7 @@ pub mod tests {
     #[test]
     fn eval_fn() {
         test_exp("((fn (x) x) 4)", "4");
+        test_exp("(+ ((fn (x y) y) 4 5) 1)", "6");
     }
 
     #[test]
@@ -118,6 +205,8 @@ pub mod tests {
         test_exp("(= 1 2)", "false");
         test_exp("(= nil false)", "false");
         test_exp("(= false false)", "true");
+        test_exp("(let (x 1 y 2) (= x y))", "false");
+        test_exp("(let (x 1 y 1) (= x y))", "true");
     }
 
     #[test]
@@ -130,6 +219,135 @@ pub mod tests {
     #[test]
     fn eval_closure() {
         test_exp("(let (n 2 f (fn (x) (+ x n))) (f 3))", "5");
+        test_exp("(((fn (x) (fn (y) (+ x y))) 1) 2)", "3");
+        test_exp("((((fn (x) (fn (y) (fn (z) (+ x y z)))) 1) 2) 3)", "6");
+        test_exp(
+            "(def mk (fn (x) (fn (y) (+ x y)))) (def a (mk 10)) (+ (a 1) (a 2))",
+            "23",
+        );
+        test_exp("((fn (a) (let (b 1) (+ b ((fn () a))))) 5)", "6");
+        test_exp("(let (x 1) (let (f (fn () x) y 2) (+ (f) y)))", "3");
+    }
+
+    #[test]
+    fn eval_named_fn() {
+        test_exp(
+            "((fn sum (n acc) (if (= n 0) acc (sum (+ n -1) (+ acc n)))) 100 0)",
+            "5050",
+        );
+        test_exp(
+            "(def f (fn count (n) (if (= n 0) 0 (+ 1 (count (+ n -1)))))) (f 10)",
+            "10",
+        );
+        test_exp(
+            "((fn me (n & xs) (if (= n 0) xs (me (+ n -1) n))) 2 9)",
+            "(1)",
+        );
+        test_exp("((fn outer (n) ((fn () (if (= n 0) 7 (outer 0))))) 3)", "7");
+        let env = SandboxEnv::default();
+        assert!(run_exp("((fn f () 1)) f", env).is_err());
+    }
+
+    #[test]
+    fn tailcall_self() {
+        let fn_ops = |src| {
+            let chunk = compile_exp(src);
+            chunk
+                .consts
+                .iter()
+                .find_map(|val| match val {
+                    zap::Value::Func(f) => Some(f.chunk.ops.clone()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let self_call = |ops: Vec<vm::Op>| ops.contains(&vm::Op::TailcallSelf(1));
+
+        assert!(self_call(fn_ops(
+            "(def rec (fn (x) (if (= x 3) x (rec (+ x 1)))))"
+        )));
+        assert!(self_call(fn_ops("(fn me (x) (me x))")));
+        // Shadowed by a param, or not in tail position
+        assert!(!self_call(fn_ops("(def rec (fn (rec) (rec 1)))")));
+        assert!(!self_call(fn_ops("(def rec (fn (x) (+ 1 (rec x))))")));
+        assert!(!self_call(fn_ops(
+            "(def rec (fn (x) ((fn (y) (rec y)) x)))"
+        )));
+
+        test_exp(
+            "(def rec (fn (x) (if (= x 100000) \"done\" (rec (+ x 1))))) (rec 0)",
+            "\"done\"",
+        );
+        test_exp(
+            "(def f (let (step 2) (fn (n acc) (if (= n 0) acc (f (+ n -1) (+ acc step)))))) (f 5 0)",
+            "10",
+        );
+        test_exp(
+            "(def f (fn (n & xs) (if (= n 0) xs (f (+ n -1) n)))) (f 3)",
+            "(1)",
+        );
+        let env = SandboxEnv::default();
+        assert!(run_exp("(def f (fn (x) (if (= x 0) 0 (f)))) (f 1)", env).is_err());
+    }
+
+    #[test]
+    fn eval_variadic() {
+        test_exp("((fn (a & rest) rest) 1 2 3)", "(2 3)");
+        test_exp("((fn (& rest) rest))", "()");
+        test_exp("((fn (a b & rest) (+ a b)) 1 2)", "3");
+        test_exp(
+            "(def f (fn (& xs) xs)) (def g (fn (x) (f x 2))) (g 1)",
+            "(1 2)",
+        );
+        test_exp("(let (n 1 f (fn (& xs) (+ n 1))) (f 5 6))", "2");
+        assert_eq!(
+            run_exp("((fn (a b & rest) a) 1)", SandboxEnv::default()),
+            Err(zap::ZapErr::Arity {
+                name: None,
+                expected: zap::Arity::AtLeast(2),
+                got: 1,
+                span: None,
+                trace: Vec::new(),
+            })
+        );
+        assert_eq!(
+            run_exp("((fn (a b) a) 1)", SandboxEnv::default()),
+            Err(zap::ZapErr::Arity {
+                name: None,
+                expected: zap::Arity::Exactly(2),
+                got: 1,
+                span: None,
+                trace: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn native_arity() {
+        use crate::env::Env;
+
+        fn first(args: &[zap::Value]) -> zap::Result<zap::Value> {
+            Ok(args[0].clone())
+        }
+
+        let mut env = SandboxEnv::default();
+        env.reg_fn_arity("first", zap::Arity::AtLeast(1), first)
+            .unwrap();
+        assert_eq!(run_exp("(first 1 2)", env).unwrap(), "1");
+
+        let mut env = SandboxEnv::default();
+        env.reg_fn_arity("first", zap::Arity::AtLeast(1), first)
+            .unwrap();
+        assert_eq!(
+            run_exp("(first)", env),
+            Err(zap::ZapErr::Arity {
+                name: Some("first".to_string()),
+                expected: zap::Arity::AtLeast(1),
+                got: 0,
+                span: None,
+                trace: Vec::new(),
+            })
+        );
     }
 
     #[test]
@@ -137,6 +355,8 @@ pub mod tests {
         test_exp("'(1 2 3)", "(1 2 3)");
         test_exp("(quote (1 2 3))", "(1 2 3)");
         test_exp("(quote (+ 2 2 2))", "(+ 2 2 2)");
+        test_exp("'foo", "foo");
+        test_exp("'(a (b \"c\") ())", "(a (b \"c\") ())");
     }
 
     #[test]
@@ -145,4 +365,2575 @@ pub mod tests {
         test_exp("(quasiquote (1 2 3))", "(1 2 3)");
         test_exp("(quasiquote (+ 2 2 2))", "(+ 2 2 2)");
     }
+
+    #[test]
+    fn eval_when_unless() {
+        test_exp("(when true 1 2 3)", "3");
+        test_exp("(when false 1 2 3)", "nil");
+        test_exp("(unless false (def x 1) (+ x 1))", "2");
+        test_exp("(unless true 1)", "nil");
+        test_exp("(when true)", "nil");
+        test_exp(
+            "(loop (i 0) (when (= i 3) (def done i)) (unless (= i 3) (recur (+ i 1)))) done",
+            "3",
+        );
+    }
+
+    #[test]
+    fn eval_case() {
+        let describe = "(def describe (fn (x) (case x 1 \"one\" \"two\" 2 'a 'sym nil \"nil\" 1 \"first wins\" \"other\")))";
+        test_exp(&format!("{} (describe 1)", describe), "\"one\"");
+        test_exp(&format!("{} (describe \"two\")", describe), "2");
+        test_exp(&format!("{} (describe 'a)", describe), "sym");
+        test_exp(&format!("{} (describe nil)", describe), "\"nil\"");
+        test_exp(&format!("{} (describe -0)", describe), "\"other\"");
+        test_exp("(case 0 -0 'zero)", "zero");
+        test_exp("(case 3 1 2)", "nil");
+        test_exp("(case 3)", "nil");
+
+        // Constant keys are dispatched by a jump table
+        let chunk = compile_exp("(case x 1 'a 2 'b 'c)");
+        assert!(chunk.ops.contains(&vm::Op::Switch(0)));
+        assert!(!chunk.ops.iter().any(|op| matches!(op, vm::Op::EqConst(_))));
+        assert_eq!(chunk.tables.len(), 1);
+
+        // Others are compared in order
+        test_exp("(let (a 1 b 2) (case (+ a 1) a 'a b 'b 'none))", "b");
+        let chunk = compile_exp("(case x y 1 2)");
+        assert!(chunk.tables.is_empty());
+
+        // The branches are in tail position
+        test_exp(
+            "(def count-down (fn (n) (case n 0 'done (count-down (+ n -1))))) (count-down 100)",
+            "done",
+        );
+        test_exp("(loop (i 0) (case i 3 'done (recur (+ i 1))))", "done");
+    }
+
+    #[test]
+    fn eval_defmacro() {
+        let swap = "(defmacro swap-if \"if with its branches swapped\" (c a b) `(if ~c ~b ~a))";
+        test_exp(&format!("{} (swap-if false 1 2)", swap), "1");
+        test_exp(&format!("{} swap-if", swap), "<Macro>");
+
+        // The args are given unevaluated, and the expansion can use other macros
+        test_exp(
+            "(defmacro quoted (x) `(quote ~x)) (defmacro twice (& body) `(quoted (~@body ~@body))) (twice a b)",
+            "(a b a b)",
+        );
+
+        // A local shadows a macro
+        test_exp("(defmacro m (x) 1) ((fn (m) (m 2)) (fn (x) x))", "2");
+
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(defmacro bad (x) (+ x 1)) (bad \"a\")", env),
+            Err(zap::ZapErr::compile(
+                "Error expanding 'bad': Can't add \"a\" + 1"
+            ))
+        );
+        let env = SandboxEnv::default();
+        assert!(run_exp("(defmacro (x) x)", env).is_err());
+    }
+
+    #[test]
+    fn eval_defn() {
+        test_exp("(defn add (a b) (+ a b)) (add 1 2)", "3");
+        test_exp("(defn f \"Defines y\" (x) (def y 1) (+ x y)) (f 2)", "3");
+        test_exp("(defn nothing ()) (nothing)", "nil");
+        test_exp(
+            "(defn count (n acc) (if (= n 0) acc (count (+ n -1) (+ acc n)))) (count 100 0)",
+            "5050",
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(defn f x)", env),
+            Err(zap::ZapErr::compile(
+                "A defn form must have a name and a list of params"
+            ))
+        );
+    }
+
+    #[test]
+    fn eval_threading() {
+        test_exp("(-> 1 (+ 2) (+ 3))", "6");
+        test_exp("(-> 5)", "5");
+        let pair = "(defn pair (a b) `(~a ~b))";
+        test_exp(&format!("{} (-> 1 (pair 2) (pair 3))", pair), "((1 2) 3)");
+        test_exp(&format!("{} (->> 1 (pair 2) (pair 3))", pair), "(3 (2 1))");
+        test_exp("(defn inc (x) (+ x 1)) (-> 1 inc inc)", "3");
+        test_exp("(->> '(2) (concat '(1)) (concat '(0)))", "(0 1 2)");
+        test_exp("(-> \"a\" (concat \"b\") (concat \"c\"))", "\"abc\"");
+
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(->)", env),
+            Err(zap::ZapErr::compile(
+                "A -> form must have a value to thread"
+            ))
+        );
+    }
+
+    #[test]
+    fn eval_nil_punning() {
+        test_exp("(some-> 1 (+ 2) (+ 3))", "6");
+        test_exp("(some-> nil (+ 2))", "nil");
+        test_exp("(defn none (x) nil) (some-> 1 none (+ 2))", "nil");
+        test_exp("(some->> '(1) (concat '(0)) (get nil))", "nil");
+        test_exp("(some->> '(1) (concat '(0)) (get 0))", "nil");
+        test_exp("(some->> 1 (get '(a b)))", "b");
+
+        test_exp("(or)", "nil");
+        test_exp("(or nil)", "nil");
+        test_exp("(or nil false 2 3)", "2");
+        test_exp("(or nil false)", "false");
+        test_exp("(let (x nil) (or x 'default))", "default");
+        test_exp(
+            "(defn count (n) (or (if (= n 0) 'done nil) (count (+ n -1)))) (count 1000)",
+            "done",
+        );
+    }
+
+    #[test]
+    fn eval_letfn() {
+        let even_odd = "(letfn ((even? (n) (if (= n 0) true (odd? (+ n -1))))
+                                (odd? (n) (if (= n 0) false (even? (+ n -1)))))";
+        test_exp(&format!("{} (even? 10))", even_odd), "true");
+        test_exp(&format!("{} (odd? 7))", even_odd), "true");
+        test_exp(&format!("{} (even? 7))", even_odd), "false");
+
+        // The functions close over the locals around them, and can call themselves
+        test_exp(
+            "(let (step -2) (letfn ((down (n) (if (= n 0) 'done (down (+ n step))))) (down 6)))",
+            "done",
+        );
+        test_exp(
+            "(letfn ((f (x) (println x) (g x)) (g (x) (+ x 1))) (f 1))",
+            "2",
+        );
+        test_exp("(letfn () 1)", "1");
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(letfn (f) 1)", env),
+            Err(zap::ZapErr::compile(
+                "A letfn form must have a list of (name (params) body...)"
+            ))
+        );
+    }
+
+    #[test]
+    fn eval_namespaces() {
+        test_exp("(ns app) (def x 1) x", "1");
+        test_exp("(ns a) (def x 1) (ns b) (def x 2) (+ a/x b/x x)", "5");
+        test_exp("(def x 1) (ns app) x", "1");
+        test_exp("(ns app) (str 1 2)", "\"12\"");
+
+        // A def in the namespace shadows the top global, and can be defined after its use
+        test_exp("(ns app) (defn str (x) 'mine) (str 1)", "mine");
+        test_exp("(ns app) (defn f () (g)) (defn g () 7) (f)", "7");
+        test_exp("(ns app) (defmacro m (x) `(quote ~x)) (m y)", "y");
+        test_exp(
+            "(ns app) (defn down (n) (if (= n 0) 'done (down (+ n -1)))) (down 3)",
+            "done",
+        );
+
+        test_exp("(ns lib) (def x 1) (ns app) (require 'lib) lib/x", "1");
+        test_exp(
+            "(ns my.lib) (def x 1) (defn helper (n) (+ n 10)) (defmacro q (v) `(quote ~v))
+             (ns app) (require '(my.lib :as l :refer (helper q))) (+ l/x (helper 1) (l/helper 2))",
+            "24",
+        );
+        test_exp(
+            "(ns my.lib) (defmacro q (v) `(quote ~v)) (ns app) (require (my.lib :refer (q))) (q y)",
+            "y",
+        );
+        // The aliases belong to the namespace that required them
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp(
+                "(ns lib) (def x 1) (ns app) (require '(lib :as l)) (ns other) l/x",
+                env
+            ),
+            Err(zap::error_msg(
+                "symbol 'l/x' not in scope. Did you mean 'lib/x'?"
+            ))
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp(
+                "(ns lib) (def x 1) (ns app) (require '(lib :refer (y)))",
+                env
+            ),
+            Err(zap::ZapErr::compile(
+                "'y' is not defined in namespace 'lib'"
+            ))
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(ns lib) (def x 1) (ns app) (require '(lib :as))", env),
+            Err(zap::ZapErr::compile(
+                "A require form takes :as alias and :refer (names...)"
+            ))
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(ns app) (require 'lib)", env),
+            Err(zap::ZapErr::compile("Namespace 'lib' is not loaded"))
+        );
+    }
+
+    #[test]
+    fn compile_time_queries() {
+        use crate::env::Capability;
+
+        test_exp("(when-available 'println 1 2)", "2");
+        test_exp("(when-available 'http-get (http-get \"url\"))", "nil");
+        test_exp(
+            "(compile-if (available? 'http-get) (http-get 1) 'offline)",
+            "offline",
+        );
+        test_exp(
+            "(def http-get (fn (x) x)) (compile-if (available? http-get) (http-get 1))",
+            "1",
+        );
+        test_exp("(let (f 1) (when-available 'f f))", "1");
+        test_exp(
+            "(compile-if (capability? 'files) (load \"x.zap\") 'sandboxed)",
+            "sandboxed",
+        );
+        test_exp(
+            "(ns lib) (def x 1) (ns app) (require '(lib :refer (x))) (when-available 'x x)",
+            "1",
+        );
+
+        let env = SandboxEnv::default().with_capabilities(vec![Capability::Files]);
+        assert_eq!(
+            run_exp("(compile-if (capability? 'files) 'files 'none)", env),
+            Ok(zap::String::from("files"))
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(compile-if (capability? 'network) 1)", env),
+            Err(zap::ZapErr::compile("Unknown capability 'network'"))
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(compile-if (defined? 'x) 1)", env),
+            Err(zap::ZapErr::compile(
+                "A compile-if test is (available? 'name) or (capability? 'name)"
+            ))
+        );
+        // Without the core functions, println isn't there to call
+        let env = SandboxEnv::default().with_core(false);
+        assert_eq!(
+            run_exp("(when-available 'println (println 1))", env),
+            Ok("nil".into())
+        );
+    }
+
+    #[test]
+    fn lookup_suggestion() {
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(prinln 1)", env),
+            Err(zap::error_msg(
+                "symbol 'prinln' not in scope. Did you mean 'println'?"
+            ))
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(def total 1) totl", env),
+            Err(zap::error_msg(
+                "symbol 'totl' not in scope. Did you mean 'total'?"
+            ))
+        );
+        // Nothing close enough
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(frobnicate 1)", env),
+            Err(zap::error_msg("symbol 'frobnicate' not in scope."))
+        );
+    }
+
+    #[test]
+    fn load_file() {
+        use crate::env::Capability;
+
+        let path = std::env::temp_dir().join(format!("zap-load-{}.zap", std::process::id()));
+        std::fs::write(&path, "(ns lib) (defn twice (x) (+ x x)) (twice 2)").unwrap();
+        let load = format!("(load {:?})", path.to_str().unwrap());
+
+        let files = || SandboxEnv::default().with_capabilities(vec![Capability::Files]);
+        assert_eq!(run_exp(&load, files()).unwrap(), "4");
+        // Its namespace doesn't outlive it
+        assert_eq!(
+            run_exp(&format!("{} (def x 1) (+ (lib/twice 3) x)", load), files()).unwrap(),
+            "7"
+        );
+        assert!(run_exp("(load \"/nonexistent.zap\")", files()).is_err());
+
+        std::fs::write(&path, "(+ 1 \"a\")").unwrap();
+        assert!(matches!(
+            run_exp(&load, files()),
+            Err(err) if err.message().starts_with("Error loading")
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            run_exp(&load, SandboxEnv::default()),
+            Err(zap::error_msg("Loading files is not allowed in this env."))
+        );
+    }
+
+    #[test]
+    fn eval_set() {
+        test_exp("(let (x 1) (do (set! x (+ x 1)) x))", "2");
+        test_exp("(let (x 1) (set! x 5))", "5");
+        test_exp("((fn (n) (set! n (+ n 10)) n) 1)", "11");
+        test_exp(
+            "(loop (i 0 acc 0) (if (= i 3) acc (do (set! acc (+ acc 10)) (recur (+ i 1) acc))))",
+            "30",
+        );
+        test_exp(
+            "(let (sum 0) (do (doseq-indexed (i x '(1 2 3)) (set! sum (+ sum x))) sum))",
+            "6",
+        );
+
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(def g 1) (set! g 2)", env),
+            Err(zap::ZapErr::compile("set! can only change a local"))
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(let (x 1) ((fn () (set! x 2))))", env),
+            Err(zap::ZapErr::compile(
+                "set! cannot change a local of an enclosing fn"
+            ))
+        );
+        let env = SandboxEnv::default();
+        assert!(run_exp("(let (x 1) (set! x))", env).is_err());
+    }
+
+    #[test]
+    fn eval_with_env() {
+        test_exp("(def x 1) (with-env (def x 2) (def y 3) (+ x y))", "5");
+        test_exp("(def x 1) (with-env (def x 2)) x", "1");
+        test_exp("(with-env)", "nil");
+        test_exp(
+            "(def x 1) (try (with-env (def x 2) (with-env (throw 'boom))) (catch e x))",
+            "1",
+        );
+        test_exp(
+            "(def x 1) (with-env (def x 2) (try (with-env (throw 'boom)) (catch e x)))",
+            "2",
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(with-env (def y 3)) y", env),
+            Err(zap::error_msg("symbol 'y' not in scope."))
+        );
+
+        // The layers are left when an error ends the evaluation
+        let mut engine = crate::prelude::Engine::new();
+        engine.eval_str("(def x 1)").unwrap();
+        assert!(engine.eval_str("(with-env (def x 2) (+ x nil))").is_err());
+        assert_eq!(engine.eval_str("x"), Ok(zap::Value::Int(1)));
+    }
+
+    #[test]
+    fn eval_try() {
+        test_exp("(try (+ 1 2) (catch e 'caught))", "3");
+        test_exp(
+            "(try (throw 'oops) 1 (catch e `(caught ~e)))",
+            "(caught oops)",
+        );
+
+        // Errors are caught with their message, from the frames they unwind
+        test_exp(
+            "(def f (fn (x) (+ x 1))) (try (f \"a\") (catch e e))",
+            "\"Can't add \\\"a\\\" + 1\"",
+        );
+        test_exp("(try (missing 1) (catch e 'no))", "no");
+
+        // The stack is back to where the try began, in a loop or a call
+        test_exp(
+            "(def safe (fn (x) (+ 10 (try (+ 1 (throw x)) (catch e e))))) (safe 5)",
+            "15",
+        );
+        test_exp(
+            "(loop (i 0 acc 0) (if (= i 3) acc (recur (+ i 1) (+ acc (try (throw i) (catch e e))))))",
+            "3",
+        );
+
+        // Nested, rethrown, and uncaught
+        test_exp(
+            "(try (try (throw 1) (catch e (throw (+ e 1)))) (catch e e))",
+            "2",
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(throw \"boom\")", env),
+            Err(zap::error_msg("boom"))
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(run_exp("(throw 'a)", env), Err(zap::error_msg("a")));
+
+        let env = SandboxEnv::default();
+        assert!(run_exp("(loop (i 0) (try (recur 1) (catch e e)))", env).is_err());
+        let env = SandboxEnv::default();
+        assert!(run_exp("(try 1)", env).is_err());
+    }
+
+    #[test]
+    fn uncaught_errors() {
+        use crate::prelude::{Engine, Error, Value};
+        use std::sync::{Arc, Mutex};
+
+        // What was defined before the error stays
+        let mut engine = Engine::new();
+        assert!(engine
+            .eval_str("(def a 1) (def f (fn () (def b 2) (+ 1 nil))) (f) (def c 3)")
+            .is_err());
+        assert_eq!(engine.eval_str("(+ a b)"), Ok(Value::Int(3)));
+        assert!(engine.eval_str("c").is_err());
+
+        // A catcher takes the error of the form, and the next ones are evaluated
+        let caught = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        let errors = Arc::clone(&caught);
+        engine.catch(Box::new(move |err: &Error| {
+            errors.lock().unwrap().push(err.message().to_string());
+            Some(Value::Int(-1))
+        }));
+        assert_eq!(
+            engine.eval_str("(def a 1) (def b (+ a (throw \"boom\"))) (def c 3) (+ a c)"),
+            Ok(Value::Int(4))
+        );
+        assert_eq!(engine.eval_str("(+ 1 nil)"), Ok(Value::Int(-1)));
+        assert_eq!(engine.eval_str("b"), Ok(Value::Int(-1)));
+
+        // A try catches first, and a None lets the error through
+        assert_eq!(
+            engine.eval_str("(try (throw 'inner) (catch e e))"),
+            engine.eval_str("'inner")
+        );
+        let caught = caught.lock().unwrap();
+        assert_eq!(caught.len(), 3);
+        assert_eq!(caught[0], "boom");
+        engine.catch(Box::new(|_: &Error| None));
+        assert!(engine.eval_str("(throw 'out)").is_err());
+    }
+
+    #[test]
+    fn eval_fn_implicit_do() {
+        test_exp("(def f (fn (x) (def y 1) (+ x y))) (f 2)", "3");
+        test_exp(
+            "((fn count (n acc) (def last n) (if (= n 0) acc (count (+ n -1) (+ acc n)))) 3 0)",
+            "6",
+        );
+        test_exp("((fn (x)) 1)", "nil");
+
+        let env = SandboxEnv::default();
+        assert!(run_exp("(fn)", env).is_err());
+        let env = SandboxEnv::default();
+        assert!(run_exp("(fn name)", env).is_err());
+    }
+
+    #[test]
+    fn eval_while() {
+        test_exp("(while false 1)", "nil");
+        test_exp("(def x true) (while x (def x false)) x", "false");
+        test_exp(
+            "(def i 0) (def done false) (while (if done false true) (def i (+ i 1)) (def done (= i 5))) i",
+            "5",
+        );
+    }
+
+    #[test]
+    fn eval_loop() {
+        test_exp("(loop (i 0) (if (= i 5) i (recur (+ i 1))))", "5");
+        test_exp(
+            "(loop (i 100 acc 0) (if (= i 0) acc (recur (+ i -1) (+ acc i))))",
+            "5050",
+        );
+        test_exp(
+            "(+ 1 (loop (i 0) (if (= i 3) (loop (j 0) (if (= j 2) (+ i j) (recur (+ j 1)))) (recur (+ i 1)))))",
+            "6",
+        );
+        test_exp(
+            "((fn (n) (loop (i 0 acc '()) (if (= i n) acc (recur (+ i 1) `(~i ~@acc))))) 3)",
+            "(2 1 0)",
+        );
+        let env = SandboxEnv::default();
+        assert!(run_exp("(loop (i 0) (+ 1 (recur i)))", env).is_err());
+        let env = SandboxEnv::default();
+        assert!(run_exp("(loop (i 0) (recur))", env).is_err());
+        let env = SandboxEnv::default();
+        assert!(run_exp("(loop (i 0) ((fn () (recur 1))))", env).is_err());
+    }
+
+    #[test]
+    fn eval_doto() {
+        test_exp("(doto 4)", "4");
+        test_exp(
+            "(def f (fn (x y) (def seen (+ x y)))) (doto 1 (f 2)) seen",
+            "3",
+        );
+        test_exp("(let (n 5) (doto n (+ 1)))", "5");
+    }
+
+    #[test]
+    fn eval_doseq_indexed() {
+        test_exp("(doseq-indexed (i x '()) x)", "nil");
+        test_exp(
+            "(def sum 0) (doseq-indexed (i x '(10 20 30)) (def sum (+ sum i x))) sum",
+            "63",
+        );
+    }
+
+    #[test]
+    fn call_many_args() {
+        use crate::env::Env;
+
+        fn count(args: &[zap::Value]) -> zap::Result<zap::Value> {
+            Ok(zap::Value::Int(args.len() as i64))
+        }
+
+        let call = |argc: usize| format!("(count{})", " 1".repeat(argc));
+
+        let mut env = SandboxEnv::default();
+        env.reg_fn("count", count).unwrap();
+        assert_eq!(run_exp(&call(300), env).unwrap(), "300");
+
+        let mut env = SandboxEnv::default();
+        env.reg_fn("count", count).unwrap();
+        assert_eq!(
+            run_exp(&call(65535), env),
+            Err(zap::ZapErr::compile(
+                "A call cannot have more than 65534 arguments."
+            ))
+        );
+    }
+
+    #[test]
+    fn quasiquote_const_data() {
+        let data: std::string::String = (0..1000).map(|n| format!(" ({} \"{}\")", n, n)).collect();
+        let chunk = compile_exp(&format!("`({})", data));
+        assert_eq!(chunk.ops, vec![vm::Op::Push(0), vm::Op::Return]);
+        assert_eq!(chunk.consts.len(), 1);
+    }
+
+    #[test]
+    fn const_dedup() {
+        // Equal consts share a slot, whatever their type, and an integer isn't equal to a float
+        let chunk = compile_exp("(f 1 1.0 \"a\" \"a\" 'a 'a nil nil true 'a 1)");
+        assert_eq!(chunk.consts.len(), 6);
+        // Those that are == but can be told apart are kept apart
+        test_exp("(do (def a -0.0) (def b 0.0) (/ 1.0 b))", "inf");
+        test_exp("(do (def a 1.0M) 1.00M)", "1.00M");
+
+        let many: std::string::String = (0..20_000).map(|n| format!(" {} {}", n, n)).collect();
+        let chunk = compile_exp(&format!("(do{})", many));
+        assert_eq!(chunk.consts.len(), 20_000);
+        assert_eq!(chunk.consts[12_345], zap::Value::Int(12_345));
+        assert!(chunk.ops.ends_with(&[vm::Op::Push(19_999), vm::Op::Return]));
+    }
+
+    #[test]
+    fn pooled_chunks() {
+        use std::sync::Arc;
+
+        let chunks = |chunk: &vm::Chunk| -> Vec<Arc<vm::Chunk>> {
+            chunk
+                .consts
+                .iter()
+                .filter_map(|val| match val {
+                    zap::Value::Func(f) => Some(f.chunk.clone()),
+                    zap::Value::Closure(closure) => Some(closure.chunk.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // The same fn compiled twice is two fns sharing a chunk
+        let chunk = compile_exp("(do (fn (x) (+ x 1)) (fn (y) (+ y 1)) (fn (x) (+ x 2)))");
+        assert_ne!(chunk.consts[0], chunk.consts[1]);
+        let fns = chunks(&chunk);
+        assert!(Arc::ptr_eq(&fns[0], &fns[1]));
+        assert!(!Arc::ptr_eq(&fns[0], &fns[2]));
+
+        // Within other fns too, but not when they're named apart
+        let chunk = compile_exp(
+            "(let (a 1) (do (fn () (fn () a)) (fn () (fn () a)) (fn f () a) (fn g () a)))",
+        );
+        let fns = chunks(&chunk);
+        assert!(Arc::ptr_eq(&fns[0], &fns[1]));
+        assert!(!Arc::ptr_eq(&fns[2], &fns[3]));
+    }
+
+    #[test]
+    fn pooled_consts() {
+        use std::sync::Arc;
+
+        let lists = |chunk: &vm::Chunk| -> Vec<zap::ZapList> {
+            chunk
+                .consts
+                .iter()
+                .filter_map(|val| match val {
+                    zap::Value::List(list) | zap::Value::Vector(list) => Some(list.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let fns = |chunk: &vm::Chunk| -> Vec<Arc<vm::Chunk>> {
+            chunk
+                .consts
+                .iter()
+                .filter_map(|val| match val {
+                    zap::Value::Func(f) => Some(f.chunk.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Two different fns quoting the same list share it, and so does the chunk they're in
+        let chunk = compile_exp(
+            "(do (fn () '(1 (2 \"a\"))) (fn (x) (if x '(1 (2 \"a\")) [:b])) '(1 (2 \"a\")))",
+        );
+        let (f, g) = (&fns(&chunk)[0], &fns(&chunk)[1]);
+        assert!(!Arc::ptr_eq(f, g));
+        let shared = &lists(f)[0];
+        assert!(Arc::ptr_eq(shared, &lists(g)[0]));
+        assert!(Arc::ptr_eq(shared, &lists(&chunk)[0]));
+        // Lists equal as values but not as consts are kept apart
+        let chunk = compile_exp("(do (fn () '(0.0)) (fn (x) '(-0.0)))");
+        let (f, g) = (&fns(&chunk)[0], &fns(&chunk)[1]);
+        assert!(!Arc::ptr_eq(&lists(f)[0], &lists(g)[0]));
+    }
+
+    #[test]
+    fn eval_lambda_literal() {
+        test_exp("(#(+ % 1) 2)", "3");
+        test_exp("(#(+ %1 %2 %1) 1 2)", "4");
+        test_exp("(#(+ %2 1) 'ignored 2)", "3");
+        test_exp("(#(concat '(1) %&) 2 3)", "(1 2 3)");
+        test_exp("(#(quote (hi)))", "(hi)");
+        test_exp("(let (n 10) (#(+ % n) 1))", "11");
+
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("#(+ #(+ % 1) 1)", env),
+            Err(zap::ZapErr::reader("Cannot nest #() lambdas"))
+        );
+    }
+
+    #[test]
+    fn reader_intern_stats() {
+        use crate::env::Env;
+        use crate::reader::InternStats;
+
+        let mut env = SandboxEnv::default();
+        let before = env.symbols_count();
+        let mut reader = Reader::new();
+        reader.tokenize("(foo bar foo 'foo)");
+        reader.read_ast(&mut env).unwrap();
+
+        assert_eq!(
+            reader.intern_stats(),
+            InternStats {
+                lookups: 4,
+                hits: 2,
+                interned: 2,
+            }
+        );
+        assert_eq!(env.symbols_count(), before + 2);
+    }
+
+    #[test]
+    fn add_reassociates_consts() {
+        test_exp("(let (a 1 b 2) (+ a 1 b 2))", "6");
+        test_exp("(let (a 1) (+ 1 a 2))", "4");
+
+        let chunk = compile_exp("(+ 1 2 3)");
+        assert_eq!(chunk.ops, vec![vm::Op::Push(0), vm::Op::Return]);
+    }
+
+    #[test]
+    fn eval_decimals() {
+        test_exp("0.10M", "0.10M");
+        test_exp("-3M", "-3M");
+        test_exp("(+ 0.1M 0.2M)", "0.3M");
+        test_exp("(= (+ 0.1M 0.2M) 0.3M)", "true");
+        test_exp("(= 1.50M 1.5M)", "true");
+        test_exp("(+ 19.99M 1)", "20.99M");
+        test_exp("(+ -0.05M 0.01M)", "-0.04M");
+        test_exp("(let (x 1.5M) (case x 1.50M 'equal 'other))", "equal");
+        // A symbol can still end with M
+        test_exp("(def M 1) (def xM 2) (+ M xM)", "3");
+
+        let env = SandboxEnv::default();
+        assert!(run_exp("(+ 1.5M 0.1)", env).is_err());
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(+ 9223372036854775807M 1M)", env),
+            Err(zap::error_msg(
+                "Decimal overflow on 9223372036854775807M and 1M"
+            ))
+        );
+    }
+
+    #[test]
+    fn dead_branches() {
+        test_exp("(if true 1 2)", "1");
+        test_exp("(if nil 1 2)", "2");
+        test_exp("(if 0 'zero 'other)", "zero");
+        test_exp("(if (= (+ 1 1) 3) 'yes 'no)", "no");
+        for src in [
+            "(if true 1 (undefined))",
+            "(if false (undefined) 1)",
+            "(if \"s\" 1 2)",
+        ] {
+            let chunk = compile_exp(src);
+            assert_eq!(chunk.ops, vec![vm::Op::Push(0), vm::Op::Return], "{}", src);
+        }
+
+        // The branch left is still in tail position
+        let func = compile_exp("(fn f (n) (if true (f n) 0))").consts[0].clone();
+        let zap::Value::Func(f) = func else { panic!() };
+        assert!(f.chunk.ops.contains(&vm::Op::TailcallSelf(1)));
+    }
+
+    #[test]
+    fn fold_consts() {
+        test_exp("(+ 1 (+ 2 3) (+))", "6");
+        test_exp("(= (+ 1 2) 3)", "true");
+        test_exp("(= (= 1 1) (= 1 2))", "false");
+        test_exp("(let (x 1) (+ x (+ 2 3)))", "6");
+        test_exp("(let (x 3) (= x (+ 1 2)))", "true");
+        test_exp("(if (= (+ 1 1) 2) 'yes 'no)", "yes");
+
+        for src in ["(+ 1 (+ 2 3) 4)", "(= (+ 1 2) (+ 2 1))", "(+ (+ (+ 1 2)))"] {
+            let chunk = compile_exp(src);
+            assert_eq!(chunk.ops, vec![vm::Op::Push(0), vm::Op::Return], "{}", src);
+        }
+        let chunk = compile_exp("(= x (+ 1 2))");
+        assert_eq!(chunk.consts, vec![zap::Value::Int(3)]);
+
+        // A macro gets its args unfolded
+        test_exp("(defmacro q (x) `(quote ~x)) (q (+ 1 2))", "(+ 1 2)");
+    }
+
+    #[test]
+    fn arithmetic() {
+        test_exp("(let (x 10) (- x 1 2))", "7");
+        test_exp("(let (x 10) (- x))", "-10");
+        test_exp("(let (x 3) (* x x 2))", "18");
+        test_exp("(let (x 3) (*))", "1");
+        test_exp("(let (x 3) (/ 12 x 2))", "2.0");
+        test_exp("(let (x 4) (/ x))", "0.25");
+        test_exp("(let (x 7) (rem x 3))", "1");
+        test_exp("(let (x -7) (rem x 3))", "-1");
+        test_exp("(let (x 1.50M) (- x 2))", "-0.50M");
+        test_exp("(let (x 1.5M) (* x 3))", "4.5M");
+        test_exp("(let (x 1M) (/ x 8))", "0.125M");
+        test_exp("(let (x 10M) (/ x 3))", "3.333333333333333333M");
+        test_exp("(let (x 7.5M) (rem x 2))", "1.5M");
+        // As many units as an i64 holds, the compact values box the ones they can't pack
+        test_exp("(= 12345678901234567.8M 12345678901234567.80M)", "true");
+        test_exp(
+            "(case (* 100000000000000000M 10) 1000000000000000000M :big :other)",
+            ":big",
+        );
+        test_exp(
+            "(let (x 4611686018427387904M) [x (- x 1M)])",
+            "[4611686018427387904M 4611686018427387903M]",
+        );
+
+        assert_eq!(
+            run_exp("(let (x 1M) (/ x 0))", SandboxEnv::default()),
+            Err(zap::error_msg("Division by zero"))
+        );
+        assert_eq!(
+            run_exp("(let (x \"a\") (* x 2))", SandboxEnv::default()),
+            Err(zap::error_msg("Can't multiply \"a\" * 2"))
+        );
+        assert_eq!(
+            run_exp("(-)", SandboxEnv::default()),
+            Err(zap::ZapErr::compile("'-' requires at least 1 argument."))
+        );
+        assert_eq!(
+            run_exp("(rem 1)", SandboxEnv::default()),
+            Err(zap::ZapErr::compile("'rem' requires 2 arguments."))
+        );
+
+        // A const operand is fused with its op, and a form of consts is folded
+        let chunk = compile_exp("(- (* x 2) (/ x 4) (rem x 3))");
+        assert!(chunk.ops.contains(&vm::Op::MulConst(0)));
+        assert!(chunk.ops.contains(&vm::Op::DivConst(1)));
+        assert!(chunk.ops.contains(&vm::Op::RemConst(2)));
+        assert!(chunk.ops.contains(&vm::Op::Sub));
+        let chunk = compile_exp("(- 10 (* 2 3) (/ 8 (rem 9 5)))");
+        assert_eq!(chunk.ops, vec![vm::Op::Push(0), vm::Op::Return]);
+        assert_eq!(chunk.consts, vec![zap::Value::Number(2.0)]);
+    }
+
+    #[test]
+    fn numeric_tower() {
+        // Integers stay exact, a float makes the operation a float one
+        test_exp("(let (id 9007199254740993) (+ id 2))", "9007199254740995");
+        test_exp("(let (x 2) (+ x 1))", "3");
+        test_exp("(let (x 2) (+ x 1.5))", "3.5");
+        test_exp("(let (x 2) (* x 1.0))", "2.0");
+        test_exp("(let (x 7) (/ x 2))", "3.5");
+        test_exp("(let (x 7) (rem x 2.0))", "1.0");
+        test_exp("(let (x 2) (+ x 0.25M))", "2.25M");
+        test_exp("(= 1 1.0)", "false");
+        test_exp("(let (x 2) (case x 2.0 'float 2 'int))", "int");
+        test_exp("'(1 1.0 -0.0 1e21)", "(1 1.0 -0.0 1e21)");
+
+        assert_eq!(
+            run_exp(
+                "(let (x 9223372036854775807) (+ x 1))",
+                SandboxEnv::default()
+            ),
+            Err(zap::error_msg(
+                "Integer overflow on 9223372036854775807 + 1"
+            ))
+        );
+        assert_eq!(
+            run_exp("(let (x 1) (rem x 0))", SandboxEnv::default()),
+            Err(zap::error_msg("Division by zero"))
+        );
+
+        // An overflow isn't folded away, the runtime raises it
+        let chunk = compile_exp("(* 9223372036854775807 2)");
+        assert_eq!(chunk.ops.len(), 3);
+    }
+
+    #[test]
+    fn keywords() {
+        use crate::prelude::{Engine, Env, Value};
+        use std::sync::Arc;
+
+        test_exp(":red", ":red");
+        test_exp("'(:a b :c)", "(:a b :c)");
+        test_exp("(= :a :a)", "true");
+        test_exp("(= :a 'a)", "false");
+        test_exp("(let (color :green) (case color :red 1 :green 2 3))", "2");
+        test_exp("(let (f (fn (x) (if x :yes :no))) (f false))", ":no");
+        // A lone colon is still a symbol
+        test_exp("(let (: 1) :)", "1");
+
+        // Their name is written with the chunk, the symbol may be elsewhere in another env
+        let mut engine = Engine::new();
+        let Ok(Value::Func(f)) = engine.eval_str("(fn (x) (case x :up 1 :down -1 :unknown))")
+        else {
+            panic!()
+        };
+        let bytes = f.chunk.serialize(engine.env_mut()).unwrap();
+        let mut other = Engine::new();
+        other.eval_str("(def a 'b) :c").unwrap();
+        let chunk = vm::Chunk::deserialize(&bytes, other.env_mut()).unwrap();
+        let f = other.env_mut().reg_symbol("f".into());
+        other
+            .env_mut()
+            .set(&f, &zap::ZapFn::new(chunk.scope_size, Arc::new(chunk)))
+            .unwrap();
+        assert_eq!(other.eval_str("(f :down)"), Ok(Value::Int(-1)));
+        assert_eq!(
+            other.eval_str("(= (f 'down) :unknown)"),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn vectors() {
+        test_exp("[1 2 3]", "[1 2 3]");
+        test_exp("[]", "[]");
+        test_exp("(let (x 1) [x (+ x 1) [x]])", "[1 2 [1]]");
+        test_exp("'[a (b) c]", "[a (b) c]");
+        test_exp("(get [:a :b] 1)", ":b");
+        // They can hold the bindings and params, as lists do
+        test_exp("(let [x 1 y 2] [y x])", "[2 1]");
+        test_exp("((fn [a & more] more) 1 2 3)", "(2 3)");
+        test_exp(
+            "(defn f [n] (loop [i 0 acc 0] (if (= i n) acc (recur (+ i 1) (+ acc i))))) (f 4)",
+            "6",
+        );
+        test_exp("(apply (fn (a b c) (+ a b c)) 1 [2 3])", "6");
+        test_exp("(let (x 1 ys '(2 3)) `[a ~x ~@ys])", "[a 1 2 3]");
+        test_exp("(let (x 1) `[a ~x])", "[a 1]");
+
+        // Only a vector of constants is one
+        let chunk = compile_exp("[1 \"a\" [:b]]");
+        assert!(!chunk.ops.iter().any(|op| matches!(op, vm::Op::MakeVec(_))));
+        let chunk = compile_exp("(fn (x) [x 1])");
+        let zap::Value::Func(f) = &chunk.consts[0] else {
+            panic!()
+        };
+        assert!(f.chunk.ops.contains(&vm::Op::MakeVec(2)));
+
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("[1 2)", env).map_err(|err| err.to_string()),
+            Err("A vector cannot end with ')'".to_string())
+        );
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(1 2]", env).map_err(|err| err.to_string()),
+            Err("A list cannot end with ']'".to_string())
+        );
+    }
+
+    #[test]
+    fn read_numbers() {
+        test_exp("(int? 42 -7 +3)", "true");
+        test_exp("(float? 4.2 4e2 .5 -1.5E-3)", "true");
+        test_exp("'(4e2 .5 -.5)", "(400.0 0.5 -0.5)");
+        // Only what starts like a number is read as one, whatever f64 parses
+        test_exp("(let (inf 1 NaN 2) (+ inf NaN))", "3");
+        test_exp(
+            "'(0xFF -0x10 0b1010 0o755 1_000_000 0xdead_beef 1_000.5 1_000.50M)",
+            "(255 -16 10 493 1000000 3735928559 1000.5 1000.50M)",
+        );
+        test_exp("(int? -0x8000000000000000)", "true");
+
+        for src in [
+            "1.2.3",
+            "12abc",
+            "-1..5",
+            "1.2.3M",
+            "(+ 1 2x)",
+            "0x",
+            "0xG1",
+            "0b102",
+            "1__0",
+            "1_",
+            "0x-5",
+            "0x10000000000000000",
+        ] {
+            let atom = src.trim_start_matches("(+ 1 ").trim_end_matches(')');
+            assert_eq!(
+                run_exp(src, SandboxEnv::default()),
+                Err(zap::ZapErr::reader(&format!(
+                    "'{}' is not a valid number",
+                    atom
+                ))),
+            );
+        }
+    }
+
+    #[test]
+    fn superinstructions() {
+        use crate::prelude::Engine;
+
+        let mut engine = Engine::new();
+        engine.set_inline_limit(0);
+        engine
+            .eval_str("(def f (fn (n) (if (= n 0) \"zero\" (+ n 1))))")
+            .unwrap();
+        let zap::Value::Func(f) = engine.eval_str("f").unwrap() else {
+            panic!()
+        };
+        let ops = &f.chunk.ops;
+        assert!(matches!(ops[0], vm::Op::LoadEqConstJmp(0, _, _)));
+        assert!(ops
+            .iter()
+            .any(|op| matches!(op, vm::Op::LoadAddConst(0, _))));
+        assert_eq!(engine.eval_str("(f 0)"), Ok(zap::Value::Str("zero".into())));
+        assert_eq!(engine.eval_str("(f 41)"), Ok(zap::Value::Int(42)));
+
+        // Through the bytecode, and inlined from the unfused ops
+        let bytes = f.chunk.serialize(engine.env_mut()).unwrap();
+        let chunk = vm::Chunk::deserialize(&bytes, engine.env_mut()).unwrap();
+        assert_eq!(&chunk, f.chunk.as_ref());
+        test_exp(
+            "(do (def f (fn (n) (if (= n 0) 0 (+ n 1)))) (+ (f 0) (f 41)))",
+            "42",
+        );
+        test_exp("(loop (i 0) (if (= i 10) i (recur (+ i 1))))", "10");
+    }
+
+    #[test]
+    fn register_ops() {
+        use crate::prelude::Engine;
+
+        let mut engine = Engine::new();
+        engine.set_inline_limit(0);
+        engine
+            .eval_str("(def g (fn (a b) (if (= a b) 0 (+ a b))))")
+            .unwrap();
+        let zap::Value::Func(g) = engine.eval_str("g").unwrap() else {
+            panic!()
+        };
+        let fused = g
+            .chunk
+            .ops
+            .iter()
+            .filter(|op| matches!(op, vm::Op::AddSlots(..) | vm::Op::EqSlots(..)))
+            .count();
+        assert_eq!(fused, if cfg!(feature = "register-ops") { 2 } else { 0 });
+        assert_eq!(engine.eval_str("(g 2 2)"), Ok(zap::Value::Int(0)));
+        assert_eq!(engine.eval_str("(g 2 3)"), Ok(zap::Value::Int(5)));
+
+        let bytes = g.chunk.serialize(engine.env_mut()).unwrap();
+        let chunk = vm::Chunk::deserialize(&bytes, engine.env_mut()).unwrap();
+        assert_eq!(&chunk, g.chunk.as_ref());
+        test_exp(
+            "(do (def g (fn (a b) (if (= a b) 0 (+ a b)))) (+ (g 1 1) (g 20 22)))",
+            "42",
+        );
+        // The same error, fused or not
+        assert_eq!(
+            engine.eval_str("(g 1 \"b\")").map_err(|err| err
+                .to_string()
+                .lines()
+                .next()
+                .map(str::to_owned)),
+            Err(Some("line 1, col 32: Can't add \"b\" + 1".to_string()))
+        );
+    }
+
+    #[test]
+    fn wide_jumps() {
+        // Bodies of more ops than a u16 counts are jumped over with the wide jumps
+        let body = " 1".repeat(40_000);
+        test_exp(&format!("(let (c false) (if c (do{body} 'a) 'b))"), "b");
+        test_exp(&format!("(let (c 1) (if c (do{body} 'a) 'b))"), "a");
+        test_exp(
+            &format!("(let (n 0) (do (while (= n 0) (set! n (+ n 1)){body}) n))"),
+            "1",
+        );
+        test_exp(
+            &format!("(loop (n 0) (if (= n 2) n (do{body} (recur (+ n 1)))))"),
+            "2",
+        );
+
+        // Each branch is 80,001 ops
+        let ops = compile_exp(&format!("(do (if c (do{body} 'a) (do{body} 'b)) nil)"))
+            .ops
+            .clone();
+        assert!(ops.contains(&vm::Op::CondJmpW(80_002)));
+        assert!(ops.contains(&vm::Op::JmpW(80_001)));
+        let ops = compile_exp(&format!("(while c{body})")).ops.clone();
+        assert!(ops.iter().any(|op| matches!(op, vm::Op::LoopW(_))));
+        assert!(ops.iter().any(|op| matches!(op, vm::Op::CondJmpW(_))));
+        assert!(compile_exp("(if c 1 2)").ops.contains(&vm::Op::CondJmp(2)));
+    }
+
+    #[test]
+    fn wide_locals() {
+        use crate::prelude::Engine;
+
+        // Past the first 256 locals, they're loaded and stored with the wide ops
+        let bindings: std::string::String = (0..300).map(|i| format!(" x{i} {i}")).collect();
+        let src = format!("(let ({bindings}) (+ x0 x255 x256 x299))");
+        test_exp(&src, "810");
+        let ops = compile_exp(&src).ops.clone();
+        assert!(ops.contains(&vm::Op::Store(255)));
+        assert!(ops.contains(&vm::Op::StoreW(256)));
+        assert!(ops.contains(&vm::Op::LoadW(299)));
+
+        // In a fn, captured by a closure, and through the bytecode
+        let mut engine = Engine::new();
+        let f = format!("(def f (fn (n) (let ({bindings}) (fn () (+ n x299)))))");
+        engine.eval_str(&f).unwrap();
+        assert_eq!(engine.eval_str("((f 1))"), Ok(zap::Value::Int(300)));
+        let f = engine.eval_str("f").unwrap();
+        let zap::Value::Func(f) = f else { panic!() };
+        let bytes = f.chunk.serialize(engine.env_mut()).unwrap();
+        let chunk = vm::Chunk::deserialize(&bytes, engine.env_mut()).unwrap();
+        assert_eq!(&chunk, f.chunk.as_ref());
+    }
+
+    #[test]
+    fn fuel() {
+        let run = |src: &str, max_ops| {
+            let mut env = SandboxEnv::default();
+            let mut reader = Reader::new();
+            reader.tokenize(src);
+            reader.flush_token();
+            let chunk = compile(reader.read_ast(&mut env)?.unwrap(), &mut env)?;
+            vm::run_with_fuel(chunk, &mut env, max_ops)
+                // Without the stack trace
+                .map_err(|err| zap::error_msg(&err.message()))
+        };
+        assert_eq!(
+            run("(do (def f (fn (n) (+ n 1))) (f 1))", 100),
+            Ok(zap::Value::Int(2))
+        );
+
+        // A loop that never ends is stopped, even in a try
+        let forever = "(do (def f (fn (n) (f n))) (f 1))";
+        let out_of_fuel: zap::Result<zap::Value> =
+            Err(zap::error_msg("Out of fuel: the evaluation ran 1000 ops."));
+        assert_eq!(run(forever, 1000), out_of_fuel);
+        let caught = "(do (def f (fn (n) (f n))) (try (f 1) (catch e e)))";
+        assert_eq!(run(caught, 1000), out_of_fuel);
+    }
+
+    #[test]
+    fn max_depth() {
+        let run = |src: &str| {
+            let mut env = SandboxEnv::default();
+            let mut reader = Reader::new();
+            reader.tokenize(src);
+            reader.flush_token();
+            let chunk = compile(reader.read_ast(&mut env)?.unwrap(), &mut env)?;
+            vm::VM::new()
+                .with_max_depth(100)
+                .run(chunk, &mut env)
+                // Without the stack trace
+                .map_err(|err| zap::error_msg(&err.message()))
+        };
+        let depth = "(def depth (fn (n) (if (= n 0) 0 (+ 1 (depth (- n 1))))))";
+        assert_eq!(
+            run(&format!("(do {depth} (depth 99))")),
+            Ok(zap::Value::Int(99))
+        );
+        assert_eq!(
+            run(&format!("(do {depth} (depth 100))")),
+            Err(zap::error_msg(
+                "Stack overflow: the calls are more than 100 deep."
+            ))
+        );
+
+        // The frames are unwound down to the try
+        let caught = format!("(do {depth} (try (depth 1000) (catch e (depth 10))))");
+        assert_eq!(run(&caught), Ok(zap::Value::Int(10)));
+
+        // A tail call doesn't stack a frame
+        let count = "(def count (fn (n) (if (= n 0) \"done\" (count (- n 1)))))";
+        assert_eq!(
+            run(&format!("(do {count} (count 1000))")),
+            Ok(zap::Value::Str("done".into()))
+        );
+    }
+
+    #[test]
+    fn cancel() {
+        let mut env = SandboxEnv::default();
+        let mut reader = Reader::new();
+        reader.tokenize("(try (loop (i 0) (recur (+ i 1))) (catch e e))");
+        reader.flush_token();
+        let chunk = compile(reader.read_ast(&mut env).unwrap().unwrap(), &mut env).unwrap();
+
+        let mut vm = vm::VM::new();
+        let handle = vm.handle();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            handle.cancel();
+        });
+        assert_eq!(
+            vm.run(chunk, &mut env),
+            Err(zap::error_msg("The evaluation was interrupted."))
+        );
+        canceller.join().unwrap();
+
+        // The next run isn't cancelled
+        reader.tokenize("(+ 1 2)");
+        reader.flush_token();
+        let chunk = compile(reader.read_ast(&mut env).unwrap().unwrap(), &mut env).unwrap();
+        assert_eq!(vm.run(chunk, &mut env), Ok(zap::Value::Int(3)));
+    }
+
+    #[test]
+    fn not() {
+        test_exp("(let (x nil) (not x))", "true");
+        test_exp("(let (x 0) (not x))", "false");
+        test_exp("(not (= 1 2))", "true");
+        test_exp("(let (x false) (if (not x) 'yes 'no))", "yes");
+        test_exp("(let (x 1) (if (not (not x)) 'yes 'no))", "yes");
+        test_exp("(let (x 1) (when (not x) 'yes))", "nil");
+        assert_eq!(
+            run_exp("(not)", SandboxEnv::default()),
+            Err(zap::ZapErr::compile("A not form must have 1 parameter"))
+        );
+
+        // The condition jumps to the branches swapped, without a NOT
+        let ops = |src| compile_exp(src).ops.clone();
+        assert_eq!(ops("(if (not x) 1 2)"), ops("(if x 2 1)"));
+        assert!(!ops("(if (not (not x)) 1 2)").contains(&vm::Op::Not));
+        assert!(ops("(let (y (not x)) y)").contains(&vm::Op::Not));
+        assert_eq!(ops("(not 1)"), vec![vm::Op::Push(0), vm::Op::Return]);
+    }
+
+    #[test]
+    fn inline_calls() {
+        use crate::prelude::Engine;
+
+        let mut engine = Engine::new();
+        let mut eval = |src: &str| {
+            let val = engine.eval_str(src).unwrap();
+            val.pr_str(engine.env_mut())
+        };
+        eval("(def inc (fn (x) (+ x 1))) (def sign (fn (n) (if (= n 0) 'zero (inc n))))");
+        assert_eq!(eval("(inc 41)"), "42");
+        assert_eq!(eval("(sign 0)"), "zero");
+        assert_eq!(eval("(sign 2)"), "3");
+        assert_eq!(eval("(let (n 5) (+ (inc n) (inc (inc n))))"), "13");
+        assert_eq!(
+            eval("(def count (fn (n) (loop (i 0) (if (= i n) i (recur (inc i)))))) (count 100)"),
+            "100"
+        );
+        assert_eq!(
+            eval("(def sum (fn (a b) (+ a b))) (sum (sum 1 2) (sum 3 4))"),
+            "10"
+        );
+
+        // A redefinition is seen by the code compiled before it
+        eval("(def bump (fn (x) (inc x)))");
+        eval("(def inc (fn (x) (+ x 100)))");
+        assert_eq!(eval("(bump 1)"), "101");
+        eval("(def inc 'gone)");
+        assert_eq!(
+            engine.eval_str("(bump 1)").map_err(|err| err.to_string()),
+            Err("line 1, col 19: Cannot call a non-function".to_string())
+        );
+    }
+
+    #[test]
+    fn inline_limit() {
+        use crate::prelude::{Engine, Value};
+
+        let compiled = |engine: &mut Engine| {
+            engine.eval_str("(def double (fn (x) (+ x x)))").unwrap();
+            match engine.eval_str("(fn (y) (double y))") {
+                Ok(Value::Func(f)) => f.chunk.ops.clone(),
+                _ => panic!(),
+            }
+        };
+
+        // The call is kept behind the guard, for when double is redefined
+        let ops = compiled(&mut Engine::new());
+        assert!(ops.contains(&vm::Op::Add));
+        assert!(ops.contains(&vm::Op::Tailcall(1)));
+
+        let mut engine = Engine::new();
+        engine.set_inline_limit(0);
+        let ops = compiled(&mut engine);
+        assert!(!ops.contains(&vm::Op::Add));
+    }
+
+    #[test]
+    fn chunk_hash_eq() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let hash = |chunk: &vm::Chunk| {
+            let mut hasher = DefaultHasher::new();
+            chunk.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        // Equal when compiled from the same code, constants and nested fns included
+        for src in [
+            "(def f (fn (x & more) (+ x 1.5 \"s\")))",
+            "(doseq-indexed (i x '(1 2)) (case x 1 'a 2 'b))",
+            "(let (a 1) (fn () `(~a (b c))))",
+        ] {
+            let (a, b) = (compile_exp(src), compile_exp(src));
+            assert!(!std::sync::Arc::ptr_eq(&a, &b));
+            assert_eq!(a, b);
+            assert_eq!(hash(&a), hash(&b));
+        }
+
+        for (a, b) in [
+            ("(+ x 1)", "(+ x 2)"),
+            ("(fn (x) x)", "(fn (x & y) x)"),
+            ("'(1 2)", "'(1 (2))"),
+            ("(case x 1 2)", "(case x 1 3)"),
+            ("0.0", "-0.0"),
+            ("1", "1.0"),
+        ] {
+            assert_ne!(compile_exp(a), compile_exp(b));
+        }
+    }
+
+    #[test]
+    fn print_values() {
+        use crate::prelude::Engine;
+
+        let mut engine = Engine::new();
+        let val = engine
+            .eval_str("'(1 -2.5 \"a \\\"b\\\" \\\\ c\" (sym nil true) ())")
+            .unwrap();
+
+        let mut out = String::new();
+        val.write_to(&mut out, engine.env()).unwrap();
+        assert_eq!(out, "(1 -2.5 \"a \\\"b\\\" \\\\ c\" (sym nil true) ())");
+        assert_eq!(val.pr_str(engine.env_mut()), out);
+        assert!(format!("{}", val).contains("(Symbol#"));
+    }
+
+    #[test]
+    fn disassemble() {
+        use crate::prelude::{Engine, Value};
+
+        let mut engine = Engine::new();
+        engine
+            .eval_str("(def f (fn (x) (case x 1 \"one\" 'a (+ x 2) (+ x 1))))")
+            .unwrap();
+        let mut disasm = |src| match engine.eval_str(src) {
+            Ok(Value::Str(text)) => Ok(text.to_string()),
+            Ok(val) => panic!("{:?}", val),
+            Err(err) => Err(err.to_string()),
+        };
+        assert_eq!(
+            disasm("(disasm f)").unwrap(),
+            "; chunk: 1 params, 1 locals
+00000 LOAD        0
+00001 SWITCH      table(0)     ; 1 -> 2, a -> 4, else -> 7
+00002 PUSH        const(0)     ; \"one\"
+00003 RETURN
+00004 LOADADDCONST 0 const(1)  ; 2
+00005 ADDCONST    const(1)     ; 2
+00006 RETURN
+00007 LOADADDCONST 0 const(2)  ; 1
+00008 ADDCONST    const(2)     ; 1
+00009 RETURN
+"
+        );
+        // The functions in the consts follow, and the globals are named
+        assert_eq!(
+            disasm("(disasm (fn (n & xs) (fn () (str n))))").unwrap(),
+            "; chunk: 1 params & rest, 2 locals
+00000 PUSH        const(0)     ; <Closure>
+00001 CLOSURE
+00002 RETURN
+
+; const(0): 0 params, 1 locals
+00000 LOOKUP      #76          ; str
+00001 LOAD        0
+00002 TAILCALL    argc(1)
+00003 RETURN
+"
+        );
+        assert_eq!(
+            disasm("(disasm 1)"),
+            Err("line 1, col 1: disasm needs a function compiled by zap".to_string())
+        );
+        assert_eq!(
+            disasm("(disasm)"),
+            Err("line 1, col 1: A disasm form must have a function".to_string())
+        );
+    }
+
+    #[test]
+    fn chunk_serialization() {
+        use crate::prelude::{compile_spanned, Engine, Env, Extensions, Reader, SandboxEnv};
+        use crate::vm::Chunk;
+        use std::sync::Arc;
+
+        let mut env = SandboxEnv::default();
+        let mut compile = |src: &str| {
+            let mut reader = Reader::new();
+            reader.tokenize(src);
+            reader.flush_token();
+            let ast = reader.read_ast(&mut env).unwrap().unwrap();
+            let chunk = compile_spanned(
+                ast,
+                reader.spans(),
+                &Extensions::new(),
+                &mut env,
+                &mut Vec::new(),
+            )
+            .unwrap();
+            chunk.serialize(&env).unwrap()
+        };
+        let program = compile(
+            "(do
+  (def greet (fn (who) (case who 'bob \"hi bob\" \"x\" 12.50M 7 0.5 (str \"hello \" who))))
+  (def adder (fn (n) (fn (x) (+ x n))))
+  (str (greet 'bob) \" \" (greet \"x\") \" \" (greet \"al\") \" \" ((adder 2) 40) \" \" (greet 7)))",
+        );
+        let failing = compile("(do\n  (greet))");
+        assert_eq!(compile("(greet 'al)"), compile("(greet 'al)"));
+
+        // The symbols get other ids in another env
+        let mut engine = Engine::new();
+        engine.eval_str("(def al 'al) (def who 0)").unwrap();
+        let mut load = |bytes: &[u8]| {
+            let chunk = Chunk::deserialize(bytes, engine.env_mut())?;
+            engine.eval_chunk(Arc::new(chunk))
+        };
+        assert_eq!(
+            load(&program),
+            Ok(zap::Value::Str("hi bob 12.50 hello al 42 0.5".into()))
+        );
+        assert_eq!(
+            load(&failing).map_err(|err| err.to_string()),
+            Err("line 2, col 3: Wrong number of args: expected 1, got 0.".to_string())
+        );
+
+        assert_eq!(
+            load(&program[..program.len() - 1]),
+            Err(zap::error_msg("The chunk is truncated."))
+        );
+        assert_eq!(
+            load(b"(+ 1 2)"),
+            Err(zap::error_msg("Not a compiled zap chunk."))
+        );
+        // A const out of the consts, the chunk ends with Push(0), Return and no spans
+        let mut bad = compile("1");
+        let push = bad.len() - 8;
+        assert_eq!(bad[push], 0);
+        bad[push + 1] = 1;
+        assert_eq!(
+            load(&bad),
+            Err(zap::error_msg(
+                "The chunk is corrupted: op 0 points outside of its chunk."
+            ))
+        );
+
+        // A native function in the consts, like the guard of an inlined call has
+        let mut env = SandboxEnv::default();
+        let str_fn = env.reg_symbol("str".into());
+        let mut chunk = Chunk::default();
+        chunk.consts.push(env.get(&str_fn).unwrap());
+        chunk.ops.extend([vm::Op::Push(0), vm::Op::Return]);
+        let natives = chunk.serialize(&env).unwrap();
+        assert!(Chunk::deserialize(&natives, &mut SandboxEnv::default()).is_ok());
+        let mut engine = Engine::new().with_core(false);
+        assert_eq!(
+            Chunk::deserialize(&natives, engine.env_mut()).map(|_| ()),
+            Err(zap::error_msg(
+                "The chunk needs the native function 'str', which this env doesn't have."
+            ))
+        );
+    }
+
+    #[test]
+    fn top_level_epilogue() {
+        use crate::vm::{Chunk, Op};
+        use std::sync::Arc;
+
+        // A call in tail position at the top is a plain call, and the chunk returns its value
+        for src in [
+            "(f 1)",
+            "(let (y 2) (f y))",
+            "(if true (f 1) 0)",
+            "(do 1 (f 1))",
+        ] {
+            let chunk = compile_exp(src);
+            assert!(!chunk.ops.contains(&Op::Tailcall(1)), "{}", src);
+            assert_eq!(chunk.verify_top_level(), Ok(()));
+        }
+        test_exp("(do (def f (fn (x) (+ x 1))) (f 1))", "2");
+        test_exp("(do (def f (fn (x) (+ x 1))) (let (y 2) (f y)))", "3");
+        test_exp("(do (def f (fn (x) (+ x 1))) (if true (f 1) 0))", "2");
+        test_exp("(str 1)", "\"1\"");
+
+        // Hand-made chunks that would break the stack
+        let verify = |ops: Vec<Op>, consts: Vec<zap::Value>| {
+            let chunk = Chunk {
+                ops,
+                consts,
+                ..Chunk::default()
+            };
+            chunk.verify_top_level().map(|()| chunk)
+        };
+        assert_eq!(
+            verify(vec![Op::Return], vec![]).map(|_| ()),
+            Err(zap::error_msg(
+                "The chunk is corrupted: op 0 pops an empty stack."
+            ))
+        );
+        assert_eq!(
+            verify(
+                vec![Op::Push(0), Op::Pop, Op::Pop, Op::Return],
+                vec![zap::Value::Nil]
+            )
+            .map(|_| ()),
+            Err(zap::error_msg(
+                "The chunk is corrupted: op 2 pops an empty stack."
+            ))
+        );
+        assert_eq!(
+            verify(
+                vec![Op::Push(0), Op::Push(0), Op::Return],
+                vec![zap::Value::Nil]
+            )
+            .map(|_| ()),
+            Err(zap::error_msg(
+                "The chunk is corrupted: op 2 leaves values behind on the stack."
+            ))
+        );
+        assert_eq!(
+            verify(
+                vec![Op::Push(0), Op::Tailcall(0), Op::Return],
+                vec![zap::Value::Nil]
+            )
+            .map(|_| ()),
+            Err(zap::error_msg(
+                "The chunk is corrupted: op 1 is a tail call at the top level."
+            ))
+        );
+        // Both branches of an if must leave the same stack
+        assert_eq!(
+            verify(
+                vec![
+                    Op::Push(0),
+                    Op::CondJmp(1),
+                    Op::Push(0),
+                    Op::Push(0),
+                    Op::Return
+                ],
+                vec![zap::Value::Nil]
+            )
+            .map(|_| ()),
+            Err(zap::error_msg(
+                "The chunk is corrupted: the paths to op 3 leave different stacks."
+            ))
+        );
+        // Functions may tail call
+        let tail = Chunk {
+            ops: vec![Op::Push(0), Op::Tailcall(0), Op::Return],
+            consts: vec![zap::Value::Nil],
+            ..Chunk::default()
+        };
+        assert_eq!(tail.verify(), Ok(()));
+
+        // Run unverified, an empty stack is an error rather than a panic
+        let empty = Chunk {
+            ops: vec![Op::Return],
+            ..Chunk::default()
+        };
+        assert_eq!(
+            vm::run(Arc::new(empty), &mut SandboxEnv::default()),
+            Err(zap::error_msg("The chunk returned without a value."))
+        );
+    }
+
+    #[test]
+    fn engine_eval_str() {
+        use crate::prelude::{eval_str, Engine, Error, Span, Value};
+
+        let mut engine = Engine::new();
+        assert_eq!(engine.eval_str("(def x 2) (+ x 1)"), Ok(Value::Int(3)));
+        assert_eq!(engine.eval_str("x"), Ok(Value::Int(2)));
+        assert_eq!(engine.eval_str(""), Ok(Value::Nil));
+        assert_eq!(
+            engine.eval_str("(+ x"),
+            Err(Error::reader("Unexpected end of input."))
+        );
+        assert_eq!(engine.eval_str("x"), Ok(Value::Int(2)));
+
+        // The same pipeline, straight on an env
+        let mut env = SandboxEnv::default();
+        let swap = "(defmacro swap-if (c a b) `(if ~c ~b ~a)) (def y 5)";
+        assert_eq!(eval_str(swap, &mut env), Ok(Value::Int(5)));
+        assert_eq!(eval_str("(swap-if true 1 y)", &mut env), Ok(Value::Int(5)));
+        assert_eq!(
+            eval_str("(do\n  (+ 1 nil))", &mut env),
+            Err(Error::Runtime {
+                msg: "Can't add 1 + nil".to_string(),
+                span: Some(Span { line: 2, col: 3 }),
+                trace: Vec::new(),
+            })
+        );
+
+        // compile finds the macros in the env too
+        let mut reader = Reader::new();
+        reader.tokenize("(swap-if false 1 2)");
+        reader.flush_token();
+        let ast = reader.read_ast(&mut env).unwrap().unwrap();
+        let chunk = compile(ast, &mut env).unwrap();
+        assert_eq!(vm::run(chunk, &mut env), Ok(Value::Int(1)));
+    }
+
+    #[test]
+    fn source_spans() {
+        use crate::prelude::{Engine, Reader, SandboxEnv};
+
+        let mut engine = Engine::new();
+        let mut eval = |src| engine.eval_str(src).map_err(|err| err.to_string());
+
+        // Raised in the body of a function, wherever it's called from
+        eval("(def half (fn (x) ; the half\n  (if (= x nil)\n    0\n    (+ x nil))))").unwrap();
+        assert_eq!(
+            eval("(half 1)"),
+            Err("line 4, col 5: Can't add 1 + nil".to_string())
+        );
+        // With the calls leading to it, the inlined ones left out
+        assert_eq!(
+            eval("(def wrap (fn (x) (half x)))\n\n  (wrap 2)"),
+            Err("line 4, col 5: Can't add 2 + nil
+  in wrap, op 10 (line 4, col 5)
+  in the top, op 2 (line 3, col 3)"
+                .to_string())
+        );
+        eval("(def count (fn (n) (if (= n 0) (+ n nil) (+ 1 (count (- n 1))))))").unwrap();
+        let trace = eval("(count 20)").unwrap_err();
+        assert!(trace.starts_with("line 1, col 32: Can't add 0 + nil\n  in count, op 3"));
+        assert!(trace.ends_with("  in count, op 10 (line 1, col 47)\n  and 12 more"));
+        assert_eq!(
+            eval("(do 1\n  (undefined 2))"),
+            Err("line 2, col 3: symbol 'undefined' not in scope.".to_string())
+        );
+        assert_eq!(
+            eval("(do\n #(recur %))"),
+            Err("line 2, col 2: recur can only be used in tail position of a loop".to_string())
+        );
+        // A caught error is only its message
+        assert_eq!(
+            eval("(try (half 3) (catch e e))"),
+            Ok(zap::Value::Str("Can't add 3 + nil".into()))
+        );
+
+        let mut env = SandboxEnv::default();
+        let mut reader = Reader::new();
+        reader.tokenize("\"a\nb\" (f\n  '(g h) #(k))");
+        let _ = reader.read_ast(&mut env);
+        let Ok(Some(zap::Value::List(form))) = reader.read_ast(&mut env) else {
+            panic!("A list was expected");
+        };
+        let spans = reader.spans();
+        assert_eq!(
+            spans.of(&form).map(|span| span.to_string()),
+            Some("line 2, col 4".to_string())
+        );
+        let zap::Value::List(quote) = &form[1] else {
+            panic!("A quote was expected");
+        };
+        let zap::Value::List(quoted) = &quote[1] else {
+            panic!("A quoted list was expected");
+        };
+        assert_eq!(spans.of(quote), None);
+        assert_eq!(
+            spans.of(quoted).map(|span| (span.line, span.col)),
+            Some((3, 4))
+        );
+        let zap::Value::List(lambda) = &form[2] else {
+            panic!("A lambda was expected");
+        };
+        assert_eq!(
+            spans.of(lambda).map(|span| (span.line, span.col)),
+            Some((3, 10))
+        );
+    }
+
+    #[test]
+    fn native_ctx() {
+        use crate::prelude::{compile, Ctx, Engine, Env, Reader, SandboxEnv, Value};
+
+        fn twice(ctx: &mut Ctx, args: &[Value]) -> zap::Result<Value> {
+            let once = ctx.call(&args[0], &args[1..])?;
+            ctx.call(&args[0], &[once])
+        }
+        fn global(ctx: &mut Ctx, args: &[Value]) -> zap::Result<Value> {
+            let Value::Str(name) = &args[0] else {
+                return Err(zap::error_msg("'global' requires a name."));
+            };
+            let env = ctx.env();
+            let Value::Symbol(id) = env.reg_symbol(name.clone()) else {
+                unreachable!()
+            };
+            Ok(env.lookup(id).unwrap_or(Value::Nil))
+        }
+        let register = |env: &mut SandboxEnv| {
+            env.reg_fn_ctx("twice", zap::Arity::Exactly(2), twice)
+                .unwrap();
+            env.reg_fn_ctx("global", zap::Arity::Exactly(1), global)
+                .unwrap();
+        };
+
+        let mut engine = Engine::new();
+        register(engine.env_mut());
+        assert_eq!(
+            engine.eval_str("(twice (fn (x) (* x 3)) 2)"),
+            Ok(Value::Int(18))
+        );
+        assert_eq!(
+            engine.eval_str("(def answer 42) (global \"answer\")"),
+            Ok(Value::Int(42))
+        );
+        assert_eq!(engine.eval_str("(global \"nothing\")"), Ok(Value::Nil));
+        // The error of the fn called back is raised by the native
+        assert_eq!(
+            engine.eval_str("(try (twice (fn (x) (+ x nil)) 1) (catch e e))"),
+            Ok(Value::Str("Can't add 1 + nil".into()))
+        );
+
+        // The fuel of the VM limits the calls back too
+        let mut env = SandboxEnv::default();
+        register(&mut env);
+        let mut reader = Reader::new();
+        reader.tokenize("(twice (fn (n) (loop (i 0) (recur i))) 1)");
+        reader.flush_token();
+        let chunk = compile(reader.read_ast(&mut env).unwrap().unwrap(), &mut env).unwrap();
+        assert_eq!(
+            vm::run_with_fuel(chunk, &mut env, 1000).map_err(|err| err.message()),
+            Err("Out of fuel: the evaluation ran 1000 ops.".to_string())
+        );
+    }
+
+    #[test]
+    fn native_tail_call() {
+        use crate::prelude::{Ctx, Engine, Env, Value};
+
+        fn bounce(ctx: &mut Ctx, args: &[Value]) -> zap::Result<Value> {
+            ctx.tail_call(args[0].clone(), args[1..].to_vec())
+        }
+        let mut engine = Engine::new();
+        engine
+            .env_mut()
+            .reg_fn_ctx("bounce", zap::Arity::AtLeast(1), bounce)
+            .unwrap();
+
+        assert_eq!(
+            engine.eval_str("(bounce (fn (x) (* x 2)) 21)"),
+            Ok(Value::Int(42))
+        );
+        assert_eq!(
+            engine.eval_str("(+ 1 (bounce (fn (x y) (- x y)) 5 2))"),
+            Ok(Value::Int(4))
+        );
+        assert_eq!(
+            engine.eval_str("(try (bounce (fn (x) (+ x nil)) 1) (catch e e))"),
+            Ok(Value::Str("Can't add 1 + nil".into()))
+        );
+        // Neither the frames nor the Rust stack grow when a fn loops through the native
+        assert_eq!(
+            engine
+                .eval_str("(defn down (n) (if (= n 0) 'done (bounce down (- n 1)))) (down 100000)"),
+            engine.eval_str("'done")
+        );
+
+        // Journaled, the call is made by the native and its value recorded
+        engine.record_journal();
+        assert_eq!(
+            engine.eval_str("(bounce (fn (x) (* x 2)) 21)"),
+            Ok(Value::Int(42))
+        );
+        let journal = engine.take_journal().unwrap();
+        assert_eq!(journal.len(), 1);
+        engine.replay_journal(journal);
+        assert_eq!(
+            engine.eval_str("(bounce (fn (x) (* x 2)) 1)"),
+            Ok(Value::Int(42))
+        );
+    }
+
+    #[test]
+    fn native_closure() {
+        use crate::prelude::{Engine, Env, Value};
+        use std::sync::atomic::{AtomicI64, Ordering};
+        use std::sync::{mpsc, Arc, Mutex};
+
+        let total = Arc::new(AtomicI64::new(0));
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+
+        let mut engine = Engine::new();
+        let counted = total.clone();
+        engine
+            .env_mut()
+            .reg_closure(
+                "add!",
+                zap::Arity::Exactly(1),
+                Box::new(move |args| match args[0] {
+                    Value::Int(n) => Ok(Value::Int(counted.fetch_add(n, Ordering::Relaxed) + n)),
+                    _ => Err(zap::error_msg("'add!' requires an int.")),
+                }),
+            )
+            .unwrap();
+        engine
+            .env_mut()
+            .reg_closure(
+                "send!",
+                zap::Arity::Exactly(1),
+                Box::new(move |args| {
+                    sender.lock().unwrap().send(args[0].clone()).unwrap();
+                    Ok(Value::Nil)
+                }),
+            )
+            .unwrap();
+
+        assert_eq!(engine.eval_str("(add! 2) (add! 3)"), Ok(Value::Int(5)));
+        assert_eq!(total.load(Ordering::Relaxed), 5);
+        assert_eq!(
+            engine.eval_str("(try (add! nil) (catch e e))"),
+            Ok(Value::Str("'add!' requires an int.".into()))
+        );
+        engine.eval_str("(send! \"hi\") (send! 42)").unwrap();
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            vec![Value::Str("hi".into()), Value::Int(42)]
+        );
+    }
+
+    #[test]
+    fn async_natives() {
+        use crate::prelude::{compile, Env, Reader, SandboxEnv, Value, VM};
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Poll, Waker};
+
+        // Ready on its second poll
+        struct Later(bool, Value);
+        impl Future for Later {
+            type Output = zap::Result<Value>;
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                if self.0 {
+                    return Poll::Ready(Ok(self.1.clone()));
+                }
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+        fn later(args: &[Value]) -> zap::Pending {
+            Box::pin(Later(false, args[0].clone()))
+        }
+        fn fail(_: &[Value]) -> zap::Pending {
+            Box::pin(async { Err(zap::error_msg("It failed later.")) })
+        }
+
+        let mut env = SandboxEnv::default();
+        env.reg_fn_async("later", zap::Arity::Exactly(1), later)
+            .unwrap();
+        env.reg_fn_async("fail", zap::Arity::Exactly(0), fail)
+            .unwrap();
+        let compiled = |src: &str, env: &mut SandboxEnv| {
+            let mut reader = Reader::new();
+            reader.tokenize(src);
+            reader.flush_token();
+            compile(reader.read_ast(env).unwrap().unwrap(), env).unwrap()
+        };
+        let sum = compiled("(+ (later 1) (later 2))", &mut env);
+        let nested = compiled("(do (def g (fn (x) (+ 1 (later x)))) (g (g 1)))", &mut env);
+        let caught = compiled("(try (fail) (catch e e))", &mut env);
+        let uncaught = compiled("(do (def h (fn () (fail))) (+ 1 (h)))", &mut env);
+
+        // Suspended at each async native, the run goes on where it was once it's polled again
+        let mut vm = VM::new();
+        let mut suspended = 0;
+        let mut run_async = |chunk| {
+            let mut run = Box::pin(vm.run_async(chunk, &mut env));
+            let mut cx = Context::from_waker(Waker::noop());
+            loop {
+                match run.as_mut().poll(&mut cx) {
+                    Poll::Ready(res) => return res,
+                    Poll::Pending => suspended += 1,
+                }
+            }
+        };
+        assert_eq!(run_async(sum.clone()), Ok(Value::Int(3)));
+        assert_eq!(run_async(nested.clone()), Ok(Value::Int(3)));
+        assert_eq!(
+            run_async(caught.clone()),
+            Ok(Value::Str("It failed later.".into()))
+        );
+        assert_eq!(
+            run_async(uncaught.clone()).map_err(|err| err.message()),
+            Err("It failed later.".to_string())
+        );
+        assert_eq!(suspended, 4);
+
+        // A run that can't be suspended waits for the future
+        assert_eq!(vm::run(sum, &mut env), Ok(Value::Int(3)));
+        assert_eq!(vm::run(nested, &mut env), Ok(Value::Int(3)));
+        assert_eq!(
+            vm::run(caught, &mut env),
+            Ok(Value::Str("It failed later.".into()))
+        );
+
+        // So it can be awaited by a task moved between threads
+        fn is_send<T: Send>(_: &T) {}
+        let one = compiled("1", &mut env);
+        is_send(&VM::new().run_async(one, &mut env));
+    }
+
+    #[test]
+    fn apply() {
+        test_exp("(apply (fn (a b c) (+ a b c)) 1 '(2 3))", "6");
+        test_exp("(apply (fn () 1) '())", "1");
+        test_exp("(apply concat '((1) (2 3)))", "(1 2 3)");
+        // The rest args forwarded to another variadic fn
+        test_exp(
+            "(defn f (& xs) xs) (defn g (x & xs) (apply f x 0 xs)) (g 1 2 3)",
+            "(1 0 2 3)",
+        );
+        test_exp("(let (x 4) (apply (fn (a b) (- a b)) `(~x 1)))", "3");
+        // The arithmetic forms and not as values
+        test_exp("(apply + (list 1 2 3))", "6");
+        test_exp("(apply + '())", "0");
+        test_exp("(apply - '(10 1 2))", "7");
+        test_exp("(apply - '(3))", "-3");
+        test_exp("(apply * 2 '(3 4))", "24");
+        test_exp("(apply / '(2))", "0.5");
+        test_exp("(apply rem '(7 4))", "3");
+        test_exp("(apply not '(nil))", "true");
+        test_exp("(let (f *) (f 6 7))", "42");
+
+        // In tail position the frame is replaced
+        let chunk = compile_exp("(fn (f xs) (apply f xs))");
+        let zap::Value::Func(f) = &chunk.consts[0] else {
+            panic!()
+        };
+        assert!(f.chunk.ops.contains(&vm::Op::TailApply(1)));
+        test_exp(
+            "(defn down (n) (if (= n 0) 'done (apply down (- n 1) '()))) (down 100000)",
+            "done",
+        );
+
+        let env = SandboxEnv::default();
+        assert_eq!(
+            run_exp("(apply (fn (x) x) 1)", env),
+            Err(zap::error_msg("apply needs a list as its last argument."))
+        );
+        let env = SandboxEnv::default();
+        assert!(run_exp("(apply (fn (x) x) '(1 2))", env).is_err());
+        let env = SandboxEnv::default();
+        assert!(run_exp("(apply (fn (x) x))", env).is_err());
+    }
+
+    #[test]
+    fn coroutines() {
+        use crate::prelude::{Engine, Value};
+
+        let mut engine = Engine::new();
+        let mut resumed = |src: &str| engine.eval_str(src).map_err(|err| err.message());
+        resumed(
+            "(def count-to (fn (n) (let (i 0) (do (while (not (= i n)) (yield i) (set! i (+ i 1))) \"end\"))))
+             (def gen (coroutine count-to 2))",
+        )
+        .unwrap();
+        // Each resume goes on from the yield it stopped at, the last one is what the fn returns
+        assert_eq!(resumed("(resume gen)"), Ok(Value::Int(0)));
+        assert_eq!(resumed("(resume gen)"), Ok(Value::Int(1)));
+        assert_eq!(resumed("(done? gen)"), Ok(Value::Bool(false)));
+        assert_eq!(resumed("(resume gen)"), Ok(Value::Str("end".into())));
+        assert_eq!(resumed("(done? gen)"), Ok(Value::Bool(true)));
+        assert_eq!(
+            resumed("(resume gen)"),
+            Err("The coroutine is done.".to_string())
+        );
+
+        // The value a resume is given is the one of the yield it resumes, and a yield in a fn
+        // it calls suspends it too
+        resumed(
+            "(def add (fn (x y) (yield (+ x y))))
+             (def acc (coroutine (fn () (add (yield nil) (yield nil)))))
+             (resume acc)",
+        )
+        .unwrap();
+        assert_eq!(resumed("(resume acc 1)"), Ok(Value::Nil));
+        assert_eq!(resumed("(resume acc 2)"), Ok(Value::Int(3)));
+        assert_eq!(resumed("(resume acc 10)"), Ok(Value::Int(10)));
+        test_exp(
+            "(do (def co (coroutine (fn (a) (+ a (yield 1) (yield 2))) 100))
+                 (+ (resume co) (resume co 10) (resume co 20)))",
+            "133",
+        );
+
+        assert_eq!(
+            resumed("(yield 1)"),
+            Err("yield can only be used in a coroutine.".to_string())
+        );
+        assert_eq!(
+            resumed("(def me (coroutine (fn () (resume me)))) (resume me)"),
+            Err("The coroutine is already running.".to_string())
+        );
+        // An error ends the coroutine, and is raised by the resume
+        assert_eq!(
+            resumed(
+                "(def bad (coroutine (fn () (yield 1) (+ 1 nil))))
+                 (resume bad)
+                 (try (resume bad) (catch e e))"
+            ),
+            Ok(Value::Str("Can't add 1 + nil".into()))
+        );
+        assert_eq!(resumed("(done? bad)"), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn channels() {
+        use crate::prelude::{Engine, Value};
+
+        test_exp(
+            "(do (def c (chan)) (send! c 1) (send! c 2) (- (recv! c) (recv! c)))",
+            "-1",
+        );
+
+        // A recv waits for what another thread sends
+        let mut engine = Engine::new();
+        let Ok(Value::Channel(c)) = engine.eval_str("(def c (chan)) c") else {
+            panic!("(chan) is not a channel");
+        };
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            c.send(Value::Int(42)).unwrap();
+            c.close();
+        });
+        assert_eq!(engine.eval_str("(recv! c)"), Ok(Value::Int(42)));
+        sender.join().unwrap();
+
+        // Once closed and empty, a recv is nil and nothing more can be sent
+        assert_eq!(engine.eval_str("(recv! c)"), Ok(Value::Nil));
+        assert_eq!(
+            engine.eval_str("(send! c 1)").map_err(|err| err.message()),
+            Err("The channel is closed.".to_string())
+        );
+    }
+
+    #[test]
+    fn atoms() {
+        use crate::prelude::{Engine, Env, Value};
+
+        test_exp("(let (a (atom 1)) (do (reset! a 2) @a))", "2");
+        test_exp(
+            "(let (a (atom 1)) (do (swap! a (fn (x y z) (+ x y z)) 10 100) (deref a)))",
+            "111",
+        );
+        test_exp("(let (a (atom 0)) (swap! a (fn (x) (+ x 1))))", "1");
+        // A closure changes what it closed over through an atom
+        test_exp(
+            "(do (def counter (let (n (atom 0)) (fn () (swap! n (fn (x) (+ x 1)))))) (counter) (counter))",
+            "2",
+        );
+        test_exp("(let (a (atom 1)) (= a a))", "true");
+        test_exp("(= (atom 1) (atom 1))", "false");
+
+        // Every swap! lands, however many threads share the atom
+        let mut engine = Engine::new();
+        let Ok(Value::Atom(shared)) = engine.eval_str("(atom 0)") else {
+            panic!("(atom 0) is not an atom");
+        };
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = Value::Atom(shared.clone());
+                std::thread::spawn(move || {
+                    let mut engine = Engine::new();
+                    let env = engine.env_mut();
+                    let a = env.reg_symbol("a".into());
+                    env.set(&a, &shared).unwrap();
+                    for _ in 0..100 {
+                        engine.eval_str("(swap! a (fn (x) (+ x 1)))").unwrap();
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(*shared.read().unwrap(), Value::Int(400));
+    }
+
+    #[cfg(feature = "gc")]
+    #[test]
+    fn gc_cycles() {
+        use crate::prelude::{Engine, Value};
+        use std::sync::Arc;
+
+        // An atom holding a fn that closed over it
+        let cycle = "(let (a (atom nil)) (do (reset! a (fn () a)) a))";
+        let mut engine = Engine::new();
+        let Ok(Value::Atom(atom)) = engine.eval_str(cycle) else {
+            panic!("{} is not an atom", cycle);
+        };
+        let weak = Arc::downgrade(&atom);
+        drop(atom);
+        assert!(weak.upgrade().is_some());
+        crate::gc::collect_cycles();
+        assert!(weak.upgrade().is_none());
+
+        // The same cycle, but still reachable from a global
+        engine.eval_str(&format!("(def b {})", cycle)).unwrap();
+        crate::gc::collect_cycles();
+        assert_eq!(engine.eval_str("(= ((deref b)) b)"), Ok(Value::Bool(true)));
+        test_exp("(int? (gc))", "true");
+        test_exp("(int? (get (get (heap-stats) 0) 1))", "true");
+    }
+
+    #[test]
+    fn journal_replay() {
+        use crate::prelude::{Engine, Env, Journal, Value};
+        use std::sync::atomic::{AtomicI64, Ordering};
+        use std::sync::Arc;
+
+        // A native giving something else at each call, like a clock
+        let engine_ticking = |start: i64| {
+            let mut engine = Engine::new();
+            let ticks = Arc::new(AtomicI64::new(start));
+            engine
+                .env_mut()
+                .reg_closure(
+                    "tick",
+                    zap::Arity::Exactly(0),
+                    Box::new(move |_| Ok(Value::Int(ticks.fetch_add(1, Ordering::Relaxed)))),
+                )
+                .unwrap();
+            engine
+        };
+        let src = "(def t (+ (* 10 (tick)) (tick))) (try (int \"x\") (catch e e))";
+
+        let mut engine = engine_ticking(1);
+        engine.record_journal();
+        let recorded = engine.eval_str(src);
+        let journal = engine.take_journal().unwrap();
+        assert_eq!(engine.eval_str("t"), Ok(Value::Int(12)));
+        assert_eq!(journal.len(), 3);
+
+        // Another engine, whose clock is elsewhere, runs the same way
+        let mut other = engine_ticking(100);
+        let bytes = journal.serialize(other.env()).unwrap();
+        let journal = Journal::deserialize(&bytes, other.env_mut()).unwrap();
+        other.replay_journal(journal);
+        assert_eq!(other.eval_str(src), recorded);
+        assert_eq!(other.eval_str("t"), Ok(Value::Int(12)));
+        assert_eq!(
+            other.eval_str("(tick)").map_err(|err| err.message()),
+            Err("The replay called more natives than it journaled.".to_string())
+        );
+        assert!(other.take_journal().unwrap().is_empty());
+        assert_eq!(other.eval_str("(tick)"), Ok(Value::Int(100)));
+    }
+
+    #[test]
+    fn profiler() {
+        use crate::prelude::{Engine, Value};
+
+        let mut engine = Engine::new();
+        engine.set_inline_limit(0);
+        let profile = engine.profile().unwrap();
+        engine
+            .eval_str("(def sq (fn (x) (* x x))) (sq 1) (sq 2) (sq 3)")
+            .unwrap();
+
+        let row = |section: &Value, name: &str| {
+            let Value::List(rows) = section else {
+                panic!("not a section: {:?}", section)
+            };
+            rows.iter()
+                .find_map(|row| match row {
+                    Value::List(row) if row[0] == Value::Str(name.into()) => Some(row.clone()),
+                    _ => None,
+                })
+                .unwrap_or_else(|| panic!("no {} in {:?}", name, section))
+        };
+        let report = profile.lock().unwrap().report();
+        let Value::List(sections) = &report else {
+            panic!("not a report: {:?}", report)
+        };
+        let sq = row(&sections[1], "sq");
+        assert_eq!(sq[1], Value::Int(3));
+        assert!(matches!(sq[2], Value::Number(ms) if ms >= 0.0));
+        // The 4 forms, each with its top chunk
+        assert_eq!(row(&sections[1], "the top")[1], Value::Int(4));
+        assert_eq!(row(&sections[0], "MUL")[1], Value::Int(3));
+        assert_eq!(row(&sections[0], "CALL")[1], Value::Int(3));
+
+        // The code profiled gets the report too, as it is when it's asked for
+        assert_eq!(
+            engine.eval_str("(get (get (profile-report) 1) 0)"),
+            Ok(Value::Str("fns".into()))
+        );
+        profile.lock().unwrap().clear();
+        assert_eq!(
+            engine
+                .eval_str("(get (profile-report) 0)")
+                .map(|ops| ops.pr_str(engine.env_mut())),
+            Ok("(\"ops\" (\"LOOKUP\" 2) (\"CALL\" 1))".to_string())
+        );
+    }
+
+    #[test]
+    fn error_kinds() {
+        use crate::prelude::{Engine, Error, Span};
+
+        let mut engine = Engine::new();
+        let err = engine.eval_str("(+ 1").unwrap_err();
+        assert_eq!((err.kind(), err.span()), ("reader", None));
+
+        let err = engine.eval_str("(do\n  (set! x 1))").unwrap_err();
+        assert_eq!(err.kind(), "compile");
+        assert_eq!(err.span(), Some(Span { line: 2, col: 3 }));
+
+        engine.set_inline_limit(0);
+        engine.eval_str("(def f (fn (a b) a))").unwrap();
+        let err = engine.eval_str("(def g (fn () (f 1)))\n(g)").unwrap_err();
+        let Error::Arity {
+            expected,
+            got,
+            span,
+            trace,
+            ..
+        } = &err
+        else {
+            panic!("An arity error was expected: {:?}", err);
+        };
+        assert_eq!((*expected, *got), (zap::Arity::Exactly(2), 1));
+        assert_eq!(*span, Some(Span { line: 1, col: 15 }));
+        assert_eq!(trace.len(), 2);
+        assert_eq!(err.message(), "Wrong number of args: expected 2, got 1.");
+
+        // The message alone is caught
+        assert_eq!(
+            engine.eval_str("(try (g) (catch e e))"),
+            Ok(zap::Value::Str(
+                "Wrong number of args: expected 2, got 1.".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn engine_eval_read() {
+        use crate::prelude::{Engine, Error, Value};
+
+        // Hands out a few bytes at a time, cutting tokens and chars
+        struct Trickle<'a>(&'a [u8]);
+        impl std::io::Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = self.0.len().min(buf.len()).min(3);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let mut engine = Engine::new();
+        let mut read = 0;
+        let src = "(def total 1200) ; ça\n(def s \"éé\")\n(+ total 34)";
+        let res = engine.eval_read(Trickle(src.as_bytes()), |n| read = n);
+        assert_eq!(res, Ok(Value::Int(1234)));
+        assert_eq!(read, src.len());
+        assert_eq!(engine.eval_str("s"), Ok(Value::Str("éé".into())));
+
+        assert_eq!(
+            engine.eval_read(Trickle(b"(+ total"), |_| {}),
+            Err(Error::reader("Unexpected end of input."))
+        );
+        assert_eq!(
+            engine.eval_read(Trickle(b"\"\xff\""), |_| {}),
+            Err(Error::reader("The input is not valid UTF-8."))
+        );
+        assert_eq!(engine.eval_str("total"), Ok(Value::Int(1200)));
+    }
+
+    #[test]
+    fn engine_record_steps() {
+        use crate::prelude::Engine;
+
+        let mut engine = Engine::new();
+        engine.eval_str("(def y 1)").unwrap();
+        assert!(engine.last_steps(10).is_empty());
+
+        engine.record_steps(3);
+        engine.eval_str("(def y (+ y 1))").unwrap();
+        let steps = engine.last_steps(10);
+        assert_eq!(steps.len(), 3);
+        assert!(steps.iter().all(|step| step.delta.is_some()));
+        assert!(steps[2].op == vm::Op::Return && steps[2].delta == Some(-1));
+
+        // The op that failed comes last
+        assert!(engine.eval_str("(+ y missing)").is_err());
+        let steps = engine.last_steps(2);
+        assert!(matches!(steps[1].op, vm::Op::LookUp(..)));
+        assert_eq!((steps[0].delta, steps[1].delta), (Some(1), None));
+        assert!(steps[1].to_string().ends_with("failed"));
+    }
+
+    #[test]
+    fn engine_trace() {
+        use crate::prelude::{Engine, Tracer, Value};
+        use std::sync::{Arc, Mutex};
+
+        // The ops seen, with their index and the depth of the stack before them
+        struct Seen(Arc<Mutex<Vec<(vm::Op, usize, usize)>>>);
+        impl Tracer for Seen {
+            fn on_op(&mut self, op: vm::Op, pc: usize, stack: &[Value]) {
+                self.0.lock().unwrap().push((op, pc, stack.len()));
+            }
+        }
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        engine.set_inline_limit(0);
+        engine.eval_str("(def inc2 (fn (x) (+ x 2)))").unwrap();
+        engine.trace(Box::new(Seen(seen.clone())));
+        assert_eq!(engine.eval_str("(inc2 1)"), Ok(Value::Int(3)));
+
+        // The pc of the ops of inc2 is counted in its own chunk
+        let seen = seen.lock().unwrap();
+        assert!(matches!(seen[0], (vm::Op::LookUp(..), 0, 0)));
+        assert!(matches!(seen[2], (vm::Op::Call(1), 2, 2)));
+        assert!(matches!(seen[3], (vm::Op::LoadAddConst(0, _), 0, 2)));
+        assert!(matches!(seen[4], (vm::Op::Return, 2, 3)));
+        assert!(matches!(seen[5], (vm::Op::Return, 3, 1)));
+    }
+
+    #[test]
+    fn engine_debug() {
+        use crate::prelude::{Debugger, Engine, Paused, Value};
+        use std::sync::{Arc, Mutex};
+
+        // Keeps the locals it's shown, and doubles the first one
+        struct Doubling(Arc<Mutex<Vec<Vec<Value>>>>);
+        impl Debugger for Doubling {
+            fn on_break(&mut self, paused: Paused) -> zap::Result<()> {
+                self.0.lock().unwrap().push(paused.locals.to_vec());
+                match paused.locals.first_mut() {
+                    Some(Value::Int(n)) => *n *= 2,
+                    _ => return Err(zap::error_msg("Nothing to double")),
+                }
+                Ok(())
+            }
+        }
+
+        let mut engine = Engine::new();
+        engine
+            .eval_str("(def f (fn (a b) (do (break) (+ a b))))")
+            .unwrap();
+        // Without a debugger, a break does nothing
+        assert_eq!(engine.eval_str("(f 1 2)"), Ok(Value::Int(3)));
+        assert_eq!(engine.eval_str("(break)"), Ok(Value::Nil));
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        engine.debug(Box::new(Doubling(seen.clone())));
+        assert_eq!(engine.eval_str("(f 1 2)"), Ok(Value::Int(4)));
+        assert_eq!(engine.eval_str("(f 5 0)"), Ok(Value::Int(10)));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                vec![Value::Int(1), Value::Int(2)],
+                vec![Value::Int(5), Value::Int(0)]
+            ]
+        );
+
+        // The error of the debugger is raised at the break
+        assert_eq!(
+            engine.eval_str("(try (f \"a\" \"b\") (catch e e))"),
+            Ok(Value::Str("Nothing to double".into()))
+        );
+        assert_eq!(
+            engine.eval_str("(break 1)").map_err(|err| err.to_string()),
+            Err("line 1, col 1: A break form takes no parameter".to_string())
+        );
+    }
+
+    #[test]
+    fn engine_reuses_vm() {
+        use crate::prelude::{Engine, Value};
+
+        // A run that failed deep in calls and tries leaves nothing behind for the next one
+        let mut engine = Engine::new();
+        engine
+            .eval_str("(def f (fn (x) (try (+ 1 (f (+ x nil))) (catch e (throw e)))))")
+            .unwrap();
+        assert!(engine.eval_str("(let (a 1 b 2) (+ a b (f 1)))").is_err());
+        assert_eq!(engine.eval_str("(let (a 1) (+ a 2))"), Ok(Value::Int(3)));
+        assert_eq!(
+            engine.eval_str("(try (throw \"again\") (catch e e))"),
+            Ok(Value::Str("again".into()))
+        );
+    }
+
+    #[test]
+    fn engine_core() {
+        use crate::prelude::{Engine, Value};
+
+        let mut engine = Engine::new();
+        assert_eq!(engine.eval_str("(false? false)"), Ok(Value::Bool(true)));
+
+        let mut engine = Engine::new().with_core(false);
+        assert_eq!(
+            engine
+                .eval_str("(false? false)")
+                .map_err(|err| err.to_string()),
+            Err("line 1, col 1: symbol 'false?' not in scope.".to_string())
+        );
+        let mut engine = engine.with_core(true);
+        assert_eq!(
+            engine.eval_str("(concat \"a\" \"b\")"),
+            Ok(Value::Str("ab".into()))
+        );
+
+        assert_eq!(
+            engine.eval_str("(zap-version)"),
+            Ok(Value::Str(env!("CARGO_PKG_VERSION").into()))
+        );
+        let features: Vec<String> = crate::core::features()
+            .map(|name| format!("{:?}", name))
+            .collect();
+        assert_eq!(
+            engine.eval_str("(features)").map(|val| format!("{}", val)),
+            Ok(format!("({})", features.join(" ")))
+        );
+        assert!(engine.eval_str("(features 'io)").is_err());
+    }
+
+    #[test]
+    fn engine_check() {
+        use crate::prelude::{Diagnostic, Engine, Severity};
+
+        let error = |message: &str, line| Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            line,
+        };
+
+        let mut engine = Engine::new();
+        assert_eq!(engine.check("(def x 1)\n(+ x 2)"), vec![]);
+        assert_eq!(
+            engine.check("(def x 1)\n\n(let (x) x)\n(if 1\n  2)\n)"),
+            vec![
+                error("Bindings must have an even number of bindings", 3),
+                error("An if form must have 3 parameters", 4),
+                error("A form cannot begin with ')'", 6),
+            ]
+        );
+        assert_eq!(
+            engine.check("(def y 1) (fn (1) 2)\n(do\n"),
+            vec![
+                error("Only symbols can be used as args in fn.", 1),
+                error("Unexpected end of input.", 2),
+            ]
+        );
+
+        // Nothing was evaluated
+        assert!(engine.eval_str("x").is_err());
+    }
+
+    #[test]
+    fn compile_warnings() {
+        use crate::prelude::{compile_with_warnings, Diagnostic, Engine};
+
+        let warnings = |src: &str| {
+            let mut env = SandboxEnv::default();
+            let mut reader = Reader::new();
+            reader.tokenize(src);
+            reader.flush_token();
+            let ast = reader.read_ast(&mut env).unwrap().unwrap();
+            let mut warnings = Vec::new();
+            compile_with_warnings(ast, &Extensions::new(), &mut env, &mut warnings).unwrap();
+            warnings
+        };
+        assert!(warnings("(let (x 1 y 2) (+ x y))").is_empty());
+        assert!(warnings("(fn f (x _unused) (+ x 1))").is_empty());
+        assert!(warnings("(let (x 1) (fn () x))").is_empty());
+        assert!(warnings("(letfn ((f (n) (g n)) (g (n) n)) (f 1))").is_empty());
+        assert_eq!(
+            warnings("(let (x 1 y 2) (do (set! y 3) x))"),
+            vec!["Local 'y' is never used"]
+        );
+        assert_eq!(
+            warnings("(fn (a b a) (loop (i 0) (+ a b)))"),
+            vec![
+                "Param 'a' shadows an earlier param",
+                "Local 'i' is never used"
+            ]
+        );
+        assert_eq!(
+            warnings("(fn (v) (def cache v))"),
+            vec!["A def of 'cache' in a fn defines a global"]
+        );
+        assert!(warnings("(def cache 1)").is_empty());
+
+        // They don't stop the compilation
+        let mut engine = Engine::new();
+        assert_eq!(
+            engine.check("(def x 1)\n(defn f (n)\n  (let (m 2) n))"),
+            vec![Diagnostic::warning(
+                "Local 'm' is never used".to_string(),
+                2
+            )]
+        );
+    }
+
+    #[test]
+    fn engine_special_form() {
+        use crate::prelude::{Emitter, Engine, Value};
+        use crate::zap::{ZapFnNative, ZapList};
+
+        fn label(args: &[Value]) -> zap::Result<Value> {
+            Ok(Value::List(Value::new_list(args.to_vec())))
+        }
+
+        // (tag name exp) calls label with name unevaluated
+        fn tag(list: &ZapList, emitter: &mut Emitter) -> zap::Result<()> {
+            if list.len() != 3 {
+                return Err(zap::error_msg("A tag form must have 2 parameters"));
+            }
+            emitter.push_const(Value::FuncNative(ZapFnNative::new("label".into(), label)));
+            emitter.push_const(list[1].clone());
+            emitter.compile(list[2].clone());
+            emitter.call(2);
+            Ok(())
+        }
+
+        let mut engine = Engine::new();
+        engine.register_form("tag", tag).unwrap();
+        let res = engine.eval_str("(tag answer (+ 40 2))").unwrap();
+        assert_eq!(res.to_string(engine.env_mut()), "(answer 42)");
+        let res = engine.eval_str("((fn (x) (tag x x)) 1)").unwrap();
+        assert_eq!(res.to_string(engine.env_mut()), "(x 1)");
+        assert!(engine.eval_str("(tag 1)").is_err());
+        assert_eq!(
+            engine.register_form("if", tag),
+            Err(zap::error_msg("'if' is a built-in special form."))
+        );
+    }
+
+    #[test]
+    fn format_source() {
+        use crate::formatter::format_source;
+
+        let fmt = |src: &str| format_source(src).unwrap();
+
+        assert_eq!(fmt("(def  x\n   (+ 1  2))"), "(def x (+ 1 2))\n");
+        assert_eq!(
+            fmt(";; head\n(def x 1)   ; one\n\n\n(def y 2)"),
+            ";; head\n(def x 1) ; one\n\n(def y 2)\n"
+        );
+        assert_eq!(fmt("'( a  `(b ~c ~@d) @e)"), "'(a `(b ~c ~@d) @e)\n");
+        assert_eq!(fmt("(map #( + %  1) xs)"), "(map #(+ % 1) xs)\n");
+        assert_eq!(fmt("(let [ x  1] [x  (f x)])"), "(let [x 1] [x (f x)])\n");
+
+        let src = "(def sum (fn (n) (loop (index 0 total 0) (if (= index n) total (recur (+ index 1) (+ total index))))))";
+        let expected = "\
+(def sum
+  (fn (n)
+    (loop (index 0 total 0)
+      (if (= index n) total (recur (+ index 1) (+ total index))))))
+";
+        assert_eq!(fmt(src), expected);
+        assert_eq!(fmt(expected), expected);
+
+        let src = "(a-function-with-a-long-name first-argument second-argument (foo ; why\n bar))";
+        let expected = "\
+(a-function-with-a-long-name first-argument
+                             second-argument
+                             (foo ; why
+                              bar))
+";
+        assert_eq!(fmt(src), expected);
+        assert_eq!(fmt(expected), expected);
+
+        assert!(format_source("(def x").is_err());
+        assert!(format_source("x)").is_err());
+    }
+
+    #[test]
+    fn eval_unquote() {
+        test_exp("`(1 ~(+ 1 1) 3)", "(1 2 3)");
+        test_exp("(let (x 5) `(1 (2 ~x)))", "(1 (2 5))");
+        test_exp("`~(+ 2 2)", "4");
+
+        // The items are left on the stack and gathered by MakeList, nothing is called
+        let chunk = compile_exp("(let (x 5) `(1 (2 ~x)))");
+        assert!(chunk.ops.contains(&vm::Op::MakeList(2)));
+        assert!(!chunk
+            .ops
+            .iter()
+            .any(|op| matches!(op, vm::Op::Call(_) | vm::Op::Tailcall(_))));
+        assert_eq!(chunk.verify(), Ok(()));
+    }
+
+    #[test]
+    fn eval_splice_unquote() {
+        test_exp("(let (xs '(2 3)) `(1 ~@xs 4))", "(1 2 3 4)");
+        test_exp("(let (xs '()) `(~@xs))", "()");
+        test_exp("`(~@'(1 2) ~@'(3))", "(1 2 3)");
+    }
 }
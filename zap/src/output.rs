@@ -0,0 +1,29 @@
+use std::cell::RefCell;
+use std::io::Write;
+
+use crate::zap::{error_msg, Result};
+
+// Where the printing builtins write. Each thread has its own sink, so a host can
+// redirect the output of an evaluation without affecting the others.
+
+thread_local! {
+    static SINK: RefCell<Option<Box<dyn Write>>> = RefCell::new(None);
+}
+
+// Replace the output sink of the current thread, returning the previous one.
+pub fn set_sink(sink: Option<Box<dyn Write>>) -> Option<Box<dyn Write>> {
+    SINK.with(|cell| cell.replace(sink))
+}
+
+// Write to the output sink of the current thread, or to stdout if there's none.
+// The output is flushed right away so it can be streamed while evaluating.
+pub fn write_str(s: &str) -> Result<()> {
+    SINK.with(|cell| match cell.borrow_mut().as_mut() {
+        Some(sink) => sink.write_all(s.as_bytes()).and_then(|_| sink.flush()),
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(s.as_bytes()).and_then(|_| stdout.flush())
+        }
+    })
+    .map_err(|err| error_msg(format!("Cannot write output: {}", err).as_str()))
+}
@@ -0,0 +1,15 @@
+// The embedding API of zap. Everything not reachable from here
+// is an implementation detail and can change without notice.
+
+pub use crate::compiler::{
+    compile, compile_spanned, compile_with, compile_with_warnings, Emitter, Extensions, SpecialForm,
+};
+pub use crate::diagnostic::{Diagnostic, Severity};
+pub use crate::engine::{eval_str, Engine};
+pub use crate::env::{Env, SandboxEnv};
+pub use crate::formatter::format_source;
+pub use crate::reader::{Reader, Span, Spans};
+pub use crate::vm::{
+    disassemble, Catcher, Ctx, Debugger, Journal, Paused, Profile, Step, Tracer, VmHandle, VM,
+};
+pub use crate::zap::{error_msg, Result, Value, ZapErr as Error};
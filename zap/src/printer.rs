@@ -1,46 +1,98 @@
 use crate::env::Env;
 use crate::zap::Value;
-use std::fmt;
+use std::fmt::{self, Write};
 
-fn escape_str(s: &str) -> String {
-    s.replace('"', "\\\"")
-        .replace('\\', "\\\\")
-        .replace('\n', "\\n")
+// Values are printed straight into the sink, so a large result doesn't build a string per element.
+
+fn write_str<W: Write + ?Sized>(s: &str, out: &mut W) -> fmt::Result {
+    out.write_char('"')?;
+    for ch in s.chars() {
+        match ch {
+            '"' => out.write_str("\\\"")?,
+            '\\' => out.write_str("\\\\")?,
+            '\n' => out.write_str("\\n")?,
+            ch => out.write_char(ch)?,
+        }
+    }
+    out.write_char('"')
 }
 
-impl Value {
-    pub fn pr_str<E: Env>(&self, env: &mut E) -> String {
-        match self {
-            Value::Symbol(s) => env.get_symbol(*s).unwrap().to_string(),
-            Value::List(l) => pr_seq(l, "(", ")", env),
-            val => format!("{}", val),
+// Symbols are printed by name when there's an env to find them in.
+fn write_value<W: Write + ?Sized, E: Env + ?Sized>(
+    val: &Value,
+    out: &mut W,
+    env: Option<&E>,
+) -> fmt::Result {
+    match val {
+        Value::Nil => out.write_str("nil"),
+        Value::Bool(true) => out.write_str("true"),
+        Value::Bool(false) => out.write_str("false"),
+        Value::Int(n) => write!(out, "{}", n),
+        // With its point, so it's read back as a float: 2.0 rather than 2
+        Value::Number(n) => write!(out, "{:?}", n),
+        Value::Decimal(d) => write!(out, "{}M", d),
+        Value::Duration(d) => write!(out, "{}", d),
+        Value::DateTime(t) => write!(out, "{}", t),
+        Value::Symbol(s) => match env.map(|env| env.get_symbol(*s)) {
+            Some(Ok(name)) => out.write_str(&name),
+            _ => write!(out, "Symbol#{}", s),
+        },
+        Value::Keyword(s) => match env.map(|env| env.get_symbol(*s)) {
+            Some(Ok(name)) => write!(out, ":{}", name),
+            _ => write!(out, ":Symbol#{}", s),
+        },
+        Value::Str(s) => write_str(s, out),
+        Value::List(l) => {
+            out.write_char('(')?;
+            for (i, val) in l.iter().enumerate() {
+                if i > 0 {
+                    out.write_char(' ')?;
+                }
+                write_value(val, out, env)?;
+            }
+            out.write_char(')')
+        }
+        Value::Vector(v) => {
+            out.write_char('[')?;
+            for (i, val) in v.iter().enumerate() {
+                if i > 0 {
+                    out.write_char(' ')?;
+                }
+                write_value(val, out, env)?;
+            }
+            out.write_char(']')
         }
+        Value::Func(func) => write!(out, "<Func [{}, {:?}]>", func.chunk.arity, func.locals),
+        Value::FuncNative(func) => write!(out, "<FuncNative {}>", func.name),
+        Value::Closure(_) => out.write_str("<Closure>"),
+        Value::Macro(_) => out.write_str("<Macro>"),
+        Value::Coroutine(_) => out.write_str("<Coroutine>"),
+        Value::Task(_) => out.write_str("<Task>"),
+        Value::Channel(_) => out.write_str("<Channel>"),
+        Value::Atom(_) => out.write_str("<Atom>"),
     }
 }
 
-fn pr_seq<E: Env>(seq: &[Value], start: &str, end: &str, env: &mut E) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| x.pr_str(env)).collect();
-    format!("{}{}{}", start, strs.join(" "), end)
+impl Value {
+    // Print the value readably into out.
+    pub fn write_to<W: Write + ?Sized, E: Env + ?Sized>(
+        &self,
+        out: &mut W,
+        env: &E,
+    ) -> fmt::Result {
+        write_value(self, out, Some(env))
+    }
+
+    pub fn pr_str<E: Env + ?Sized>(&self, env: &mut E) -> String {
+        let mut out = String::new();
+        // Writing in a String can't fail
+        self.write_to(&mut out, env).unwrap();
+        out
+    }
 }
 
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Value::Nil => write!(f, "nil"),
-            Value::Bool(true) => write!(f, "true"),
-            Value::Bool(false) => write!(f, "false"),
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Symbol(n) => write!(f, "Symbol#{}", n),
-            Value::Str(s) => write!(f, "\"{}\"", escape_str(s)),
-            Value::List(l) => write!(f, "{}", debug_seq(l, "(", ")")),
-            Value::Func(func) => write!(f, "<Func [{}, {:?}]>", func.chunk.arity, func.locals),
-            Value::FuncNative(func) => write!(f, "<FuncNative {}>", func.name),
-            Value::Closure(_) => write!(f, "<Closure>"),
-        }
+        write_value::<_, dyn Env>(self, f, None)
     }
 }
-
-fn debug_seq(seq: &[Value], start: &str, end: &str) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| format!("{}", x)).collect();
-    format!("{}{}{}", start, strs.join(" "), end)
-}
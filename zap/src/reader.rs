@@ -1,10 +1,15 @@
+use std::borrow::Cow;
 use std::collections::VecDeque;
 use std::iter::Peekable;
 use std::num::ParseFloatError;
 use std::str::Chars;
+use std::sync::Arc;
 
-use crate::env::Env;
-use crate::zap::{error_msg, String, Value, ZapErr};
+use fxhash::FxHashMap;
+
+use crate::decimal::Decimal;
+use crate::env::{symbols, Env};
+use crate::zap::{String, Symbol, Value, ZapErr, ZapList};
 
 /* Tokenizer */
 
@@ -14,8 +19,11 @@ enum Token {
     Quote,
     Quasiquote,
     Unquote,
-    ListStart,
+    ListStart(Span),
+    LambdaStart(Span),
     ListEnd,
+    VectorStart,
+    VectorEnd,
     SpliceUnquote,
     Deref,
 }
@@ -29,14 +37,19 @@ impl std::fmt::Display for Token {
             Token::Unquote => write!(f, "Unquote"),
             Token::SpliceUnquote => write!(f, "SpliceUnquote"),
             Token::Deref => write!(f, "Deref"),
-            Token::ListStart => write!(f, "ListStart"),
+            Token::ListStart(_) => write!(f, "ListStart"),
+            Token::LambdaStart(_) => write!(f, "LambdaStart"),
             Token::ListEnd => write!(f, "ListEnd"),
+            Token::VectorStart => write!(f, "VectorStart"),
+            Token::VectorEnd => write!(f, "VectorEnd"),
         }
     }
 }
 
 enum ParentForm {
-    List(Vec<Value>),
+    List(Vec<Value>, Option<Span>), // None for the lists of the reader macros
+    Lambda(Vec<Value>, Span),
+    Vector(Vec<Value>),
     Quote,
     Quasiquote,
     Unquote,
@@ -44,11 +57,63 @@ enum ParentForm {
     Deref,
 }
 
+// Interning statistics of a reader, since its creation.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct InternStats {
+    pub lookups: usize,  // Number of symbol atoms read
+    pub hits: usize,     // Symbols resolved without going to the env
+    pub interned: usize, // Distinct symbols known by the reader
+}
+
+// The positional params used in the body of a #() lambda.
+#[derive(Default)]
+struct LambdaParams {
+    arity: usize, // The highest %n
+    rest: bool,   // %& is used
+}
+
+// Where a form starts in the source, both counted from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, col {}", self.line, self.col)
+    }
+}
+
+// The spans of the lists of the last form read, found by the address of the list. The lists are
+// kept alive here, so an address can't be reused by another list while it's a key.
+#[derive(Default)]
+pub struct Spans(FxHashMap<usize, (ZapList, Span)>);
+
+impl Spans {
+    pub fn of(&self, list: &ZapList) -> Option<Span> {
+        self.0
+            .get(&(Arc::as_ptr(list) as usize))
+            .map(|(_, span)| *span)
+    }
+
+    fn insert(&mut self, list: &ZapList, span: Span) {
+        self.0
+            .insert(Arc::as_ptr(list) as usize, (list.clone(), span));
+    }
+}
+
 pub struct Reader {
     lines: u32,
+    col: u32, // The chars read on the current line
     tokens: VecDeque<Token>,
     token_buf: std::string::String,
     stack: Vec<ParentForm>,
+    // A Reader must always be used with the same env, since the symbols are cached here.
+    interned: FxHashMap<std::string::String, Symbol>,
+    stats: InternStats,
+    lambda: Option<LambdaParams>, // Set while reading a #() lambda
+    spans: Spans,
 }
 
 impl Default for Reader {
@@ -61,17 +126,64 @@ impl Reader {
     pub fn new() -> Reader {
         Reader {
             lines: 1,
+            col: 0,
             tokens: VecDeque::new(),
             token_buf: std::string::String::with_capacity(32),
             stack: Vec::with_capacity(64),
+            interned: FxHashMap::default(),
+            stats: InternStats::default(),
+            lambda: None,
+            spans: Spans::default(),
         }
     }
 
+    // True when the reader is in the middle of a form.
+    pub fn is_pending(&self) -> bool {
+        !self.stack.is_empty() || !self.token_buf.is_empty() || !self.tokens.is_empty()
+    }
+
+    // Drop everything that was not read yet.
+    pub fn reset(&mut self) {
+        self.tokens.clear();
+        self.token_buf.truncate(0);
+        self.stack.truncate(0);
+        self.lambda = None;
+        self.spans.0.clear();
+    }
+
+    // Count the lines and cols from the start again, for the next source read.
+    pub fn new_source(&mut self) {
+        self.lines = 1;
+        self.col = 0;
+    }
+
+    pub fn intern_stats(&self) -> InternStats {
+        self.stats
+    }
+
+    // Where the lists of the last form read start, until the next one is read.
+    pub fn spans(&self) -> &Spans {
+        &self.spans
+    }
+
+    fn span(&self) -> Span {
+        Span {
+            line: self.lines,
+            col: self.col,
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.lines += 1;
+        self.col = 0;
+    }
+
     fn tokenize_string(&mut self, chars: &mut Peekable<Chars>) {
         let mut escaped = self.token_buf.ends_with('\\');
 
         #[allow(clippy::while_let_on_iterator)]
         while let Some(ch) = chars.next() {
+            self.col += 1;
             if escaped {
                 match ch {
                     'n' => self.token_buf.push('\n'),
@@ -92,7 +204,7 @@ impl Reader {
                         continue;
                     }
                     '\n' => {
-                        self.lines += 1;
+                        self.new_line();
                         self.token_buf.push(ch)
                     }
                     _ => self.token_buf.push(ch),
@@ -121,12 +233,14 @@ impl Reader {
         // If the last tokenize call ended in a comment
         else if self.token_buf.starts_with(';') {
             if chars.any(|ch| ch == '\n') {
+                self.new_line();
                 self.token_buf.truncate(0);
             }
         } else if self.token_buf.starts_with('~') {
             match chars.peek() {
                 Some('@') => {
                     chars.next();
+                    self.col += 1;
                     self.tokens.push_back(Token::SpliceUnquote);
                 }
                 Some(_) => {
@@ -139,9 +253,10 @@ impl Reader {
 
         #[allow(clippy::while_let_on_iterator)]
         while let Some(ch) = chars.next() {
+            self.col += 1;
             match ch {
                 '\n' => {
-                    self.lines += 1;
+                    self.new_line();
                     self.flush_token();
                 }
                 ' ' | '\t' | ',' => {
@@ -149,12 +264,20 @@ impl Reader {
                 }
                 '(' => {
                     self.flush_token();
-                    self.tokens.push_back(Token::ListStart);
+                    self.tokens.push_back(Token::ListStart(self.span()));
                 }
                 ')' => {
                     self.flush_token();
                     self.tokens.push_back(Token::ListEnd);
                 }
+                '[' => {
+                    self.flush_token();
+                    self.tokens.push_back(Token::VectorStart);
+                }
+                ']' => {
+                    self.flush_token();
+                    self.tokens.push_back(Token::VectorEnd);
+                }
                 '\'' => {
                     self.flush_token();
                     self.tokens.push_back(Token::Quote);
@@ -165,34 +288,31 @@ impl Reader {
                 '`' => {
                     self.tokens.push_back(Token::Quasiquote);
                 }
-                '^' => {
-                    if self.token_buf.is_empty() {
-                        self.tokens.push_back(Token::Atom(ch.to_string()));
-                    } else {
-                        self.token_buf.push(ch);
-                    }
+                '#' if self.token_buf.is_empty() && chars.peek() == Some(&'(') => {
+                    chars.next();
+                    self.tokens.push_back(Token::LambdaStart(self.span()));
+                    self.col += 1;
                 }
-                '~' => {
-                    if self.token_buf.is_empty() {
-                        match chars.peek() {
-                            Some('@') => {
-                                chars.next();
-                                self.tokens.push_back(Token::SpliceUnquote);
-                            }
-                            Some(_) => self.tokens.push_back(Token::Unquote),
-                            None => {
-                                self.token_buf.push(ch);
-                                break;
-                            }
-                        }
-                    } else {
+                '^' if self.token_buf.is_empty() => {
+                    self.tokens.push_back(Token::Atom(ch.to_string()));
+                }
+                '~' if self.token_buf.is_empty() => match chars.peek() {
+                    Some('@') => {
+                        chars.next();
+                        self.col += 1;
+                        self.tokens.push_back(Token::SpliceUnquote);
+                    }
+                    Some(_) => self.tokens.push_back(Token::Unquote),
+                    None => {
                         self.token_buf.push(ch);
+                        break;
                     }
-                }
+                },
                 ';' => {
                     self.flush_token();
                     self.token_buf.push(';');
                     if chars.any(|ch| ch == '\n') {
+                        self.new_line();
                         self.token_buf.truncate(0);
                     }
                 }
@@ -208,40 +328,131 @@ impl Reader {
         }
     }
 
-    fn read_atom<E: Env>(mut atom: std::string::String, env: &mut E) -> Value {
-        match atom.as_ref() {
+    // :name is a keyword. 42 is an integer, 4.2 and 4e2 are floats. 0xFF, 0b1010 and 0o755 are integers too, and
+    // underscores can group the digits, as in 1_000_000. What starts like a number but isn't one,
+    // like 1.2.3, is an error rather than a symbol.
+    fn read_atom<E: Env>(
+        &mut self,
+        mut atom: std::string::String,
+        env: &mut E,
+    ) -> Result<Value, ZapErr> {
+        Ok(match atom.as_ref() {
             "nil" => Value::Nil,
             "true" => Value::Bool(true),
             "false" => Value::Bool(false),
             _ => {
                 if atom.starts_with('"') {
-                    return Value::Str(String::from(atom.split_off(1)));
+                    return Ok(Value::Str(String::from(atom.split_off(1))));
+                }
+                if let Some(name) = atom.strip_prefix(':').filter(|name| !name.is_empty()) {
+                    return Ok(match self.intern(name.to_string(), env) {
+                        Value::Symbol(s) => Value::Keyword(s),
+                        other => other,
+                    });
+                }
+                if !is_number_like(&atom) {
+                    let atom = self.lambda_param(atom);
+                    return Ok(self.intern(atom, env));
+                }
+                let Some(number) = without_underscores(&atom) else {
+                    return Err(self.read_error(&format!("'{}' is not a valid number", atom)));
+                };
+                if let Some(d) = number.strip_suffix('M').and_then(Decimal::parse) {
+                    return Ok(Value::Decimal(d));
                 }
 
-                let potential_float: Result<f64, ParseFloatError> = atom.parse();
+                if let Some(n) = parse_int(&number) {
+                    return Ok(Value::Int(n));
+                }
+                let potential_float: Result<f64, ParseFloatError> = number.parse();
                 match potential_float {
                     Ok(v) => Value::Number(v),
-                    Err(_) => env.reg_symbol(String::from(atom)),
+                    Err(_) => {
+                        return Err(self.read_error(&format!("'{}' is not a valid number", atom)))
+                    }
+                }
+            }
+        })
+    }
+
+    // In a #() lambda, % is the same param as %1
+    fn lambda_param(&mut self, atom: std::string::String) -> std::string::String {
+        let Some(params) = self.lambda.as_mut() else {
+            return atom;
+        };
+        match atom.as_str() {
+            "%" => {
+                params.arity = params.arity.max(1);
+                "%1".to_string()
+            }
+            "%&" => {
+                params.rest = true;
+                atom
+            }
+            _ => {
+                if let Some(n) = atom.strip_prefix('%').and_then(|n| n.parse::<usize>().ok()) {
+                    params.arity = params.arity.max(n);
                 }
+                atom
             }
         }
     }
 
+    // #(f % %2) becomes (fn (%1 %2) (f %1 %2))
+    fn read_lambda<E: Env>(&mut self, body: Vec<Value>, span: Span, env: &mut E) -> Value {
+        let params = self.lambda.take().unwrap_or_default();
+        let mut args: Vec<Value> = (1..=params.arity)
+            .map(|n| self.intern(format!("%{}", n), env))
+            .collect();
+        if params.rest {
+            args.push(Value::Symbol(symbols::REST));
+            args.push(self.intern("%&".to_string(), env));
+        }
+        let body = Value::new_list(body);
+        let lambda = Value::new_list(vec![
+            Value::Symbol(symbols::FN),
+            Value::List(Value::new_list(args)),
+            Value::List(body.clone()),
+        ]);
+        self.spans.insert(&body, span);
+        self.spans.insert(&lambda, span);
+        Value::List(lambda)
+    }
+
+    fn intern<E: Env>(&mut self, atom: std::string::String, env: &mut E) -> Value {
+        self.stats.lookups += 1;
+        if let Some(id) = self.interned.get(&atom) {
+            self.stats.hits += 1;
+            return Value::Symbol(*id);
+        }
+
+        let symbol = env.reg_symbol(String::from(atom.as_str()));
+        if let Value::Symbol(id) = symbol {
+            self.interned.insert(atom, id);
+            self.stats.interned += 1;
+        }
+        symbol
+    }
+
     fn read_error(&mut self, msg: &str) -> ZapErr {
         self.stack.truncate(0);
-        error_msg(msg)
+        self.lambda = None;
+        ZapErr::reader(msg)
     }
 
     #[inline(always)]
     fn expand_reader_macro(&mut self, form: Value, exp: Value) {
         self.tokens.push_front(Token::ListEnd);
-        self.stack.push(ParentForm::List(vec![form, exp]));
+        self.stack.push(ParentForm::List(vec![form, exp], None));
     }
 
     pub fn read_ast<E: Env>(&mut self, env: &mut E) -> Result<Option<Value>, ZapErr> {
+        if self.stack.is_empty() {
+            self.spans.0.clear();
+        }
         while let Some(token) = self.tokens.pop_front() {
             let exp = match token {
-                Token::Atom(s) => Reader::read_atom(s, env),
+                Token::Atom(s) => self.read_atom(s, env)?,
                 Token::Quote => {
                     self.stack.push(ParentForm::Quote);
                     continue;
@@ -262,46 +473,53 @@ impl Reader {
                     self.stack.push(ParentForm::Deref);
                     continue;
                 }
-                Token::ListStart => {
-                    self.stack.push(ParentForm::List(Vec::new()));
+                Token::ListStart(span) => {
+                    self.stack.push(ParentForm::List(Vec::new(), Some(span)));
                     continue;
                 }
-                Token::ListEnd => match self.stack.pop() {
-                    Some(ParentForm::List(seq)) => Value::List(Value::new_list(seq)),
-                    Some(ParentForm::Quote) => return Err(self.read_error("Cannot quote a ')'")),
-                    Some(ParentForm::Quasiquote) => {
-                        return Err(self.read_error("Cannot quasiquote a ')'"))
-                    }
-                    Some(ParentForm::Unquote) => {
-                        return Err(self.read_error("Cannot unquote a ')'"))
-                    }
-                    Some(ParentForm::SpliceUnquote) => {
-                        return Err(self.read_error("Cannot splice-unquote a ')'"))
+                Token::LambdaStart(span) => {
+                    if self.lambda.is_some() {
+                        return Err(self.read_error("Cannot nest #() lambdas"));
                     }
-                    Some(ParentForm::Deref) => return Err(self.read_error("Cannot deref a ')'")),
-                    None => return Err(self.read_error("A form cannot begin with ')'")),
-                },
+                    self.lambda = Some(LambdaParams::default());
+                    self.stack.push(ParentForm::Lambda(Vec::new(), span));
+                    continue;
+                }
+                Token::VectorStart => {
+                    self.stack.push(ParentForm::Vector(Vec::new()));
+                    continue;
+                }
+                Token::ListEnd => self.read_end(')', env)?,
+                Token::VectorEnd => self.read_end(']', env)?,
             };
 
             match self.stack.pop() {
-                Some(ParentForm::List(mut parent)) => {
+                Some(ParentForm::List(mut parent, span)) => {
                     parent.push(exp);
-                    self.stack.push(ParentForm::List(parent));
+                    self.stack.push(ParentForm::List(parent, span));
+                }
+                Some(ParentForm::Lambda(mut body, span)) => {
+                    body.push(exp);
+                    self.stack.push(ParentForm::Lambda(body, span));
+                }
+                Some(ParentForm::Vector(mut items)) => {
+                    items.push(exp);
+                    self.stack.push(ParentForm::Vector(items));
                 }
                 Some(ParentForm::Quote) => {
-                    self.expand_reader_macro(env.reg_symbol(String::from("quote")), exp)
+                    self.expand_reader_macro(Value::Symbol(symbols::QUOTE), exp)
                 }
                 Some(ParentForm::Quasiquote) => {
-                    self.expand_reader_macro(env.reg_symbol(String::from("quasiquote")), exp)
+                    self.expand_reader_macro(Value::Symbol(symbols::QUASIQUOTE), exp)
                 }
                 Some(ParentForm::Unquote) => {
-                    self.expand_reader_macro(env.reg_symbol(String::from("unquote")), exp)
+                    self.expand_reader_macro(Value::Symbol(symbols::UNQUOTE), exp)
                 }
                 Some(ParentForm::SpliceUnquote) => {
-                    self.expand_reader_macro(env.reg_symbol(String::from("splice-unquote")), exp)
+                    self.expand_reader_macro(Value::Symbol(symbols::SPLICE_UNQUOTE), exp)
                 }
                 Some(ParentForm::Deref) => {
-                    self.expand_reader_macro(env.reg_symbol(String::from("deref")), exp)
+                    self.expand_reader_macro(Value::Symbol(symbols::DEREF), exp)
                 }
                 None => return Ok(Some(exp)),
             }
@@ -309,4 +527,77 @@ impl Reader {
 
         Ok(None)
     }
+
+    // The form a ')' or a ']' closes, which must be the one it opened
+    fn read_end<E: Env>(&mut self, end: char, env: &mut E) -> Result<Value, ZapErr> {
+        let msg = match (self.stack.pop(), end) {
+            (Some(ParentForm::List(seq, span)), ')') => {
+                let list = Value::new_list(seq);
+                if let Some(span) = span {
+                    self.spans.insert(&list, span);
+                }
+                return Ok(Value::List(list));
+            }
+            (Some(ParentForm::Lambda(body, span)), ')') => {
+                return Ok(self.read_lambda(body, span, env))
+            }
+            (Some(ParentForm::Vector(items)), ']') => {
+                return Ok(Value::Vector(Value::new_list(items)))
+            }
+            (Some(ParentForm::List(..) | ParentForm::Lambda(..)), _) => {
+                format!("A list cannot end with '{}'", end)
+            }
+            (Some(ParentForm::Vector(_)), _) => format!("A vector cannot end with '{}'", end),
+            (Some(ParentForm::Quote), _) => format!("Cannot quote a '{}'", end),
+            (Some(ParentForm::Quasiquote), _) => format!("Cannot quasiquote a '{}'", end),
+            (Some(ParentForm::Unquote), _) => format!("Cannot unquote a '{}'", end),
+            (Some(ParentForm::SpliceUnquote), _) => format!("Cannot splice-unquote a '{}'", end),
+            (Some(ParentForm::Deref), _) => format!("Cannot deref a '{}'", end),
+            (None, _) => format!("A form cannot begin with '{}'", end),
+        };
+        Err(self.read_error(&msg))
+    }
+}
+
+// A number starts with a digit, or with a sign or a point right before one
+pub(crate) fn is_number_like(atom: &str) -> bool {
+    let unsigned = atom.strip_prefix(['+', '-']).unwrap_or(atom);
+    let digits = unsigned.strip_prefix('.').unwrap_or(unsigned);
+    digits.starts_with(|c: char| c.is_ascii_digit())
+}
+
+// The digits without the underscores between them, unless one isn't between two
+fn without_underscores(atom: &str) -> Option<Cow<'_, str>> {
+    if !atom.contains('_') {
+        return Some(Cow::Borrowed(atom));
+    }
+    let bytes = atom.as_bytes();
+    let grouping = |idx: usize| {
+        idx > 0
+            && bytes[idx - 1].is_ascii_alphanumeric()
+            && bytes.get(idx + 1).is_some_and(u8::is_ascii_alphanumeric)
+    };
+    (0..bytes.len())
+        .filter(|idx| bytes[*idx] == b'_')
+        .all(grouping)
+        .then(|| Cow::Owned(atom.replace('_', "")))
+}
+
+// An integer in base 10, or in base 16, 2 or 8 after a 0x, 0b or 0o
+fn parse_int(number: &str) -> Option<i64> {
+    let (sign, unsigned) = match number.strip_prefix(['+', '-']) {
+        Some(unsigned) => (&number[..1], unsigned),
+        None => ("", number),
+    };
+    let (radix, digits) = match unsigned.get(..2) {
+        Some("0x" | "0X") => (16, &unsigned[2..]),
+        Some("0b" | "0B") => (2, &unsigned[2..]),
+        Some("0o" | "0O") => (8, &unsigned[2..]),
+        _ => return number.parse().ok(),
+    };
+    if !digits.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    // With its sign, so i64::MIN can be read
+    i64::from_str_radix(&format!("{}{}", sign, digits), radix).ok()
 }
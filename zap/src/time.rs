@@ -0,0 +1,361 @@
+use std::fmt;
+
+// Durations and datetimes, to the nanosecond. A duration is signed and written like 2h 3m 4s. A
+// datetime is an instant in UTC, counted from the Unix epoch, so it holds the years 1678 to 2261.
+// It's written in RFC 3339, 2024-03-01T12:00:00Z.
+
+const UNITS: [(&str, i64); 7] = [
+    ("d", 86_400_000_000_000),
+    ("h", 3_600_000_000_000),
+    ("m", 60_000_000_000),
+    ("s", 1_000_000_000),
+    ("ms", 1_000_000),
+    ("us", 1_000),
+    ("ns", 1),
+];
+
+const NANOS_PER_DAY: i64 = UNITS[0].1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Duration {
+    nanos: i64,
+}
+
+impl Duration {
+    pub const fn from_nanos(nanos: i64) -> Duration {
+        Duration { nanos }
+    }
+
+    pub fn nanos(self) -> i64 {
+        self.nanos
+    }
+
+    // One of a unit, named like it's written: d, h, m, s, ms, us or ns
+    pub fn unit(name: &str) -> Option<Duration> {
+        UNITS
+            .iter()
+            .find(|(unit, _)| *unit == name)
+            .map(|(_, nanos)| Duration::from_nanos(*nanos))
+    }
+
+    // Parses what Display writes, like -2h 3m 4s
+    pub fn parse(s: &str) -> Option<Duration> {
+        let s = s.trim();
+        let (negative, s) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let mut total = Duration::default();
+        let mut parts = s.split_whitespace().peekable();
+        parts.peek()?;
+        for part in parts {
+            let digits = part.find(|ch: char| !ch.is_ascii_digit())?;
+            let (amount, unit) = part.split_at(digits);
+            total = total.checked_add(Duration::unit(unit)?.checked_mul(amount.parse().ok()?)?)?;
+        }
+        if negative {
+            Some(Duration::from_nanos(total.nanos.checked_neg()?))
+        } else {
+            Some(total)
+        }
+    }
+
+    pub fn checked_add(self, other: Duration) -> Option<Duration> {
+        self.nanos
+            .checked_add(other.nanos)
+            .map(Duration::from_nanos)
+    }
+
+    pub fn checked_sub(self, other: Duration) -> Option<Duration> {
+        self.nanos
+            .checked_sub(other.nanos)
+            .map(Duration::from_nanos)
+    }
+
+    pub fn checked_mul(self, n: i64) -> Option<Duration> {
+        self.nanos.checked_mul(n).map(Duration::from_nanos)
+    }
+
+    pub fn checked_div(self, n: i64) -> Option<Duration> {
+        self.nanos.checked_div(n).map(Duration::from_nanos)
+    }
+
+    pub fn checked_rem(self, other: Duration) -> Option<Duration> {
+        self.nanos
+            .checked_rem(other.nanos)
+            .map(Duration::from_nanos)
+    }
+
+    // Scaled by a float, to the nearest nanosecond
+    pub fn mul_f64(self, n: f64) -> Option<Duration> {
+        from_f64(self.nanos as f64 * n)
+    }
+
+    pub fn div_f64(self, n: f64) -> Option<Duration> {
+        from_f64(self.nanos as f64 / n)
+    }
+
+    // How many times other fits in it, fractions included
+    pub fn ratio(self, other: Duration) -> f64 {
+        self.nanos as f64 / other.nanos as f64
+    }
+
+    // To a multiple of unit, down, up or to the nearest one, half away from zero
+    pub fn floor(self, unit: Duration) -> Option<Duration> {
+        floor(self.nanos, unit).map(Duration::from_nanos)
+    }
+
+    pub fn ceil(self, unit: Duration) -> Option<Duration> {
+        ceil(self.nanos, unit).map(Duration::from_nanos)
+    }
+
+    pub fn round(self, unit: Duration) -> Option<Duration> {
+        round(self.nanos, unit).map(Duration::from_nanos)
+    }
+}
+
+fn from_f64(nanos: f64) -> Option<Duration> {
+    let nanos = nanos.round();
+    // i64::MAX as f64 rounds up, out of the range
+    (nanos >= i64::MIN as f64 && nanos < i64::MAX as f64)
+        .then(|| Duration::from_nanos(nanos as i64))
+}
+
+pub(crate) fn floor(nanos: i64, unit: Duration) -> Option<i64> {
+    let rem = nanos.checked_rem_euclid(unit.nanos.checked_abs()?)?;
+    nanos.checked_sub(rem)
+}
+
+pub(crate) fn ceil(nanos: i64, unit: Duration) -> Option<i64> {
+    match floor(nanos, unit)? {
+        floor if floor == nanos => Some(floor),
+        floor => floor.checked_add(unit.nanos.abs()),
+    }
+}
+
+pub(crate) fn round(nanos: i64, unit: Duration) -> Option<i64> {
+    let down = floor(nanos, unit)?;
+    let up = ceil(nanos, unit)?;
+    let (below, above) = (
+        i128::from(nanos) - i128::from(down),
+        i128::from(up) - i128::from(nanos),
+    );
+    Some(match below.cmp(&above) {
+        std::cmp::Ordering::Less => down,
+        std::cmp::Ordering::Greater => up,
+        std::cmp::Ordering::Equal if nanos < 0 => down,
+        std::cmp::Ordering::Equal => up,
+    })
+}
+
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.nanos == 0 {
+            return f.write_str("0s");
+        }
+        if self.nanos < 0 {
+            f.write_str("-")?;
+        }
+        let mut left = self.nanos.unsigned_abs();
+        let mut first = true;
+        for (unit, nanos) in UNITS {
+            let nanos = nanos.unsigned_abs();
+            if left >= nanos {
+                if !first {
+                    f.write_str(" ")?;
+                }
+                write!(f, "{}{}", left / nanos, unit)?;
+                left %= nanos;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    nanos: i64, // Since 1970-01-01T00:00:00Z
+}
+
+impl DateTime {
+    pub const fn from_unix_nanos(nanos: i64) -> DateTime {
+        DateTime { nanos }
+    }
+
+    pub fn unix_nanos(self) -> i64 {
+        self.nanos
+    }
+
+    // The clock of the system, which may go back
+    pub fn now() -> Option<DateTime> {
+        let since = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?;
+        i64::try_from(since.as_nanos())
+            .ok()
+            .map(DateTime::from_unix_nanos)
+    }
+
+    // A date, 2024-03-01, or a datetime with its offset, 2024-03-01T12:30:00.5+01:00
+    pub fn parse(s: &str) -> Option<DateTime> {
+        let s = s.trim();
+        let (date, time) = match s.split_once(['T', 't', ' ']) {
+            Some((date, time)) => (date, Some(time)),
+            None => (s, None),
+        };
+
+        let mut fields = date.splitn(3, '-');
+        let year: i64 = number(fields.next()?, 4)?;
+        let month: i64 = number(fields.next()?, 2)?;
+        let day: i64 = number(fields.next()?, 2)?;
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+        let mut nanos = i128::from(days_from_civil(year, month, day)) * i128::from(NANOS_PER_DAY);
+
+        if let Some(time) = time {
+            let (time, offset) = match time.strip_suffix(['Z', 'z']) {
+                Some(time) => (time, 0),
+                None => {
+                    let sign = time.rfind(['+', '-'])?;
+                    let (time, offset) = time.split_at(sign);
+                    let (hours, minutes) = offset[1..].split_once(':')?;
+                    let minutes = number(hours, 2)? * 60 + number(minutes, 2)?;
+                    (
+                        time,
+                        if offset.starts_with('-') {
+                            -minutes
+                        } else {
+                            minutes
+                        },
+                    )
+                }
+            };
+            let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+            let mut fields = time.splitn(3, ':');
+            let hour = number(fields.next()?, 2)?;
+            let minute = number(fields.next()?, 2)?;
+            let second = number(fields.next()?, 2)?;
+            if hour > 23 || minute > 59 || second > 59 {
+                return None;
+            }
+            let fraction = if fraction.is_empty() {
+                0
+            } else if fraction.len() <= 9 && fraction.bytes().all(|b| b.is_ascii_digit()) {
+                format!("{:0<9}", fraction).parse().ok()?
+            } else {
+                return None;
+            };
+            let seconds = hour * 3600 + minute * 60 + second - offset * 60;
+            nanos += i128::from(seconds) * 1_000_000_000 + i128::from(fraction);
+        }
+        i64::try_from(nanos).ok().map(DateTime::from_unix_nanos)
+    }
+
+    pub fn checked_add(self, d: Duration) -> Option<DateTime> {
+        self.nanos
+            .checked_add(d.nanos())
+            .map(DateTime::from_unix_nanos)
+    }
+
+    pub fn checked_sub(self, d: Duration) -> Option<DateTime> {
+        self.nanos
+            .checked_sub(d.nanos())
+            .map(DateTime::from_unix_nanos)
+    }
+
+    // The time from other to it
+    pub fn since(self, other: DateTime) -> Option<Duration> {
+        self.nanos
+            .checked_sub(other.nanos)
+            .map(Duration::from_nanos)
+    }
+
+    // To a multiple of unit since the epoch, so a day starts at midnight UTC
+    pub fn floor(self, unit: Duration) -> Option<DateTime> {
+        floor(self.nanos, unit).map(DateTime::from_unix_nanos)
+    }
+
+    pub fn ceil(self, unit: Duration) -> Option<DateTime> {
+        ceil(self.nanos, unit).map(DateTime::from_unix_nanos)
+    }
+
+    pub fn round(self, unit: Duration) -> Option<DateTime> {
+        round(self.nanos, unit).map(DateTime::from_unix_nanos)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = self.nanos.div_euclid(NANOS_PER_DAY);
+        let nanos = self.nanos.rem_euclid(NANOS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        let seconds = nanos / 1_000_000_000;
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )?;
+        // In milliseconds, microseconds or nanoseconds, whichever is enough
+        let fraction = nanos % 1_000_000_000;
+        if fraction % 1_000_000 == 0 && fraction != 0 {
+            write!(f, ".{:03}", fraction / 1_000_000)?;
+        } else if fraction % 1_000 == 0 && fraction != 0 {
+            write!(f, ".{:06}", fraction / 1_000)?;
+        } else if fraction != 0 {
+            write!(f, ".{:09}", fraction)?;
+        }
+        f.write_str("Z")
+    }
+}
+
+// Exactly so many digits
+fn number(digits: &str, len: usize) -> Option<i64> {
+    (digits.len() == len && digits.bytes().all(|b| b.is_ascii_digit()))
+        .then(|| digits.parse().ok())
+        .flatten()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// The days since 1970-01-01 of a date of the proleptic Gregorian calendar, after Howard Hinnant's
+// algorithms, which count the years from March so the leap day is the last of the year.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
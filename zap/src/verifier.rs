@@ -0,0 +1,205 @@
+use crate::vm::{Chunk, LocalIndex, Op};
+use crate::zap::{error_msg, Result, ZapErr};
+
+// What the VM takes for granted about a chunk, checked before trusting it.
+//
+// The operands must point inside the chunk, and every path through the ops must agree on how
+// many values are on the stack above the locals. A Return leaves exactly one, a tail call has
+// nothing under its function and its args, so it replaces the frame without leftovers. A
+// top-level chunk has no frame to replace and must not tail call at all.
+
+impl Chunk {
+    // The chunk of a function.
+    pub fn verify(&self) -> Result<()> {
+        self.check_operands()?;
+        self.check_stack(false)
+    }
+
+    // The chunk run at the top, which always ends with its value alone on the stack.
+    pub fn verify_top_level(&self) -> Result<()> {
+        self.check_operands()?;
+        self.check_stack(true)
+    }
+
+    fn check_operands(&self) -> Result<()> {
+        let len = self.ops.len();
+        let is_const = |n: u16| usize::from(n) < self.consts.len();
+        let is_local = |slot: LocalIndex| usize::from(slot) < self.scope_size;
+        // Forward jumps are counted from the next op
+        let lands = |idx: usize, n: usize| idx + 1 + n < len;
+
+        if self.ops.last() != Some(&Op::Return) {
+            return Err(invalid("a chunk doesn't end with a return"));
+        }
+        if usize::from(self.arity) + usize::from(self.variadic) > self.scope_size
+            || !self.self_slot.is_none_or(is_local)
+        {
+            return Err(invalid("a chunk has fewer locals than its params"));
+        }
+        for (idx, op) in self.ops.iter().enumerate() {
+            let valid = match *op {
+                Op::Push(n)
+                | Op::AddConst(n)
+                | Op::SubConst(n)
+                | Op::MulConst(n)
+                | Op::DivConst(n)
+                | Op::RemConst(n)
+                | Op::EqConst(n) => is_const(n),
+                Op::Load(slot) | Op::Store(slot) => is_local(slot.into()),
+                Op::LoadW(slot) | Op::StoreW(slot) => is_local(slot),
+                Op::CondJmp(n) | Op::Jmp(n) | Op::Try(n) => lands(idx, n.into()),
+                Op::CondJmpW(n) | Op::JmpW(n) => lands(idx, n as usize),
+                Op::Loop(n) => usize::from(n) <= idx + 1,
+                Op::LoopW(n) => n as usize <= idx + 1,
+                Op::LoadAddConst(slot, c) => is_local(slot.into()) && is_const(c) && lands(idx, 1),
+                Op::LoadEqConstJmp(slot, c, n) => {
+                    is_local(slot.into()) && is_const(c) && lands(idx, 2 + usize::from(n))
+                }
+                Op::AddSlots(a, b) | Op::EqSlots(a, b) => {
+                    is_local(a.into()) && is_local(b.into()) && lands(idx, 2)
+                }
+                Op::Switch(n) => self.tables.get(usize::from(n)).is_some_and(|table| {
+                    lands(idx, table.default.into())
+                        && table.targets.values().all(|t| lands(idx, (*t).into()))
+                }),
+                _ => true,
+            };
+            if !valid {
+                return Err(invalid(&format!("op {} points outside of its chunk", idx)));
+            }
+        }
+        if self.spans.iter().any(|(idx, _)| *idx >= len) {
+            return Err(invalid("a span is past the last op"));
+        }
+        Ok(())
+    }
+
+    // Walks the ops reachable from the first one, with the depth of the stack before each.
+    fn check_stack(&self, top_level: bool) -> Result<()> {
+        let mut depths: Vec<Option<usize>> = vec![None; self.ops.len()];
+        let mut pending = vec![(0, 0)];
+
+        while let Some((idx, depth)) = pending.pop() {
+            match depths[idx] {
+                Some(seen) if seen == depth => continue,
+                Some(_) => {
+                    return Err(invalid(&format!(
+                        "the paths to op {} leave different stacks",
+                        idx
+                    )))
+                }
+                None => depths[idx] = Some(depth),
+            }
+
+            let op = self.ops[idx];
+            let (takes, gives) = stack_effect(op);
+            if depth < takes {
+                return Err(invalid(&format!("op {} pops an empty stack", idx)));
+            }
+            let after = depth - takes + gives;
+            let next = idx + 1;
+
+            match op {
+                Op::Return | Op::Tailcall(_) | Op::TailcallSelf(_) | Op::TailApply(_) => {
+                    if top_level && op != Op::Return {
+                        return Err(invalid(&format!(
+                            "op {} is a tail call at the top level",
+                            idx
+                        )));
+                    }
+                    if depth != takes {
+                        return Err(invalid(&format!(
+                            "op {} leaves values behind on the stack",
+                            idx
+                        )));
+                    }
+                }
+                Op::Throw => {}
+                Op::Jmp(n) => pending.push((next + usize::from(n), after)),
+                Op::JmpW(n) => pending.push((next + n as usize, after)),
+                Op::Loop(n) => pending.push((next - usize::from(n), after)),
+                Op::LoopW(n) => pending.push((next - n as usize, after)),
+                Op::CondJmp(n) => {
+                    pending.push((next, after));
+                    pending.push((next + usize::from(n), after));
+                }
+                Op::CondJmpW(n) => {
+                    pending.push((next, after));
+                    pending.push((next + n as usize, after));
+                }
+                // The rest of a fused sequence is skipped
+                Op::LoadAddConst(..) => pending.push((next + 1, after)),
+                Op::AddSlots(..) | Op::EqSlots(..) => pending.push((next + 2, after)),
+                Op::LoadEqConstJmp(_, _, n) => {
+                    pending.push((next + 2, after));
+                    pending.push((next + 2 + usize::from(n), after));
+                }
+                Op::Switch(n) => {
+                    let table = &self.tables[usize::from(n)];
+                    pending.push((next + usize::from(table.default), after));
+                    for target in table.targets.values() {
+                        pending.push((next + usize::from(*target), after));
+                    }
+                }
+                // The catch starts with the error on top of the stack of the try
+                Op::Try(n) => {
+                    pending.push((next, after));
+                    pending.push((next + usize::from(n), after + 1));
+                }
+                _ => pending.push((next, after)),
+            }
+        }
+        Ok(())
+    }
+}
+
+// How many values an op pops, and how many it pushes back.
+fn stack_effect(op: Op) -> (usize, usize) {
+    match op {
+        Op::Push(_)
+        | Op::LookUp(..)
+        | Op::Load(_)
+        | Op::LoadW(_)
+        | Op::Break
+        | Op::LoadAddConst(..)
+        | Op::AddSlots(..)
+        | Op::EqSlots(..) => (0, 1),
+        Op::Call(argc) | Op::Apply(argc) => (usize::from(argc) + 1, 1),
+        Op::MakeList(n) | Op::MakeVec(n) => (usize::from(n), 1),
+        Op::Tailcall(argc) | Op::TailApply(argc) => (usize::from(argc) + 1, 0),
+        Op::TailcallSelf(argc) => (usize::from(argc), 0),
+        Op::Define | Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Rem | Op::Eq => (2, 1),
+        Op::Pop
+        | Op::Store(_)
+        | Op::StoreW(_)
+        | Op::CondJmp(_)
+        | Op::CondJmpW(_)
+        | Op::Switch(_)
+        | Op::Throw
+        | Op::Return => (1, 0),
+        Op::AddConst(_)
+        | Op::SubConst(_)
+        | Op::MulConst(_)
+        | Op::DivConst(_)
+        | Op::RemConst(_)
+        | Op::EqConst(_)
+        | Op::Not
+        | Op::Closure
+        | Op::LoadFile
+        | Op::Disasm
+        | Op::Yield => (1, 1),
+        Op::Jmp(_)
+        | Op::JmpW(_)
+        | Op::Loop(_)
+        | Op::LoopW(_)
+        | Op::Try(_)
+        | Op::LoadEqConstJmp(..)
+        | Op::EndTry
+        | Op::EnterEnv
+        | Op::LeaveEnv => (0, 0),
+    }
+}
+
+fn invalid(what: &str) -> ZapErr {
+    error_msg(&format!("The chunk is corrupted: {}.", what))
+}
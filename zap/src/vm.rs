@@ -1,32 +1,129 @@
 use core::ptr;
+use std::collections::VecDeque;
 use std::fmt;
-use std::sync::Arc;
+use std::hash::{Hash, Hasher};
+use std::mem::Discriminant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
 
+use crate::decimal::Decimal;
 use crate::env::Env;
-use crate::zap::{error_msg, Result, Symbol, Value, ZapFn};
+use crate::reader::Span;
+use crate::time::{self, DateTime};
+use crate::zap::{
+    error_msg, Arity, Closure, NativeFunc, Pending, Result, String, Structural, Symbol, Value,
+    ZapErr, ZapFn, ZapFnNative,
+};
+use fxhash::FxHashMap;
 
 // Here lives the VM.
 //
-pub type LocalIndex = u8;
+pub type LocalIndex = u16;
 
-#[derive(Clone, Copy, PartialEq)]
+#[doc(hidden)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Op {
-    Push(u16),         // Push a constant on the top of the stack
-    Call(u8),          // Call the function at stack[len-argc]
-    Tailcall(u8),      // Call the function at stack[len-argc], but truncate the stack to ret
-    CondJmp(u16),      // Jump forward n ops if the top of the stack is falsy
-    Jmp(u16),          // Jump forward n ops
-    LookUp(Symbol),    // LookUp the value of a constant and push result
+    Push(u16),           // Push a constant on the top of the stack
+    Call(u16),           // Call the function at stack[len-argc]
+    Tailcall(u16),       // Call the function at stack[len-argc], but truncate the stack to ret
+    TailcallSelf(u16), // Call the running function again with the argc args on top, rewinding its frame
+    Apply(u16),        // Call like Call, the items of the list at the top being its last args
+    TailApply(u16), // Tailcall like Tailcall, the items of the list at the top being its last args
+    CondJmp(u16),   // Jump forward n ops if the top of the stack is falsy
+    Jmp(u16),       // Jump forward n ops
+    Loop(u16),      // Jump backward n ops
+    CondJmpW(u32),  // CondJmp over more ops than a u16 counts
+    JmpW(u32),      // Jmp over more ops than a u16 counts
+    LoopW(u32),     // Loop over more ops than a u16 counts
+    LookUp(Symbol, u16), // LookUp the value of a global, cached at the index, and push it
     Define, // Associate the value at the top with the symbol right under it and set the value back at the top
     Pop,    // Pop the top of the stack
-    Load(LocalIndex), // Push a load on the stack
-    Store(LocalIndex), // Copy a local on the top of the stack
+    Load(u8), // Push a load on the stack
+    Store(u8), // Copy a local on the top of the stack
+    LoadW(LocalIndex), // Load a local past the 256 first ones
+    StoreW(LocalIndex), // Store a local past the 256 first ones
     AddConst(u16), // Add the element at the top of the stack and a constant and push the result
     Add,    // Add 2 elements at the top of the stack and push the result
+    SubConst(u16), // Subtract a constant from the element at the top of the stack
+    Sub, // Subtract the element at the top of the stack from the one under it and push the result
+    MulConst(u16), // Multiply the element at the top of the stack by a constant
+    Mul, // Multiply 2 elements at the top of the stack and push the result
+    DivConst(u16), // Divide the element at the top of the stack by a constant
+    Div, // Divide the element under the top of the stack by the top and push the result
+    RemConst(u16), // The remainder of the element at the top of the stack divided by a constant
+    Rem, // The remainder of the element under the top of the stack divided by the top
     EqConst(u16), // Compare the element at the top of the stack with a constant push true if they're equal and false if they aren't
+    Not,          // Replace the top of the stack by true if it's falsy and false if it isn't
     Eq, // Compare 2 elements at the top of the stack and push true if they're equal and false if they aren't
     Return, // Reserved for end of chunk
     Closure, // Transform the closure at the top of the stack into a func, capturing the outers.
+    Switch(u16), // Pop the top of the stack and jump forward to its target in a jump table
+    Try(u16), // Install a handler catching the errors until EndTry, its catch is n ops forward
+    EndTry, // Remove the handler of the innermost try
+    Throw, // Pop the top of the stack and raise it
+    EnterEnv, // Enter a layer of the env, whose globals are discarded by LeaveEnv
+    LeaveEnv, // Leave the innermost layer of the env
+    LoadFile, // Pop a path and evaluate the file there in the env
+    Disasm, // Pop a function and push the disassembly of its chunk
+    Break, // Hand the frame to the debugger of the VM, if it has one, then push nil
+    Yield, // Suspend the coroutine running, handing it the top, which is replaced when it resumes
+    MakeList(u16), // Pop n values and push the list of them, the deepest first
+    MakeVec(u16), // Pop n values and push the vector of them, the deepest first
+
+    // Superinstructions, replacing the first op of a sequence the compiler fused. The ops of
+    // the sequence are left after it, skipped, so the jumps landing in them still work.
+    LoadAddConst(u8, u16),        // Load; AddConst
+    LoadEqConstJmp(u8, u16, u16), // Load; EqConst; CondJmp
+    // Register-style, taking both operands from their slots. Only fused with register-ops.
+    AddSlots(u8, u8), // Load; Load; Add
+    EqSlots(u8, u8),  // Load; Load; Eq
+}
+
+// The jumps are narrow unless they don't fit, a wide one takes the same place in the chunk so a
+// placeholder can be patched either way.
+impl Op {
+    pub fn jmp(n: usize) -> Result<Op> {
+        Op::sized(n, Op::Jmp, Op::JmpW)
+    }
+
+    pub fn cond_jmp(n: usize) -> Result<Op> {
+        Op::sized(n, Op::CondJmp, Op::CondJmpW)
+    }
+
+    pub fn loop_back(n: usize) -> Result<Op> {
+        Op::sized(n, Op::Loop, Op::LoopW)
+    }
+
+    // The first 256 locals, the most used, get the narrow ops
+    pub fn load(slot: LocalIndex) -> Op {
+        u8::try_from(slot).map_or(Op::LoadW(slot), Op::Load)
+    }
+
+    pub fn store(slot: LocalIndex) -> Op {
+        u8::try_from(slot).map_or(Op::StoreW(slot), Op::Store)
+    }
+
+    // The first op of the sequence a superinstruction stands for
+    pub fn unfused(self) -> Op {
+        match self {
+            Op::LoadAddConst(slot, _)
+            | Op::LoadEqConstJmp(slot, _, _)
+            | Op::AddSlots(slot, _)
+            | Op::EqSlots(slot, _) => Op::Load(slot),
+            op => op,
+        }
+    }
+
+    fn sized(n: usize, narrow: fn(u16) -> Op, wide: fn(u32) -> Op) -> Result<Op> {
+        match u16::try_from(n) {
+            Ok(n) => Ok(narrow(n)),
+            Err(_) => u32::try_from(n)
+                .map(wide)
+                .map_err(|_| error_msg("A jump is too far.")),
+        }
+    }
 }
 
 impl fmt::Debug for Op {
@@ -39,71 +136,489 @@ impl fmt::Debug for Op {
             Op::Tailcall(argc) => {
                 write!(f, "TAILCALL    argc({})", argc)
             }
+            Op::TailcallSelf(argc) => {
+                write!(f, "TAILSELF    argc({})", argc)
+            }
+            Op::Apply(argc) => write!(f, "APPLY       argc({})", argc),
+            Op::TailApply(argc) => write!(f, "TAILAPPLY   argc({})", argc),
             Op::CondJmp(n) => write!(f, "CONDJMP     {}", n),
             Op::Jmp(n) => write!(f, "JMP         {}", n),
-            Op::LookUp(id) => write!(f, "LOOKUP      #{}", id),
+            Op::Loop(n) => write!(f, "LOOP        {}", n),
+            Op::CondJmpW(n) => write!(f, "CONDJMPW    {}", n),
+            Op::JmpW(n) => write!(f, "JMPW        {}", n),
+            Op::LoopW(n) => write!(f, "LOOPW       {}", n),
+            Op::LookUp(id, _) => write!(f, "LOOKUP      #{}", id),
             Op::Define => write!(f, "DEFINE"),
             Op::Pop => write!(f, "POP"),
             Op::Load(idx) => write!(f, "LOAD        {}", idx),
             Op::Store(idx) => write!(f, "STORE       {}", idx),
+            Op::LoadW(idx) => write!(f, "LOADW       {}", idx),
+            Op::StoreW(idx) => write!(f, "STOREW      {}", idx),
             Op::AddConst(idx) => write!(f, "ADDCONST    const({})", idx),
             Op::Add => write!(f, "ADD"),
+            Op::SubConst(idx) => write!(f, "SUBCONST    const({})", idx),
+            Op::Sub => write!(f, "SUB"),
+            Op::MulConst(idx) => write!(f, "MULCONST    const({})", idx),
+            Op::Mul => write!(f, "MUL"),
+            Op::DivConst(idx) => write!(f, "DIVCONST    const({})", idx),
+            Op::Div => write!(f, "DIV"),
+            Op::RemConst(idx) => write!(f, "REMCONST    const({})", idx),
+            Op::Rem => write!(f, "REM"),
             Op::EqConst(idx) => write!(f, "EQCONST     const({})", idx),
             Op::Eq => write!(f, "EQ"),
+            Op::Not => write!(f, "NOT"),
             Op::Return => write!(f, "RETURN"),
             Op::Closure => write!(f, "CLOSURE"),
+            Op::Switch(idx) => write!(f, "SWITCH      table({})", idx),
+            Op::Try(n) => write!(f, "TRY         {}", n),
+            Op::EndTry => write!(f, "ENDTRY"),
+            Op::Throw => write!(f, "THROW"),
+            Op::EnterEnv => write!(f, "ENTERENV"),
+            Op::LeaveEnv => write!(f, "LEAVEENV"),
+            Op::LoadFile => write!(f, "LOADFILE"),
+            Op::Disasm => write!(f, "DISASM"),
+            Op::Break => write!(f, "BREAK"),
+            Op::Yield => write!(f, "YIELD"),
+            Op::MakeList(n) => write!(f, "MAKELIST    {}", n),
+            Op::MakeVec(n) => write!(f, "MAKEVEC     {}", n),
+            Op::LoadAddConst(slot, idx) => write!(f, "LOADADDCONST {} const({})", slot, idx),
+            Op::LoadEqConstJmp(slot, idx, n) => {
+                write!(f, "LOADEQCONSTJMP {} const({}) {}", slot, idx, n)
+            }
+            Op::AddSlots(a, b) => write!(f, "ADDSLOTS    {} {}", a, b),
+            Op::EqSlots(a, b) => write!(f, "EQSLOTS     {} {}", a, b),
         }
     }
 }
 
+// The values a jump table can dispatch on, hashed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum CaseKey {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Number(u64),
+    Decimal(Decimal),
+    Duration(time::Duration),
+    DateTime(DateTime),
+    Symbol(Symbol),
+    Keyword(Symbol),
+    Str(String),
+}
+
+impl CaseKey {
+    #[inline]
+    pub(crate) fn of(val: &Value) -> Option<CaseKey> {
+        match val {
+            Value::Nil => Some(CaseKey::Nil),
+            Value::Bool(b) => Some(CaseKey::Bool(*b)),
+            Value::Int(n) => Some(CaseKey::Int(*n)),
+            // NaN is equal to nothing, and 0.0 is equal to -0.0
+            Value::Number(n) if n.is_nan() => None,
+            Value::Number(n) => Some(CaseKey::Number((n + 0.0).to_bits())),
+            Value::Decimal(d) => Some(CaseKey::Decimal(d.clone())),
+            Value::Duration(d) => Some(CaseKey::Duration(*d)),
+            Value::DateTime(t) => Some(CaseKey::DateTime(*t)),
+            Value::Symbol(s) => Some(CaseKey::Symbol(*s)),
+            Value::Keyword(s) => Some(CaseKey::Keyword(*s)),
+            Value::Str(s) => Some(CaseKey::Str(s.clone())),
+            _ => None,
+        }
+    }
+
+    fn value(&self) -> Value {
+        match self {
+            CaseKey::Nil => Value::Nil,
+            CaseKey::Bool(b) => Value::Bool(*b),
+            CaseKey::Int(n) => Value::Int(*n),
+            CaseKey::Number(bits) => Value::Number(f64::from_bits(*bits)),
+            CaseKey::Decimal(d) => Value::Decimal(d.clone()),
+            CaseKey::Duration(d) => Value::Duration(*d),
+            CaseKey::DateTime(t) => Value::DateTime(*t),
+            CaseKey::Symbol(s) => Value::Symbol(*s),
+            CaseKey::Keyword(s) => Value::Keyword(*s),
+            CaseKey::Str(s) => Value::Str(s.clone()),
+        }
+    }
+}
+
+// The branches of a case, as offsets from the op following its switch.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct JumpTable {
+    pub(crate) targets: FxHashMap<CaseKey, u16>,
+    pub(crate) default: u16,
+}
+
+impl JumpTable {
+    // Lists and functions are compared by identity, they can't be keys.
+    pub fn accepts(key: &Value) -> bool {
+        CaseKey::of(key).is_some()
+    }
+
+    // The first branch of a key is the one taken
+    pub fn insert(&mut self, key: &Value, target: u16) {
+        if let Some(key) = CaseKey::of(key) {
+            self.targets.entry(key).or_insert(target);
+        }
+    }
+
+    pub fn set_default(&mut self, target: u16) {
+        self.default = target;
+    }
+
+    #[inline]
+    fn target(&self, val: &Value) -> u16 {
+        CaseKey::of(val)
+            .and_then(|key| self.targets.get(&key).copied())
+            .unwrap_or(self.default)
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Chunk {
     pub ops: Vec<Op>,
     pub consts: Vec<Value>,
     pub scope_size: usize,
     pub arity: u8,
+    pub variadic: bool, // Surplus args are collected in a list, right after the fixed ones
+    pub self_slot: Option<LocalIndex>, // Where a named fn finds itself
+    pub tables: Vec<JumpTable>,
+    pub spans: Vec<(usize, Span)>, // The ops from each index on were compiled from the span
+    pub name: Option<Symbol>,      // The name of the fn, for the stack traces
+    pub(crate) lookups: Box<[LookupCache]>, // One for each LookUp, see cache_lookups
+}
+
+// Two chunks are equal when they were compiled from the same code, wherever they live, and
+// wherever it was read, whatever it's named. The
+// hash is deterministic, so it can key caches of compiled code.
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.ops == other.ops
+            && self.consts.len() == other.consts.len()
+            && self
+                .consts
+                .iter()
+                .zip(other.consts.iter())
+                .all(|(a, b)| Structural(a) == Structural(b))
+            && self.scope_size == other.scope_size
+            && self.arity == other.arity
+            && self.variadic == other.variadic
+            && self.self_slot == other.self_slot
+            && self.tables == other.tables
+    }
+}
+
+impl Eq for Chunk {}
+
+impl Hash for Chunk {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ops.hash(state);
+        self.consts.len().hash(state);
+        for val in &self.consts {
+            Structural(val).hash(state);
+        }
+        self.scope_size.hash(state);
+        self.arity.hash(state);
+        self.variadic.hash(state);
+        self.self_slot.hash(state);
+        // The order of the targets is not deterministic, they are left to eq
+        for table in &self.tables {
+            table.default.hash(state);
+        }
+    }
 }
 
 impl Chunk {
+    pub fn get_arity(&self) -> Arity {
+        if self.variadic {
+            Arity::AtLeast(self.arity.into())
+        } else {
+            Arity::Exactly(self.arity.into())
+        }
+    }
+
+    // Where the code of the op at idx was read, when the chunk was compiled with spans
+    pub fn span_at(&self, idx: usize) -> Option<Span> {
+        let run = self.spans.partition_point(|(start, _)| *start <= idx);
+        run.checked_sub(1).map(|run| self.spans[run].1)
+    }
+
+    // Give each LookUp a cache of its own, once the ops are final. The ones past u16::MAX are
+    // left without.
+    pub(crate) fn cache_lookups(&mut self) {
+        let mut count = 0;
+        for op in &mut self.ops {
+            if let Op::LookUp(_, cache) = op {
+                *cache = u16::try_from(count).unwrap_or(u16::MAX);
+                count += 1;
+            }
+        }
+        self.lookups = (0..count.min(usize::from(u16::MAX)))
+            .map(|_| LookupCache::default())
+            .collect();
+    }
+
     #[inline]
     fn get_callframe(&self, ret: usize) -> CallFrame {
         CallFrame {
             pc: self.ops.as_ptr(),
             consts: self.consts.as_ptr(),
+            tables: self.tables.as_ptr(),
+            lookups: &raw const *self.lookups,
             ret,
-            #[cfg(debug_assertions)]
-            start: self.ops.as_ptr(),
+            func: None,
+        }
+    }
+}
+
+// What a LookUp found, kept while the env is at the same generation. A chunk calling itself, or
+// another one calling it back, would own itself through the cache, so the fns are held weakly.
+// The values that could hold a fn aren't cached.
+#[derive(Default, Debug)]
+pub(crate) struct LookupCache(RwLock<Option<(u64, Cached)>>);
+
+#[derive(Debug)]
+enum Cached {
+    Value(Value),
+    Func(Weak<ZapFn>),
+    Closure(Weak<Closure>),
+}
+
+impl LookupCache {
+    #[inline]
+    fn get(&self, generation: u64, lookup: impl FnOnce() -> Result<Value>) -> Result<Value> {
+        if let Ok(cached) = self.0.read() {
+            if let Some((at, cached)) = &*cached {
+                let val = match cached {
+                    _ if *at != generation => None,
+                    Cached::Value(val) => Some(val.clone()),
+                    Cached::Func(f) => f.upgrade().map(Value::Func),
+                    Cached::Closure(f) => f.upgrade().map(Value::Closure),
+                };
+                if let Some(val) = val {
+                    return Ok(val);
+                }
+            }
+        }
+        let val = lookup()?;
+        let cached = match &val {
+            Value::Func(f) => Some(Cached::Func(Arc::downgrade(f))),
+            Value::Closure(f) => Some(Cached::Closure(Arc::downgrade(f))),
+            Value::List(_)
+            | Value::Vector(_)
+            | Value::Macro(_)
+            | Value::Coroutine(_)
+            | Value::Task(_)
+            | Value::Channel(_)
+            | Value::Atom(_) => None,
+            val => Some(Cached::Value(val.clone())),
+        };
+        // Another thread filling it meanwhile is left to it
+        if let Ok(mut cache) = self.0.try_write() {
+            *cache = cached.map(|cached| (generation, cached));
         }
+        Ok(val)
     }
 }
 
-pub struct CallFrame {
+// The ops of a chunk as text, one a line, with the consts and globals they use written out and
+// the targets of the jumps as op indexes. The chunks of the functions in its consts follow.
+pub fn disassemble<E: Env + ?Sized>(chunk: &Chunk, env: &E) -> std::string::String {
+    let mut out = std::string::String::new();
+    write_chunk(&mut out, chunk, env, "chunk");
+    out
+}
+
+fn write_chunk<E: Env + ?Sized>(out: &mut std::string::String, chunk: &Chunk, env: &E, name: &str) {
+    let pr = |val: &Value| {
+        let mut s = std::string::String::new();
+        // Writing in a String can't fail
+        val.write_to(&mut s, env).unwrap();
+        s
+    };
+    let rest = if chunk.variadic { " & rest" } else { "" };
+    out.push_str(&format!(
+        "; {}: {} params{}, {} locals\n",
+        name, chunk.arity, rest, chunk.scope_size
+    ));
+
+    for (idx, op) in chunk.ops.iter().enumerate() {
+        let next = idx + 1;
+        let note = match *op {
+            Op::Push(n)
+            | Op::AddConst(n)
+            | Op::SubConst(n)
+            | Op::MulConst(n)
+            | Op::DivConst(n)
+            | Op::RemConst(n)
+            | Op::EqConst(n)
+            | Op::LoadAddConst(_, n) => pr(&chunk.consts[usize::from(n)]),
+            Op::LoadEqConstJmp(_, c, n) => format!(
+                "{} -> {}",
+                pr(&chunk.consts[usize::from(c)]),
+                next + 2 + usize::from(n)
+            ),
+            Op::LookUp(s, _) => pr(&Value::Symbol(s)),
+            Op::CondJmp(n) | Op::Jmp(n) | Op::Try(n) => format!("-> {}", next + usize::from(n)),
+            Op::Loop(n) => format!("-> {}", next - usize::from(n)),
+            Op::CondJmpW(n) | Op::JmpW(n) => format!("-> {}", next + n as usize),
+            Op::LoopW(n) => format!("-> {}", next - n as usize),
+            Op::Switch(n) => {
+                let table = &chunk.tables[usize::from(n)];
+                let mut targets: Vec<_> = table
+                    .targets
+                    .iter()
+                    .map(|(key, target)| (*target, pr(&key.value())))
+                    .collect();
+                targets.sort();
+                let mut note: Vec<_> = targets
+                    .iter()
+                    .map(|(target, key)| format!("{} -> {}", key, next + usize::from(*target)))
+                    .collect();
+                note.push(format!("else -> {}", next + usize::from(table.default)));
+                note.join(", ")
+            }
+            _ => std::string::String::new(),
+        };
+        let op = format!("{:?}", op);
+        if note.is_empty() {
+            out.push_str(&format!("{:0>5} {}\n", idx, op));
+        } else {
+            out.push_str(&format!("{:0>5} {:<24} ; {}\n", idx, op, note));
+        }
+    }
+
+    for (idx, val) in chunk.consts.iter().enumerate() {
+        let inner = match val {
+            Value::Func(f) | Value::Macro(f) => &f.chunk,
+            Value::Closure(c) => &c.chunk,
+            _ => continue,
+        };
+        out.push('\n');
+        write_chunk(out, inner, env, &format!("const({})", idx));
+    }
+}
+
+struct CallFrame {
     pc: *const Op,
     consts: *const Value,
+    tables: *const JumpTable,
+    lookups: *const [LookupCache],
     ret: usize,
-    #[cfg(debug_assertions)]
-    start: *const Op,
+    func: Option<Arc<ZapFn>>, // The function running, None for a chunk run at the top
 }
 
 impl CallFrame {
-    #[inline]
-    pub fn get_ret(&self) -> usize {
-        self.ret
+    // The op this frame is running, or calling from, when it runs the ops of chunk
+    fn op_index(&self, chunk: &Chunk) -> Option<usize> {
+        let offset = (self.pc as usize).checked_sub(chunk.ops.as_ptr() as usize)?;
+        let next = offset / std::mem::size_of::<Op>();
+        (1..=chunk.ops.len()).contains(&next).then(|| next - 1)
     }
 }
 
+// Where to resume when an error is raised in the body of a try.
+struct Handler {
+    calls: usize,  // The depth of the frame of the try
+    stack: usize,  // The size of the stack when the try began
+    layers: usize, // The env layers entered when the try began
+    catch: *const Op,
+}
+
 struct VmState {
     callframe: CallFrame,
     stack: Vec<Value>,
     calls: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    layers: usize,               // The env layers entered by this run
+    max_depth: usize,            // The frames calls can stack up to
+    fuel: u64,                   // The ops left to run, kept while the run is suspended
+    pending: Option<Pending>,    // What an async native gave back, the run waits for it
+    yielded: Option<Value>,      // What the coroutine yielded, the run waits to be resumed
+    in_coroutine: bool,          // Whether a yield can suspend the run
+    journal: Option<Journaling>, // Lent by the VM for the run
+}
+
+// The frames point in the chunks they hold, or in the top one held by the run, and the run
+// holds the state. It can be moved to another thread while suspended.
+unsafe impl Send for VmState {}
+
+// The vectors of a VmState, kept by a VM between its runs to spare the allocations.
+struct Buffers {
+    stack: Vec<Value>,
+    calls: Vec<CallFrame>,
+    handlers: Vec<Handler>,
 }
 
+// The frames and the handlers point in the chunks of a run, and they're cleared at its end. Kept
+// by a VM, they're empty.
+unsafe impl Send for Buffers {}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Buffers {
+            stack: Vec::with_capacity(8),
+            calls: Vec::with_capacity(4),
+            handlers: Vec::new(),
+        }
+    }
+}
+
+// The capacity kept of each buffer, a deep recursion shouldn't hold on to its memory
+const KEPT_CAPACITY: usize = 1024;
+
 impl VmState {
-    fn new(chunk: &Arc<Chunk>) -> Self {
+    fn new(chunk: &Arc<Chunk>, limits: Limits, buffers: Buffers) -> Self {
+        let mut stack = buffers.stack;
+        // Make place for the locals
+        stack.resize_with(chunk.scope_size, Default::default);
         VmState {
             callframe: chunk.get_callframe(0),
-            calls: Vec::with_capacity(4),
-            stack: Vec::with_capacity(8),
+            calls: buffers.calls,
+            stack,
+            handlers: buffers.handlers,
+            layers: 0,
+            max_depth: limits.depth.unwrap_or(usize::MAX),
+            // Without a limit, there's more fuel than an evaluation could burn
+            fuel: limits.ops.unwrap_or(u64::MAX),
+            pending: None,
+            yielded: None,
+            in_coroutine: false,
+            journal: None,
+        }
+    }
+
+    // The run goes on with what the async native it waited for gave, at the op that called it
+    fn resume<E: Env + ?Sized>(
+        &mut self,
+        out: Result<Value>,
+        chunk: &Chunk,
+        env: &mut E,
+        locate: bool,
+    ) -> Result<()> {
+        if let Some(Journaling::Record(journal)) = &mut self.journal {
+            journal.results.push_back(out.clone());
+        }
+        match out {
+            Ok(val) => {
+                *self.stack.last_mut().unwrap() = val;
+                Ok(())
+            }
+            Err(err) => raise(self, err, chunk, env, locate),
+        }
+    }
+
+    fn into_buffers(mut self) -> Buffers {
+        self.stack.clear();
+        self.stack.shrink_to(KEPT_CAPACITY);
+        self.calls.clear();
+        self.calls.shrink_to(KEPT_CAPACITY);
+        self.handlers.clear();
+        self.handlers.shrink_to(KEPT_CAPACITY);
+        Buffers {
+            stack: self.stack,
+            calls: self.calls,
+            handlers: self.handlers,
         }
     }
 
@@ -116,12 +631,68 @@ impl VmState {
         }
     }
 
+    // The calls that led to the op that failed, innermost first. There's none when the top chunk
+    // failed, and a deep recursion is cut short.
+    fn backtrace<E: Env + ?Sized>(&self, top: &Chunk, env: &E) -> Vec<std::string::String> {
+        const SHOWN: usize = 10;
+        if self.calls.is_empty() {
+            return Vec::new();
+        }
+        let frames = std::iter::once(&self.callframe).chain(self.calls.iter().rev());
+        let mut lines: Vec<std::string::String> = frames
+            .take(SHOWN)
+            .map(|frame| {
+                let chunk = frame.func.as_ref().map_or(top, |func| &func.chunk);
+                let name = match (&frame.func, chunk.name) {
+                    (None, _) => "the top".to_string(),
+                    (Some(_), Some(name)) => env
+                        .get_symbol(name)
+                        .map_or_else(|_| "an anonymous fn".to_string(), |name| name.to_string()),
+                    (Some(_), None) => "an anonymous fn".to_string(),
+                };
+                let idx = frame.op_index(chunk).unwrap_or_default();
+                match chunk.span_at(idx) {
+                    Some(span) => format!("in {}, op {} ({})", name, idx, span),
+                    None => format!("in {}, op {}", name, idx),
+                }
+            })
+            .collect();
+        let hidden = (self.calls.len() + 1).saturating_sub(SHOWN);
+        if hidden > 0 {
+            lines.push(format!("and {} more", hidden));
+        }
+        lines
+    }
+
+    // The span of the op that failed, or of the closest call leading to it when its chunk has no
+    // spans.
+    fn error_span(&self, top: &Chunk) -> Option<Span> {
+        std::iter::once(&self.callframe)
+            .chain(self.calls.iter().rev())
+            .find_map(|frame| {
+                let chunk = frame.func.as_ref().map_or(top, |func| &func.chunk);
+                chunk.span_at(frame.op_index(chunk)?)
+            })
+    }
+
+    fn pause(&mut self, top: &Chunk, debugger: &mut dyn Debugger) -> Result<()> {
+        let chunk = self.callframe.func.as_ref().map_or(top, |func| &func.chunk);
+        let (locals, temps) = self.stack[self.callframe.ret..].split_at_mut(chunk.scope_size);
+        debugger.on_break(Paused {
+            chunk,
+            pc: self.callframe.op_index(chunk).unwrap_or_default(),
+            locals,
+            temps,
+        })
+    }
+
     #[inline]
     fn pop_call(&mut self) -> bool {
         if let Some(frame) = self.calls.pop() {
+            // The result replaces the called function, right under the frame
             let tos = self.stack.len() - 1;
-            self.stack.swap(self.callframe.ret, tos);
-            self.stack.truncate(self.callframe.ret + 1);
+            self.stack.swap(self.callframe.ret - 1, tos);
+            self.stack.truncate(self.callframe.ret);
             self.callframe = frame;
             true
         } else {
@@ -130,25 +701,53 @@ impl VmState {
     }
 
     #[inline]
-    fn call(&mut self, argc: usize) -> Result<()> {
-        let ret = self.stack.len() - (argc + 1);
-        let head = std::mem::take(unsafe { self.stack.get_unchecked_mut(ret) });
+    fn call<E: Env + ?Sized>(
+        &mut self,
+        argc: usize,
+        env: &mut E,
+        limits: Limits,
+        cancelled: &AtomicBool,
+    ) -> Result<()> {
+        let ret = self.stack.len() - argc;
+        let head = std::mem::take(unsafe { self.stack.get_unchecked_mut(ret - 1) });
         match head {
             Value::Func(func) => {
+                check_arity(&func, argc)?;
+                if self.calls.len() >= self.max_depth {
+                    return Err(error_msg(&format!(
+                        "Stack overflow: the calls are more than {} deep.",
+                        self.max_depth
+                    )));
+                }
                 self.calls.push(std::mem::replace(
                     &mut self.callframe,
                     func.chunk.get_callframe(ret),
                 ));
 
-                self.stack.extend_from_slice(&func.locals);
-
+                self.enter(&func);
+                self.callframe.func = Some(func);
                 Ok(())
             }
             Value::FuncNative(f) => {
-                let args = unsafe { &self.stack.get_unchecked((ret + 1)..self.stack.len()) };
+                check_native_arity(&f, argc)?;
+                let args = unsafe { &self.stack.get_unchecked(ret..self.stack.len()) };
 
-                let mut output = (f.func)(args)?;
-                self.stack.truncate(ret + 1);
+                let mut tail = None;
+                let mut output = call_native(
+                    &f,
+                    args,
+                    env,
+                    limits,
+                    cancelled,
+                    &mut self.pending,
+                    self.journal.as_mut(),
+                    &mut tail,
+                )?;
+                self.stack.truncate(ret);
+                if let Some((f, args)) = tail {
+                    let argc = self.bounce(f, args);
+                    return self.call(argc, env, limits, cancelled);
+                }
                 std::mem::swap(self.stack.last_mut().unwrap(), &mut output);
                 Ok(())
             }
@@ -157,30 +756,46 @@ impl VmState {
     }
 
     #[inline]
-    fn tailcall(&mut self, argc: usize) -> Result<()> {
+    fn tailcall<E: Env + ?Sized>(
+        &mut self,
+        argc: usize,
+        env: &mut E,
+        limits: Limits,
+        cancelled: &AtomicBool,
+    ) -> Result<()> {
         let args_base = self.stack.len() - argc;
         let head = std::mem::take(unsafe { self.stack.get_unchecked_mut(args_base - 1) });
         match head {
             Value::Func(func) => {
+                check_arity(&func, argc)?;
                 self.callframe = func.chunk.get_callframe(self.callframe.ret);
 
-                // Move the args
-                unsafe {
-                    let stack = self.stack.as_mut_ptr();
-                    let start = stack.add(self.callframe.ret);
-                    let args = stack.add(args_base);
-                    ptr::swap_nonoverlapping(start, args, argc);
-                }
-
-                self.stack.truncate(self.callframe.ret + argc);
-                self.stack.extend_from_slice(&func.locals);
-
+                // Move the args down over the old frame. They can overlap it when the caller has fewer slots than argc.
+                self.stack.drain(self.callframe.ret..args_base);
+                self.enter(&func);
+                self.callframe.func = Some(func);
                 Ok(())
             }
             Value::FuncNative(f) => {
+                check_native_arity(&f, argc)?;
                 let args = unsafe { &self.stack.get_unchecked((args_base)..self.stack.len()) };
 
-                let mut output = (f.func)(args)?;
+                let mut tail = None;
+                let mut output = call_native(
+                    &f,
+                    args,
+                    env,
+                    limits,
+                    cancelled,
+                    &mut self.pending,
+                    self.journal.as_mut(),
+                    &mut tail,
+                )?;
+                if let Some((f, args)) = tail {
+                    self.stack.truncate(args_base);
+                    let argc = self.bounce(f, args);
+                    return self.tailcall(argc, env, limits, cancelled);
+                }
                 self.stack.truncate(self.callframe.ret + 1);
                 std::mem::swap(self.stack.last_mut().unwrap(), &mut output);
                 Ok(())
@@ -189,6 +804,66 @@ impl VmState {
         }
     }
 
+    // The function calls itself: there is nothing to look up and its frame is only rewound.
+    #[inline]
+    fn tailcall_self(&mut self, argc: usize) -> Result<()> {
+        match &self.callframe.func {
+            Some(func) => check_arity(func, argc)?,
+            None => return Err(error_msg("Only a function can call itself")),
+        }
+        let Some(func) = self.callframe.func.take() else {
+            unreachable!()
+        };
+        self.callframe.pc = func.chunk.ops.as_ptr();
+
+        let args_base = self.stack.len() - argc;
+        self.stack.drain(self.callframe.ret..args_base);
+        self.enter(&func);
+        self.callframe.func = Some(func);
+        Ok(())
+    }
+
+    // Replaces the list at the top by its items, and is how many args the call gets then
+    fn spread(&mut self, argc: usize) -> Result<usize> {
+        match self.stack.pop() {
+            Some(Value::List(list) | Value::Vector(list)) => {
+                let len = list.len();
+                match Arc::try_unwrap(list) {
+                    Ok(items) => self.stack.extend(items),
+                    Err(list) => self.stack.extend_from_slice(&list),
+                }
+                Ok(argc - 1 + len)
+            }
+            _ => Err(error_msg("apply needs a list as its last argument.")),
+        }
+    }
+
+    // Puts the fn a native asked to call where the native was, under its args
+    fn bounce(&mut self, f: Value, args: Vec<Value>) -> usize {
+        *self.stack.last_mut().unwrap() = f;
+        let argc = args.len();
+        self.stack.extend(args);
+        argc
+    }
+
+    // The args are on top of the stack, at the base of the new frame. Bind them and make place for the locals.
+    // Their count was checked by the caller, while it still had its frame.
+    #[inline]
+    fn enter(&mut self, func: &Arc<ZapFn>) {
+        let arity: usize = func.chunk.arity.into();
+        if func.chunk.variadic {
+            let rest: Vec<Value> = self.stack.drain((self.callframe.ret + arity)..).collect();
+            self.push(Value::List(Arc::new(rest)));
+            self.stack.extend_from_slice(&func.locals[1..]);
+        } else {
+            self.stack.extend_from_slice(&func.locals);
+        }
+
+        if let Some(slot) = func.chunk.self_slot {
+            self.stack[self.callframe.ret + slot as usize] = Value::Func(func.clone());
+        }
+    }
+
     #[inline]
     fn push(&mut self, val: Value) {
         let len = self.stack.len();
@@ -223,26 +898,75 @@ impl VmState {
     }
 
     #[inline]
-    fn jump(&mut self, n: u16) {
-        unsafe { self.callframe.pc = self.callframe.pc.add(n as usize) };
+    fn jump(&mut self, n: usize) {
+        unsafe { self.callframe.pc = self.callframe.pc.add(n) };
+    }
+
+    #[inline]
+    fn jump_back(&mut self, n: usize) {
+        unsafe { self.callframe.pc = self.callframe.pc.sub(n) };
     }
 
     #[inline]
-    fn cond_jump(&mut self, n: u16) {
+    fn cond_jump(&mut self, n: usize) {
         if !self.pop().is_truthy() {
             self.jump(n);
         }
     }
 
     #[inline]
-    fn lookup<E: Env>(&mut self, id: Symbol, env: &mut E) -> Result<()> {
-        let val = env.get_by_id(id)?;
+    fn switch(&mut self, idx: u16) {
+        let subject = self.pop();
+        let table = unsafe { &*self.callframe.tables.add(idx.into()) };
+        self.jump(table.target(&subject).into());
+    }
+
+    #[inline]
+    fn try_begin(&mut self, n: u16) {
+        self.handlers.push(Handler {
+            calls: self.calls.len(),
+            stack: self.stack.len(),
+            layers: self.layers,
+            catch: unsafe { self.callframe.pc.add(n as usize) },
+        });
+    }
+
+    #[inline]
+    fn try_end(&mut self) {
+        self.handlers.pop();
+    }
+
+    // Unwind to the innermost handler, and resume at its catch with the thrown value on the
+    // top of the stack. The value is given back when nothing catches it.
+    fn catch(&mut self, thrown: Value) -> std::result::Result<(), Value> {
+        let Some(handler) = self.handlers.pop() else {
+            self.layers = 0;
+            return Err(thrown);
+        };
+        self.layers = handler.layers;
+        while self.calls.len() > handler.calls {
+            self.callframe = self.calls.pop().unwrap();
+        }
+        self.callframe.pc = handler.catch;
+        self.stack.truncate(handler.stack);
+        self.push(thrown);
+        Ok(())
+    }
+
+    // The env is only asked again once its generation changed
+    #[inline]
+    fn lookup<E: Env + ?Sized>(&mut self, id: Symbol, cache: u16, env: &mut E) -> Result<()> {
+        let cache = unsafe { (&*self.callframe.lookups).get(usize::from(cache)) };
+        let val = match (cache, env.generation()) {
+            (Some(cache), Some(generation)) => cache.get(generation, || env.get_by_id(id))?,
+            _ => env.get_by_id(id)?,
+        };
         self.push(val);
         Ok(())
     }
 
     #[inline]
-    fn define<E: Env>(&mut self, env: &mut E) -> Result<()> {
+    fn define<E: Env + ?Sized>(&mut self, env: &mut E) -> Result<()> {
         env.set(
             &self.stack.swap_remove(self.stack.len() - 2),
             self.stack.last().unwrap(),
@@ -282,7 +1006,7 @@ impl VmState {
         unsafe {
             let a = self.get_top_mut();
             let b = self.get_const(idx);
-            *a = (&*a + &*b)?
+            *a = (&*a + b)?
         }
         Ok(())
     }
@@ -298,6 +1022,78 @@ impl VmState {
         Ok(())
     }
 
+    // The other arithmetic ops, whose operands don't commute
+    #[inline]
+    fn arith_const(&mut self, idx: u16, op: fn(&Value, &Value) -> Result<Value>) -> Result<()> {
+        unsafe {
+            let a = self.get_top_mut();
+            let b = self.get_const(idx);
+            *a = op(&*a, b)?
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn arith(&mut self, op: fn(&Value, &Value) -> Result<Value>) -> Result<()> {
+        unsafe {
+            let a = self.get_top_mut();
+            let b = a.sub(1);
+            *b = op(&*b, &*a)?
+        }
+        self.pop_void();
+        Ok(())
+    }
+
+    #[inline]
+    fn load_add_const(&mut self, slot: u8, idx: u16) -> Result<()> {
+        let sum = unsafe {
+            let a = self
+                .stack
+                .get_unchecked(self.callframe.ret + usize::from(slot));
+            (a + &*self.callframe.consts.add(idx.into()))?
+        };
+        self.push(sum);
+        self.jump(1);
+        Ok(())
+    }
+
+    // Skips the Load and the Add of the sequence
+    #[inline]
+    fn add_slots(&mut self, a: u8, b: u8) -> Result<()> {
+        // In the order of add, which takes the top of the stack, b, first
+        let sum = unsafe {
+            let base = self.callframe.ret;
+            (self.stack.get_unchecked(base + usize::from(b))
+                + self.stack.get_unchecked(base + usize::from(a)))?
+        };
+        self.push(sum);
+        self.jump(2);
+        Ok(())
+    }
+
+    #[inline]
+    fn eq_slots(&mut self, a: u8, b: u8) {
+        let equal = unsafe {
+            let base = self.callframe.ret;
+            *self.stack.get_unchecked(base + usize::from(a))
+                == *self.stack.get_unchecked(base + usize::from(b))
+        };
+        self.push(Value::Bool(equal));
+        self.jump(2);
+    }
+
+    // Skips the EqConst and the CondJmp of the sequence, or jumps where the CondJmp would
+    #[inline]
+    fn load_eq_const_jmp(&mut self, slot: u8, idx: u16, n: u16) {
+        let equal = unsafe {
+            *self
+                .stack
+                .get_unchecked(self.callframe.ret + usize::from(slot))
+                == *self.callframe.consts.add(idx.into())
+        };
+        self.jump(if equal { 2 } else { 2 + usize::from(n) });
+    }
+
     #[inline]
     fn eq_const(&mut self, idx: u16) {
         unsafe {
@@ -307,6 +1103,14 @@ impl VmState {
         }
     }
 
+    #[inline]
+    fn not(&mut self) {
+        unsafe {
+            let a = self.get_top_mut();
+            *a = Value::Bool(!(*a).is_truthy());
+        }
+    }
+
     #[inline]
     fn eq(&mut self) {
         unsafe {
@@ -317,10 +1121,21 @@ impl VmState {
         self.pop_void();
     }
 
+    fn make_list(&mut self, n: u16) {
+        let items = self.stack.split_off(self.stack.len() - usize::from(n));
+        self.stack.push(Value::List(Value::new_list(items)));
+    }
+
+    fn make_vec(&mut self, n: u16) {
+        let items = self.stack.split_off(self.stack.len() - usize::from(n));
+        self.stack.push(Value::Vector(Value::new_list(items)));
+    }
+
     #[inline]
     fn closure(&mut self) -> Result<()> {
         if let Value::Closure(closure) = std::mem::take(self.stack.last_mut().unwrap()) {
-            let mut func = ZapFn::from_closure(closure, &self.calls, &self.stack);
+            let frame = &self.stack[self.callframe.ret..];
+            let mut func = ZapFn::from_closure(closure, frame);
             std::mem::swap(self.stack.last_mut().unwrap(), &mut func);
             Ok(())
         } else {
@@ -329,53 +1144,1041 @@ impl VmState {
     }
 }
 
-pub fn run<E: Env>(chunk: Arc<Chunk>, env: &mut E) -> Result<Value> {
-    let mut vm = VmState::new(&chunk);
+#[inline]
+fn check_arity(func: &ZapFn, argc: usize) -> Result<()> {
+    let expected = func.chunk.get_arity();
+    if expected.accepts(argc) {
+        Ok(())
+    } else {
+        Err(ZapErr::Arity {
+            name: None,
+            expected,
+            got: argc,
+            span: None,
+            trace: Vec::new(),
+        })
+    }
+}
+
+// An async native leaves nil where its value goes, the run is suspended until it's there. Its
+// result is journaled once it's ready. A native asking for a tail call leaves it in tail, unless
+// it's journaled: its value must be known when it returns then, the call is made right away.
+#[inline]
+#[allow(clippy::too_many_arguments)]
+fn call_native<E: Env + ?Sized>(
+    f: &ZapFnNative,
+    args: &[Value],
+    mut env: &mut E,
+    limits: Limits,
+    cancelled: &AtomicBool,
+    pending: &mut Option<Pending>,
+    journal: Option<&mut Journaling>,
+    tail: &mut Option<TailCall>,
+) -> Result<Value> {
+    let journal = match journal {
+        Some(Journaling::Replay(journal)) => return journal.next(),
+        Some(Journaling::Record(journal)) if !matches!(f.func, NativeFunc::Async(_)) => journal,
+        _ => return call_native_unjournaled(f, args, env, limits, cancelled, pending, Some(tail)),
+    };
+    let res = call_native_unjournaled(f, args, &mut env, limits, cancelled, pending, None);
+    journal.results.push_back(res.clone());
+    res
+}
+
+#[inline]
+fn call_native_unjournaled<E: Env + ?Sized>(
+    f: &ZapFnNative,
+    args: &[Value],
+    mut env: &mut E,
+    limits: Limits,
+    cancelled: &AtomicBool,
+    pending: &mut Option<Pending>,
+    tail: Option<&mut Option<TailCall>>,
+) -> Result<Value> {
+    match &f.func {
+        NativeFunc::Plain(func) => func(args),
+        NativeFunc::Closure(func) => func(args),
+        NativeFunc::Async(func) => {
+            *pending = Some(func(args));
+            Ok(Value::Nil)
+        }
+        NativeFunc::WithCtx(func) => func(
+            &mut Ctx {
+                env: &mut env,
+                limits,
+                cancelled,
+                tail,
+            },
+            args,
+        ),
+    }
+}
+
+// What a native registered with a Ctx reaches while it runs: the env, and the VM it's called
+// from, to call zap fns in turn.
+pub struct Ctx<'a> {
+    env: &'a mut dyn Env,
+    limits: Limits,
+    cancelled: &'a AtomicBool,
+    tail: Option<&'a mut Option<TailCall>>,
+}
+
+// A fn a native hands back to the VM, to be called with the args in its place
+type TailCall = (Value, Vec<Value>);
+
+impl Ctx<'_> {
+    pub fn env(&mut self) -> &mut dyn Env {
+        self.env
+    }
+
+    // Calls f on a stack of its own. The limits of the VM apply to the call as they do to a run,
+    // and cancelling the VM stops it too.
+    pub fn call(&mut self, f: &Value, args: &[Value]) -> Result<Value> {
+        call_with(f.clone(), args, self.env, self.limits, self.cancelled)
+    }
+
+    // Has the VM call f once the native returned, the value of the call being the native's. The
+    // native returns what tail_call does: there's no stack nor run of its own, so the call is
+    // made in a tail position like one from zap, and natives can loop through fns this way.
+    pub fn tail_call(&mut self, f: Value, args: Vec<Value>) -> Result<Value> {
+        match self.tail.as_deref_mut() {
+            Some(tail) => {
+                *tail = Some((f, args));
+                Ok(Value::Nil)
+            }
+            None => self.call(&f, &args),
+        }
+    }
+
+    // Runs the coroutine until it yields or returns, sent being the value of the yield it was
+    // suspended at. Each resume gets the limits of the VM, and an error ends the coroutine.
+    pub fn resume(&mut self, co: &Coroutine, sent: Value) -> Result<Value> {
+        let mut state = co
+            .state
+            .try_lock()
+            .map_err(|_| error_msg("The coroutine is already running."))?;
+        let mut vm = match std::mem::replace(&mut *state, CoState::Done) {
+            CoState::Fresh(vm) => vm,
+            CoState::Suspended(mut vm) => {
+                *vm.stack.last_mut().unwrap() = sent;
+                vm
+            }
+            CoState::Done => return Err(error_msg("The coroutine is done.")),
+        };
+        vm.max_depth = self.limits.depth.unwrap_or(usize::MAX);
+        vm.fuel = self.limits.ops.unwrap_or(u64::MAX);
+        let mut hooks = Hooks {
+            recorder: None,
+            tracer: None,
+            debugger: None,
+            profile: None,
+        };
+        loop {
+            let ran = run_chunk::<dyn Env, false>(
+                &mut vm,
+                &co.chunk,
+                self.env,
+                &mut hooks,
+                self.limits,
+                self.cancelled,
+                false,
+            )?;
+            if let Some(val) = ran {
+                return Ok(val);
+            }
+            if let Some(val) = vm.yielded.take() {
+                *state = CoState::Suspended(vm);
+                return Ok(val);
+            }
+            let out = block_on(vm.pending.take().unwrap());
+            vm.resume(out, &co.chunk, self.env, false)?;
+        }
+    }
+}
+
+// A call that a yield suspends halfway, keeping its stack until it's resumed.
+pub struct Coroutine {
+    chunk: Arc<Chunk>,
+    state: Mutex<CoState>,
+}
+
+enum CoState {
+    Fresh(VmState),
+    Suspended(VmState),
+    Done,
+}
+
+impl Coroutine {
+    // The coroutine calling f with the given args, once it's first resumed.
+    pub fn new(f: Value, args: &[Value]) -> Result<Coroutine> {
+        let chunk = call_chunk(f, args)?;
+        let mut vm = VmState::new(&chunk, Limits::default(), Buffers::default());
+        vm.in_coroutine = true;
+        Ok(Coroutine {
+            chunk,
+            state: Mutex::new(CoState::Fresh(vm)),
+        })
+    }
+
+    // Whether it returned or failed. A coroutine running isn't done.
+    pub fn is_done(&self) -> bool {
+        self.state
+            .try_lock()
+            .is_ok_and(|state| matches!(*state, CoState::Done))
+    }
+}
+
+#[inline]
+fn check_native_arity(f: &ZapFnNative, argc: usize) -> Result<()> {
+    if f.arity.accepts(argc) {
+        Ok(())
+    } else {
+        Err(ZapErr::Arity {
+            name: Some(f.name.to_string()),
+            expected: f.arity,
+            got: argc,
+            span: None,
+            trace: Vec::new(),
+        })
+    }
+}
+
+// A step of a recorded evaluation: an op and how it moved the top of the stack.
+#[derive(Clone, Copy, Debug)]
+pub struct Step {
+    pub op: Op,
+    pub depth: usize,         // The size of the stack before the op
+    pub delta: Option<isize>, // None when the op failed
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = format!("{:?}", self.op);
+        match self.delta {
+            Some(delta) => write!(f, "{:<30} {:>5} {:+}", op, self.depth, delta),
+            None => write!(f, "{:<30} {:>5} failed", op, self.depth),
+        }
+    }
+}
+
+// The last steps of an evaluation, kept in a ring buffer.
+struct Recorder {
+    steps: VecDeque<Step>,
+    capacity: usize,
+}
+
+impl Recorder {
+    #[inline]
+    fn record(&mut self, op: Op, depth: usize) {
+        self.settle(depth);
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(Step {
+            op,
+            depth,
+            delta: None,
+        });
+    }
+
+    // The previous op went through, it left the stack at depth
+    #[inline]
+    fn settle(&mut self, depth: usize) {
+        if let Some(last) = self.steps.back_mut() {
+            last.delta = Some(depth as isize - last.depth as isize);
+        }
+    }
+}
+
+// Where the evaluations of a VM spend their time: how many ops of each kind they ran, and how many
+// calls each fn got and how long its own ops took. The time of an op is the time until the next
+// one starts, so a fn waiting on a native is charged for it.
+#[derive(Default)]
+pub struct Profile {
+    ops: FxHashMap<Discriminant<Op>, (Op, u64)>,
+    fns: FxHashMap<usize, FnProfile>, // By the address of their chunk, 0 for the top chunks
+    last: Option<(usize, Instant)>,   // The fn of the op running, and when it started
+}
+
+struct FnProfile {
+    name: std::string::String,
+    calls: u64,
+    time: Duration,
+    _chunk: Option<Arc<Chunk>>, // So its address isn't given to another one
+}
+
+impl Profile {
+    fn on_op<E: Env + ?Sized>(&mut self, op: Op, frame: &CallFrame, top: &Chunk, env: &E) {
+        let now = Instant::now();
+        self.settle(now);
+        self.ops
+            .entry(std::mem::discriminant(&op))
+            .or_insert((op, 0))
+            .1 += 1;
+
+        let chunk = frame.func.as_ref().map(|func| &func.chunk);
+        let key = chunk.map_or(0, |chunk| Arc::as_ptr(chunk) as usize);
+        let profile = self.fns.entry(key).or_insert_with(|| FnProfile {
+            name: match chunk.map(|chunk| chunk.name) {
+                None => "the top".to_string(),
+                Some(Some(name)) => env
+                    .get_symbol(name)
+                    .map_or_else(|_| "an anonymous fn".to_string(), |name| name.to_string()),
+                Some(None) => "an anonymous fn".to_string(),
+            },
+            calls: 0,
+            time: Duration::ZERO,
+            _chunk: chunk.cloned(),
+        });
+        // Its first op starts a call, a self tail call going back to it included
+        if frame.op_index(chunk.map_or(top, |chunk| chunk)) == Some(0) {
+            profile.calls += 1;
+        }
+        self.last = Some((key, now));
+    }
+
+    // The op running is over
+    fn settle(&mut self, now: Instant) {
+        if let Some((key, start)) = self.last.take() {
+            if let Some(profile) = self.fns.get_mut(&key) {
+                profile.time += now - start;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Profile::default();
+    }
+
+    // As zap data: (("ops" (kind count)...) ("fns" (name calls ms)...)), the most run ops and
+    // the slowest fns first
+    pub fn report(&self) -> Value {
+        let mut ops: Vec<(std::string::String, u64)> = self
+            .ops
+            .values()
+            .map(|(op, count)| {
+                let kind = format!("{:?}", op);
+                (
+                    kind.split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                    *count,
+                )
+            })
+            .collect();
+        ops.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let mut fns: Vec<&FnProfile> = self.fns.values().collect();
+        fns.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| a.name.cmp(&b.name)));
+
+        let section = |title: &str, rows: Vec<Value>| {
+            let mut list = vec![Value::Str(String::from(title))];
+            list.extend(rows);
+            Value::List(Value::new_list(list))
+        };
+        let ops = ops
+            .into_iter()
+            .map(|(kind, count)| {
+                Value::List(Value::new_list(vec![
+                    Value::Str(String::from(kind.as_str())),
+                    Value::Int(count.try_into().unwrap_or(i64::MAX)),
+                ]))
+            })
+            .collect();
+        let fns = fns
+            .into_iter()
+            .map(|profile| {
+                Value::List(Value::new_list(vec![
+                    Value::Str(String::from(profile.name.as_str())),
+                    Value::Int(profile.calls.try_into().unwrap_or(i64::MAX)),
+                    Value::Number(profile.time.as_secs_f64() * 1000.0),
+                ]))
+            })
+            .collect();
+        Value::List(Value::new_list(vec![
+            section("ops", ops),
+            section("fns", fns),
+        ]))
+    }
+}
+
+// The results of the natives an evaluation called, in the order it called them. Replaying them,
+// the natives aren't called again: each call gets the next result instead, so an evaluation that
+// depends on the time, on randomness or on the world outside runs the same way again. The natives
+// called back by a native aren't in it, they aren't called when it's replayed either.
+#[derive(Clone, Default)]
+pub struct Journal {
+    pub(crate) results: VecDeque<Result<Value>>,
+}
+
+impl Journal {
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    fn next(&mut self) -> Result<Value> {
+        self.results.pop_front().unwrap_or_else(|| {
+            Err(error_msg(
+                "The replay called more natives than it journaled.",
+            ))
+        })
+    }
+}
+
+enum Journaling {
+    Record(Journal),
+    Replay(Journal),
+}
+
+// Sees each op before it runs, with its index in the chunk it's from and the stack as it is,
+// locals included. It's how debuggers, coverage tools and the like follow an evaluation.
+pub trait Tracer: Send {
+    fn on_op(&mut self, op: Op, pc: usize, stack: &[Value]);
+}
+
+// Prints the ops as they run, the way a debug build used to.
+pub struct PrintTracer;
+
+impl Tracer for PrintTracer {
+    fn on_op(&mut self, op: Op, pc: usize, stack: &[Value]) {
+        println!("{:0>5} {:<30} STACK: {:?}", pc, format!("{:?}", op), stack);
+    }
+}
+
+// The frame a (break) stopped in. The locals can be changed, the evaluation resumes with them
+// when on_break returns.
+pub struct Paused<'a> {
+    pub chunk: &'a Chunk,
+    pub pc: usize,
+    pub locals: &'a mut [Value],
+    pub temps: &'a [Value], // The values above the locals, the top last
+}
 
-    // Make place for the locals
-    vm.stack
-        .resize_with(chunk.scope_size as usize, Default::default);
+// Gets the control at each (break), until it resumes the evaluation by returning. An error
+// stops it instead, raised where the break is.
+pub trait Debugger: Send {
+    fn on_break(&mut self, paused: Paused) -> Result<()>;
+}
+
+// The outermost handler, given the errors no try caught, located. The value it returns is the one
+// of the evaluation, as if it were all in a try, and None lets the error through. It runs in the
+// host, so the errors a try can't swallow, out of fuel or interrupted, are given too.
+pub trait Catcher: Send {
+    fn on_error(&mut self, err: &ZapErr) -> Option<Value>;
+}
+
+impl<F: FnMut(&ZapErr) -> Option<Value> + Send> Catcher for F {
+    fn on_error(&mut self, err: &ZapErr) -> Option<Value> {
+        self(err)
+    }
+}
+
+// What's told of an evaluation as it runs.
+struct Hooks<'a> {
+    recorder: Option<&'a mut Recorder>,
+    tracer: Option<&'a mut (dyn Tracer + 'static)>,
+    debugger: Option<&'a mut (dyn Debugger + 'static)>,
+    profile: Option<&'a Mutex<Profile>>, // Locked an op at a time, so it can be read meanwhile
+}
+
+// What an evaluation is allowed to use before it's stopped, nothing by default.
+#[derive(Clone, Copy, Default)]
+struct Limits {
+    ops: Option<u64>,
+    depth: Option<usize>,
+}
+
+// Interrupts the evaluation of a VM from another thread, between two of its ops. Like running
+// out of fuel, the error can't be swallowed by a try.
+#[derive(Clone, Default)]
+pub struct VmHandle(Arc<AtomicBool>);
+
+impl VmHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+// The VM is the entry point for running chunks.
+#[derive(Default)]
+pub struct VM {
+    recorder: Option<Recorder>,
+    tracer: Option<Box<dyn Tracer>>,
+    debugger: Option<Box<dyn Debugger>>,
+    catcher: Option<Box<dyn Catcher>>,
+    profile: Option<Arc<Mutex<Profile>>>,
+    journal: Option<Journaling>,
+    limits: Limits,
+    handle: VmHandle,
+    buffers: Buffers,
+}
+
+impl VM {
+    pub fn new() -> Self {
+        VM::default()
+    }
+
+    // A VM keeping the last steps of each evaluation, for a post-mortem look with last_steps.
+    // The recording has a cost, so it's opt-in.
+    pub fn with_recording(capacity: usize) -> Self {
+        VM {
+            recorder: (capacity > 0).then(|| Recorder {
+                steps: VecDeque::with_capacity(capacity),
+                capacity,
+            }),
+            ..VM::default()
+        }
+    }
+
+    // Like the recording, a tracer slows every op down, even with an empty on_op.
+    pub fn with_tracer(mut self, tracer: Box<dyn Tracer>) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    // Without a debugger, a (break) does nothing.
+    pub fn with_debugger(mut self, debugger: Box<dyn Debugger>) -> Self {
+        self.debugger = Some(debugger);
+        self
+    }
+
+    // Without a catcher, an error nothing caught ends the evaluation with it.
+    pub fn with_catcher(mut self, catcher: Box<dyn Catcher>) -> Self {
+        self.catcher = Some(catcher);
+        self
+    }
+
+    // Adds up what the evaluations run in profile, which is shared so it can be read while they
+    // run. Timing each op slows them down a lot.
+    pub fn with_profile(mut self, profile: Arc<Mutex<Profile>>) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    // Journal the natives the evaluations call from now on, until take_journal.
+    pub fn record_journal(&mut self) {
+        self.journal = Some(Journaling::Record(Journal::default()));
+    }
+
+    // The evaluations from now on take the results of their natives from journal, as they were
+    // recorded, instead of calling them.
+    pub fn replay_journal(&mut self, journal: Journal) {
+        self.journal = Some(Journaling::Replay(journal));
+    }
+
+    // Stops recording or replaying, and gives the journal back. What's left of one replayed wasn't
+    // used.
+    pub fn take_journal(&mut self) -> Option<Journal> {
+        match self.journal.take()? {
+            Journaling::Record(journal) | Journaling::Replay(journal) => Some(journal),
+        }
+    }
+
+    // A cancel only stops the evaluation running, each run starts with a clear handle.
+    pub fn handle(&self) -> VmHandle {
+        self.handle.clone()
+    }
+
+    // An evaluation that ran max_ops ops is stopped by an error. A try can't swallow it, its
+    // catch has no fuel left to run either.
+    pub fn with_fuel(mut self, max_ops: u64) -> Self {
+        self.limits.ops = Some(max_ops);
+        self
+    }
+
+    // A call deeper than max_depth frames fails with a stack overflow, instead of growing the
+    // frames until the host runs out of memory. Tail calls don't add a frame.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.limits.depth = Some(max_depth);
+        self
+    }
+
+    // An error unwinds to the innermost try, and one nothing caught says where it was raised,
+    // when the chunk has spans. It's the catcher's then, if there's one, else it ends the run but
+    // not the env: what was defined before the error stays, and the layers entered are left, so
+    // a REPL carries on from there.
+    pub fn run<E: Env + ?Sized>(&mut self, chunk: Arc<Chunk>, env: &mut E) -> Result<Value> {
+        let cancelled = &self.handle.0;
+        cancelled.store(false, Ordering::Relaxed);
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.steps.clear();
+        }
+        let mut hooks = Hooks {
+            recorder: self.recorder.as_mut(),
+            tracer: self.tracer.as_deref_mut(),
+            debugger: self.debugger.as_deref_mut(),
+            profile: self.profile.as_deref(),
+        };
+        let buffers = std::mem::take(&mut self.buffers);
+        let mut vm = VmState::new(&chunk, self.limits, buffers);
+        vm.journal = self.journal.take();
+        let hooked = hooks.recorder.is_some()
+            || hooks.tracer.is_some()
+            || hooks.debugger.is_some()
+            || hooks.profile.is_some();
+        let limits = self.limits;
+        let res = if !hooked {
+            run_blocking::<E, false>(&mut vm, &chunk, env, &mut hooks, limits, cancelled, true)
+        } else {
+            run_blocking::<E, true>(&mut vm, &chunk, env, &mut hooks, limits, cancelled, true)
+        };
+        if let Some(profile) = &self.profile {
+            profile.lock().unwrap().settle(Instant::now());
+        }
+        self.journal = vm.journal.take();
+        self.buffers = vm.into_buffers();
+        self.caught(res)
+    }
+
+    // Same as run, but an async native suspends the run instead of blocking the thread, until
+    // its future is ready. Cancelling the VM is seen once it's resumed.
+    pub async fn run_async<E: Env + ?Sized>(
+        &mut self,
+        chunk: Arc<Chunk>,
+        env: &mut E,
+    ) -> Result<Value> {
+        let cancelled = &self.handle.0;
+        cancelled.store(false, Ordering::Relaxed);
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.steps.clear();
+        }
+        let mut hooks = Hooks {
+            recorder: self.recorder.as_mut(),
+            tracer: self.tracer.as_deref_mut(),
+            debugger: self.debugger.as_deref_mut(),
+            profile: self.profile.as_deref(),
+        };
+        let buffers = std::mem::take(&mut self.buffers);
+        let mut vm = VmState::new(&chunk, self.limits, buffers);
+        vm.journal = self.journal.take();
+        let hooked = hooks.recorder.is_some()
+            || hooks.tracer.is_some()
+            || hooks.debugger.is_some()
+            || hooks.profile.is_some();
+        let limits = self.limits;
+        let res = loop {
+            let ran = if !hooked {
+                run_chunk::<E, false>(&mut vm, &chunk, env, &mut hooks, limits, cancelled, true)
+            } else {
+                run_chunk::<E, true>(&mut vm, &chunk, env, &mut hooks, limits, cancelled, true)
+            };
+            match ran {
+                Ok(Some(val)) => break Ok(val),
+                Ok(None) => {
+                    let out = vm.pending.take().unwrap().await;
+                    if let Err(err) = vm.resume(out, &chunk, env, true) {
+                        break Err(err);
+                    }
+                }
+                Err(err) => break Err(err),
+            }
+        };
+        if let Some(profile) = &self.profile {
+            profile.lock().unwrap().settle(Instant::now());
+        }
+        self.journal = vm.journal.take();
+        self.buffers = vm.into_buffers();
+        self.caught(res)
+    }
+
+    fn caught(&mut self, res: Result<Value>) -> Result<Value> {
+        match (res, self.catcher.as_deref_mut()) {
+            (Err(err), Some(catcher)) => catcher.on_error(&err).ok_or(err),
+            (res, _) => res,
+        }
+    }
+
+    // The last n steps of the latest evaluation, oldest first. When it failed, the last one is
+    // the op that failed.
+    pub fn last_steps(&self, n: usize) -> Vec<Step> {
+        self.recorder.as_ref().map_or_else(Vec::new, |recorder| {
+            let skip = recorder.steps.len().saturating_sub(n);
+            recorder.steps.iter().skip(skip).copied().collect()
+        })
+    }
+}
+
+pub fn run<E: Env + ?Sized>(chunk: Arc<Chunk>, env: &mut E) -> Result<Value> {
+    VM::new().run(chunk, env)
+}
+
+pub fn run_with_fuel<E: Env + ?Sized>(
+    chunk: Arc<Chunk>,
+    env: &mut E,
+    max_ops: u64,
+) -> Result<Value> {
+    VM::new().with_fuel(max_ops).run(chunk, env)
+}
+
+// Call f with the given args, from outside of the VM. The compiler uses it to expand macros,
+// and it's the compiler that says where, so the errors are left as they are.
+pub fn call<E: Env + ?Sized>(f: Value, args: &[Value], env: &mut E) -> Result<Value> {
+    call_with(f, args, env, Limits::default(), &AtomicBool::new(false))
+}
+
+fn call_with<E: Env + ?Sized>(
+    f: Value,
+    args: &[Value],
+    env: &mut E,
+    limits: Limits,
+    cancelled: &AtomicBool,
+) -> Result<Value> {
+    let chunk = call_chunk(f, args)?;
+    run_blocking::<E, false>(
+        &mut VmState::new(&chunk, limits, Buffers::default()),
+        &chunk,
+        env,
+        &mut Hooks {
+            recorder: None,
+            tracer: None,
+            debugger: None,
+            profile: None,
+        },
+        limits,
+        cancelled,
+        false,
+    )
+}
+
+// A chunk calling f with the given args, and returning what it returns.
+pub fn call_chunk(f: Value, args: &[Value]) -> Result<Arc<Chunk>> {
+    let argc: u16 = (args.len() + 1)
+        .try_into()
+        .map_err(|_| error_msg("A call cannot have more than 65534 arguments."))?;
+
+    let mut chunk = Chunk::default();
+    chunk.consts.push(f);
+    chunk.consts.extend_from_slice(args);
+    chunk.ops.extend((0..argc).map(Op::Push));
+    chunk.ops.push(Op::Call(argc - 1));
+    chunk.ops.push(Op::Return);
+    Ok(Arc::new(chunk))
+}
+
+// The env layers the unwinding got out of are left, all of them when nothing catches.
+fn throw<E: Env + ?Sized>(
+    vm: &mut VmState,
+    thrown: Value,
+    env: &mut E,
+) -> std::result::Result<(), Value> {
+    let entered = vm.layers;
+    let res = vm.catch(thrown);
+    for _ in vm.layers..entered {
+        env.leave_layer();
+    }
+    res
+}
+
+// A thrown string is the message of the error, any other value is printed.
+fn uncaught<E: Env + ?Sized>(thrown: &Value, env: &mut E) -> ZapErr {
+    match thrown {
+        Value::Str(msg) => error_msg(msg),
+        val => error_msg(&val.pr_str(env)),
+    }
+}
+
+// An error is caught by the innermost try, its message being the value caught
+fn raise<E: Env + ?Sized>(
+    vm: &mut VmState,
+    err: ZapErr,
+    chunk: &Chunk,
+    env: &mut E,
+    locate: bool,
+) -> Result<()> {
+    throw(vm, Value::Str(String::from(err.message())), env).map_err(|_| {
+        if !locate {
+            return err;
+        }
+        let err = err.with_trace(vm.backtrace(chunk, env));
+        match vm.error_span(chunk) {
+            Some(span) => err.at(span),
+            None => err,
+        }
+    })
+}
+
+// Runs the chunk to its end, waiting on this thread for the async natives.
+fn run_blocking<E: Env + ?Sized, const HOOKED: bool>(
+    vm: &mut VmState,
+    chunk: &Arc<Chunk>,
+    env: &mut E,
+    hooks: &mut Hooks,
+    limits: Limits,
+    cancelled: &AtomicBool,
+    locate: bool,
+) -> Result<Value> {
+    loop {
+        if let Some(val) = run_chunk::<E, HOOKED>(vm, chunk, env, hooks, limits, cancelled, locate)?
+        {
+            return Ok(val);
+        }
+        let out = block_on(vm.pending.take().unwrap());
+        vm.resume(out, chunk, env, locate)?;
+    }
+}
+
+// Polls the future until it's ready, parking the thread in between.
+fn block_on(mut fut: Pending) -> Result<Value> {
+    struct Unpark(std::thread::Thread);
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+            return out;
+        }
+        std::thread::park();
+    }
+}
+
+// Runs until the chunk returns its value, or until an async native suspends it: its future is
+// left pending in the state, and None is returned.
+fn run_chunk<E: Env + ?Sized, const HOOKED: bool>(
+    vm: &mut VmState,
+    chunk: &Arc<Chunk>,
+    env: &mut E,
+    hooks: &mut Hooks,
+    limits: Limits,
+    cancelled: &AtomicBool,
+    locate: bool,
+) -> Result<Option<Value>> {
+    let mut fuel = vm.fuel;
 
     loop {
         let op = vm.get_next_op();
 
-        #[cfg(debug_assertions)]
-        let op_no = unsafe { vm.callframe.pc.offset_from(vm.callframe.start) };
-
-        match op {
-            Op::Push(const_idx) => vm.push_const(const_idx),
-            Op::Call(argc) => vm.call(argc.into())?,
-            Op::Tailcall(argc) => vm.tailcall(argc.into())?,
-            Op::CondJmp(n) => vm.cond_jump(n),
-            Op::Jmp(n) => vm.jump(n),
-            Op::LookUp(id) => vm.lookup(id, env)?,
-            Op::Define => vm.define(env)?,
-            Op::Load(offset) => vm.load(offset),
-            Op::Store(offset) => vm.store(offset),
-            Op::AddConst(const_idx) => vm.add_const(const_idx)?,
-            Op::Add => vm.add()?,
-            Op::EqConst(const_idx) => vm.eq_const(const_idx),
-            Op::Eq => vm.eq(),
-            Op::Closure => vm.closure()?,
+        if HOOKED {
+            if let Some(recorder) = hooks.recorder.as_deref_mut() {
+                recorder.record(op, vm.stack.len());
+            }
+            if let Some(tracer) = hooks.tracer.as_deref_mut() {
+                let running = vm.callframe.func.as_ref().map_or(chunk, |func| &func.chunk);
+                let pc = vm.callframe.op_index(running).unwrap_or_default();
+                tracer.on_op(op, pc, &vm.stack);
+            }
+            if let Some(profile) = hooks.profile {
+                profile.lock().unwrap().on_op(op, &vm.callframe, chunk, env);
+            }
+        }
+
+        let res = match op {
+            _ if fuel == 0 => Err(error_msg(&format!(
+                "Out of fuel: the evaluation ran {} ops.",
+                limits.ops.unwrap_or_default()
+            ))),
+            _ if cancelled.load(Ordering::Relaxed) => {
+                Err(error_msg("The evaluation was interrupted."))
+            }
+            Op::Push(const_idx) => {
+                vm.push_const(const_idx);
+                Ok(())
+            }
+            Op::Call(argc) => {
+                let res = vm.call(argc.into(), env, limits, cancelled);
+                if vm.pending.is_some() {
+                    vm.fuel = fuel.saturating_sub(1);
+                    return Ok(None);
+                }
+                res
+            }
+            Op::Tailcall(argc) => {
+                let res = vm.tailcall(argc.into(), env, limits, cancelled);
+                if vm.pending.is_some() {
+                    vm.fuel = fuel.saturating_sub(1);
+                    return Ok(None);
+                }
+                res
+            }
+            Op::TailcallSelf(argc) => vm.tailcall_self(argc.into()),
+            Op::Apply(argc) => {
+                let res = vm
+                    .spread(argc.into())
+                    .and_then(|argc| vm.call(argc, env, limits, cancelled));
+                if vm.pending.is_some() {
+                    vm.fuel = fuel.saturating_sub(1);
+                    return Ok(None);
+                }
+                res
+            }
+            Op::TailApply(argc) => {
+                let res = vm
+                    .spread(argc.into())
+                    .and_then(|argc| vm.tailcall(argc, env, limits, cancelled));
+                if vm.pending.is_some() {
+                    vm.fuel = fuel.saturating_sub(1);
+                    return Ok(None);
+                }
+                res
+            }
+            Op::CondJmp(n) => {
+                vm.cond_jump(n.into());
+                Ok(())
+            }
+            Op::Jmp(n) => {
+                vm.jump(n.into());
+                Ok(())
+            }
+            Op::Loop(n) => {
+                vm.jump_back(n.into());
+                Ok(())
+            }
+            Op::CondJmpW(n) => {
+                vm.cond_jump(n as usize);
+                Ok(())
+            }
+            Op::JmpW(n) => {
+                vm.jump(n as usize);
+                Ok(())
+            }
+            Op::LoopW(n) => {
+                vm.jump_back(n as usize);
+                Ok(())
+            }
+            Op::LookUp(id, cache) => vm.lookup(id, cache, env),
+            Op::Define => vm.define(env),
+            Op::Load(offset) => {
+                vm.load(offset.into());
+                Ok(())
+            }
+            Op::Store(offset) => {
+                vm.store(offset.into());
+                Ok(())
+            }
+            Op::LoadW(offset) => {
+                vm.load(offset);
+                Ok(())
+            }
+            Op::StoreW(offset) => {
+                vm.store(offset);
+                Ok(())
+            }
+            Op::AddConst(const_idx) => vm.add_const(const_idx),
+            Op::Add => vm.add(),
+            Op::SubConst(const_idx) => vm.arith_const(const_idx, |a, b| a - b),
+            Op::Sub => vm.arith(|a, b| a - b),
+            Op::MulConst(const_idx) => vm.arith_const(const_idx, |a, b| a * b),
+            Op::Mul => vm.arith(|a, b| a * b),
+            Op::DivConst(const_idx) => vm.arith_const(const_idx, |a, b| a / b),
+            Op::Div => vm.arith(|a, b| a / b),
+            Op::RemConst(const_idx) => vm.arith_const(const_idx, |a, b| a % b),
+            Op::Rem => vm.arith(|a, b| a % b),
+            Op::EqConst(const_idx) => {
+                vm.eq_const(const_idx);
+                Ok(())
+            }
+            Op::LoadAddConst(slot, const_idx) => vm.load_add_const(slot, const_idx),
+            Op::LoadEqConstJmp(slot, const_idx, n) => {
+                vm.load_eq_const_jmp(slot, const_idx, n);
+                Ok(())
+            }
+            Op::AddSlots(a, b) => vm.add_slots(a, b),
+            Op::EqSlots(a, b) => {
+                vm.eq_slots(a, b);
+                Ok(())
+            }
+            Op::Eq => {
+                vm.eq();
+                Ok(())
+            }
+            Op::Not => {
+                vm.not();
+                Ok(())
+            }
+            Op::Closure => vm.closure(),
+            Op::MakeList(n) => {
+                vm.make_list(n);
+                Ok(())
+            }
+            Op::MakeVec(n) => {
+                vm.make_vec(n);
+                Ok(())
+            }
+            Op::Switch(idx) => {
+                vm.switch(idx);
+                Ok(())
+            }
+            Op::Try(n) => {
+                vm.try_begin(n);
+                Ok(())
+            }
+            Op::EndTry => {
+                vm.try_end();
+                Ok(())
+            }
+            Op::Throw => {
+                let thrown = vm.pop();
+                throw(vm, thrown, env).map_err(|thrown| uncaught(&thrown, env))
+            }
+            Op::EnterEnv => env.enter_layer().map(|()| vm.layers += 1),
+            Op::LeaveEnv => {
+                env.leave_layer();
+                vm.layers -= 1;
+                Ok(())
+            }
+            Op::LoadFile => match vm.stack.pop() {
+                Some(Value::Str(path)) => {
+                    crate::engine::load_file(&path, env).map(|val| vm.stack.push(val))
+                }
+                _ => Err(error_msg("load needs the path of a file")),
+            },
+            Op::Disasm => match vm.stack.pop() {
+                Some(Value::Func(f) | Value::Macro(f)) => {
+                    vm.stack.push(Value::Str(disassemble(&f.chunk, env).into()));
+                    Ok(())
+                }
+                Some(Value::Closure(c)) => {
+                    vm.stack.push(Value::Str(disassemble(&c.chunk, env).into()));
+                    Ok(())
+                }
+                _ => Err(error_msg("disasm needs a function compiled by zap")),
+            },
             Op::Pop => {
                 vm.pop_void();
+                Ok(())
+            }
+            Op::Break => {
+                let res = match hooks.debugger.as_deref_mut() {
+                    Some(debugger) => vm.pause(chunk, debugger),
+                    _ => Ok(()),
+                };
+                vm.stack.push(Value::Nil);
+                res
+            }
+            Op::Yield if vm.in_coroutine => {
+                vm.yielded = Some(std::mem::take(vm.stack.last_mut().unwrap()));
+                vm.fuel = fuel.saturating_sub(1);
+                return Ok(None);
             }
+            Op::Yield => Err(error_msg("yield can only be used in a coroutine.")),
             Op::Return => {
                 if !vm.pop_call() {
-                    return Ok(vm.pop());
+                    let res = vm
+                        .stack
+                        .pop()
+                        .ok_or_else(|| error_msg("The chunk returned without a value."))?;
+                    if HOOKED {
+                        if let Some(recorder) = hooks.recorder.as_deref_mut() {
+                            recorder.settle(vm.stack.len());
+                        }
+                    }
+                    return Ok(Some(res));
                 }
+                Ok(())
             }
         };
 
-        #[cfg(debug_assertions)]
-        #[allow(clippy::format_in_format_args)]
-        {
-            println!(
-                "{:0>5} {:<30} {}",
-                op_no,
-                format!("{:?}", &op),
-                format!("STACK: {:?}", &vm.stack)
-            );
+        fuel = fuel.saturating_sub(1);
+
+        if let Err(err) = res {
+            raise(vm, err, chunk, env, locate)?;
         }
     }
 }
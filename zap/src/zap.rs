@@ -1,35 +1,62 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
 use std::ptr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll, Waker};
 
+#[cfg(feature = "compact-values")]
+pub use crate::compact::String;
+#[cfg(not(feature = "compact-values"))]
 pub use smartstring::alias::String;
 
 use crate::compiler::Outer;
+use crate::decimal::Decimal;
 use crate::env::Env;
-use crate::vm::{CallFrame, Chunk};
+use crate::reader::Span;
+use crate::time::{DateTime, Duration};
+use crate::vm::{Chunk, Coroutine, Ctx};
 
 pub type Symbol = u32;
 
 pub type ZapList = Arc<Vec<Value>>;
 pub type Result<T> = std::result::Result<T, ZapErr>;
 
-#[derive(Clone)]
+// A value is 32 bytes, the inline strings being the largest. It's an enum the embedders build and
+// match on, so rather than NaN-boxed it's made smaller by its payloads: with the compact-values
+// feature, the strings are behind a pointer and the decimals packed, or boxed when they don't
+// fit, and a value is 16 bytes.
+#[derive(Clone, Default)]
 pub enum Value {
+    #[default]
     Nil,
     Bool(bool),
-    Number(f64),
+    Int(i64),           // Written without a point, 42
+    Number(f64),        // A float, written with a point or an exponent, 4.2
+    Decimal(Decimal),   // Exact, written 12.50M
+    Duration(Duration), // To the nanosecond, written 2h 3m 4s
+    DateTime(DateTime), // An instant in UTC, written 2024-03-01T12:00:00Z
     Symbol(Symbol),
+    Keyword(Symbol), // :name, evaluating to itself, the symbol being its name without the colon
     Str(String),
     List(ZapList),
+    Vector(ZapList), // [a b], its items evaluated where a list would be a call
     FuncNative(Arc<ZapFnNative>),
     Func(Arc<ZapFn>),
     Closure(Arc<Closure>),
+    Macro(Arc<ZapFn>), // Called by the compiler on the forms it's given, to compile what it returns
+    Coroutine(Arc<Coroutine>),
+    Task(Arc<Task>),
+    Channel(Arc<Channel>),
+    Atom(Arc<RwLock<Value>>), // A mutable reference, which threads can share
 }
 
 impl Value {
-    pub fn to_string<E: Env>(&self, _env: &mut E) -> std::string::String {
+    pub fn to_string<E: Env>(&self, env: &mut E) -> std::string::String {
         match self {
             Value::Func(_) => "Func<>".to_string(),
-            x => format!("{}", x),
+            x => x.pr_str(env),
         }
     }
 
@@ -49,38 +76,194 @@ impl std::fmt::Debug for Value {
     }
 }
 
+// The decimals of a decimal operation. An integer can be mixed with a decimal, and so can an
+// integral float, another float would make the result inexact.
+fn decimals(a: &Value, b: &Value) -> Option<(Decimal, Decimal)> {
+    let decimal = |val: &Value| match val {
+        Value::Decimal(d) => Some(d.clone()),
+        Value::Int(n) => Decimal::new(*n, 0),
+        Value::Number(n) => Decimal::from_integer(*n),
+        _ => None,
+    };
+    match (a, b) {
+        (Value::Number(_) | Value::Int(_), Value::Number(_) | Value::Int(_)) => None,
+        (a, b) => Some((decimal(a)?, decimal(b)?)),
+    }
+}
+
+fn decimal_op(
+    a: &Value,
+    b: &Value,
+    op: fn(&Decimal, &Decimal) -> Option<Decimal>,
+) -> Option<Result<Value>> {
+    let (a, b) = decimals(a, b)?;
+    Some(
+        op(&a, &b)
+            .map(Value::Decimal)
+            .ok_or_else(|| error_msg(&format!("Decimal overflow on {}M and {}M", a, b))),
+    )
+}
+
+// The arithmetic of durations and datetimes: a duration scaled by a number, added to another or
+// to a datetime, and the duration between two datetimes.
+fn time_op(a: &Value, b: &Value, sign: char) -> Option<Result<Value>> {
+    let scale = |n: &Value,
+                 d: Duration,
+                 int: fn(Duration, i64) -> Option<Duration>,
+                 float: fn(Duration, f64) -> Option<Duration>| {
+        match n {
+            Value::Int(n) => Some(int(d, *n)),
+            Value::Number(n) => Some(float(d, *n)),
+            _ => None,
+        }
+    };
+    let res = match (a, b, sign) {
+        (Value::Duration(a), Value::Duration(b), '+') => a.checked_add(*b).map(Value::Duration),
+        (Value::Duration(a), Value::Duration(b), '-') => a.checked_sub(*b).map(Value::Duration),
+        (Value::Duration(a), Value::Duration(b), '%') => {
+            if b.nanos() == 0 {
+                return Some(Err(error_msg("Division by zero")));
+            }
+            a.checked_rem(*b).map(Value::Duration)
+        }
+        (Value::Duration(a), Value::Duration(b), '/') => Some(Value::Number(a.ratio(*b))),
+        (Value::DateTime(t), Value::Duration(d), '+')
+        | (Value::Duration(d), Value::DateTime(t), '+') => t.checked_add(*d).map(Value::DateTime),
+        (Value::DateTime(t), Value::Duration(d), '-') => t.checked_sub(*d).map(Value::DateTime),
+        (Value::DateTime(a), Value::DateTime(b), '-') => a.since(*b).map(Value::Duration),
+        (Value::Duration(d), n, '*') | (n, Value::Duration(d), '*') => {
+            scale(n, *d, Duration::checked_mul, Duration::mul_f64)?.map(Value::Duration)
+        }
+        (Value::Duration(d), n, '/') => {
+            if matches!(n, Value::Int(0)) {
+                return Some(Err(error_msg("Division by zero")));
+            }
+            scale(n, *d, Duration::checked_div, Duration::div_f64)?.map(Value::Duration)
+        }
+        _ => return None,
+    };
+    Some(res.ok_or_else(|| error_msg(&format!("Time overflow on {} and {}", a, b))))
+}
+
+// An integer mixed with a float is promoted to a float.
+fn floats(a: &Value, b: &Value) -> Option<(f64, f64)> {
+    match (a, b) {
+        (Value::Number(a), Value::Int(b)) => Some((*a, *b as f64)),
+        (Value::Int(a), Value::Number(b)) => Some((*a as f64, *b)),
+        _ => None,
+    }
+}
+
+// Integers stay exact, an operation that would overflow them is an error rather than a float.
+fn int_op(a: i64, b: i64, op: fn(i64, i64) -> Option<i64>, sign: &str) -> Result<Value> {
+    op(a, b)
+        .map(Value::Int)
+        .ok_or_else(|| error_msg(&format!("Integer overflow on {} {} {}", a, sign, b)))
+}
+
 impl core::ops::Add for &Value {
     type Output = Result<Value>;
 
     #[inline(always)]
     fn add(self, other: Self) -> Self::Output {
         match (self, other) {
+            (Value::Int(a), Value::Int(b)) => int_op(*a, *b, i64::checked_add, "+"),
             (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
-            (a, b) => Err(error_msg(format!("Can't add {} + {}", a, b).as_str())),
+            (a, b) => floats(a, b)
+                .map(|(a, b)| Ok(Value::Number(a + b)))
+                .or_else(|| decimal_op(a, b, Decimal::checked_add))
+                .or_else(|| time_op(a, b, '+'))
+                .unwrap_or_else(|| Err(error_msg(format!("Can't add {} + {}", a, b).as_str()))),
         }
     }
 }
 
-impl core::ops::Sub for Value {
+impl core::ops::Sub for &Value {
     type Output = Result<Value>;
 
     #[inline(always)]
     fn sub(self, other: Self) -> Self::Output {
         match (self, other) {
+            (Value::Int(a), Value::Int(b)) => int_op(*a, *b, i64::checked_sub, "-"),
             (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
-            (a, b) => Err(error_msg(format!("Can't substract {} - {}", a, b).as_str())),
+            (a, b) => floats(a, b)
+                .map(|(a, b)| Ok(Value::Number(a - b)))
+                .or_else(|| decimal_op(a, b, Decimal::checked_sub))
+                .or_else(|| time_op(a, b, '-'))
+                .unwrap_or_else(|| {
+                    Err(error_msg(format!("Can't subtract {} - {}", a, b).as_str()))
+                }),
         }
     }
 }
 
-impl core::ops::Mul for Value {
+impl core::ops::Mul for &Value {
     type Output = Result<Value>;
 
     #[inline(always)]
     fn mul(self, other: Self) -> Self::Output {
         match (self, other) {
+            (Value::Int(a), Value::Int(b)) => int_op(*a, *b, i64::checked_mul, "*"),
             (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
-            (a, b) => Err(error_msg(format!("Can't multiply {} - {}", a, b).as_str())),
+            (a, b) => floats(a, b)
+                .map(|(a, b)| Ok(Value::Number(a * b)))
+                .or_else(|| decimal_op(a, b, Decimal::checked_mul))
+                .or_else(|| time_op(a, b, '*'))
+                .unwrap_or_else(|| {
+                    Err(error_msg(format!("Can't multiply {} * {}", a, b).as_str()))
+                }),
+        }
+    }
+}
+
+// A float divided by zero is an infinity or NaN, a decimal has neither.
+fn decimal_division(
+    a: &Value,
+    b: &Value,
+    op: fn(&Decimal, &Decimal) -> Option<Decimal>,
+) -> Option<Result<Value>> {
+    match decimals(a, b)? {
+        (_, d) if d.is_zero() => Some(Err(error_msg("Division by zero"))),
+        _ => decimal_op(a, b, op),
+    }
+}
+
+impl core::ops::Div for &Value {
+    type Output = Result<Value>;
+
+    // The quotient of integers is a float, exact or not
+    #[inline(always)]
+    fn div(self, other: Self) -> Self::Output {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Ok(Value::Number(*a as f64 / *b as f64)),
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a / b)),
+            (a, b) => floats(a, b)
+                .map(|(a, b)| Ok(Value::Number(a / b)))
+                .or_else(|| decimal_division(a, b, Decimal::checked_div))
+                .or_else(|| time_op(a, b, '/'))
+                .unwrap_or_else(|| Err(error_msg(format!("Can't divide {} / {}", a, b).as_str()))),
+        }
+    }
+}
+
+impl core::ops::Rem for &Value {
+    type Output = Result<Value>;
+
+    #[inline(always)]
+    fn rem(self, other: Self) -> Self::Output {
+        match (self, other) {
+            (Value::Int(_), Value::Int(0)) => Err(error_msg("Division by zero")),
+            (Value::Int(a), Value::Int(b)) => int_op(*a, *b, i64::checked_rem, "rem"),
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a % b)),
+            (a, b) => floats(a, b)
+                .map(|(a, b)| Ok(Value::Number(a % b)))
+                .or_else(|| decimal_division(a, b, Decimal::checked_rem))
+                .or_else(|| time_op(a, b, '%'))
+                .unwrap_or_else(|| {
+                    Err(error_msg(
+                        format!("Can't take the remainder of {} / {}", a, b).as_str(),
+                    ))
+                }),
         }
     }
 }
@@ -91,40 +274,259 @@ impl PartialEq for Value {
         match (self, other) {
             (Value::Nil, Value::Nil) => true,
             (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
             (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Decimal(a), Value::Decimal(b)) => a == b,
+            (Value::Duration(a), Value::Duration(b)) => a == b,
+            (Value::DateTime(a), Value::DateTime(b)) => a == b,
             (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            (Value::Keyword(a), Value::Keyword(b)) => a == b,
             (Value::Str(a), Value::Str(b)) => a == b,
-            (Value::List(a), Value::List(b)) => Arc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) | (Value::Vector(a), Value::Vector(b)) => {
+                Arc::ptr_eq(a, b)
+            }
             (Value::FuncNative(a), Value::FuncNative(b)) => Arc::ptr_eq(a, b),
-            (Value::Func(a), Value::Func(b)) => Arc::ptr_eq(a, b),
+            (Value::Func(a), Value::Func(b)) | (Value::Macro(a), Value::Macro(b)) => {
+                Arc::ptr_eq(a, b)
+            }
+            (Value::Coroutine(a), Value::Coroutine(b)) => Arc::ptr_eq(a, b),
+            (Value::Task(a), Value::Task(b)) => Arc::ptr_eq(a, b),
+            (Value::Channel(a), Value::Channel(b)) => Arc::ptr_eq(a, b),
+            (Value::Atom(a), Value::Atom(b)) => Arc::ptr_eq(a, b),
             (_, _) => false,
         }
     }
 }
 
-impl Default for Value {
-    fn default() -> Self {
-        Value::Nil
+// Compares values by their content rather than their identity, so the constants of two chunks
+// compiled from the same source are the same. Natives are known by their name and function.
+pub(crate) struct Structural<'a>(pub &'a Value);
+
+impl PartialEq for Structural<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.0, other.0) {
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            (Value::Decimal(a), Value::Decimal(b)) => a.identical(b),
+            (Value::List(a), Value::List(b)) | (Value::Vector(a), Value::Vector(b)) => {
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b.iter())
+                        .all(|(a, b)| Structural(a) == Structural(b))
+            }
+            (Value::FuncNative(a), Value::FuncNative(b)) => {
+                a.name == b.name && a.arity == b.arity && a.func.addr() == b.func.addr()
+            }
+            (Value::Func(a), Value::Func(b)) | (Value::Macro(a), Value::Macro(b)) => {
+                a.chunk == b.chunk
+                    && a.locals.len() == b.locals.len()
+                    && a.locals
+                        .iter()
+                        .zip(b.locals.iter())
+                        .all(|(a, b)| Structural(a) == Structural(b))
+            }
+            (Value::Closure(a), Value::Closure(b)) => a.outers == b.outers && a.chunk == b.chunk,
+            (a, b) => a == b,
+        }
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl Eq for Structural<'_> {}
+
+impl Hash for Structural<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self.0).hash(state);
+        match self.0 {
+            Value::Nil => {}
+            Value::Bool(b) => b.hash(state),
+            Value::Int(n) => n.hash(state),
+            Value::Number(n) => n.to_bits().hash(state),
+            Value::Decimal(d) => d.to_string().hash(state),
+            Value::Duration(d) => d.hash(state),
+            Value::DateTime(t) => t.hash(state),
+            Value::Symbol(s) | Value::Keyword(s) => s.hash(state),
+            Value::Str(s) => s.hash(state),
+            Value::List(list) | Value::Vector(list) => {
+                list.len().hash(state);
+                list.iter().for_each(|val| Structural(val).hash(state));
+            }
+            // Where the function lives changes from a run to the other
+            Value::FuncNative(f) => f.name.hash(state),
+            Value::Func(f) | Value::Macro(f) => f.chunk.hash(state),
+            Value::Closure(closure) => {
+                closure.outers.hash(state);
+                closure.chunk.hash(state);
+            }
+            Value::Coroutine(co) => Arc::as_ptr(co).hash(state),
+            Value::Task(task) => Arc::as_ptr(task).hash(state),
+            Value::Channel(chan) => Arc::as_ptr(chan).hash(state),
+            Value::Atom(atom) => Arc::as_ptr(atom).hash(state),
+        }
+    }
+}
+
+// What went wrong, and when: while reading the source, compiling it, or running it. The span is
+// where the faulty form starts, when it's known. The trace of a runtime error holds the calls that
+// led to it, innermost first.
+#[derive(Debug, Clone, PartialEq)]
 pub enum ZapErr {
-    Msg(std::string::String),
+    Reader {
+        msg: std::string::String,
+        span: Option<Span>,
+    },
+    Compile {
+        msg: std::string::String,
+        span: Option<Span>,
+    },
+    Runtime {
+        msg: std::string::String,
+        span: Option<Span>,
+        trace: Vec<std::string::String>,
+    },
+    Arity {
+        name: Option<std::string::String>, // None for an anonymous fn
+        expected: Arity,
+        got: usize,
+        span: Option<Span>,
+        trace: Vec<std::string::String>,
+    },
 }
 
+// The error of a native or of the VM, located later if it isn't caught.
 pub fn error_msg(msg: &str) -> ZapErr {
-    ZapErr::Msg(msg.to_string())
+    ZapErr::Runtime {
+        msg: msg.to_string(),
+        span: None,
+        trace: Vec::new(),
+    }
+}
+
+impl ZapErr {
+    pub fn reader(msg: &str) -> ZapErr {
+        ZapErr::Reader {
+            msg: msg.to_string(),
+            span: None,
+        }
+    }
+
+    pub fn compile(msg: &str) -> ZapErr {
+        ZapErr::Compile {
+            msg: msg.to_string(),
+            span: None,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ZapErr::Reader { .. } => "reader",
+            ZapErr::Compile { .. } => "compile",
+            ZapErr::Runtime { .. } => "runtime",
+            ZapErr::Arity { .. } => "arity",
+        }
+    }
+
+    // What went wrong, without where
+    pub fn message(&self) -> std::string::String {
+        match self {
+            ZapErr::Reader { msg, .. }
+            | ZapErr::Compile { msg, .. }
+            | ZapErr::Runtime { msg, .. } => msg.clone(),
+            ZapErr::Arity {
+                name: Some(name),
+                expected,
+                got,
+                ..
+            } => format!(
+                "Wrong number of args to '{}': expected {}, got {}.",
+                name, expected, got
+            ),
+            ZapErr::Arity {
+                name: None,
+                expected,
+                got,
+                ..
+            } => format!("Wrong number of args: expected {}, got {}.", expected, got),
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ZapErr::Reader { span, .. }
+            | ZapErr::Compile { span, .. }
+            | ZapErr::Runtime { span, .. }
+            | ZapErr::Arity { span, .. } => *span,
+        }
+    }
+
+    pub fn trace(&self) -> &[std::string::String] {
+        match self {
+            ZapErr::Runtime { trace, .. } | ZapErr::Arity { trace, .. } => trace,
+            _ => &[],
+        }
+    }
+
+    // The error, raised by the form at span. An error that already knows where it was raised
+    // keeps its span.
+    pub fn at(mut self, at: Span) -> ZapErr {
+        match &mut self {
+            ZapErr::Reader { span, .. }
+            | ZapErr::Compile { span, .. }
+            | ZapErr::Runtime { span, .. }
+            | ZapErr::Arity { span, .. } => {
+                span.get_or_insert(at);
+            }
+        }
+        self
+    }
+
+    pub fn with_trace(mut self, calls: Vec<std::string::String>) -> ZapErr {
+        if let ZapErr::Runtime { trace, .. } | ZapErr::Arity { trace, .. } = &mut self {
+            *trace = calls;
+        }
+        self
+    }
+
+    // The same error, found while compiling. A macro failing to expand is a compile error.
+    pub fn into_compile(self) -> ZapErr {
+        match self {
+            ZapErr::Reader { .. } | ZapErr::Compile { .. } => self,
+            err => ZapErr::Compile {
+                span: err.span(),
+                msg: err.message(),
+            },
+        }
+    }
 }
 
+impl std::fmt::Display for ZapErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(span) = self.span() {
+            write!(f, "{}: ", span)?;
+        }
+        write!(f, "{}", self.message())?;
+        for call in self.trace() {
+            write!(f, "\n  {}", call)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ZapErr {}
+
 //
 // ZapFn
 //
 
 #[derive(Debug)]
 pub struct Closure {
-    outers: Vec<Outer>,
-    chunk: Arc<Chunk>,
+    pub(crate) outers: Vec<Outer>,
+    pub(crate) chunk: Arc<Chunk>,
+}
+
+impl Closure {
+    // The chunk of the fns made from it
+    pub fn chunk(&self) -> &Arc<Chunk> {
+        &self.chunk
+    }
 }
 
 #[derive(Debug)]
@@ -134,33 +536,27 @@ pub struct ZapFn {
 }
 
 impl ZapFn {
-    pub fn new(scope_size: usize, chunk: Chunk) -> Value {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(scope_size: usize, chunk: Arc<Chunk>) -> Value {
         let arity: usize = chunk.arity.into();
         Value::Func(Arc::new(ZapFn {
             locals: vec![Value::Nil; scope_size - arity],
-            chunk: Arc::new(chunk),
+            chunk,
         }))
     }
 
-    pub fn new_closure(outers: Vec<Outer>, chunk: Chunk) -> Value {
-        Value::Closure(Arc::new(Closure {
-            outers,
-            chunk: Arc::new(chunk),
-        }))
+    pub fn new_closure(outers: Vec<Outer>, chunk: Arc<Chunk>) -> Value {
+        Value::Closure(Arc::new(Closure { outers, chunk }))
     }
 
-    pub fn from_closure(closure: Arc<Closure>, callframes: &[CallFrame], stack: &[Value]) -> Value {
+    pub(crate) fn from_closure(closure: Arc<Closure>, stack: &[Value]) -> Value {
+        // The outers are taken from the frame at the bottom of the given stack
         let arity: usize = closure.chunk.arity.into();
         let mut locals = vec![Value::default(); closure.chunk.scope_size - arity];
 
         for outer in &closure.outers {
             unsafe {
-                let base = if outer.level == 0 {
-                    0
-                } else {
-                    callframes.get_unchecked(outer.level - 1).get_ret()
-                };
-                let val = stack.get_unchecked(base + outer.position).clone();
+                let val = stack.get_unchecked(outer.position as usize).clone();
                 ptr::write(locals.as_mut_ptr().add((outer.dest as usize) - arity), val);
             }
         }
@@ -172,13 +568,213 @@ impl ZapFn {
     }
 }
 
+// How many args a function accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    #[inline]
+    pub fn accepts(self, argc: usize) -> bool {
+        match self {
+            Arity::Exactly(n) => argc == n,
+            Arity::AtLeast(n) => argc >= n,
+        }
+    }
+}
+
+impl std::fmt::Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Arity::Exactly(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+        }
+    }
+}
+
+// Most natives only need their args. The others are given a Ctx, to reach the env and call
+// back into zap. A closure keeps what the host gave it, like a handle to a database.
+pub enum NativeFunc {
+    Plain(fn(&[Value]) -> Result<Value>),
+    WithCtx(fn(&mut Ctx, &[Value]) -> Result<Value>),
+    Closure(Box<NativeClosure>),
+    Async(fn(&[Value]) -> Pending),
+}
+
+pub type NativeClosure = dyn Fn(&[Value]) -> Result<Value> + Send + Sync;
+
+// The value of an async native, the VM runs on once it's ready
+pub type Pending = Pin<Box<dyn Future<Output = Result<Value>> + Send>>;
+
+// The outcome of an evaluation running elsewhere, on a task or a thread of its own. What runs it
+// finishes it, and any number of joins wait for it.
+#[derive(Default)]
+pub struct Task {
+    state: Mutex<(Option<Result<Value>>, Vec<Waker>)>,
+}
+
+impl Task {
+    pub fn finish(&self, res: Result<Value>) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = Some(res);
+        state.1.drain(..).for_each(Waker::wake);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state.lock().unwrap().0.is_some()
+    }
+
+    // Ready with what the evaluation returned, or its error
+    pub fn join(self: &Arc<Self>) -> Pending {
+        Box::pin(Join(self.clone()))
+    }
+}
+
+struct Join(Arc<Task>);
+
+impl Future for Join {
+    type Output = Result<Value>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.0.state.lock().unwrap();
+        match &state.0 {
+            Some(res) => Poll::Ready(res.clone()),
+            None => {
+                if !state.1.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    state.1.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// Values sent from an evaluation to another, which may run on another task or thread. A recv
+// waits for a value while the channel is empty, and gets nil once it's closed.
+#[derive(Default)]
+pub struct Channel {
+    state: Mutex<ChannelState>,
+}
+
+#[derive(Default)]
+struct ChannelState {
+    queue: VecDeque<Value>,
+    closed: bool,
+    waiting: Vec<Waker>, // The recvs to poll again
+}
+
+impl Channel {
+    pub fn send(&self, val: Value) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(error_msg("The channel is closed."));
+        }
+        state.queue.push_back(val);
+        state.waiting.drain(..).for_each(Waker::wake);
+        Ok(())
+    }
+
+    // The values already sent can still be received
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.waiting.drain(..).for_each(Waker::wake);
+    }
+
+    pub fn recv(self: &Arc<Self>) -> Pending {
+        Box::pin(Recv(self.clone()))
+    }
+}
+
+struct Recv(Arc<Channel>);
+
+impl Future for Recv {
+    type Output = Result<Value>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.0.state.lock().unwrap();
+        if let Some(val) = state.queue.pop_front() {
+            return Poll::Ready(Ok(val));
+        }
+        if state.closed {
+            return Poll::Ready(Ok(Value::Nil));
+        }
+        if !state
+            .waiting
+            .iter()
+            .any(|waker| waker.will_wake(cx.waker()))
+        {
+            state.waiting.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl NativeFunc {
+    fn addr(&self) -> usize {
+        match self {
+            NativeFunc::Plain(f) => *f as usize,
+            NativeFunc::WithCtx(f) => *f as usize,
+            NativeFunc::Async(f) => *f as usize,
+            NativeFunc::Closure(f) => ptr::from_ref(&**f).cast::<()>() as usize,
+        }
+    }
+}
+
 pub struct ZapFnNative {
     pub name: String,
-    pub func: fn(&[Value]) -> Result<Value>,
+    pub arity: Arity,
+    pub func: NativeFunc,
 }
 
 impl ZapFnNative {
     pub fn new(name: String, func: fn(&[Value]) -> Result<Value>) -> Arc<ZapFnNative> {
-        Arc::new(ZapFnNative { name, func })
+        Self::with_arity(name, Arity::AtLeast(0), func)
+    }
+
+    pub fn with_arity(
+        name: String,
+        arity: Arity,
+        func: fn(&[Value]) -> Result<Value>,
+    ) -> Arc<ZapFnNative> {
+        Arc::new(ZapFnNative {
+            name,
+            arity,
+            func: NativeFunc::Plain(func),
+        })
+    }
+
+    pub fn with_ctx(
+        name: String,
+        arity: Arity,
+        func: fn(&mut Ctx, &[Value]) -> Result<Value>,
+    ) -> Arc<ZapFnNative> {
+        Arc::new(ZapFnNative {
+            name,
+            arity,
+            func: NativeFunc::WithCtx(func),
+        })
+    }
+
+    pub fn with_closure(name: String, arity: Arity, func: Box<NativeClosure>) -> Arc<ZapFnNative> {
+        Arc::new(ZapFnNative {
+            name,
+            arity,
+            func: NativeFunc::Closure(func),
+        })
+    }
+
+    pub fn with_async(
+        name: String,
+        arity: Arity,
+        func: fn(&[Value]) -> Pending,
+    ) -> Arc<ZapFnNative> {
+        Arc::new(ZapFnNative {
+            name,
+            arity,
+            func: NativeFunc::Async(func),
+        })
     }
 }